@@ -0,0 +1,83 @@
+//! What a million deposits take in memory with either [DepositStore], and how long looking one
+//! up for a dispute takes. Run with `cargo bench --bench deposits`.
+use rust_decimal::Decimal;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use track::account::{AccountState, DepositState};
+use track::deposits::{CompactDeposits, DepositStore, LazyDeposits, MapDeposits};
+use track::transaction::Transaction;
+
+/// Counts the bytes allocated, which is the memory that matters rather than the estimate.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const DEPOSITS: u32 = 1_000_000;
+const LOOKUPS: u32 = 1_000_000;
+
+/// Deposits recorded the way an account records them, taken from one that made them all.
+fn deposits() -> Vec<(u32, DepositState)> {
+    let mut account = AccountState::new();
+    for tx in 0..DEPOSITS {
+        account.transact(Transaction::Deposit {
+            client: 0,
+            tx,
+            amount: Decimal::new(i64::from(tx % 10_000) + 1, 2),
+            currency: None,
+            timestamp: None,
+        });
+    }
+    let mut deposits: Vec<_> = account.deposits.iter().collect();
+    deposits.sort_unstable_by_key(|(tx, _)| *tx);
+    deposits
+}
+
+fn measure<S: DepositStore>(name: &str, deposits: &[(u32, DepositState)]) {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let mut store = S::default();
+    for (tx, deposit) in deposits {
+        store.insert(*tx, *deposit);
+    }
+    let bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+
+    // Spread the lookups over the whole range, so they aren't all served from the cache.
+    let start = Instant::now();
+    for n in 0..LOOKUPS {
+        let tx = n.wrapping_mul(2_654_435_761) % DEPOSITS;
+        // Getting it for a change packs it back in for the compact store, even with no change.
+        black_box(store.get_mut(tx).unwrap().disputed());
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:>8}: {:>6.1} bytes per deposit, {:>6.1} ns per dispute lookup",
+        name,
+        bytes as f64 / f64::from(DEPOSITS),
+        elapsed.as_nanos() as f64 / f64::from(LOOKUPS)
+    );
+    black_box(store);
+}
+
+fn main() {
+    let deposits = deposits();
+    println!("{} deposits, {} lookups", DEPOSITS, LOOKUPS);
+    measure::<MapDeposits>("map", &deposits);
+    measure::<LazyDeposits>("lazy", &deposits);
+    measure::<CompactDeposits>("compact", &deposits);
+}
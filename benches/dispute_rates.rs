@@ -0,0 +1,134 @@
+//! Which [DepositStore] makes for the fastest transacting at realistic dispute rates. Disputes,
+//! resolves and chargebacks are usually well under 1% of the rows, so deposits are written a
+//! lot more than they're read. Run with `cargo bench --bench dispute_rates`.
+//!
+//! Every store goes through what [AccountState::transact] asks of it for each row: a deposit
+//! is checked for being a duplicate and recorded, and a dispute, resolve or chargeback changes
+//! its deposit in place.
+//! The end-to-end numbers are for accounts with the store the crate was built with, see
+//! [track::deposits::Deposits], so comparing those takes a run per feature.
+use rust_decimal::Decimal;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use track::account::{AccountState, DepositState};
+use track::deposits::{CompactDeposits, DepositStore, LazyDeposits, MapDeposits};
+use track::transaction::{ClientId, Transaction};
+use track::tune::Xorshift;
+
+const ROWS: u32 = 2_000_000;
+const CLIENTS: u32 = 1_000;
+/// Disputes per thousand rows.
+const RATES: [u32; 3] = [1, 10, 100];
+
+/// Deposits over all clients, with a dispute of an earlier deposit of the same client every so
+/// often, resolved right away. A chargeback would lock the account, and a locked account
+/// doesn't record deposits anymore. Tx ids increase, as they usually do, or decrease, which is
+/// the worst a store that counts on them increasing can get.
+fn workload(per_thousand: u32, increasing: bool) -> Vec<Transaction> {
+    let mut random = Xorshift::new(0x2545_f491_4f6c_dd1d);
+    let mut deposits: Vec<Vec<u32>> = vec![Vec::new(); CLIENTS as usize];
+    let mut rows = Vec::with_capacity(ROWS as usize);
+    for row in 0..ROWS {
+        let client = (row % CLIENTS) as ClientId;
+        let tx = if increasing { row } else { ROWS - 1 - row };
+        let made = &mut deposits[client as usize];
+        if made.is_empty() || random.below(1_000) >= u64::from(per_thousand) {
+            made.push(tx);
+            rows.push(Transaction::Deposit {
+                client,
+                tx,
+                amount: Decimal::new(i64::from(tx % 10_000) + 1, 2),
+                currency: None,
+                timestamp: None,
+            });
+            continue;
+        }
+        let disputed = made[random.below(made.len() as u64) as usize];
+        rows.push(Transaction::Dispute {
+            client,
+            tx: disputed,
+            timestamp: None,
+        });
+        rows.push(Transaction::Resolve {
+            client,
+            tx: disputed,
+            amount: None,
+            timestamp: None,
+        });
+    }
+    rows
+}
+
+/// What a deposit looks like once recorded, taken from an account that made one.
+fn recorded(amount: Decimal) -> DepositState {
+    let mut account = AccountState::new();
+    account.transact(Transaction::Deposit {
+        client: 0,
+        tx: 0,
+        amount,
+        currency: None,
+        timestamp: None,
+    });
+    account.deposits.get(0).unwrap()
+}
+
+/// Puts the rows through a store per client, the way accounts would.
+fn replay<S: DepositStore>(rows: &[Transaction], deposit: DepositState) -> Duration {
+    let mut stores = vec![S::default(); CLIENTS as usize];
+    let start = Instant::now();
+    for row in rows {
+        let store = &mut stores[*row.id() as usize];
+        let tx = row.tx();
+        match row {
+            Transaction::Deposit { .. } => {
+                if !store.contains_key(tx) {
+                    store.insert(tx, deposit);
+                }
+            }
+            _ => {
+                black_box(store.get_mut(tx).map(|deposit| deposit.disputed()));
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+    black_box(stores);
+    elapsed
+}
+
+/// The rows through actual accounts, with whatever store they were built with.
+fn transact(rows: &[Transaction]) -> Duration {
+    let mut accounts = vec![AccountState::new(); CLIENTS as usize];
+    let start = Instant::now();
+    for row in rows {
+        black_box(accounts[*row.id() as usize].transact(row.clone()));
+    }
+    let elapsed = start.elapsed();
+    black_box(accounts);
+    elapsed
+}
+
+fn main() {
+    let deposit = recorded(Decimal::new(12345, 2));
+    println!(
+        "{} rows over {} clients, end to end with {}",
+        ROWS,
+        CLIENTS,
+        std::any::type_name::<track::deposits::Deposits>()
+    );
+    for (increasing, order) in [(true, "increasing"), (false, "decreasing")] {
+        for per_thousand in RATES {
+            let rows = workload(per_thousand, increasing);
+            let throughput = |elapsed: Duration| rows.len() as f64 / elapsed.as_secs_f64() / 1e6;
+            println!(
+                "{:>5.1}% disputes, {} tx ids: map {:>6.2}, lazy {:>6.2}, compact {:>6.2}, \
+                 end to end {:>6.2} million rows/s",
+                f64::from(per_thousand) / 10.0,
+                order,
+                throughput(replay::<MapDeposits>(&rows, deposit)),
+                throughput(replay::<LazyDeposits>(&rows, deposit)),
+                throughput(replay::<CompactDeposits>(&rows, deposit)),
+                throughput(transact(&rows)),
+            );
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! How long writing a report of a million rows takes with every [FlushPolicy], to a file so
+//! that every flush is a syscall. Run with `cargo bench --bench flush`.
+use rust_decimal::Decimal;
+use std::fs::File;
+use std::io::BufWriter;
+use std::time::{Duration, Instant};
+use track::currency::Currency;
+use track::flush::{FlushPolicy, ReportWriter};
+use track::system::{ReportOptions, ShardedAccountSystem};
+use track::transaction::Transaction;
+
+const CLIENTS: usize = 50_000;
+/// Every account holds this many currencies, and the report has a row for each.
+const CURRENCIES: u8 = 20;
+const SHARDS: usize = 256;
+
+fn main() {
+    let currencies: Vec<Currency> = (0..CURRENCIES)
+        .map(|n| {
+            let code = [b'A' + n / 26, b'A' + n % 26, b'X'];
+            std::str::from_utf8(&code).unwrap().parse().unwrap()
+        })
+        .collect();
+    let mut system = ShardedAccountSystem::new(SHARDS);
+    let mut tx = 0;
+    for client in (0..).take(CLIENTS) {
+        for currency in currencies.iter() {
+            system.transact(Transaction::Deposit {
+                client,
+                tx,
+                amount: Decimal::new(12345, 2),
+                currency: Some(*currency),
+                timestamp: None,
+            });
+            tx += 1;
+        }
+    }
+    let options = ReportOptions {
+        currencies: true,
+        ..ReportOptions::default()
+    };
+    let path = std::env::temp_dir().join(format!("track-flush-{}.csv", std::process::id()));
+    let policies = [
+        ("end", FlushPolicy::AtEnd),
+        ("per shard", FlushPolicy::PerShard),
+        ("every 1000 rows", FlushPolicy::EveryRows(1000)),
+        ("every row", FlushPolicy::EveryRows(1)),
+        ("every 10ms", FlushPolicy::Every(Duration::from_millis(10))),
+    ];
+    println!(
+        "{} rows, {} shards",
+        CLIENTS * usize::from(CURRENCIES),
+        SHARDS
+    );
+    for (name, policy) in policies {
+        // The buffer makes flushes the only syscalls that differ between policies.
+        let file = BufWriter::new(File::create(&path).unwrap());
+        let start = Instant::now();
+        let mut writer = ReportWriter::new(file, policy);
+        system.write(&mut writer, options).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        println!("{:>16}: {:?}", name, start.elapsed());
+    }
+    std::fs::remove_file(&path).unwrap();
+}
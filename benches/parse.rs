@@ -0,0 +1,113 @@
+//! How long reading rows from a large in-memory CSV takes with the `type` column read into
+//! [track::transaction::RecordType], against reading it into a `String` the way [Input] used
+//! to, how long parsing its amounts takes with [track::fast_input::decimal] against
+//! [Decimal::from_str], and how long processing the same file takes with and without the fast
+//! path of [track::config::Config::fast_parse]. Run with `cargo bench --bench parse`.
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::fmt::Write;
+use std::hint::black_box;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use track::config::Config;
+use track::transaction::ClientId;
+use track::Input;
+
+const ROWS: u32 = 2_000_000;
+
+/// [Input] as it was, with a string for the type. About a third slower to read, for the
+/// allocation every row takes.
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct StringInput {
+    #[serde(rename = "type")]
+    type_: String,
+    client: ClientId,
+    tx: u32,
+    amount: Option<Decimal>,
+}
+
+fn csv() -> String {
+    let types = ["deposit", "withdrawal", "deposit", "dispute", "resolve"];
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 0..ROWS {
+        let type_ = types[tx as usize % types.len()];
+        let amount = match type_ {
+            "dispute" | "resolve" => String::new(),
+            _ => format!("{}.{:04}", tx % 1_000, tx % 10_000),
+        };
+        writeln!(csv, "{},{},{},{}", type_, tx % 1_000, tx, amount).unwrap();
+    }
+    csv
+}
+
+/// Reads every row the way the pipeline does, a record at a time.
+fn read<T: for<'de> Deserialize<'de>>(csv: &str) -> Duration {
+    let mut reader = csv::Reader::from_reader(csv.as_bytes());
+    let headers = reader.headers().unwrap().clone();
+    let start = Instant::now();
+    for record in reader.records() {
+        let row: T = record.unwrap().deserialize(Some(&headers)).unwrap();
+        black_box(row);
+    }
+    start.elapsed()
+}
+
+/// Parses every amount of the file with `parse`.
+fn amounts(csv: &str, parse: impl Fn(&str) -> Decimal) -> Duration {
+    let amounts: Vec<&str> = csv
+        .lines()
+        .filter_map(|line| line.rsplit(',').next())
+        .filter(|amount| amount.contains('.'))
+        .collect();
+    let start = Instant::now();
+    for amount in amounts {
+        black_box(parse(black_box(amount)));
+    }
+    start.elapsed()
+}
+
+/// The whole run, report and all.
+fn process(csv: &str, fast_parse: bool) -> Duration {
+    let config = Config {
+        fast_parse,
+        ..Config::default()
+    };
+    let start = Instant::now();
+    track::process(&config, csv.as_bytes(), std::io::sink()).unwrap();
+    start.elapsed()
+}
+
+fn main() {
+    let csv = csv();
+    println!("{} rows, {} bytes", ROWS, csv.len());
+    for _ in 0..3 {
+        let string = read::<StringInput>(&csv);
+        let record_type = read::<Input>(&csv);
+        println!(
+            "string {:>6.0} ms, record type {:>6.0} ms",
+            string.as_secs_f64() * 1e3,
+            record_type.as_secs_f64() * 1e3,
+        );
+    }
+    for _ in 0..3 {
+        let general = amounts(&csv, |amount| Decimal::from_str(amount).unwrap());
+        let fast = amounts(&csv, |amount| {
+            track::fast_input::decimal(amount.as_bytes()).unwrap()
+        });
+        println!(
+            "amounts from_str {:>6.0} ms, fast {:>6.0} ms",
+            general.as_secs_f64() * 1e3,
+            fast.as_secs_f64() * 1e3,
+        );
+    }
+    for _ in 0..3 {
+        let serde = process(&csv, false);
+        let fast = process(&csv, true);
+        println!(
+            "process through serde {:>6.0} ms, fast {:>6.0} ms",
+            serde.as_secs_f64() * 1e3,
+            fast.as_secs_f64() * 1e3,
+        );
+    }
+}
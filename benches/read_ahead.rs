@@ -0,0 +1,54 @@
+//! How much reading the input on a thread of its own takes off processing a large in-memory
+//! CSV, see [track::config::Config::read_ahead], with the engine on one thread and with a
+//! thread per shard. It takes a spare core to make a difference. Run with
+//! `cargo bench --bench read_ahead`.
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+use track::config::Config;
+
+const ROWS: u32 = 2_000_000;
+
+fn csv() -> String {
+    let types = ["deposit", "withdrawal", "deposit", "dispute", "resolve"];
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 0..ROWS {
+        let type_ = types[tx as usize % types.len()];
+        let amount = match type_ {
+            "dispute" | "resolve" => String::new(),
+            _ => format!("{}.{:04}", tx % 1_000, tx % 10_000),
+        };
+        writeln!(csv, "{},{},{},{}", type_, tx % 1_000, tx, amount).unwrap();
+    }
+    csv
+}
+
+/// The whole run, report and all.
+fn process(csv: &str, read_ahead: usize, parallel: bool) -> Duration {
+    let config = Config {
+        read_ahead,
+        parallel,
+        ..Config::default()
+    };
+    let start = Instant::now();
+    track::process(&config, csv.as_bytes(), std::io::sink()).unwrap();
+    start.elapsed()
+}
+
+fn main() {
+    let csv = csv();
+    println!("{} rows, {} bytes", ROWS, csv.len());
+    // Not the default, which is to not read ahead on a single core.
+    let read_ahead = 64 * 1024;
+    for parallel in [false, true] {
+        for _ in 0..3 {
+            let one_thread = process(&csv, 0, parallel);
+            let overlapped = process(&csv, read_ahead, parallel);
+            println!(
+                "{:>8}: reading on the engine's thread {:>6.0} ms, ahead of it {:>6.0} ms",
+                if parallel { "parallel" } else { "serial" },
+                one_thread.as_secs_f64() * 1e3,
+                overlapped.as_secs_f64() * 1e3,
+            );
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! What looking up a deposit costs once it's spilled to disk, compared to one in memory, see
+//! [SpillDeposits]. Run with `cargo bench --bench spill --features spill-deposits`.
+//!
+//! A spilled deposit is a seek and a read of the spill file under a lock, served from the page
+//! cache here since the file is fresh. On the machine this was written on that took about
+//! 0.9 µs against 90 ns in memory, ten times as long. That's fine for disputes at realistic
+//! rates, but not for anything that looks at every deposit.
+use rust_decimal::Decimal;
+use std::hint::black_box;
+use std::time::Instant;
+use track::account::{AccountState, DepositState};
+use track::deposits::DepositStore;
+use track::spill::{SpillDeposits, SpillFile};
+use track::transaction::Transaction;
+
+const DEPOSITS: u32 = 1_000_000;
+const LOOKUPS: u32 = 1_000_000;
+
+/// What a deposit looks like once recorded, taken from an account that made one.
+fn recorded(amount: Decimal) -> DepositState {
+    let mut account = AccountState::new();
+    account.transact(Transaction::Deposit {
+        client: 0,
+        tx: 0,
+        amount,
+        currency: None,
+        timestamp: None,
+    });
+    account.deposits.get(0).unwrap()
+}
+
+/// Nanoseconds per lookup, spread over the whole range so they aren't all served from the
+/// cache.
+fn lookups(store: &SpillDeposits) -> f64 {
+    let start = Instant::now();
+    for n in 0..LOOKUPS {
+        let tx = n.wrapping_mul(2_654_435_761) % DEPOSITS;
+        black_box(store.get(tx).unwrap());
+    }
+    start.elapsed().as_nanos() as f64 / f64::from(LOOKUPS)
+}
+
+fn main() {
+    let deposit = recorded(Decimal::new(12345, 2));
+    // Without a spill file yet, everything stays in memory.
+    let mut store = SpillDeposits::default();
+    for tx in 0..DEPOSITS {
+        store.insert(tx, deposit);
+    }
+    let hot = lookups(&store);
+
+    let path = std::env::temp_dir().join(format!("track-spill-bench-{}", std::process::id()));
+    store.attach(&SpillFile::create(&path, None).unwrap());
+    let start = Instant::now();
+    let spilled = store.spill();
+    let spilling = start.elapsed();
+    let cold = lookups(&store);
+    std::fs::remove_file(&path).unwrap();
+
+    println!("{} deposits, {} lookups", DEPOSITS, LOOKUPS);
+    println!("spilling all of them took {:?}", spilling);
+    println!(
+        "in memory {:>7.1} ns, spilled {:>7.1} ns per lookup, {:.0} times as long",
+        hot,
+        cold,
+        cold / hot
+    );
+    assert_eq!(spilled, DEPOSITS as usize);
+}
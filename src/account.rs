@@ -1,27 +1,344 @@
-use crate::Transaction;
+use crate::currency::Currency;
+use crate::deposits::{DepositStore, Deposits};
+use crate::invariants::{self, Violation};
+use crate::policy::{AccountPolicy, NegativePolicy};
+use crate::transaction::Transaction;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::fmt;
 use rust_decimal::prelude::Zero;
 use rust_decimal::Decimal;
-use std::collections::HashMap;
+
+/// What adjustments are kept in, by transaction id, and deposits with `map-deposits`. Without
+/// `std` there is no `HashMap`, so it's a `BTreeMap` then.
+#[cfg(feature = "std")]
+pub type Map<K, V> = std::collections::HashMap<K, V>;
+#[cfg(not(feature = "std"))]
+pub type Map<K, V> = BTreeMap<K, V>;
 
 /// Apart from the amount of the deposit, a deposit could be disputed as well as
 /// it could be linked to a chargeback. It is easy to store that state in a structure
 /// private to the module for convenience.
+///
+/// Withdrawals are recorded the same way when [AccountPolicy::disputable_withdrawals] allows
+/// disputing them, see [DepositState::is_withdrawal].
 #[derive(Debug, Copy, Clone, Hash, PartialEq)]
 pub struct DepositState {
-    amount: Decimal,
-    dispute: bool,
-    chargeback: bool,
+    pub(crate) amount: Decimal,
+    pub(crate) withdrawal: bool,
+    pub(crate) dispute: bool,
+    /// How much of the deposit was charged back, see [Transaction::Chargeback].
+    pub(crate) chargeback: Option<Decimal>,
+    /// Timestamp of the dispute that is currently open, if the dispute came with one.
+    pub(crate) disputed_at: Option<u64>,
+    pub(crate) currency: Option<Currency>,
+    /// The account's [AccountState::operations] when a dispute of the deposit was last
+    /// resolved. Only kept under [AccountPolicy::redispute_window].
+    pub(crate) resolved_at: Option<u64>,
 }
 
 impl DepositState {
     /// A simple constructor. Serves no other purpose than convenience.
-    fn new(amount: Decimal) -> Self {
+    pub(crate) fn new(amount: Decimal, currency: Option<Currency>) -> Self {
         DepositState {
             amount,
+            withdrawal: false,
             dispute: false,
-            chargeback: false,
+            chargeback: None,
+            disputed_at: None,
+            currency,
+            resolved_at: None,
+        }
+    }
+
+    /// A withdrawal that can be disputed, see [AccountPolicy::disputable_withdrawals].
+    pub(crate) fn withdrawal(amount: Decimal, currency: Option<Currency>) -> Self {
+        DepositState {
+            withdrawal: true,
+            ..DepositState::new(amount, currency)
+        }
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    /// Whether this records a withdrawal rather than a deposit. Disputing a withdrawal holds
+    /// the withdrawn funds as if they had been credited back, and charging it back reverses
+    /// the withdrawal for good.
+    pub fn is_withdrawal(&self) -> bool {
+        self.withdrawal
+    }
+
+    /// The currency of the deposit, which is also the one disputes hold funds in.
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    pub fn disputed(&self) -> bool {
+        self.dispute
+    }
+
+    pub fn charged_back(&self) -> bool {
+        self.chargeback.is_some()
+    }
+
+    /// The part of the deposit that was reversed by a chargeback, if any.
+    pub fn charged_back_amount(&self) -> Option<Decimal> {
+        self.chargeback
+    }
+
+    /// When the open dispute was raised. `None` if there is no open dispute or it had no
+    /// timestamp.
+    pub fn disputed_at(&self) -> Option<u64> {
+        self.disputed_at
+    }
+
+    /// How many operations the account had seen when a dispute of the deposit was last
+    /// resolved, see [AccountPolicy::redispute_window].
+    pub fn resolved_at(&self) -> Option<u64> {
+        self.resolved_at
+    }
+}
+
+/// Funds in a currency other than the implicit one. [AccountState] keeps the implicit
+/// currency in fields of its own, see [AccountState::balance].
+#[derive(Debug, Default, Copy, Clone, Hash, PartialEq, Eq)]
+pub struct Balance {
+    pub held: Decimal,
+    pub total: Decimal,
+    /// What was paid out when the account was closed.
+    pub payout: Decimal,
+}
+
+impl Balance {
+    /// Saturates rather than panics for balances at the edge of what a [Decimal] can hold,
+    /// see [RejectReason::Overflow].
+    pub fn available(&self) -> Decimal {
+        self.total.saturating_sub(self.held)
+    }
+
+    /// More is held than there is in total, see [AccountState::deficit].
+    pub fn deficit(&self) -> bool {
+        self.held > self.total
+    }
+}
+
+/// Mutable access to the funds in one currency, see [AccountState::funds].
+struct Funds<'a> {
+    held: &'a mut Decimal,
+    total: &'a mut Decimal,
+}
+
+/// Fees and interest are kept for the audit trail. Unlike deposits they can't be disputed,
+/// unless [AccountPolicy::disputable_interest] says otherwise.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum Adjustment {
+    Fee(Decimal),
+    Interest(Decimal),
+}
+
+/// Invalid transactions (withdrawing more than what's available, disputing unknown deposits,
+/// etc.) are not errors, they simply don't happen. Callers that care can tell from this.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum TransactionOutcome {
+    /// The transaction changed the account.
+    Applied,
+    /// The transaction was not valid for the account and left it untouched, for the given
+    /// reason.
+    Ignored(IgnoreReason),
+    /// The transaction was refused outright, for the given reason.
+    Rejected(RejectReason),
+}
+
+/// Why a transaction was ignored.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IgnoreReason {
+    /// The account is locked, see [AccountState::locked].
+    Locked,
+    /// The transaction id is taken by an earlier deposit or adjustment.
+    DuplicateTx,
+    /// The withdrawal or fee exceeds the available funds.
+    InsufficientFunds,
+    /// The dispute, resolve or chargeback refers to a transaction the account doesn't have.
+    UnknownTx,
+    /// The dispute refers to a deposit that was charged back already.
+    ChargedBack,
+    /// The dispute refers to a deposit that is disputed already. Its funds are held once.
+    AlreadyDisputed,
+    /// The resolve or chargeback refers to a deposit that isn't disputed.
+    NotDisputed,
+    /// The amount of a partial resolve or chargeback is out of range, or a resolve for less
+    /// refers to a withdrawal.
+    InvalidAmount,
+    /// Transactions of the type are turned off, see [AccountPolicy::disabled].
+    Disabled,
+}
+
+impl IgnoreReason {
+    /// A short, stable name for logs and other machine-readable output.
+    pub fn code(&self) -> &'static str {
+        match self {
+            IgnoreReason::Locked => "locked",
+            IgnoreReason::DuplicateTx => "duplicate_tx",
+            IgnoreReason::InsufficientFunds => "insufficient_funds",
+            IgnoreReason::UnknownTx => "unknown_tx",
+            IgnoreReason::ChargedBack => "charged_back",
+            IgnoreReason::AlreadyDisputed => "already_disputed",
+            IgnoreReason::NotDisputed => "not_disputed",
+            IgnoreReason::InvalidAmount => "invalid_amount",
+            IgnoreReason::Disabled => "disabled",
+        }
+    }
+}
+
+impl fmt::Display for IgnoreReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+/// Why a transaction was rejected rather than just ignored.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum RejectReason {
+    /// There was no shard to route the transaction to.
+    NoShard,
+    /// The system is shut down, see [crate::parallel::ParallelAccountSystem::shutdown].
+    ShutDown,
+    /// The account is closed, see [Transaction::Close].
+    Closed,
+    /// The transaction would have taken funds below zero, see [NegativePolicy::Reject].
+    NegativeBalance,
+    /// The transaction would have taken funds beyond what a [Decimal] can hold.
+    Overflow,
+    /// The deposit would have taken the total beyond the cap, see [AccountPolicy::balance_cap].
+    BalanceCap(Decimal),
+    /// The dispute would have opened more disputes than the cap allows, see
+    /// [AccountPolicy::max_open_disputes].
+    DisputeCap(u32),
+    /// The deposit was resolved too many operations ago to be disputed again, see
+    /// [AccountPolicy::redispute_window].
+    RedisputeWindow(u32),
+    /// The account is locked and takes no disputes or resolves, see
+    /// [crate::policy::LockedPolicy::disputes].
+    Locked,
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::NoShard => write!(f, "no shard to route the transaction to"),
+            RejectReason::ShutDown => write!(f, "the system is shut down"),
+            RejectReason::Closed => write!(f, "the account is closed"),
+            RejectReason::NegativeBalance => write!(f, "funds would go below zero"),
+            RejectReason::Overflow => write!(f, "funds would overflow"),
+            RejectReason::BalanceCap(cap) => {
+                write!(f, "total would exceed the balance cap of {}", cap)
+            }
+            RejectReason::DisputeCap(cap) => {
+                write!(f, "there are {} open disputes already", cap)
+            }
+            RejectReason::RedisputeWindow(window) => {
+                write!(
+                    f,
+                    "the dispute was resolved more than {} operations ago",
+                    window
+                )
+            }
+            RejectReason::Locked => write!(f, "the account is locked"),
+        }
+    }
+}
+
+const OVERFLOW: TransactionOutcome = TransactionOutcome::Rejected(RejectReason::Overflow);
+
+/// What an account looked like before a transaction was applied, as far as the transaction
+/// changed it. See [AccountState::undo_last], and [NegativePolicy::Reject] which puts it back
+/// right away. The scalars are copied up front, the rest is recorded by [AccountState::apply]
+/// as it goes, so nothing the transaction leaves alone is read or copied.
+#[derive(Debug, Clone)]
+struct UndoEntry {
+    held: Decimal,
+    total: Decimal,
+    chargebacks: u32,
+    disputed: u32,
+    operations: u64,
+    first_activity: Option<u64>,
+    last_activity: Option<u64>,
+    closed: bool,
+    closed_at: Option<u64>,
+    payout: Decimal,
+    deposited: Decimal,
+    withdrawn: Decimal,
+    /// The named currency the transaction changed, with its balance if it had one.
+    currency: Option<(Currency, Option<Balance>)>,
+    /// Every named currency, once a transaction changed more than one, as closing does.
+    currencies: Option<BTreeMap<Currency, Balance>>,
+    /// The id of the deposit the transaction recorded or changed, with what was recorded
+    /// under it.
+    deposit: Option<(u32, Option<DepositState>)>,
+    /// The id of the adjustment the transaction recorded.
+    adjustment: Option<u32>,
+}
+
+impl UndoEntry {
+    fn new(account: &AccountState) -> Self {
+        UndoEntry {
+            held: account.held,
+            total: account.total,
+            chargebacks: account.chargebacks,
+            disputed: account.disputed,
+            operations: account.operations,
+            first_activity: account.first_activity,
+            last_activity: account.last_activity,
+            closed: account.closed,
+            closed_at: account.closed_at,
+            payout: account.payout,
+            deposited: account.deposited,
+            withdrawn: account.withdrawn,
+            currency: None,
+            currencies: None,
+            deposit: None,
+            adjustment: None,
+        }
+    }
+
+    /// Keeps the balance of `currency` in `currencies`, about to be changed.
+    fn record_currency(&mut self, currencies: &BTreeMap<Currency, Balance>, currency: Currency) {
+        if self.currencies.is_some() {
+            return;
+        }
+        match self.currency {
+            None => self.currency = Some((currency, currencies.get(&currency).copied())),
+            Some((recorded, _)) if recorded == currency => {}
+            Some((recorded, balance)) => {
+                let mut all = currencies.clone();
+                match balance {
+                    Some(balance) => all.insert(recorded, balance),
+                    None => all.remove(&recorded),
+                };
+                self.currency = None;
+                self.currencies = Some(all);
+            }
         }
     }
+
+    /// The balance of `currency` before the transaction, `account` being what it left.
+    fn balance(&self, account: &AccountState, currency: Option<Currency>) -> Balance {
+        let Some(currency) = currency else {
+            return Balance {
+                held: self.held,
+                total: self.total,
+                payout: self.payout,
+            };
+        };
+        let balance = match (&self.currencies, self.currency) {
+            (Some(all), _) => all.get(&currency).copied(),
+            (None, Some((recorded, balance))) if recorded == currency => balance,
+            _ => account.currencies.get(&currency).copied(),
+        };
+        balance.unwrap_or_default()
+    }
 }
 
 /// At any given point an account will have a state that is represented by this structure.
@@ -31,89 +348,700 @@ impl DepositState {
 /// have gone ahead and stored this in an RDBMS. The benefits of that are that many of
 /// the calculations can be done as a complex SQL query without any need for network I/O between
 /// database an application code.
+#[derive(Debug, Clone)]
 pub struct AccountState {
+    /// Funds in the implicit currency. Those in named currencies are in `currencies`.
     pub held: Decimal,
     pub total: Decimal,
+    pub currencies: BTreeMap<Currency, Balance>,
     pub chargebacks: u32,
-    pub deposits: HashMap<u32, DepositState>,
+    /// How many deposits are under dispute right now, see [AccountState::open_disputes].
+    pub disputed: u32,
+    /// How many transactions were applied to the account. Ignored and rejected ones don't
+    /// count. Like the journal, this is history, and two accounts can be equal without it.
+    pub operations: u64,
+    /// An operator can freeze an account administratively. Unlike a chargeback, that lock can
+    /// be lifted again, which is why we don't fold it into `chargebacks`.
+    pub frozen: bool,
+    pub deposits: Deposits,
+    pub adjustments: Map<u32, Adjustment>,
+    /// Earliest and latest timestamps of transactions routed to this account. Feeds may
+    /// arrive out of order, so these are the minimum and maximum rather than first and last seen.
+    pub first_activity: Option<u64>,
+    pub last_activity: Option<u64>,
+    /// A closed account only takes disputes, resolves and chargebacks anymore, so that
+    /// deposits made before the closure can still be disputed.
+    pub closed: bool,
+    /// When the account was closed, if the closing transaction had a timestamp.
+    pub closed_at: Option<u64>,
+    /// The funds in the implicit currency that were available at closure and got paid out to
+    /// the client.
+    pub payout: Decimal,
+    /// Everything deposited and withdrawn in the implicit currency, see
+    /// [AccountState::net_flow].
+    deposited: Decimal,
+    withdrawn: Decimal,
+    /// The most recently applied transactions, latest last, see [AccountPolicy::undo_depth].
+    journal: VecDeque<UndoEntry>,
+}
+
+/// The journal is history rather than state, so two accounts that got to the same place in
+/// different ways are still equal.
+impl PartialEq for AccountState {
+    fn eq(&self, other: &Self) -> bool {
+        self.held == other.held
+            && self.total == other.total
+            && self.currencies == other.currencies
+            && self.chargebacks == other.chargebacks
+            && self.disputed == other.disputed
+            && self.frozen == other.frozen
+            && self.deposits == other.deposits
+            && self.adjustments == other.adjustments
+            && self.first_activity == other.first_activity
+            && self.last_activity == other.last_activity
+            && self.closed == other.closed
+            && self.closed_at == other.closed_at
+            && self.payout == other.payout
+            && self.deposited == other.deposited
+            && self.withdrawn == other.withdrawn
+    }
+}
+
+impl Default for AccountState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AccountState {
+    /// Like [Balance::available], this saturates rather than panics.
     pub fn available(&self) -> Decimal {
-        self.total - self.held
+        self.total.saturating_sub(self.held)
+    }
+
+    /// What would be available if every open dispute got resolved in the client's favour.
+    /// [AccountState::available] remains the conservative figure.
+    pub fn optimistic_available(&self) -> Decimal {
+        self.total
     }
 
     pub fn locked(&self) -> bool {
-        self.chargebacks != 0
+        self.chargebacks != 0 || self.frozen
+    }
+
+    /// Everything ever deposited in the implicit currency. Disputes and chargebacks don't
+    /// change what came in, unlike the balance.
+    pub fn gross_deposits(&self) -> Decimal {
+        self.deposited
+    }
+
+    /// Everything ever withdrawn in the implicit currency.
+    pub fn gross_withdrawals(&self) -> Decimal {
+        self.withdrawn
+    }
+
+    /// Deposits minus withdrawals, i.e. what the client moved in on balance, regardless of
+    /// what disputes did to it. Fees, interest and payouts at closure aren't part of it.
+    pub fn net_flow(&self) -> Decimal {
+        self.deposited.saturating_sub(self.withdrawn)
+    }
+
+    /// Some currency holds more than there is in total, i.e. the account owes us. Only
+    /// [NegativePolicy::Allow] lets a dispute of funds that are gone already get that far, and
+    /// an overdrawing fee has the same effect. Deposits that cover the shortfall clear it.
+    pub fn deficit(&self) -> bool {
+        self.balances().any(|(_, balance)| balance.deficit())
+    }
+
+    /// Deposits under dispute that haven't been charged back, i.e. whose funds are held while
+    /// waiting for a resolution.
+    pub fn open_disputes(&self) -> impl Iterator<Item = (u32, DepositState)> + '_ {
+        self.deposits
+            .iter()
+            .filter(|(_, deposit)| deposit.dispute && !deposit.charged_back())
+    }
+
+    /// Roughly how many bytes the account takes, for capacity planning: the struct itself
+    /// plus an entry for every deposit, adjustment, currency and journal entry. Computed from
+    /// the sizes of the types, not measured, so the maps' spare capacity and bookkeeping
+    /// aren't in it.
+    pub fn memory_footprint(&self) -> usize {
+        use core::mem::size_of;
+        let currency = size_of::<Currency>() + size_of::<Balance>();
+        let journal: usize = self
+            .journal
+            .iter()
+            .map(|entry| {
+                let currencies = entry.currencies.as_ref().map_or(0, BTreeMap::len);
+                size_of::<UndoEntry>() + currencies * currency
+            })
+            .sum();
+        size_of::<Self>()
+            + self.deposits.memory_footprint()
+            + self.adjustments.len() * (size_of::<u32>() + size_of::<Adjustment>())
+            + self.currencies.len() * currency
+            + journal
+    }
+
+    /// The open disputes making up what's held in the implicit currency, as transaction ids
+    /// and amounts in ascending order of id. They add up to `held`, unless the
+    /// [NegativePolicy] allowed or clamped a transaction that broke the invariants.
+    pub fn held_breakdown(&self) -> Vec<(u32, Decimal)> {
+        let mut breakdown: Vec<(u32, Decimal)> = self
+            .open_disputes()
+            .filter(|(_, deposit)| deposit.currency.is_none())
+            .map(|(tx, deposit)| (tx, deposit.amount))
+            .collect();
+        breakdown.sort_unstable();
+        breakdown
+    }
+
+    /// Nothing held, nothing in total in any currency and not locked, e.g. after withdrawing
+    /// everything. A closed account is never empty, its payout still needs reporting.
+    pub fn is_empty(&self) -> bool {
+        self.balances()
+            .all(|(_, balance)| balance.total.is_zero() && balance.held.is_zero())
+            && !self.locked()
+            && !self.closed
+    }
+
+    /// Whether the account can be forgotten without losing anything: no funds in any
+    /// currency, no deposits that could still be disputed, no fees or interest on record, not
+    /// locked or closed, and no activity at or after `horizon` (seconds since the Unix epoch).
+    pub fn is_disposable(&self, horizon: u64) -> bool {
+        self.is_empty()
+            && self.deposits.is_empty()
+            && self.adjustments.is_empty()
+            && self.last_activity.is_none_or(|last| last < horizon)
+    }
+
+    /// The funds in a currency, `None` being the implicit one.
+    pub fn balance(&self, currency: Option<Currency>) -> Balance {
+        match currency {
+            None => Balance {
+                held: self.held,
+                total: self.total,
+                payout: self.payout,
+            },
+            Some(currency) => self.currencies.get(&currency).copied().unwrap_or_default(),
+        }
+    }
+
+    /// The funds in every currency the account has seen, the implicit one first. An account
+    /// that only deals in named currencies has nothing in the implicit one, which is then left
+    /// out.
+    pub fn balances(&self) -> impl Iterator<Item = (Option<Currency>, Balance)> + '_ {
+        let implicit = self.balance(None);
+        let implicit = (self.currencies.is_empty() || implicit != Balance::default())
+            .then_some((None, implicit));
+        implicit.into_iter().chain(
+            self.currencies
+                .iter()
+                .map(|(currency, balance)| (Some(*currency), *balance)),
+        )
+    }
+
+    fn funds(&mut self, currency: Option<Currency>) -> Funds<'_> {
+        match currency {
+            None => Funds {
+                held: &mut self.held,
+                total: &mut self.total,
+            },
+            Some(currency) => {
+                let balance = self.currencies.entry(currency).or_default();
+                Funds {
+                    held: &mut balance.held,
+                    total: &mut balance.total,
+                }
+            }
+        }
     }
 
     pub fn new() -> Self {
         AccountState {
             held: Decimal::zero(),
             total: Decimal::zero(),
+            currencies: BTreeMap::new(),
             chargebacks: 0,
-            deposits: HashMap::new(),
+            disputed: 0,
+            operations: 0,
+            frozen: false,
+            deposits: Deposits::default(),
+            adjustments: Map::new(),
+            first_activity: None,
+            last_activity: None,
+            closed: false,
+            closed_at: None,
+            payout: Decimal::zero(),
+            deposited: Decimal::zero(),
+            withdrawn: Decimal::zero(),
+            journal: VecDeque::new(),
+        }
+    }
+
+    /// A new account with room for `deposits` deposits, for clients known to make a lot of
+    /// them, see [DepositStore::reserve].
+    pub fn with_deposit_capacity(deposits: usize) -> Self {
+        let mut account = Self::new();
+        account.deposits.reserve(deposits);
+        account
+    }
+
+    /// Whether a deposit, fee or interest payment has been recorded under this id.
+    /// [AccountState::funds], keeping the balance in `undo` first.
+    fn recorded_funds(&mut self, currency: Option<Currency>, undo: &mut UndoEntry) -> Funds<'_> {
+        if let Some(currency) = currency {
+            undo.record_currency(&self.currencies, currency);
+        }
+        self.funds(currency)
+    }
+
+    fn recorded(&self, tx: u32) -> bool {
+        self.deposits.contains_key(tx) || self.adjustments.contains_key(&tx)
+    }
+
+    fn record_activity(&mut self, timestamp: Option<u64>) {
+        if let Some(timestamp) = timestamp {
+            self.first_activity = Some(self.first_activity.map_or(timestamp, |t| t.min(timestamp)));
+            self.last_activity = Some(self.last_activity.map_or(timestamp, |t| t.max(timestamp)));
         }
     }
 
     /// Few things to add:
     /// 1. There are more than one ways to think about chargebacks. These are the assumptions we're making:
-    ///     a) More than one transaction can have a chargeback. Think of more than one transaction being
-    ///         disputed and then reversed. That will be a double chargeback. We consider them all by
-    ///         marking that in the deposit state.
-    ///     b) We could have also used `chargebacks` as a vector of deposit IDs and identified the lock status
-    ///         of an account based on the count. We just maintain a counter and mark the individual deposits
-    ///         instead. There is little difference between the two, so I went with my first instinct.
+    ///    a) More than one transaction can have a chargeback. Think of more than one transaction being
+    ///    disputed and then reversed. That will be a double chargeback. We consider them all by
+    ///    marking that in the deposit state.
+    ///    b) We could have also used `chargebacks` as a vector of deposit IDs and identified the lock status
+    ///    of an account based on the count. We just maintain a counter and mark the individual deposits
+    ///    instead. There is little difference between the two, so I went with my first instinct.
     ///
     /// 2. Several style guides will argue against the early return pattern. Google's style-guide is one that
-    ///     says that early returns are good. Like all interesting problems -- I'd say, it depends. I'm using
-    ///     early returns here because the code is likely not going to get too big and this appears to be
-    ///     well readable.
-    pub fn transact(&mut self, transaction: Transaction) {
+    ///    says that early returns are good. Like all interesting problems -- I'd say, it depends. I'm using
+    ///    early returns here because the code is likely not going to get too big and this appears to be
+    ///    well readable.
+    ///
+    /// The returned [TransactionOutcome] tells whether the transaction changed anything.
+    pub fn transact(&mut self, transaction: Transaction) -> TransactionOutcome {
+        self.transact_with(transaction, &AccountPolicy::default())
+    }
+
+    /// [AccountState::transact] under a specific [AccountPolicy].
+    pub fn transact_with(
+        &mut self,
+        transaction: Transaction,
+        policy: &AccountPolicy,
+    ) -> TransactionOutcome {
+        self.transact_checked(transaction, policy).0
+    }
+
+    /// [AccountState::transact_with], also telling whether the transaction broke the
+    /// invariants of the account. If it did, the outcome and the account are what
+    /// [AccountPolicy::negative] made of it.
+    pub fn transact_checked(
+        &mut self,
+        transaction: Transaction,
+        policy: &AccountPolicy,
+    ) -> (TransactionOutcome, Option<Violation>) {
+        let exempt = policy.fees_may_overdraw && matches!(transaction, Transaction::Fee { .. });
+        let mut before = UndoEntry::new(self);
+        let outcome = self.apply(transaction, policy, &mut before);
+        match outcome {
+            TransactionOutcome::Applied => {}
+            TransactionOutcome::Ignored(_) => return (outcome, None),
+            // Rejections may come halfway through, after an overflow.
+            TransactionOutcome::Rejected(_) => {
+                self.restore(before);
+                return (outcome, None);
+            }
+        }
+        let violation = if exempt {
+            None
+        } else {
+            invariants::check(|currency| before.balance(self, currency), self)
+        };
+        if let Some(violation) = violation {
+            match policy.negative {
+                NegativePolicy::Reject => {
+                    self.restore(before);
+                    let outcome = TransactionOutcome::Rejected(RejectReason::NegativeBalance);
+                    return (outcome, Some(violation));
+                }
+                NegativePolicy::Clamp => {
+                    let floor = before.balance(self, violation.currency);
+                    let disputes = self.disputed > 0;
+                    let funds = self.recorded_funds(violation.currency, &mut before);
+                    invariants::clamp(funds.held, funds.total, &floor, disputes);
+                }
+                NegativePolicy::Allow => {}
+            }
+        }
+        debug_assert!(
+            exempt
+                || policy.negative == NegativePolicy::Allow
+                || invariants::check(|currency| before.balance(self, currency), self).is_none(),
+            "{:?} left behind by {:?}",
+            self,
+            policy.negative
+        );
+        if policy.undo_depth > 0 {
+            while self.journal.len() >= policy.undo_depth {
+                self.journal.pop_front();
+            }
+            self.journal.push_back(before);
+        }
+        self.operations += 1;
+        (outcome, violation)
+    }
+
+    /// Reverts the most recently applied transaction that is still in the journal, see
+    /// [AccountPolicy::undo_depth]. Returns whether there was anything to revert.
+    ///
+    /// The activity window goes back to what it was before that transaction, which also
+    /// forgets about anything ignored since.
+    pub fn undo_last(&mut self) -> bool {
+        let Some(undo) = self.journal.pop_back() else {
+            return false;
+        };
+        self.restore(undo);
+        true
+    }
+
+    fn restore(&mut self, undo: UndoEntry) {
+        self.held = undo.held;
+        self.total = undo.total;
+        self.chargebacks = undo.chargebacks;
+        self.disputed = undo.disputed;
+        self.operations = undo.operations;
+        self.first_activity = undo.first_activity;
+        self.last_activity = undo.last_activity;
+        self.closed = undo.closed;
+        self.closed_at = undo.closed_at;
+        self.payout = undo.payout;
+        self.deposited = undo.deposited;
+        self.withdrawn = undo.withdrawn;
+        if let Some(currencies) = undo.currencies {
+            self.currencies = currencies;
+        }
+        match undo.currency {
+            Some((currency, Some(balance))) => self.currencies.insert(currency, balance),
+            Some((currency, None)) => self.currencies.remove(&currency),
+            None => None,
+        };
+        match undo.deposit {
+            Some((tx, Some(deposit))) => self.deposits.insert(tx, deposit),
+            Some((tx, None)) => self.deposits.remove(tx),
+            None => None,
+        };
+        if let Some(tx) = undo.adjustment {
+            self.adjustments.remove(&tx);
+        }
+    }
+
+    /// Applies `transaction`, keeping in `undo` whatever it changes beyond the scalars.
+    fn apply(
+        &mut self,
+        transaction: Transaction,
+        policy: &AccountPolicy,
+        undo: &mut UndoEntry,
+    ) -> TransactionOutcome {
+        if policy.disabled.contains(transaction.record_type()) {
+            return TransactionOutcome::Ignored(IgnoreReason::Disabled);
+        }
+        if self.closed {
+            match transaction {
+                Transaction::Dispute { .. }
+                | Transaction::Resolve { .. }
+                | Transaction::Chargeback { .. } => {}
+                _ => return TransactionOutcome::Rejected(RejectReason::Closed),
+            }
+        }
+        if self.locked() && !policy.locked.disputes {
+            if let Transaction::Dispute { .. } | Transaction::Resolve { .. } = transaction {
+                return TransactionOutcome::Rejected(RejectReason::Locked);
+            }
+        }
+        self.record_activity(transaction.timestamp());
         match transaction {
-            Transaction::Deposit { tx, amount, .. } => {
+            Transaction::Deposit {
+                tx,
+                amount,
+                currency,
+                ..
+            } => {
                 if self.locked() {
-                    return;
+                    return TransactionOutcome::Ignored(IgnoreReason::Locked);
+                }
+                // Transaction ids are supposed to be unique. Letting a second deposit with the
+                // same id through would overwrite the first one's record, and with it any
+                // dispute or chargeback history. So the first deposit wins, always.
+                if self.recorded(tx) {
+                    return TransactionOutcome::Ignored(IgnoreReason::DuplicateTx);
+                }
+                let funds = self.recorded_funds(currency, undo);
+                let Some(total) = funds.total.checked_add(amount) else {
+                    return OVERFLOW;
+                };
+                if let Some(cap) = policy.balance_cap.filter(|cap| total > *cap) {
+                    return TransactionOutcome::Rejected(RejectReason::BalanceCap(cap));
                 }
-                self.total += amount;
-                self.deposits.insert(tx, DepositState::new(amount));
+                *funds.total = total;
+                if currency.is_none() {
+                    self.deposited = self.deposited.saturating_add(amount);
+                }
+                self.deposits
+                    .insert(tx, DepositState::new(amount, currency));
+                undo.deposit = Some((tx, None));
             }
-            Transaction::Withdrawal { amount, .. } => {
+            Transaction::Withdrawal {
+                tx,
+                amount,
+                currency,
+                ..
+            } => {
                 if self.locked() {
-                    return;
+                    return TransactionOutcome::Ignored(IgnoreReason::Locked);
+                }
+                // Same as for deposits: a record, once there, is never overwritten.
+                if policy.disputable_withdrawals && self.recorded(tx) {
+                    return TransactionOutcome::Ignored(IgnoreReason::DuplicateTx);
+                }
+                if self.balance(currency).available() < amount {
+                    return TransactionOutcome::Ignored(IgnoreReason::InsufficientFunds);
+                }
+                let funds = self.recorded_funds(currency, undo);
+                let Some(total) = funds.total.checked_sub(amount) else {
+                    return OVERFLOW;
+                };
+                *funds.total = total;
+                if currency.is_none() {
+                    self.withdrawn = self.withdrawn.saturating_add(amount);
                 }
-                if self.available() > amount {
-                    self.total -= amount;
+                if policy.disputable_withdrawals {
+                    self.deposits
+                        .insert(tx, DepositState::withdrawal(amount, currency));
+                    undo.deposit = Some((tx, None));
                 }
             }
-            Transaction::Dispute { tx, .. } => {
-                if let Some(tx) = self.deposits.get_mut(&tx) {
+            Transaction::Dispute {
+                tx: id, timestamp, ..
+            } => {
+                // The deposit is only changed through `tx`, and needs to be let go of before
+                // the funds can be.
+                let (amount, currency, withdrawal) = {
+                    let Some(mut tx) = self.deposits.get_mut(id) else {
+                        return TransactionOutcome::Ignored(IgnoreReason::UnknownTx);
+                    };
+                    // The funds of a charged back deposit are gone already, there is nothing
+                    // left to hold.
+                    if tx.charged_back() {
+                        return TransactionOutcome::Ignored(IgnoreReason::ChargedBack);
+                    }
+                    // Holding the funds a second time would leave a hold behind that no resolve
+                    // or chargeback ever releases.
+                    if tx.dispute {
+                        return TransactionOutcome::Ignored(IgnoreReason::AlreadyDisputed);
+                    }
+                    // What came since doesn't include the resolve itself.
+                    let window = policy.redispute_window.filter(|window| {
+                        tx.resolved_at.is_some_and(|resolved_at| {
+                            self.operations - resolved_at > u64::from(*window)
+                        })
+                    });
+                    if let Some(window) = window {
+                        let reason = RejectReason::RedisputeWindow(window);
+                        return TransactionOutcome::Rejected(reason);
+                    }
+                    if let Some(cap) = policy.max_open_disputes.filter(|cap| self.disputed >= *cap)
+                    {
+                        return TransactionOutcome::Rejected(RejectReason::DisputeCap(cap));
+                    }
+                    undo.deposit = Some((id, Some(*tx)));
+                    self.disputed += 1;
                     tx.dispute = true;
-                    self.held += tx.amount;
-                }
+                    tx.disputed_at = timestamp;
+                    (tx.amount, tx.currency, tx.withdrawal)
+                };
+                let funds = self.recorded_funds(currency, undo);
+                // A disputed withdrawal is credited back, but held until the dispute is
+                // settled, so the available funds stay the same either way.
+                let credit = if withdrawal { amount } else { Decimal::zero() };
+                let (Some(held), Some(total)) = (
+                    funds.held.checked_add(amount),
+                    funds.total.checked_add(credit),
+                ) else {
+                    return OVERFLOW;
+                };
+                *funds.held = held;
+                *funds.total = total;
             }
-            Transaction::Resolve { tx, .. } => {
-                if let Some(tx) = self.deposits.get_mut(&tx) {
+            // The whole deposit is released from `held`. The funds never left `total`, so
+            // that stays the same, unless the client is credited less than the deposit: the
+            // difference leaves `total`, the same way a partial chargeback takes its part.
+            //
+            // A resolved withdrawal stands after all, so what was credited back for the dispute
+            // leaves `total` again. There is nothing to credit for less.
+            Transaction::Resolve { tx: id, amount, .. } => {
+                let (deposited, credited, currency) = {
+                    let Some(mut tx) = self.deposits.get_mut(id) else {
+                        return TransactionOutcome::Ignored(IgnoreReason::UnknownTx);
+                    };
+                    // Only a hold that's actually there can be released.
+                    if !tx.dispute {
+                        return TransactionOutcome::Ignored(IgnoreReason::NotDisputed);
+                    }
+                    if let Some(amount) = amount {
+                        if tx.withdrawal || amount < Decimal::zero() || amount > tx.amount {
+                            return TransactionOutcome::Ignored(IgnoreReason::InvalidAmount);
+                        }
+                    }
+                    let credited = match tx.withdrawal {
+                        true => Decimal::zero(),
+                        false => amount.unwrap_or(tx.amount),
+                    };
+                    undo.deposit = Some((id, Some(*tx)));
+                    self.disputed -= 1;
+                    if policy.redispute_window.is_some() {
+                        tx.resolved_at = Some(self.operations);
+                    }
                     tx.dispute = false;
-                    self.total += tx.amount;
-                    self.held -= tx.amount;
-                }
+                    tx.disputed_at = None;
+                    (tx.amount, credited, tx.currency)
+                };
+                let funds = self.recorded_funds(currency, undo);
+                let (Some(held), Some(total)) = (
+                    funds.held.checked_sub(deposited),
+                    funds.total.checked_sub(deposited - credited),
+                ) else {
+                    return OVERFLOW;
+                };
+                *funds.held = held;
+                *funds.total = total;
             }
-            Transaction::Chargeback { tx, .. } => {
-                if let Some(tx) = self.deposits.get_mut(&tx) {
-                    if tx.dispute {
-                        tx.chargeback = true;
-                        self.chargebacks += 1;
+            // The whole deposit is released from `held`, but only the charged back part
+            // leaves `total`. Whatever remains becomes available again. Either way the dispute
+            // is over, and the deposit can't be disputed again.
+            //
+            // Charging back a withdrawal reverses it: the charged back part of what the dispute
+            // credited stays in `total`, and becomes available. The client did nothing wrong,
+            // so unlike a deposit chargeback this doesn't lock the account.
+            Transaction::Chargeback { tx: id, amount, .. } => {
+                let (amount, deposited, currency, withdrawal) = {
+                    let Some(mut tx) = self.deposits.get_mut(id) else {
+                        return TransactionOutcome::Ignored(IgnoreReason::UnknownTx);
+                    };
+                    if !tx.dispute {
+                        return TransactionOutcome::Ignored(IgnoreReason::NotDisputed);
+                    }
+                    let amount = amount.unwrap_or(tx.amount);
+                    if amount <= Decimal::zero() || amount > tx.amount {
+                        return TransactionOutcome::Ignored(IgnoreReason::InvalidAmount);
                     }
+                    undo.deposit = Some((id, Some(*tx)));
+                    self.disputed -= 1;
+                    tx.dispute = false;
+                    tx.disputed_at = None;
+                    tx.chargeback = Some(amount);
+                    (amount, tx.amount, tx.currency, tx.withdrawal)
+                };
+                let removed = if withdrawal {
+                    deposited - amount
+                } else {
+                    amount
+                };
+                let funds = self.recorded_funds(currency, undo);
+                let (Some(held), Some(total)) = (
+                    funds.held.checked_sub(deposited),
+                    funds.total.checked_sub(removed),
+                ) else {
+                    return OVERFLOW;
+                };
+                *funds.held = held;
+                *funds.total = total;
+                if !withdrawal {
+                    self.chargebacks += 1;
+                }
+            }
+            Transaction::Fee {
+                tx,
+                amount,
+                currency,
+                ..
+            } => {
+                if self.locked() && !policy.locked.charge_fees {
+                    return TransactionOutcome::Ignored(IgnoreReason::Locked);
+                }
+                if self.recorded(tx) {
+                    return TransactionOutcome::Ignored(IgnoreReason::DuplicateTx);
+                }
+                if !policy.fees_may_overdraw && self.balance(currency).available() < amount {
+                    return TransactionOutcome::Ignored(IgnoreReason::InsufficientFunds);
+                }
+                let funds = self.recorded_funds(currency, undo);
+                let Some(total) = funds.total.checked_sub(amount) else {
+                    return OVERFLOW;
+                };
+                *funds.total = total;
+                self.adjustments.insert(tx, Adjustment::Fee(amount));
+                undo.adjustment = Some(tx);
+            }
+            Transaction::Interest {
+                tx,
+                amount,
+                currency,
+                ..
+            } => {
+                if self.locked() && !policy.locked.accrue_interest {
+                    return TransactionOutcome::Ignored(IgnoreReason::Locked);
+                }
+                if self.recorded(tx) {
+                    return TransactionOutcome::Ignored(IgnoreReason::DuplicateTx);
+                }
+                let funds = self.recorded_funds(currency, undo);
+                let Some(total) = funds.total.checked_add(amount) else {
+                    return OVERFLOW;
+                };
+                *funds.total = total;
+                if policy.disputable_interest {
+                    self.deposits
+                        .insert(tx, DepositState::new(amount, currency));
+                    undo.deposit = Some((tx, None));
+                } else {
+                    self.adjustments.insert(tx, Adjustment::Interest(amount));
+                    undo.adjustment = Some(tx);
+                }
+            }
+            // Whatever is available gets paid out, in every currency. Held funds stay until their
+            // disputes are settled, and an overdrawn account has nothing to pay out.
+            Transaction::Close { timestamp, .. } => {
+                if self.locked() {
+                    return TransactionOutcome::Ignored(IgnoreReason::Locked);
+                }
+                undo.currencies = Some(self.currencies.clone());
+                self.closed = true;
+                self.closed_at = timestamp;
+                self.payout = self.available().max(Decimal::zero());
+                let Some(total) = self.total.checked_sub(self.payout) else {
+                    return OVERFLOW;
+                };
+                self.total = total;
+                for balance in self.currencies.values_mut() {
+                    balance.payout = balance.available().max(Decimal::zero());
+                    let Some(total) = balance.total.checked_sub(balance.payout) else {
+                        return OVERFLOW;
+                    };
+                    balance.total = total;
                 }
             }
         }
+        TransactionOutcome::Applied
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::policy::LockedPolicy;
 
     #[test]
     /// Test that basic deposit and withdraw works
@@ -123,6 +1051,8 @@ mod tests {
             client: 0,
             tx: 0,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         // Deposit was successful
         assert_eq!(state.available(), Decimal::from(100));
@@ -130,11 +1060,100 @@ mod tests {
             client: 0,
             tx: 1,
             amount: Decimal::from(50),
+            currency: None,
+            timestamp: None,
         });
         // Withdraw successful
         assert_eq!(state.available(), Decimal::from(50));
     }
 
+    #[test]
+    /// Gross deposits and withdrawals add up what went through, and disputes leave them be
+    fn net_flow() {
+        let mut state = AccountState::new();
+        for (tx, amount) in [(1, 100), (2, 200)] {
+            state.transact(Transaction::Deposit {
+                client: 0,
+                tx,
+                amount: Decimal::from(amount),
+                currency: None,
+                timestamp: None,
+            });
+        }
+        for amount in [100, 1_000] {
+            state.transact(Transaction::Withdrawal {
+                client: 0,
+                tx: 3,
+                amount: Decimal::from(amount),
+                currency: None,
+                timestamp: None,
+            });
+        }
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 2,
+            timestamp: None,
+        });
+        state.transact(Transaction::Chargeback {
+            client: 0,
+            tx: 2,
+            amount: None,
+            timestamp: None,
+        });
+        assert_eq!(state.gross_deposits(), Decimal::from(300));
+        assert_eq!(state.gross_withdrawals(), Decimal::from(100));
+        assert_eq!(state.net_flow(), Decimal::from(200));
+        assert_eq!(state.total, Decimal::zero());
+    }
+
+    #[test]
+    /// The outcome tells apart transactions that did something from those that didn't
+    fn outcomes() {
+        let mut state = AccountState::new();
+        let deposit = Transaction::Deposit {
+            client: 0,
+            tx: 0,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        };
+        assert_eq!(state.transact(deposit.clone()), TransactionOutcome::Applied);
+        assert_eq!(
+            state.transact(deposit),
+            TransactionOutcome::Ignored(IgnoreReason::DuplicateTx)
+        );
+        let withdrawal = Transaction::Withdrawal {
+            client: 0,
+            tx: 1,
+            amount: Decimal::from(150),
+            currency: None,
+            timestamp: None,
+        };
+        assert_eq!(
+            state.transact(withdrawal),
+            TransactionOutcome::Ignored(IgnoreReason::InsufficientFunds)
+        );
+        let dispute = Transaction::Dispute {
+            client: 0,
+            tx: 7,
+            timestamp: None,
+        };
+        assert_eq!(
+            state.transact(dispute),
+            TransactionOutcome::Ignored(IgnoreReason::UnknownTx)
+        );
+        let chargeback = Transaction::Chargeback {
+            client: 0,
+            tx: 0,
+            amount: None,
+            timestamp: None,
+        };
+        assert_eq!(
+            state.transact(chargeback),
+            TransactionOutcome::Ignored(IgnoreReason::NotDisputed)
+        );
+    }
+
     #[test]
     /// Fail withdrawal when not enough funds are available
     fn fail_withdraw_no_funds() {
@@ -143,6 +1162,8 @@ mod tests {
             client: 0,
             tx: 0,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         // Deposit was successful
         assert_eq!(state.available(), Decimal::from(100));
@@ -150,11 +1171,35 @@ mod tests {
             client: 0,
             tx: 1,
             amount: Decimal::from(150),
+            currency: None,
+            timestamp: None,
         });
         // Withdraw failure
         assert_eq!(state.available(), Decimal::from(100));
     }
 
+    #[test]
+    /// Withdrawing exactly what's available empties the account
+    fn withdraw_everything() {
+        let mut state = AccountState::new();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 0,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        let outcome = state.transact(Transaction::Withdrawal {
+            client: 0,
+            tx: 1,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        assert!(state.is_empty());
+    }
+
     #[test]
     /// A broken transaction should not brake the state management
     fn success_successive_no_transactions_after_failure() {
@@ -163,6 +1208,8 @@ mod tests {
             client: 0,
             tx: 0,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         // Deposit was successful
         assert_eq!(state.available(), Decimal::from(100));
@@ -170,6 +1217,8 @@ mod tests {
             client: 0,
             tx: 1,
             amount: Decimal::from(150),
+            currency: None,
+            timestamp: None,
         });
         // Withdraw failure
         assert_eq!(state.available(), Decimal::from(100));
@@ -177,6 +1226,8 @@ mod tests {
             client: 0,
             tx: 1,
             amount: Decimal::from(50),
+            currency: None,
+            timestamp: None,
         });
         // Withdraw success
         assert_eq!(state.available(), Decimal::from(50));
@@ -190,14 +1241,49 @@ mod tests {
             client: 0,
             tx: 0,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         state.transact(Transaction::Deposit {
             client: 0,
             tx: 1,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            timestamp: None,
+        });
+        assert_eq!(state.available(), Decimal::from(100));
+    }
+
+    #[test]
+    /// Open disputes only reduce the conservative available figure
+    fn optimistic_available_ignores_holds() {
+        let mut state = AccountState::new();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 0,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 1,
+            amount: Decimal::from(50),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            timestamp: None,
         });
-        state.transact(Transaction::Dispute { client: 0, tx: 1 });
         assert_eq!(state.available(), Decimal::from(100));
+        assert_eq!(state.optimistic_available(), Decimal::from(150));
     }
 
     #[test]
@@ -208,13 +1294,21 @@ mod tests {
             client: 0,
             tx: 0,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         state.transact(Transaction::Deposit {
             client: 0,
             tx: 1,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 3,
+            timestamp: None,
         });
-        state.transact(Transaction::Dispute { client: 0, tx: 3 });
         assert_eq!(state.available(), Decimal::from(200));
     }
 
@@ -226,20 +1320,34 @@ mod tests {
             client: 0,
             tx: 0,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         // Deposit was successful
         state.transact(Transaction::Deposit {
             client: 0,
             tx: 1,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         state.transact(Transaction::Deposit {
             client: 0,
             tx: 2,
             amount: Decimal::from(200),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 0,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            timestamp: None,
         });
-        state.transact(Transaction::Dispute { client: 0, tx: 0 });
-        state.transact(Transaction::Dispute { client: 0, tx: 1 });
         assert_eq!(state.available(), Decimal::from(200));
     }
 
@@ -251,14 +1359,23 @@ mod tests {
             client: 0,
             tx: 0,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         // Deposit was successful
         state.transact(Transaction::Deposit {
             client: 0,
             tx: 1,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Chargeback {
+            client: 0,
+            tx: 1,
+            amount: None,
+            timestamp: None,
         });
-        state.transact(Transaction::Chargeback { client: 0, tx: 1 });
         assert!(!state.locked());
     }
 
@@ -270,15 +1387,28 @@ mod tests {
             client: 0,
             tx: 0,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         // Deposit was successful
         state.transact(Transaction::Deposit {
             client: 0,
             tx: 1,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            timestamp: None,
+        });
+        state.transact(Transaction::Chargeback {
+            client: 0,
+            tx: 1,
+            amount: None,
+            timestamp: None,
         });
-        state.transact(Transaction::Dispute { client: 0, tx: 1 });
-        state.transact(Transaction::Chargeback { client: 0, tx: 1 });
         assert!(state.locked());
     }
 
@@ -290,19 +1420,34 @@ mod tests {
             client: 0,
             tx: 0,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         state.transact(Transaction::Deposit {
             client: 0,
             tx: 1,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            timestamp: None,
+        });
+        state.transact(Transaction::Chargeback {
+            client: 0,
+            tx: 1,
+            amount: None,
+            timestamp: None,
         });
-        state.transact(Transaction::Dispute { client: 0, tx: 1 });
-        state.transact(Transaction::Chargeback { client: 0, tx: 1 });
         assert!(state.locked());
         state.transact(Transaction::Deposit {
             client: 0,
             tx: 2,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         // I'm assuming that funds still show up as available even if withdrawal fails
         assert_eq!(state.available(), Decimal::from(100));
@@ -310,30 +1455,1446 @@ mod tests {
             client: 0,
             tx: 2,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         assert_eq!(state.available(), Decimal::from(100));
     }
 
     #[test]
-    /// A chargeback doesn't mean that further disputes aren't possible
+    /// Timestamps widen the activity window, transactions without one leave it alone
+    fn activity_window() {
+        let mut state = AccountState::new();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 0,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: Some(20),
+        });
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 1,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 0,
+            timestamp: Some(10),
+        });
+        state.transact(Transaction::Resolve {
+            client: 0,
+            tx: 0,
+            amount: None,
+            timestamp: Some(30),
+        });
+        assert_eq!(state.first_activity, Some(10));
+        assert_eq!(state.last_activity, Some(30));
+    }
+
+    #[test]
+    /// A full chargeback takes the whole deposit out of held and total
+    fn full_chargeback() {
+        let mut state = AccountState::new();
+        for (tx, amount) in [(0, 100), (1, 40)] {
+            state.transact(Transaction::Deposit {
+                client: 0,
+                tx,
+                amount: Decimal::from(amount),
+                currency: None,
+                timestamp: None,
+            });
+        }
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            timestamp: Some(10),
+        });
+        let outcome = state.transact(Transaction::Chargeback {
+            client: 0,
+            tx: 1,
+            amount: None,
+            timestamp: None,
+        });
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        assert_eq!(state.held, Decimal::from(0));
+        assert_eq!(state.total, Decimal::from(100));
+        assert!(state.locked());
+        let deposit = state.deposits.get(1).unwrap();
+        assert!(!deposit.disputed());
+        assert_eq!(deposit.disputed_at(), None);
+        assert_eq!(deposit.charged_back_amount(), Some(Decimal::from(40)));
+        // The dispute is over, and it can't be raised again.
+        let dispute = Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            timestamp: None,
+        };
+        assert_eq!(
+            state.transact(dispute),
+            TransactionOutcome::Ignored(IgnoreReason::ChargedBack)
+        );
+        assert_eq!(state.open_disputes().count(), 0);
+    }
+
+    #[test]
+    /// A partial chargeback only takes its amount, the rest of the deposit is released
+    fn partial_chargeback() {
+        let mut state = AccountState::new();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 0,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 0,
+            timestamp: None,
+        });
+        for amount in [0, 101] {
+            let chargeback = Transaction::Chargeback {
+                client: 0,
+                tx: 0,
+                amount: Some(Decimal::from(amount)),
+                timestamp: None,
+            };
+            assert_eq!(
+                state.transact(chargeback),
+                TransactionOutcome::Ignored(IgnoreReason::InvalidAmount)
+            );
+        }
+        let outcome = state.transact(Transaction::Chargeback {
+            client: 0,
+            tx: 0,
+            amount: Some(Decimal::from(30)),
+            timestamp: None,
+        });
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        assert_eq!(state.held, Decimal::from(0));
+        assert_eq!(state.total, Decimal::from(70));
+        assert_eq!(state.available(), Decimal::from(70));
+        assert!(state.locked());
+        assert_eq!(
+            state.deposits.get(0).unwrap().charged_back_amount(),
+            Some(Decimal::from(30))
+        );
+        // A second chargeback finds no dispute to act on.
+        let chargeback = Transaction::Chargeback {
+            client: 0,
+            tx: 0,
+            amount: None,
+            timestamp: None,
+        };
+        assert_eq!(
+            state.transact(chargeback),
+            TransactionOutcome::Ignored(IgnoreReason::NotDisputed)
+        );
+        assert_eq!(state.chargebacks, 1);
+    }
+
+    #[test]
+    /// Resolving releases the hold, and the deposit is available again as it was
+    fn resolve() {
+        let mut state = AccountState::new();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 0,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 0,
+            timestamp: None,
+        });
+        let resolve = Transaction::Resolve {
+            client: 0,
+            tx: 0,
+            amount: None,
+            timestamp: None,
+        };
+        assert_eq!(state.transact(resolve), TransactionOutcome::Applied);
+        assert_eq!(state.held, Decimal::from(0));
+        assert_eq!(state.total, Decimal::from(100));
+        assert_eq!(state.available(), Decimal::from(100));
+        assert!(!state.deposits.get(0).unwrap().dispute);
+    }
+
+    #[test]
+    /// An adjusted resolve releases the whole hold but only credits its amount, which can't
+    /// exceed what's held
+    fn adjusted_resolve() {
+        let mut state = AccountState::new();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 0,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        let resolve = |amount| Transaction::Resolve {
+            client: 0,
+            tx: 0,
+            amount: Some(Decimal::from(amount)),
+            timestamp: None,
+        };
+        // Nothing is held before the dispute.
+        assert_eq!(
+            state.transact(resolve(95)),
+            TransactionOutcome::Ignored(IgnoreReason::NotDisputed)
+        );
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 0,
+            timestamp: None,
+        });
+        for amount in [-1, 101] {
+            assert_eq!(
+                state.transact(resolve(amount)),
+                TransactionOutcome::Ignored(IgnoreReason::InvalidAmount)
+            );
+        }
+        assert_eq!(state.transact(resolve(95)), TransactionOutcome::Applied);
+        assert_eq!(state.held, Decimal::from(0));
+        assert_eq!(state.total, Decimal::from(95));
+        assert_eq!(state.available(), Decimal::from(95));
+        assert!(!state.locked());
+        assert_eq!(
+            state.transact(resolve(95)),
+            TransactionOutcome::Ignored(IgnoreReason::NotDisputed)
+        );
+    }
+
+    #[test]
+    /// Reusing the id of a charged back deposit must not overwrite its history
+    fn no_deposit_reuse_after_chargeback() {
+        let mut state = AccountState::new();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 5,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 5,
+            timestamp: None,
+        });
+        state.transact(Transaction::Chargeback {
+            client: 0,
+            tx: 5,
+            amount: None,
+            timestamp: None,
+        });
+        let before = state.clone();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 5,
+            amount: Decimal::from(30),
+            currency: None,
+            timestamp: None,
+        });
+        assert_eq!(state, before);
+        assert!(state.deposits.get(5).unwrap().charged_back());
+    }
+
+    #[test]
+    /// A second deposit with the same id is ignored, even without a chargeback
+    fn no_deposit_reuse() {
+        let mut state = AccountState::new();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 5,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 5,
+            timestamp: None,
+        });
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 5,
+            amount: Decimal::from(30),
+            currency: None,
+            timestamp: None,
+        });
+        assert_eq!(state.total, Decimal::from(100));
+        assert_eq!(state.held, Decimal::from(100));
+        assert!(state.deposits.get(5).unwrap().dispute);
+    }
+
+    #[test]
+    /// Unless the policy freezes locked accounts, which rejects disputes and resolves alike
+    fn disputes_rejected_after_chargeback() {
+        let policy = AccountPolicy {
+            locked: LockedPolicy {
+                disputes: false,
+                ..LockedPolicy::default()
+            },
+            ..AccountPolicy::default()
+        };
+        let mut state = AccountState::new();
+        for tx in [0, 1, 2] {
+            state.transact_with(
+                Transaction::Deposit {
+                    client: 0,
+                    tx,
+                    amount: Decimal::from(100),
+                    currency: None,
+                    timestamp: None,
+                },
+                &policy,
+            );
+        }
+        for tx in [1, 2] {
+            let dispute = Transaction::Dispute {
+                client: 0,
+                tx,
+                timestamp: None,
+            };
+            assert_eq!(
+                state.transact_with(dispute, &policy),
+                TransactionOutcome::Applied
+            );
+        }
+        let chargeback = Transaction::Chargeback {
+            client: 0,
+            tx: 1,
+            amount: None,
+            timestamp: None,
+        };
+        state.transact_with(chargeback, &policy);
+        assert!(state.locked());
+
+        let dispute = Transaction::Dispute {
+            client: 0,
+            tx: 0,
+            timestamp: None,
+        };
+        let resolve = Transaction::Resolve {
+            client: 0,
+            tx: 2,
+            amount: None,
+            timestamp: None,
+        };
+        let locked = TransactionOutcome::Rejected(RejectReason::Locked);
+        assert_eq!(state.transact_with(dispute, &policy), locked);
+        assert_eq!(state.transact_with(resolve, &policy), locked);
+        assert_eq!(state.available(), Decimal::from(100));
+        assert_eq!(state.held, Decimal::from(100));
+    }
+
+    #[test]
+    /// A chargeback doesn't mean that further disputes aren't possible
     fn disputes_possible_after_chargeback() {
         let mut state = AccountState::new();
         state.transact(Transaction::Deposit {
             client: 0,
             tx: 0,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
         });
         // Deposit was successful
         state.transact(Transaction::Deposit {
             client: 0,
             tx: 1,
             amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            timestamp: None,
+        });
+        state.transact(Transaction::Chargeback {
+            client: 0,
+            tx: 1,
+            amount: None,
+            timestamp: None,
         });
-        state.transact(Transaction::Dispute { client: 0, tx: 1 });
-        state.transact(Transaction::Chargeback { client: 0, tx: 1 });
         assert!(state.locked());
-        state.transact(Transaction::Dispute { client: 0, tx: 0 });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 0,
+            timestamp: None,
+        });
         assert_eq!(state.available(), Decimal::from(0));
         assert!(state.locked()); // Still locked
     }
+
+    fn fee(tx: u32, amount: u32) -> Transaction {
+        Transaction::Fee {
+            client: 0,
+            tx,
+            amount: Decimal::from(amount),
+            currency: None,
+            timestamp: None,
+        }
+    }
+
+    fn interest(tx: u32, amount: u32) -> Transaction {
+        Transaction::Interest {
+            client: 0,
+            tx,
+            amount: Decimal::from(amount),
+            currency: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    /// A fee is charged even if it takes the available funds below zero, unless the policy
+    /// says otherwise
+    fn fee_overdraws() {
+        let mut state = AccountState::new();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 0,
+            amount: Decimal::from(10),
+            currency: None,
+            timestamp: None,
+        });
+        assert_eq!(state.transact(fee(1, 15)), TransactionOutcome::Applied);
+        assert_eq!(state.available(), Decimal::from(-5));
+        assert_eq!(state.adjustments[&1], Adjustment::Fee(Decimal::from(15)));
+
+        let policy = AccountPolicy {
+            fees_may_overdraw: false,
+            ..AccountPolicy::default()
+        };
+        assert_eq!(
+            state.transact_with(fee(2, 1), &policy),
+            TransactionOutcome::Ignored(IgnoreReason::InsufficientFunds)
+        );
+        assert_eq!(state.available(), Decimal::from(-5));
+    }
+
+    #[test]
+    /// Locked accounts accrue interest but aren't charged fees by default
+    fn adjustments_on_locked_account() {
+        let mut state = AccountState::new();
+        state.frozen = true;
+        assert_eq!(state.transact(interest(1, 5)), TransactionOutcome::Applied);
+        assert_eq!(
+            state.transact(fee(2, 1)),
+            TransactionOutcome::Ignored(IgnoreReason::Locked)
+        );
+        assert_eq!(state.total, Decimal::from(5));
+
+        let policy = AccountPolicy {
+            locked: LockedPolicy {
+                accrue_interest: false,
+                charge_fees: true,
+                ..LockedPolicy::default()
+            },
+            ..AccountPolicy::default()
+        };
+        assert_eq!(
+            state.transact_with(interest(3, 5), &policy),
+            TransactionOutcome::Ignored(IgnoreReason::Locked)
+        );
+        assert_eq!(
+            state.transact_with(fee(4, 1), &policy),
+            TransactionOutcome::Applied
+        );
+        assert_eq!(state.total, Decimal::from(4));
+    }
+
+    #[test]
+    /// Interest can only be disputed if the policy allows it, fees never can
+    fn disputing_adjustments() {
+        let dispute = |tx| Transaction::Dispute {
+            client: 0,
+            tx,
+            timestamp: None,
+        };
+        let mut state = AccountState::new();
+        state.transact(interest(1, 5));
+        state.transact(fee(2, 1));
+        assert_eq!(
+            state.transact(dispute(1)),
+            TransactionOutcome::Ignored(IgnoreReason::UnknownTx)
+        );
+        assert_eq!(
+            state.transact(dispute(2)),
+            TransactionOutcome::Ignored(IgnoreReason::UnknownTx)
+        );
+        assert_eq!(state.held, Decimal::from(0));
+
+        let policy = AccountPolicy {
+            disputable_interest: true,
+            ..AccountPolicy::default()
+        };
+        let mut state = AccountState::new();
+        state.transact_with(interest(1, 5), &policy);
+        assert_eq!(
+            state.transact_with(dispute(1), &policy),
+            TransactionOutcome::Applied
+        );
+        assert_eq!(state.held, Decimal::from(5));
+        // Ids are shared with deposits
+        assert_eq!(
+            state.transact_with(interest(1, 5), &policy),
+            TransactionOutcome::Ignored(IgnoreReason::DuplicateTx)
+        );
+    }
+
+    #[test]
+    /// The breakdown lists every open dispute in the implicit currency, and adds up to what's
+    /// held
+    fn held_breakdown() {
+        let policy = AccountPolicy {
+            disputable_withdrawals: true,
+            ..AccountPolicy::default()
+        };
+        let usd = "USD".parse().ok();
+        let mut state = AccountState::new();
+        let transactions = [
+            (1, "deposit", 100, None),
+            (2, "deposit", 30, None),
+            (3, "deposit", 25, None),
+            (4, "deposit", 70, usd),
+            (5, "withdrawal", 10, None),
+            (6, "deposit", 200, None),
+        ];
+        for (tx, kind, amount, currency) in transactions {
+            let amount = Decimal::from(amount);
+            let transaction = match kind {
+                "deposit" => Transaction::Deposit {
+                    client: 0,
+                    tx,
+                    amount,
+                    currency,
+                    timestamp: None,
+                },
+                _ => Transaction::Withdrawal {
+                    client: 0,
+                    tx,
+                    amount,
+                    currency,
+                    timestamp: None,
+                },
+            };
+            state.transact_with(transaction, &policy);
+        }
+        for tx in [5, 3, 1, 2, 4] {
+            let dispute = Transaction::Dispute {
+                client: 0,
+                tx,
+                timestamp: None,
+            };
+            state.transact_with(dispute, &policy);
+        }
+        let resolve = Transaction::Resolve {
+            client: 0,
+            tx: 2,
+            amount: None,
+            timestamp: None,
+        };
+        state.transact_with(resolve, &policy);
+
+        let breakdown = state.held_breakdown();
+        let amounts = |amounts: &[(u32, i64)]| -> Vec<(u32, Decimal)> {
+            amounts
+                .iter()
+                .map(|(tx, amount)| (*tx, Decimal::from(*amount)))
+                .collect()
+        };
+        assert_eq!(breakdown, amounts(&[(1, 100), (3, 25), (5, 10)]));
+        let sum: Decimal = breakdown.iter().map(|(_, amount)| *amount).sum();
+        assert_eq!(sum, state.held);
+        assert_eq!(state.held, Decimal::from(135));
+    }
+
+    #[test]
+    /// Disputes of the same amount are listed by transaction id rather than in the order they
+    /// were disputed in
+    fn held_breakdown_equal_amounts() {
+        let mut state = AccountState::new();
+        for tx in [7, 3, 5] {
+            state.transact(Transaction::Deposit {
+                client: 0,
+                tx,
+                amount: Decimal::from(50),
+                currency: None,
+                timestamp: None,
+            });
+        }
+        for tx in [7, 3] {
+            state.transact(Transaction::Dispute {
+                client: 0,
+                tx,
+                timestamp: None,
+            });
+        }
+        let fifty = Decimal::from(50);
+        assert_eq!(state.held_breakdown(), vec![(3, fifty), (7, fifty)]);
+    }
+
+    #[test]
+    /// A disputed withdrawal is credited back but held, a chargeback makes the client whole
+    /// without locking the account, and a resolve lets the withdrawal stand
+    fn withdrawal_disputes() {
+        let deposit = |tx, amount| Transaction::Deposit {
+            client: 0,
+            tx,
+            amount: Decimal::from(amount),
+            currency: None,
+            timestamp: None,
+        };
+        let withdrawal = |tx, amount| Transaction::Withdrawal {
+            client: 0,
+            tx,
+            amount: Decimal::from(amount),
+            currency: None,
+            timestamp: None,
+        };
+        let dispute = Transaction::Dispute {
+            client: 0,
+            tx: 2,
+            timestamp: None,
+        };
+        let chargeback = |amount: Option<u32>| Transaction::Chargeback {
+            client: 0,
+            tx: 2,
+            amount: amount.map(Decimal::from),
+            timestamp: None,
+        };
+        let setup = |policy: &AccountPolicy| {
+            let mut state = AccountState::new();
+            state.transact_with(deposit(1, 100), policy);
+            state.transact_with(withdrawal(2, 40), policy);
+            state
+        };
+        let funds = |state: &AccountState| (state.available(), state.held, state.total);
+        let funds_of = |available: u32, held: u32, total: u32| {
+            let decimal = Decimal::from;
+            (decimal(available), decimal(held), decimal(total))
+        };
+
+        // Without the policy, withdrawals can't be disputed.
+        let mut state = setup(&AccountPolicy::default());
+        assert_eq!(
+            state.transact(dispute.clone()),
+            TransactionOutcome::Ignored(IgnoreReason::UnknownTx)
+        );
+        assert!(!state.deposits.contains_key(2));
+
+        let policy = AccountPolicy {
+            disputable_withdrawals: true,
+            ..AccountPolicy::default()
+        };
+        let mut state = setup(&policy);
+        assert!(state.deposits.get(2).unwrap().is_withdrawal());
+        let outcome = state.transact_with(dispute.clone(), &policy);
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        assert_eq!(funds(&state), funds_of(60, 40, 100));
+        let outcome = state.transact_with(chargeback(None), &policy);
+        assert_eq!(outcome, TransactionOutcome::Applied);
+        assert_eq!(funds(&state), funds_of(100, 0, 100));
+        assert!(!state.locked());
+        // Reversed for good, there's nothing left to dispute.
+        let outcome = state.transact_with(dispute.clone(), &policy);
+        assert_eq!(
+            outcome,
+            TransactionOutcome::Ignored(IgnoreReason::ChargedBack)
+        );
+
+        let mut state = setup(&policy);
+        state.transact_with(dispute.clone(), &policy);
+        state.transact_with(chargeback(Some(30)), &policy);
+        assert_eq!(funds(&state), funds_of(90, 0, 90));
+
+        let mut state = setup(&policy);
+        state.transact_with(dispute, &policy);
+        let resolve = Transaction::Resolve {
+            client: 0,
+            tx: 2,
+            amount: None,
+            timestamp: None,
+        };
+        assert_eq!(
+            state.transact_with(resolve, &policy),
+            TransactionOutcome::Applied
+        );
+        assert_eq!(funds(&state), funds_of(60, 0, 60));
+        // Withdrawal ids can't be reused either.
+        let outcome = state.transact_with(deposit(2, 5), &policy);
+        assert_eq!(
+            outcome,
+            TransactionOutcome::Ignored(IgnoreReason::DuplicateTx)
+        );
+    }
+
+    #[test]
+    /// Disputing a withdrawal twice credits and holds it once, so that a resolve or chargeback
+    /// after that ends up where it would after a single dispute
+    fn withdrawal_double_dispute() {
+        let policy = AccountPolicy {
+            disputable_withdrawals: true,
+            ..AccountPolicy::default()
+        };
+        let settles = [
+            (
+                Transaction::Resolve {
+                    client: 0,
+                    tx: 2,
+                    amount: None,
+                    timestamp: None,
+                },
+                (50, 50),
+            ),
+            (
+                Transaction::Chargeback {
+                    client: 0,
+                    tx: 2,
+                    amount: None,
+                    timestamp: None,
+                },
+                (100, 100),
+            ),
+        ];
+        for (settle, (available, total)) in settles {
+            let mut state = AccountState::new();
+            state.transact_with(
+                Transaction::Deposit {
+                    client: 0,
+                    tx: 1,
+                    amount: Decimal::from(100),
+                    currency: None,
+                    timestamp: None,
+                },
+                &policy,
+            );
+            state.transact_with(
+                Transaction::Withdrawal {
+                    client: 0,
+                    tx: 2,
+                    amount: Decimal::from(50),
+                    currency: None,
+                    timestamp: None,
+                },
+                &policy,
+            );
+            let dispute = Transaction::Dispute {
+                client: 0,
+                tx: 2,
+                timestamp: None,
+            };
+            assert_eq!(
+                state.transact_with(dispute.clone(), &policy),
+                TransactionOutcome::Applied
+            );
+            assert_eq!(
+                state.transact_with(dispute, &policy),
+                TransactionOutcome::Ignored(IgnoreReason::AlreadyDisputed)
+            );
+            assert_eq!(
+                (state.held, state.total),
+                (Decimal::from(50), Decimal::from(100))
+            );
+            assert_eq!(
+                state.transact_with(settle, &policy),
+                TransactionOutcome::Applied
+            );
+            assert_eq!(state.available(), Decimal::from(available));
+            assert_eq!(state.held, Decimal::zero());
+            assert_eq!(state.total, Decimal::from(total));
+            assert!(!state.locked());
+        }
+    }
+
+    #[test]
+    /// Undoing a transaction of any type gets the account back to where it was before
+    fn undo_every_transaction_type() {
+        let policy = AccountPolicy {
+            disputable_interest: true,
+            undo_depth: 4,
+            ..AccountPolicy::default()
+        };
+        let transactions = [
+            Transaction::Deposit {
+                client: 0,
+                tx: 1,
+                amount: Decimal::from(100),
+                currency: None,
+                timestamp: Some(10),
+            },
+            Transaction::Withdrawal {
+                client: 0,
+                tx: 2,
+                amount: Decimal::from(2),
+                currency: None,
+                timestamp: Some(20),
+            },
+            fee(3, 5),
+            interest(4, 7),
+            Transaction::Dispute {
+                client: 0,
+                tx: 1,
+                timestamp: Some(30),
+            },
+            Transaction::Resolve {
+                client: 0,
+                tx: 1,
+                amount: None,
+                timestamp: None,
+            },
+            Transaction::Dispute {
+                client: 0,
+                tx: 4,
+                timestamp: Some(5),
+            },
+            Transaction::Chargeback {
+                client: 0,
+                tx: 4,
+                amount: Some(Decimal::from(3)),
+                timestamp: None,
+            },
+        ];
+        let mut state = AccountState::new();
+        for transaction in transactions {
+            let before = state.clone();
+            assert_eq!(
+                state.transact_with(transaction.clone(), &policy),
+                TransactionOutcome::Applied
+            );
+            let after = state.clone();
+            assert!(state.undo_last());
+            assert_eq!(state, before, "undoing {:?}", transaction);
+            state.transact_with(transaction, &policy);
+            assert_eq!(state, after);
+        }
+        assert!(state.locked());
+        // Only the last four are remembered.
+        for _ in 0..4 {
+            assert!(state.undo_last());
+        }
+        assert!(!state.undo_last());
+        assert!(!state.locked());
+        assert_eq!(state.total, Decimal::from(100));
+        assert!(state.deposits.contains_key(4));
+    }
+
+    #[test]
+    /// A rejected dispute puts back the deposit and the currency it touched, and undoing a
+    /// closure the balances in every currency
+    fn rollback_in_currencies() {
+        let eur = Some("EUR".parse().unwrap());
+        let usd = Some("USD".parse().unwrap());
+        let policy = AccountPolicy {
+            undo_depth: 1,
+            ..AccountPolicy::default()
+        };
+        let mut state = AccountState::new();
+        for (tx, currency) in [(1, eur), (2, usd)] {
+            state.transact(Transaction::Deposit {
+                client: 0,
+                tx,
+                amount: Decimal::from(10),
+                currency,
+                timestamp: None,
+            });
+        }
+        state.transact(Transaction::Withdrawal {
+            client: 0,
+            tx: 3,
+            amount: Decimal::from(8),
+            currency: eur,
+            timestamp: None,
+        });
+        let before = state.clone();
+        let dispute = Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            timestamp: Some(5),
+        };
+        assert_eq!(
+            state.transact_with(dispute, &policy),
+            TransactionOutcome::Rejected(RejectReason::NegativeBalance)
+        );
+        assert_eq!(state, before);
+        assert!(!state.deposits.get(1).unwrap().dispute);
+
+        assert_eq!(
+            state.transact_with(close(4, None), &policy),
+            TransactionOutcome::Applied
+        );
+        assert_eq!(state.balance(usd).available(), Decimal::zero());
+        assert!(state.undo_last());
+        assert_eq!(state, before);
+        assert_eq!(state.balance(usd).available(), Decimal::from(10));
+    }
+
+    #[test]
+    /// Without anything in the journal there is nothing to undo
+    fn undo_empty_journal() {
+        let mut state = AccountState::new();
+        assert!(!state.undo_last());
+        assert_eq!(state, AccountState::new());
+        // The default policy keeps no journal at all.
+        state.transact(interest(1, 5));
+        let before = state.clone();
+        assert!(!state.undo_last());
+        assert_eq!(state, before);
+    }
+
+    fn close(tx: u32, timestamp: Option<u64>) -> Transaction {
+        Transaction::Close {
+            client: 0,
+            tx,
+            timestamp,
+        }
+    }
+
+    #[test]
+    /// Closing pays out what's available and rejects anything but dispute handling afterwards
+    fn close_account() {
+        let mut state = AccountState::new();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 1,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 2,
+            amount: Decimal::from(30),
+            currency: None,
+            timestamp: None,
+        });
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 2,
+            timestamp: None,
+        });
+        assert_eq!(
+            state.transact(close(3, Some(50))),
+            TransactionOutcome::Applied
+        );
+        assert!(state.closed);
+        assert_eq!(state.closed_at, Some(50));
+        assert_eq!(state.payout, Decimal::from(100));
+        assert_eq!(state.available(), Decimal::from(0));
+        assert_eq!(state.held, Decimal::from(30));
+
+        let rejected = TransactionOutcome::Rejected(RejectReason::Closed);
+        let before = state.clone();
+        for transaction in [
+            Transaction::Deposit {
+                client: 0,
+                tx: 4,
+                amount: Decimal::from(10),
+                currency: None,
+                timestamp: Some(60),
+            },
+            Transaction::Withdrawal {
+                client: 0,
+                tx: 5,
+                amount: Decimal::from(10),
+                currency: None,
+                timestamp: None,
+            },
+            fee(6, 1),
+            interest(7, 1),
+            close(8, None),
+        ] {
+            assert_eq!(state.transact(transaction), rejected);
+        }
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    /// Deposits made before the closure can still be disputed, resolved and charged back
+    fn disputes_after_close() {
+        // Everything was paid out, so any dispute takes the funds below zero.
+        let policy = AccountPolicy {
+            negative: NegativePolicy::Allow,
+            ..AccountPolicy::default()
+        };
+        let mut state = AccountState::new();
+        for tx in [1, 2] {
+            state.transact(Transaction::Deposit {
+                client: 0,
+                tx,
+                amount: Decimal::from(40),
+                currency: None,
+                timestamp: None,
+            });
+        }
+        state.transact(close(3, None));
+        assert_eq!(state.total, Decimal::from(0));
+        let dispute = |tx| Transaction::Dispute {
+            client: 0,
+            tx,
+            timestamp: None,
+        };
+        assert_eq!(
+            state.transact_with(dispute(1), &policy),
+            TransactionOutcome::Applied
+        );
+        assert_eq!(
+            state.transact_with(
+                Transaction::Resolve {
+                    client: 0,
+                    tx: 1,
+                    amount: None,
+                    timestamp: None,
+                },
+                &policy
+            ),
+            TransactionOutcome::Applied
+        );
+        assert_eq!(
+            state.transact_with(dispute(2), &policy),
+            TransactionOutcome::Applied
+        );
+        assert_eq!(
+            state.transact_with(
+                Transaction::Chargeback {
+                    client: 0,
+                    tx: 2,
+                    amount: None,
+                    timestamp: None,
+                },
+                &policy
+            ),
+            TransactionOutcome::Applied
+        );
+        assert!(state.locked());
+        assert!(state.closed);
+    }
+
+    #[test]
+    /// Disputing funds that were withdrawn already leaves a deficit only if the policy allows
+    /// it, and deposits that cover the hold clear it again
+    fn deficit() {
+        let deposit = |tx, amount| Transaction::Deposit {
+            client: 0,
+            tx,
+            amount: Decimal::from(amount),
+            currency: None,
+            timestamp: None,
+        };
+        let sequence = [
+            deposit(1, 100),
+            Transaction::Withdrawal {
+                client: 0,
+                tx: 2,
+                amount: Decimal::from(100),
+                currency: None,
+                timestamp: None,
+            },
+            Transaction::Dispute {
+                client: 0,
+                tx: 1,
+                timestamp: None,
+            },
+        ];
+        for (negative, held, deficit) in [
+            (NegativePolicy::Reject, 0, false),
+            (NegativePolicy::Clamp, 0, false),
+            (NegativePolicy::Allow, 100, true),
+        ] {
+            let policy = AccountPolicy {
+                negative,
+                ..AccountPolicy::default()
+            };
+            let mut state = AccountState::new();
+            for transaction in sequence.iter().cloned() {
+                state.transact_with(transaction, &policy);
+            }
+            assert_eq!(state.held, Decimal::from(held), "{:?}", negative);
+            assert_eq!(state.deficit(), deficit, "{:?}", negative);
+            // Covering part of the hold isn't enough, covering all of it is.
+            state.transact_with(deposit(3, 60), &policy);
+            assert_eq!(state.deficit(), deficit, "{:?}", negative);
+            state.transact_with(deposit(4, 40), &policy);
+            assert!(!state.deficit(), "{:?}", negative);
+        }
+    }
+
+    #[test]
+    /// Disputing a deposit that was partly withdrawn holds what's left of it when clamping,
+    /// all of it when allowed to go negative, and nothing when rejected
+    fn dispute_after_partial_withdrawal() {
+        let deposit = Transaction::Deposit {
+            client: 0,
+            tx: 1,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        };
+        let withdrawal = Transaction::Withdrawal {
+            client: 0,
+            tx: 2,
+            amount: Decimal::from(60),
+            currency: None,
+            timestamp: None,
+        };
+        let dispute = Transaction::Dispute {
+            client: 0,
+            tx: 1,
+            timestamp: None,
+        };
+        for (negative, outcome, held, available) in [
+            (
+                NegativePolicy::Reject,
+                TransactionOutcome::Rejected(RejectReason::NegativeBalance),
+                0,
+                40,
+            ),
+            (NegativePolicy::Clamp, TransactionOutcome::Applied, 40, 0),
+            (NegativePolicy::Allow, TransactionOutcome::Applied, 100, -60),
+        ] {
+            let policy = AccountPolicy {
+                negative,
+                ..AccountPolicy::default()
+            };
+            let mut state = AccountState::new();
+            state.transact_with(deposit.clone(), &policy);
+            state.transact_with(withdrawal.clone(), &policy);
+            assert_eq!(
+                state.transact_with(dispute.clone(), &policy),
+                outcome,
+                "{:?}",
+                negative
+            );
+            assert_eq!(state.held, Decimal::from(held), "{:?}", negative);
+            assert_eq!(
+                state.available(),
+                Decimal::from(available),
+                "{:?}",
+                negative
+            );
+            assert_eq!(state.total, Decimal::from(40), "{:?}", negative);
+        }
+    }
+
+    #[test]
+    /// Amounts near the limits of a Decimal never panic. Whatever would overflow is rejected
+    /// and leaves the account alone
+    fn overflow() {
+        let amounts = [
+            Decimal::MAX,
+            Decimal::MAX - Decimal::ONE,
+            Decimal::MAX / Decimal::TWO,
+            Decimal::ONE,
+            Decimal::MIN,
+        ];
+        let transactions = |tx: u32, amount: Decimal| {
+            [
+                Transaction::Deposit {
+                    client: 0,
+                    tx,
+                    amount,
+                    currency: None,
+                    timestamp: None,
+                },
+                Transaction::Withdrawal {
+                    client: 0,
+                    tx,
+                    amount,
+                    currency: None,
+                    timestamp: None,
+                },
+                Transaction::Dispute {
+                    client: 0,
+                    tx,
+                    timestamp: None,
+                },
+                Transaction::Resolve {
+                    client: 0,
+                    tx,
+                    amount: None,
+                    timestamp: None,
+                },
+                Transaction::Chargeback {
+                    client: 0,
+                    tx,
+                    amount: Some(amount),
+                    timestamp: None,
+                },
+                Transaction::Fee {
+                    client: 0,
+                    tx,
+                    amount,
+                    currency: None,
+                    timestamp: None,
+                },
+                Transaction::Interest {
+                    client: 0,
+                    tx,
+                    amount,
+                    currency: None,
+                    timestamp: None,
+                },
+            ]
+        };
+        for negative in [
+            NegativePolicy::Reject,
+            NegativePolicy::Clamp,
+            NegativePolicy::Allow,
+        ] {
+            let policy = AccountPolicy {
+                negative,
+                ..AccountPolicy::default()
+            };
+            let mut state = AccountState::new();
+            let mut tx = 0;
+            // Every transaction type with every amount, twice over, each referring to the
+            // deposits of the round before.
+            for _ in 0..2 {
+                for amount in amounts {
+                    for transaction in transactions(tx % 8, amount) {
+                        let before = state.clone();
+                        let outcome = state.transact_with(transaction, &policy);
+                        if let TransactionOutcome::Rejected(_) = outcome {
+                            assert_eq!(state, before);
+                        }
+                        tx += 1;
+                    }
+                }
+            }
+        }
+        let mut state = AccountState::new();
+        let deposit = |tx| Transaction::Deposit {
+            client: 0,
+            tx,
+            amount: Decimal::MAX,
+            currency: None,
+            timestamp: None,
+        };
+        assert_eq!(state.transact(deposit(1)), TransactionOutcome::Applied);
+        assert_eq!(
+            state.transact(deposit(2)),
+            TransactionOutcome::Rejected(RejectReason::Overflow)
+        );
+        assert_eq!(state.total, Decimal::MAX);
+        assert!(!state.deposits.contains_key(2));
+    }
+
+    #[test]
+    /// Deposits may take the total up to the cap, but not beyond
+    fn balance_cap() {
+        let policy = AccountPolicy {
+            balance_cap: Some(Decimal::from(100)),
+            ..AccountPolicy::default()
+        };
+        let deposit = |tx, amount| Transaction::Deposit {
+            client: 0,
+            tx,
+            amount,
+            currency: None,
+            timestamp: None,
+        };
+        let mut state = AccountState::new();
+        let applied = TransactionOutcome::Applied;
+        let capped = TransactionOutcome::Rejected(RejectReason::BalanceCap(Decimal::from(100)));
+        assert_eq!(
+            state.transact_with(deposit(1, Decimal::from(60)), &policy),
+            applied
+        );
+        assert_eq!(
+            state.transact_with(deposit(2, Decimal::new(400001, 4)), &policy),
+            capped
+        );
+        assert_eq!(
+            state.transact_with(deposit(3, Decimal::from(40)), &policy),
+            applied
+        );
+        assert_eq!(
+            state.transact_with(deposit(4, Decimal::new(1, 4)), &policy),
+            capped
+        );
+        assert_eq!(state.total, Decimal::from(100));
+        assert!(!state.deposits.contains_key(4));
+        // Interest isn't a deposit.
+        assert_eq!(state.transact_with(interest(5, 1), &policy), applied);
+        assert_eq!(state.total, Decimal::from(101));
+    }
+
+    #[test]
+    /// Once as many disputes are open as the cap allows, further ones are rejected until one
+    /// of them is settled
+    fn dispute_cap() {
+        let policy = AccountPolicy {
+            max_open_disputes: Some(1),
+            ..AccountPolicy::default()
+        };
+        let dispute = |tx| Transaction::Dispute {
+            client: 0,
+            tx,
+            timestamp: None,
+        };
+        let mut state = AccountState::new();
+        for tx in [1, 2] {
+            let deposit = Transaction::Deposit {
+                client: 0,
+                tx,
+                amount: Decimal::from(10 * tx),
+                currency: None,
+                timestamp: None,
+            };
+            state.transact_with(deposit, &policy);
+        }
+        let applied = TransactionOutcome::Applied;
+        let capped = TransactionOutcome::Rejected(RejectReason::DisputeCap(1));
+        assert_eq!(state.transact_with(dispute(1), &policy), applied);
+        assert_eq!(state.transact_with(dispute(2), &policy), capped);
+        assert_eq!(state.held, Decimal::from(10));
+        assert!(!state.deposits.get(2).unwrap().dispute);
+        assert_eq!(state.disputed, 1);
+
+        let resolve = Transaction::Resolve {
+            client: 0,
+            tx: 1,
+            amount: None,
+            timestamp: None,
+        };
+        state.transact_with(resolve, &policy);
+        assert_eq!(state.disputed, 0);
+        assert_eq!(state.transact_with(dispute(2), &policy), applied);
+        assert_eq!(state.held, Decimal::from(20));
+    }
+
+    #[test]
+    /// A resolved deposit can be disputed again while fewer transactions than the window came
+    /// since, and not after that. Without a window there is no limit
+    fn redispute_window() {
+        let policy = AccountPolicy {
+            redispute_window: Some(2),
+            ..AccountPolicy::default()
+        };
+        let deposit = |tx| Transaction::Deposit {
+            client: 0,
+            tx,
+            amount: Decimal::from(10),
+            currency: None,
+            timestamp: None,
+        };
+        let dispute = |tx| Transaction::Dispute {
+            client: 0,
+            tx,
+            timestamp: None,
+        };
+        let resolve = |tx| Transaction::Resolve {
+            client: 0,
+            tx,
+            amount: None,
+            timestamp: None,
+        };
+        let applied = TransactionOutcome::Applied;
+        let late = TransactionOutcome::Rejected(RejectReason::RedisputeWindow(2));
+        for policy in [policy, AccountPolicy::default()] {
+            let mut state = AccountState::new();
+            state.transact_with(deposit(1), &policy);
+            state.transact_with(dispute(1), &policy);
+            state.transact_with(resolve(1), &policy);
+            state.transact_with(deposit(2), &policy);
+            assert_eq!(state.transact_with(dispute(1), &policy), applied);
+            state.transact_with(resolve(1), &policy);
+            state.transact_with(deposit(3), &policy);
+            state.transact_with(deposit(4), &policy);
+            let outcome = state.transact_with(dispute(1), &policy);
+            match policy.redispute_window {
+                Some(_) => {
+                    assert_eq!(outcome, late);
+                    assert_eq!(state.held, Decimal::zero());
+                    assert!(!state.deposits.get(1).unwrap().dispute);
+                }
+                None => assert_eq!(outcome, applied),
+            }
+            assert_eq!(state.operations, 9 - u64::from(outcome != applied));
+        }
+    }
+
+    #[test]
+    /// A locked account can't be closed, a frozen one neither
+    fn no_close_when_locked() {
+        let mut state = AccountState::new();
+        state.transact(Transaction::Deposit {
+            client: 0,
+            tx: 1,
+            amount: Decimal::from(40),
+            currency: None,
+            timestamp: None,
+        });
+        state.frozen = true;
+        assert_eq!(
+            state.transact(close(2, None)),
+            TransactionOutcome::Ignored(IgnoreReason::Locked)
+        );
+        assert!(!state.closed);
+        assert_eq!(state.available(), Decimal::from(40));
+        state.frozen = false;
+        assert_eq!(state.transact(close(2, None)), TransactionOutcome::Applied);
+        assert!(!state.is_empty());
+    }
+
+    #[test]
+    /// Balances in different currencies don't mix, and disputes hold funds in the currency of
+    /// their deposit
+    fn currencies() {
+        let usd = Some("USD".parse().unwrap());
+        let eur = Some("EUR".parse().unwrap());
+        let mut state = AccountState::new();
+        for (tx, amount, currency) in [(1, 100, usd), (2, 50, eur)] {
+            state.transact(Transaction::Deposit {
+                client: 0,
+                tx,
+                amount: Decimal::from(amount),
+                currency,
+                timestamp: None,
+            });
+        }
+        let withdrawal = |tx, amount, currency| Transaction::Withdrawal {
+            client: 0,
+            tx,
+            amount: Decimal::from(amount),
+            currency,
+            timestamp: None,
+        };
+        // There are only 50 EUR, however many dollars there are.
+        assert_eq!(
+            state.transact(withdrawal(3, 80, eur)),
+            TransactionOutcome::Ignored(IgnoreReason::InsufficientFunds)
+        );
+        assert_eq!(
+            state.transact(withdrawal(4, 80, usd)),
+            TransactionOutcome::Applied
+        );
+        state.transact(Transaction::Dispute {
+            client: 0,
+            tx: 2,
+            timestamp: None,
+        });
+        assert_eq!(
+            state.balance(usd),
+            Balance {
+                held: Decimal::from(0),
+                total: Decimal::from(20),
+                payout: Decimal::from(0),
+            }
+        );
+        assert_eq!(state.balance(eur).held, Decimal::from(50));
+        assert_eq!(state.balance(eur).available(), Decimal::from(0));
+        // Nothing ever happened in the implicit currency, so it isn't listed.
+        assert_eq!(state.available(), Decimal::from(0));
+        let currencies: Vec<_> = state.balances().map(|(currency, _)| currency).collect();
+        assert_eq!(currencies, vec![eur, usd]);
+    }
 }
@@ -0,0 +1,148 @@
+use crate::account::AccountState;
+use crate::transaction::ClientId;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Index;
+
+/// Where a shard keeps its accounts. Hashed is what lookups like best, and what transacting
+/// is all about. Ordered costs a little on every lookup, but hands out accounts in client
+/// order without having to sort them, which makes writing a sorted report of tens of millions
+/// of accounts possible without first collecting all of their ids, see
+/// [crate::system::ShardedAccountSystem::enable_ordered_accounts].
+#[derive(Debug, Clone)]
+pub(crate) enum AccountMap {
+    Hashed(HashMap<ClientId, AccountState>),
+    Ordered(BTreeMap<ClientId, AccountState>),
+}
+
+impl Default for AccountMap {
+    fn default() -> Self {
+        AccountMap::Hashed(HashMap::new())
+    }
+}
+
+/// The same accounts, however they're kept.
+impl PartialEq for AccountMap {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(client, account)| other.get(client) == Some(account))
+    }
+}
+
+impl AccountMap {
+    /// Keeps the accounts in client order from now on.
+    pub fn make_ordered(&mut self) {
+        if let AccountMap::Hashed(accounts) = self {
+            *self = AccountMap::Ordered(std::mem::take(accounts).into_iter().collect());
+        }
+    }
+
+    pub fn is_ordered(&self) -> bool {
+        matches!(self, AccountMap::Ordered(_))
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            AccountMap::Hashed(accounts) => accounts.len(),
+            AccountMap::Ordered(accounts) => accounts.len(),
+        }
+    }
+
+    pub fn contains_key(&self, client: &ClientId) -> bool {
+        match self {
+            AccountMap::Hashed(accounts) => accounts.contains_key(client),
+            AccountMap::Ordered(accounts) => accounts.contains_key(client),
+        }
+    }
+
+    pub fn get(&self, client: &ClientId) -> Option<&AccountState> {
+        match self {
+            AccountMap::Hashed(accounts) => accounts.get(client),
+            AccountMap::Ordered(accounts) => accounts.get(client),
+        }
+    }
+
+    pub fn get_mut(&mut self, client: &ClientId) -> Option<&mut AccountState> {
+        match self {
+            AccountMap::Hashed(accounts) => accounts.get_mut(client),
+            AccountMap::Ordered(accounts) => accounts.get_mut(client),
+        }
+    }
+
+    /// The client's account, opened if there is none yet.
+    pub fn get_or_insert_with(
+        &mut self,
+        client: ClientId,
+        new: impl FnOnce() -> AccountState,
+    ) -> &mut AccountState {
+        match self {
+            AccountMap::Hashed(accounts) => accounts.entry(client).or_insert_with(new),
+            AccountMap::Ordered(accounts) => accounts.entry(client).or_insert_with(new),
+        }
+    }
+
+    pub fn insert(&mut self, client: ClientId, account: AccountState) -> Option<AccountState> {
+        match self {
+            AccountMap::Hashed(accounts) => accounts.insert(client, account),
+            AccountMap::Ordered(accounts) => accounts.insert(client, account),
+        }
+    }
+
+    pub fn remove(&mut self, client: &ClientId) -> Option<AccountState> {
+        match self {
+            AccountMap::Hashed(accounts) => accounts.remove(client),
+            AccountMap::Ordered(accounts) => accounts.remove(client),
+        }
+    }
+
+    /// In client order if ordered, in no particular order otherwise.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (&ClientId, &AccountState)> + '_> {
+        match self {
+            AccountMap::Hashed(accounts) => Box::new(accounts.iter()),
+            AccountMap::Ordered(accounts) => Box::new(accounts.iter()),
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &ClientId> {
+        self.iter().map(|(client, _)| client)
+    }
+
+    /// In client order either way. Hashed accounts are sorted for that, which takes an id
+    /// per account.
+    pub fn sorted(&self) -> Box<dyn Iterator<Item = (ClientId, &AccountState)> + '_> {
+        match self {
+            AccountMap::Hashed(accounts) => {
+                let mut sorted: Vec<(ClientId, &AccountState)> = accounts
+                    .iter()
+                    .map(|(client, account)| (*client, account))
+                    .collect();
+                sorted.sort_unstable_by_key(|(client, _)| *client);
+                Box::new(sorted.into_iter())
+            }
+            AccountMap::Ordered(accounts) => {
+                Box::new(accounts.iter().map(|(client, account)| (*client, account)))
+            }
+        }
+    }
+}
+
+impl Index<&ClientId> for AccountMap {
+    type Output = AccountState;
+
+    fn index(&self, client: &ClientId) -> &AccountState {
+        self.get(client).expect("no account for the client")
+    }
+}
+
+impl IntoIterator for AccountMap {
+    type Item = (ClientId, AccountState);
+    type IntoIter = Box<dyn Iterator<Item = (ClientId, AccountState)>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        match self {
+            AccountMap::Hashed(accounts) => Box::new(accounts.into_iter()),
+            AccountMap::Ordered(accounts) => Box::new(accounts.into_iter()),
+        }
+    }
+}
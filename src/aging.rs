@@ -0,0 +1,238 @@
+use crate::system::ShardedAccountSystem;
+use crate::transaction::ClientId;
+use rust_decimal::prelude::Zero;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io::Write;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Upper bounds (inclusive, in days) of the age buckets. Anything older lands in the last one.
+const BUCKETS: [(u64, &str); 2] = [(30, "0-30d"), (60, "31-60d")];
+const OLDEST_BUCKET: &str = ">60d";
+/// Disputes without a timestamp have no age.
+const UNKNOWN_BUCKET: &str = "unknown";
+
+/// A deposit whose funds are held because of an open dispute.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeldEntry {
+    pub client: ClientId,
+    pub tx: u32,
+    #[serde(with = "rust_decimal::serde::float")]
+    pub amount: Decimal,
+    pub disputed_at: Option<u64>,
+    /// Whole days between the dispute and the report date.
+    pub age_days: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct BucketRow<'a> {
+    bucket: &'a str,
+    #[serde(with = "rust_decimal::serde::float")]
+    amount: Decimal,
+}
+
+/// How long money has been sitting in `held`: every open dispute with its age, oldest first,
+/// plus the held amounts summed up per age bucket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeldReport {
+    pub entries: Vec<HeldEntry>,
+    /// Held amounts for 0-30, 31-60 and more than 60 days.
+    pub buckets: [Decimal; 3],
+    /// Held amounts whose dispute had no timestamp.
+    pub unknown: Decimal,
+}
+
+impl HeldReport {
+    /// Collects the open disputes of every account and ages them relative to `as_of` (seconds
+    /// since the Unix epoch).
+    pub fn new(system: &ShardedAccountSystem, as_of: u64) -> Self {
+        let mut entries: Vec<HeldEntry> = system
+            .accounts()
+            .flat_map(|(client, account)| {
+                account.open_disputes().map(move |(tx, deposit)| HeldEntry {
+                    client,
+                    tx,
+                    amount: deposit.amount(),
+                    disputed_at: deposit.disputed_at(),
+                    age_days: deposit
+                        .disputed_at()
+                        .map(|disputed_at| as_of.saturating_sub(disputed_at) / DAY),
+                })
+            })
+            .collect();
+        // Oldest first, disputes of unknown age last. Client and tx make the order total.
+        entries.sort_by(|a, b| {
+            b.age_days
+                .cmp(&a.age_days)
+                .then(a.client.cmp(&b.client))
+                .then(a.tx.cmp(&b.tx))
+        });
+
+        let mut buckets = [Decimal::zero(); 3];
+        let mut unknown = Decimal::zero();
+        for entry in entries.iter() {
+            match entry.age_days {
+                Some(age) => {
+                    let bucket = BUCKETS
+                        .iter()
+                        .position(|(limit, _)| age <= *limit)
+                        .unwrap_or(BUCKETS.len());
+                    buckets[bucket] += entry.amount;
+                }
+                None => unknown += entry.amount,
+            }
+        }
+        HeldReport {
+            entries,
+            buckets,
+            unknown,
+        }
+    }
+
+    /// Writes the entries as CSV, followed by an empty line and the bucket totals as a second
+    /// CSV table. The unknown bucket only shows up if there is something in it.
+    pub fn write<W: Write>(&self, mut writer: W) -> anyhow::Result<()> {
+        let mut entries = csv::Writer::from_writer(&mut writer);
+        if self.entries.is_empty() {
+            entries.write_record(["client", "tx", "amount", "disputed_at", "age_days"])?;
+        }
+        for entry in self.entries.iter() {
+            entries.serialize(entry)?;
+        }
+        entries.flush()?;
+        drop(entries);
+        writer.write_all(b"\n")?;
+
+        let mut buckets = csv::Writer::from_writer(&mut writer);
+        let names = BUCKETS.iter().map(|(_, name)| *name).chain([OLDEST_BUCKET]);
+        for (bucket, amount) in names.zip(self.buckets) {
+            buckets.serialize(BucketRow { bucket, amount })?;
+        }
+        if !self.unknown.is_zero() {
+            buckets.serialize(BucketRow {
+                bucket: UNKNOWN_BUCKET,
+                amount: self.unknown,
+            })?;
+        }
+        buckets.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+
+    fn deposit(client: ClientId, tx: u32, amount: u32) -> Transaction {
+        Transaction::Deposit {
+            client,
+            tx,
+            amount: Decimal::from(amount),
+            currency: None,
+            timestamp: None,
+        }
+    }
+
+    fn dispute(client: ClientId, tx: u32, timestamp: Option<u64>) -> Transaction {
+        Transaction::Dispute {
+            client,
+            tx,
+            timestamp,
+        }
+    }
+
+    #[test]
+    /// Open disputes come out oldest first and are summed up per bucket. Resolved and charged
+    /// back ones are gone.
+    fn ages_and_buckets() {
+        let now = 100 * DAY;
+        let mut system = ShardedAccountSystem::new(2);
+        for tx in 1..=7 {
+            system.transact(deposit(tx as ClientId % 3, tx, tx * 10));
+        }
+        system.transact(dispute(1, 1, Some(now - 5 * DAY)));
+        system.transact(dispute(2, 2, Some(now - 30 * DAY)));
+        system.transact(dispute(0, 3, Some(now - 31 * DAY)));
+        system.transact(dispute(1, 4, Some(now - 90 * DAY)));
+        system.transact(dispute(2, 5, None));
+        system.transact(dispute(0, 6, Some(now - 40 * DAY)));
+        system.transact(Transaction::Resolve {
+            client: 0,
+            tx: 6,
+            amount: None,
+            timestamp: None,
+        });
+        system.transact(dispute(1, 7, Some(now - 70 * DAY)));
+        system.transact(Transaction::Chargeback {
+            client: 1,
+            tx: 7,
+            amount: None,
+            timestamp: None,
+        });
+
+        let report = HeldReport::new(&system, now);
+        let order: Vec<(u32, Option<u64>)> = report
+            .entries
+            .iter()
+            .map(|entry| (entry.tx, entry.age_days))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (4, Some(90)),
+                (3, Some(31)),
+                (2, Some(30)),
+                (1, Some(5)),
+                (5, None)
+            ]
+        );
+        assert_eq!(
+            report.buckets,
+            [Decimal::from(30), Decimal::from(30), Decimal::from(40)]
+        );
+        assert_eq!(report.unknown, Decimal::from(50));
+
+        let mut output = Vec::new();
+        report.write(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,tx,amount,disputed_at,age_days
+1,4,40.0,864000,90
+0,3,30.0,5961600,31
+2,2,20.0,6048000,30
+1,1,10.0,8208000,5
+2,5,50.0,,
+
+bucket,amount
+0-30d,30.0
+31-60d,30.0
+>60d,40.0
+unknown,50.0
+"
+        );
+    }
+
+    #[test]
+    /// Disputes of the same amount and age are listed by client and transaction id, not in the
+    /// order they were disputed in
+    fn equal_amounts_and_ages() {
+        let now = 100 * DAY;
+        let mut system = ShardedAccountSystem::new(2);
+        for (client, tx) in [(2, 9), (1, 8), (2, 4)] {
+            system.transact(deposit(client, tx, 50));
+        }
+        for (client, tx) in [(2, 9), (1, 8), (2, 4)] {
+            system.transact(dispute(client, tx, Some(now - 10 * DAY)));
+        }
+        let report = HeldReport::new(&system, now);
+        let order: Vec<(ClientId, u32)> = report
+            .entries
+            .iter()
+            .map(|entry| (entry.client, entry.tx))
+            .collect();
+        assert_eq!(order, vec![(1, 8), (2, 4), (2, 9)]);
+        assert_eq!(report.buckets[0], Decimal::from(150));
+    }
+}
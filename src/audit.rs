@@ -0,0 +1,77 @@
+use crate::account::TransactionOutcome;
+use crate::transaction::{ClientId, Transaction};
+use csv::Writer;
+use std::io::Write;
+
+/// What happened to a single transaction, see [AuditLog].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// Numbers entries in the order the transactions were applied, across every shard,
+    /// starting at zero.
+    pub seq: u64,
+    pub client: ClientId,
+    pub tx: u32,
+    /// The transaction type, as used in the input.
+    pub kind: &'static str,
+    pub outcome: TransactionOutcome,
+}
+
+/// Append-only record of every transaction a [crate::system::ShardedAccountSystem] applied,
+/// ignored or rejected, including scheduled ones once they become due. Entries are only ever
+/// added, so the sequence numbers increase without gaps.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AuditLog {
+    entries: Vec<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an entry for `transaction`, numbered after the last one.
+    pub(crate) fn record(&mut self, transaction: &Transaction, outcome: TransactionOutcome) {
+        self.entries.push(AuditEntry {
+            seq: self.entries.len() as u64,
+            client: *transaction.id(),
+            tx: transaction.tx(),
+            kind: transaction.kind(),
+            outcome,
+        });
+    }
+
+    /// Every entry, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &AuditEntry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Dumps the log as CSV, oldest entry first. Ignored and rejected transactions come with
+    /// their reason.
+    pub fn write<W: Write>(&self, writer: &mut Writer<W>) -> csv::Result<()> {
+        writer.write_record(["seq", "client", "tx", "type", "outcome"])?;
+        for entry in &self.entries {
+            let outcome = match entry.outcome {
+                TransactionOutcome::Applied => "applied".to_string(),
+                TransactionOutcome::Ignored(reason) => format!("ignored: {}", reason),
+                TransactionOutcome::Rejected(reason) => format!("rejected: {}", reason),
+            };
+            writer.write_record([
+                entry.seq.to_string(),
+                entry.client.to_string(),
+                entry.tx.to_string(),
+                entry.kind.to_string(),
+                outcome,
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
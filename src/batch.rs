@@ -0,0 +1,53 @@
+use crate::account::TransactionOutcome;
+
+/// Knobs for [crate::system::ShardedAccountSystem::transact_all_with] and its parallel
+/// counterpart.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct BatchOptions {
+    /// Keep the outcomes of the first this many transactions in [BatchReport::outcomes]. The
+    /// counters always cover the whole batch, but a list of every outcome would grow with the
+    /// input, which is why none are kept by default.
+    pub max_outcomes: usize,
+}
+
+/// What came of a batch of transactions.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BatchReport {
+    pub applied: u64,
+    pub ignored: u64,
+    pub rejected: u64,
+    /// Individual outcomes in the order of the batch, see [BatchOptions::max_outcomes].
+    pub outcomes: Vec<TransactionOutcome>,
+}
+
+impl BatchReport {
+    /// Number of transactions in the batch.
+    pub fn len(&self) -> u64 {
+        self.applied + self.ignored + self.rejected
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Counts the outcome of the `index`-th transaction of the batch.
+    pub(crate) fn record(
+        &mut self,
+        index: usize,
+        outcome: TransactionOutcome,
+        options: &BatchOptions,
+    ) {
+        match outcome {
+            TransactionOutcome::Applied => self.applied += 1,
+            TransactionOutcome::Ignored(_) => self.ignored += 1,
+            TransactionOutcome::Rejected(_) => self.rejected += 1,
+        }
+        if index < options.max_outcomes {
+            // Outcomes may arrive out of order from parallel shards, so make room first.
+            if self.outcomes.len() <= index {
+                self.outcomes.resize(index + 1, TransactionOutcome::Applied);
+            }
+            self.outcomes[index] = outcome;
+        }
+    }
+}
@@ -0,0 +1,1374 @@
+use crate::config_file;
+use crate::flush::FlushPolicy;
+use crate::ordering::{TxOrderScope, Until};
+use crate::parallel::ParallelAccountSystem;
+use crate::policy::{AccountPolicy, NegativePolicy};
+use crate::reporter::Verbosity;
+use crate::sink::Compression;
+use crate::system::ReportOptions;
+use crate::transaction::{ClientId, RecordType};
+use anyhow::{anyhow, bail};
+use rust_decimal::{Decimal, RoundingStrategy};
+use std::num::NonZeroU32;
+use std::str::FromStr;
+
+/// What a run is supposed to do.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Apply the transactions in the input CSV and report on the accounts.
+    Process,
+    /// Rebuild the accounts from an event log and report on them.
+    Replay,
+    /// Apply the transactions in the input CSV and report on the funds held by open disputes.
+    HeldReport,
+    /// Read the input CSV without applying anything and estimate the memory processing it
+    /// would take, see [crate::estimate::MemoryEstimate].
+    Estimate,
+    /// Apply the input, or made-up transactions if there's none, at growing shard counts and
+    /// suggest one, see [crate::tune::tune].
+    Tune,
+    /// Print a configuration file with every option at its default, see
+    /// [crate::config_file::default_file].
+    PrintDefaultConfig,
+    /// Compare the input, a report, against another one and list how they differ, see
+    /// [crate::report_diff::Report::diff].
+    Diff,
+}
+
+/// How `track diff` lists the differences between the reports.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum DiffFormat {
+    /// A line for every difference, for people.
+    #[default]
+    Text,
+    /// A JSON object for every difference, one per line, see
+    /// [crate::reconcile::Mismatch].
+    Json,
+}
+
+/// What to do with scheduled transactions that haven't become due by the end of the input.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PendingAtEnd {
+    /// Apply them anyway, in the order they would have become due.
+    Apply,
+    /// Leave the accounts alone and list them in a section of their own after the report.
+    Report,
+}
+
+/// The order accounts come in in the report.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ReportOrder {
+    /// Shard after shard, each in client order. Every shard's accounts are written in one go,
+    /// see [crate::system::ShardedAccountSystem::write].
+    #[default]
+    Shards,
+    /// In client order throughout, see [crate::system::ShardedAccountSystem::write_sorted].
+    Client,
+    /// By total in the implicit currency, largest first, ties in client order. See
+    /// [crate::system::ShardedAccountSystem::write_by_total].
+    Total,
+}
+
+/// What to do with amounts that carry more than the four decimal places we keep.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PrecisionPolicy {
+    /// Round them using [Config::rounding].
+    Round,
+    /// Cut off the extra places, whatever `--rounding` says.
+    Truncate,
+    /// Reject the row rather than change an amount the partner sent us.
+    Reject,
+}
+
+/// What happens once the accounts are estimated to take more than [Config::max_memory].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum MemoryAction {
+    /// Stop the run with an error.
+    #[default]
+    Abort,
+    /// Forget accounts that hold nothing and were last active before the latest timestamp,
+    /// see [crate::system::ShardedAccountSystem::compact], and stop only if that isn't enough.
+    /// The accounts forgotten are missing from the report.
+    Compact,
+    /// Move deposits that aren't disputed to [Config::spill_file], and stop only if that isn't
+    /// enough. Needs the `spill-deposits` feature.
+    Spill,
+}
+
+/// Everything the command line lets one tweak about a run. The defaults reproduce the
+/// behaviour required by the problem statement, so `track transactions.csv` keeps working
+/// exactly as before.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub command: Command,
+    /// Path to the CSV file containing the transactions.
+    pub input: String,
+    /// Strategy used when rounding amounts to four decimal places. `round_dp` uses banker's
+    /// rounding, so that's what we default to.
+    pub rounding: RoundingStrategy,
+    /// Whether amounts with more than four decimal places are rounded at all.
+    pub precision: PrecisionPolicy,
+    /// How fees, interest and locked accounts are handled.
+    pub policy: AccountPolicy,
+    /// Transactions moving more than this are rejected as they are read, before they can do
+    /// any harm. Amounts are compared after rounding.
+    pub max_amount: Option<Decimal>,
+    /// Warn about deposits and withdrawals whose transaction id does not increase.
+    pub check_tx_order: Option<TxOrderScope>,
+    /// Optional CSV file that receives warnings and rejected transactions.
+    pub rejects: Option<String>,
+    /// File the report is written to instead of stdout.
+    pub output: Option<String>,
+    /// Writes every shard's accounts to a file of its own instead, named after this template
+    /// with `{shard}` replaced by the shard's index.
+    pub output_per_shard: Option<String>,
+    /// How the report and the rejects file are compressed. `None` goes by their extension,
+    /// see [Compression::detect].
+    pub compress: Option<Compression>,
+    /// How much to print to stderr, see [crate::reporter::Reporter]. Stdout only ever gets
+    /// the report.
+    pub verbosity: Verbosity,
+    /// Print the [crate::summary::RunSummary] to stderr once done.
+    pub summary: bool,
+    /// Keep this many ignored transactions in [crate::summary::RunSummary::ignored_records].
+    /// They are counted by reason either way, so none are kept by default.
+    pub max_ignored: usize,
+    /// Measure how long parsing, transacting and writing take and print that to stderr once
+    /// done, see [crate::summary::Timings]. Replaying doesn't collect any.
+    pub timings: bool,
+    /// Buffer the whole file and apply transactions in timestamp order.
+    pub sort_by_time: bool,
+    /// The input is sorted by client, so every account can be reported (and forgotten) as soon
+    /// as the next client shows up. Rows of a client that was done already are rejected, see
+    /// [crate::ordering::ClientRuns].
+    pub sorted_by_client: bool,
+    /// Add the optimistic available funds and the account activity window to the report.
+    pub extended: bool,
+    /// CSV file with exchange rates into a base currency, see [crate::rates::Rates]. The
+    /// extended report then has every balance in the base currency as well.
+    pub rates: Option<String>,
+    /// Leave accounts without any funds out of the report, unless they are locked.
+    pub suppress_empty: bool,
+    /// When the report is flushed while it's written.
+    pub flush: FlushPolicy,
+    /// The order of the accounts in the report.
+    pub sort: ReportOrder,
+    /// Write the locked accounts before the others, each group in [Config::sort] order.
+    pub locked_first: bool,
+    /// Write the locked accounts to this file instead, and only the others to the report.
+    pub locked_output: Option<String>,
+    /// Keep the accounts in client order while processing, so a sorted report doesn't need to
+    /// sort them first.
+    pub ordered_accounts: bool,
+    /// What happens to scheduled transactions that are still pending at the end.
+    pub pending: PendingAtEnd,
+    /// Feed at most this many transactions per second to the engine, see
+    /// [crate::throttle::RateLimiter].
+    pub max_rate: Option<NonZeroU32>,
+    /// Soft limit on the estimated memory the accounts take, in bytes. See
+    /// [crate::system::ShardedAccountSystem::memory_footprint].
+    pub max_memory: Option<usize>,
+    /// Do the arithmetic on integers rather than decimals, see [crate::minor]. Only for the
+    /// plain report under the default policy.
+    pub integer_amounts: bool,
+    /// Lines of the input starting with this character are comments, and skipped. `#` by
+    /// default, which no row of transactions starts with.
+    pub comment: Option<u8>,
+    /// Read the input as gzip whatever its name. Without this only a name ending in `.gz` is,
+    /// see [crate::source::Source::open].
+    pub gzip: bool,
+    /// Headers sent along when the input is a URL, as `--header "Name: value"` gives them.
+    pub headers: Vec<(String, String)>,
+    /// Read files with nothing but the `type,client,tx,amount` header without serde. On by
+    /// default, off is there to rule it out. Either way the outcome is the same.
+    pub fast_parse: bool,
+    /// How many rows a thread of its own may read and parse ahead of the engine. Zero reads
+    /// on the engine's thread, as does [Config::integer_amounts].
+    pub read_ahead: usize,
+    /// Deposits every new account has room for from the start, see
+    /// [crate::account::AccountState::with_deposit_capacity]. Zero grows them as needed.
+    pub deposit_capacity: usize,
+    /// What to do once [Config::max_memory] is crossed.
+    pub on_max_memory: MemoryAction,
+    /// File that deposits are spilled to when accounts outgrow memory, see [crate::spill].
+    /// Needs the `spill-deposits` feature.
+    pub spill_file: Option<String>,
+    /// Accounts with more deposits than this in memory spill the older half. Without it, only
+    /// [MemoryAction::Spill] spills deposits.
+    pub spill_threshold: Option<usize>,
+    /// Stop applying transactions after a given row or time.
+    pub until: Until,
+    /// Only read the transactions of this client, and so only report on its account. The
+    /// rows of other clients aren't even parsed, and don't count towards `until`.
+    pub only_client: Option<ClientId>,
+    /// NDJSON event log. Processing appends every applied transaction to it, replaying reads
+    /// transactions back from it.
+    pub event_log: Option<String>,
+    /// Offset in the event log to start replaying from.
+    pub replay_from: u64,
+    /// How many shards the accounts are spread over.
+    pub shards: usize,
+    /// The most shards `track tune` tries.
+    pub max_shards: usize,
+    /// How many transactions `track tune` runs with, at most. Made-up ones if there's no
+    /// input.
+    pub sample: usize,
+    /// Run every shard on a thread of its own.
+    pub parallel: bool,
+    /// Record how long applying every transaction takes, and show the percentiles in the
+    /// summary, see [crate::metrics::LatencyHistogram].
+    pub latency: bool,
+    /// How many transactions may queue up per shard in parallel mode before reading pauses.
+    pub queue_capacity: usize,
+    /// Let disputes, resolves and chargebacks name another client's deposit, see
+    /// [crate::deposit_index::DepositIndex].
+    pub cross_account_disputes: bool,
+    /// Point in time (seconds since the Unix epoch) the held report measures ages against.
+    /// Defaults to now.
+    pub as_of: Option<u64>,
+    /// CSV file with the report we expect. Every difference to it is reported, and makes the
+    /// run fail.
+    pub validate_balances: Option<String>,
+    /// The report `track diff` compares the input against.
+    pub diff_against: String,
+    /// How `track diff` lists the differences.
+    pub diff_format: DiffFormat,
+    /// URL that gets a JSON POST for every chargeback and lock change. Needs the `notify`
+    /// feature.
+    pub webhook_url: Option<String>,
+    /// Problems with the configuration file that aren't worth failing over, such as keys we
+    /// don't know. Up to the caller to show them.
+    pub warnings: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            command: Command::Process,
+            input: String::new(),
+            rounding: RoundingStrategy::MidpointNearestEven,
+            precision: PrecisionPolicy::Round,
+            policy: AccountPolicy::default(),
+            max_amount: None,
+            check_tx_order: None,
+            rejects: None,
+            output: None,
+            output_per_shard: None,
+            compress: None,
+            verbosity: Verbosity::Normal,
+            summary: false,
+            max_ignored: 0,
+            timings: false,
+            sort_by_time: false,
+            sorted_by_client: false,
+            extended: false,
+            rates: None,
+            suppress_empty: false,
+            flush: FlushPolicy::PerShard,
+            pending: PendingAtEnd::Report,
+            max_rate: None,
+            max_memory: None,
+            integer_amounts: false,
+            fast_parse: true,
+            comment: Some(b'#'),
+            gzip: false,
+            headers: Vec::new(),
+            read_ahead: default_read_ahead(),
+            deposit_capacity: 0,
+            on_max_memory: MemoryAction::Abort,
+            spill_file: None,
+            spill_threshold: None,
+            sort: ReportOrder::Shards,
+            locked_first: false,
+            locked_output: None,
+            ordered_accounts: false,
+            until: Until::default(),
+            only_client: None,
+            event_log: None,
+            replay_from: 0,
+            parallel: false,
+            queue_capacity: ParallelAccountSystem::DEFAULT_QUEUE_CAPACITY,
+            shards: DEFAULT_SHARDS,
+            latency: false,
+            max_shards: 32,
+            sample: 200_000,
+            cross_account_disputes: false,
+            as_of: None,
+            validate_balances: None,
+            diff_against: String::new(),
+            diff_format: DiffFormat::Text,
+            webhook_url: None,
+            warnings: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// How amounts are brought to four decimal places, which only matters when the
+    /// [PrecisionPolicy] lets them through.
+    pub fn rounding_strategy(&self) -> RoundingStrategy {
+        match self.precision {
+            PrecisionPolicy::Truncate => RoundingStrategy::ToZero,
+            PrecisionPolicy::Round | PrecisionPolicy::Reject => self.rounding,
+        }
+    }
+
+    /// The parts of the configuration that shape the report. Whether there are currencies to
+    /// report on depends on the input rather than the configuration, so that's left off.
+    pub fn report_options(&self) -> ReportOptions<'static> {
+        ReportOptions {
+            extended: self.extended,
+            suppress_empty: self.suppress_empty,
+            currencies: false,
+            rates: None,
+        }
+    }
+
+    /// Parses the command line arguments (without the program name). We only have a handful of
+    /// options, so a hand-rolled parser is good enough and saves us a dependency.
+    ///
+    /// A configuration file given with `--config` is applied first, wherever the flag is, so
+    /// that the other flags override it.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        Self::from_sources(args, Vec::new())
+    }
+
+    /// [Config::from_args], with the `TRACK_*` environment variables in between the
+    /// configuration file and the flags: they override the file, the flags override them. See
+    /// [config_file::env_args] for the variables there are. `TRACK_CONFIG` names the
+    /// configuration file, unless `--config` does.
+    pub fn from_env_and_args(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let env = std::env::vars()
+            .filter(|(name, _)| name.starts_with(config_file::ENV_PREFIX))
+            .collect();
+        Self::from_sources(args, env)
+    }
+
+    fn from_sources(
+        args: impl IntoIterator<Item = String>,
+        mut env: Vec<(String, String)>,
+    ) -> anyhow::Result<Self> {
+        let mut config = Config::default();
+        let mut input = None;
+        let mut args: Vec<String> = args.into_iter().collect();
+        match args.first().map(String::as_str) {
+            Some("replay") => config.command = Command::Replay,
+            Some("held-report") => config.command = Command::HeldReport,
+            Some("estimate") => config.command = Command::Estimate,
+            Some("tune") => config.command = Command::Tune,
+            Some("diff") => config.command = Command::Diff,
+            Some("config") => match args.get(1).map(String::as_str) {
+                Some("--print-default") if args.len() == 2 => {
+                    config.command = Command::PrintDefaultConfig;
+                    return Ok(config);
+                }
+                _ => bail!("Usage: track config --print-default"),
+            },
+            _ => {}
+        }
+        if config.command != Command::Process {
+            args.remove(0);
+        }
+        let file = env
+            .iter()
+            .position(|(name, _)| name == CONFIG_VARIABLE)
+            .map(|position| env.remove(position).1);
+        let file = match args.iter().position(|arg| arg == "--config") {
+            Some(position) => {
+                let path = args
+                    .get(position + 1)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("--config expects a value"))?;
+                args.drain(position..position + 2);
+                Some(path)
+            }
+            None => file,
+        };
+        if let Some(path) = file {
+            config.apply_file(&path)?;
+        }
+        config.apply_env(env)?;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            config.apply_flag(arg, &mut args, &mut input)?;
+        }
+        if config.sorted_by_client && (config.sort_by_time || config.parallel) {
+            bail!("--sorted-by-client can't be combined with --sort-by-time or --parallel");
+        }
+        if let Some(template) = config.output_per_shard.as_deref() {
+            if !template.contains("{shard}") {
+                bail!(
+                    "--output-per-shard needs {{shard}} in the file name, like part-{{shard}}.csv"
+                );
+            }
+            if config.output.is_some() || config.sorted_by_client {
+                bail!("--output-per-shard can't be combined with --output or --sorted-by-client");
+            }
+        }
+        if (config.locked_first || config.locked_output.is_some())
+            && (config.sorted_by_client || config.output_per_shard.is_some())
+        {
+            bail!(
+                "--locked-first and --locked-output can't be combined with --sorted-by-client or \
+                 --output-per-shard"
+            );
+        }
+        if config.sort == ReportOrder::Total
+            && (config.sorted_by_client || config.output_per_shard.is_some())
+        {
+            // Both write accounts before all of them are known.
+            bail!("--sort total can't be combined with --sorted-by-client or --output-per-shard");
+        }
+        if config.sorted_by_client && config.cross_account_disputes {
+            // Accounts are written out and dropped once the client's rows are done, a later
+            // dispute of another client couldn't reach them.
+            bail!("--sorted-by-client can't be combined with --cross-account-disputes");
+        }
+        if config.integer_amounts {
+            let unsupported = [
+                (config.parallel, "--parallel"),
+                (config.extended, "--extended"),
+                (config.suppress_empty, "--suppress-empty"),
+                (config.sort_by_time, "--sort-by-time"),
+                (config.sorted_by_client, "--sorted-by-client"),
+                (config.check_tx_order.is_some(), "--check-tx-order"),
+                (config.only_client.is_some(), "--only-client"),
+                (config.event_log.is_some(), "--event-log"),
+                (config.validate_balances.is_some(), "--validate-balances"),
+                (config.webhook_url.is_some(), "--webhook-url"),
+                (config.output_per_shard.is_some(), "--output-per-shard"),
+                (config.cross_account_disputes, "--cross-account-disputes"),
+                (config.max_memory.is_some(), "--max-memory"),
+                (config.shards != DEFAULT_SHARDS, "--shards"),
+                (config.deposit_capacity > 0, "--deposit-capacity"),
+                (config.spill_file.is_some(), "--spill-file"),
+                (config.timings, "--timings"),
+                (config.sort == ReportOrder::Total, "--sort total"),
+                (config.locked_first, "--locked-first"),
+                (config.latency, "--latency"),
+                (config.locked_output.is_some(), "--locked-output"),
+                (config.command != Command::Process, "a subcommand"),
+                (config.policy != AccountPolicy::default(), "policy options"),
+            ];
+            if let Some((_, option)) = unsupported.iter().find(|(set, _)| *set) {
+                bail!("--integer-amounts can't be combined with {}", option);
+            }
+        }
+        if config.shards == 0 || config.max_shards == 0 {
+            bail!("The accounts need at least one shard to live in");
+        }
+        if config.rates.is_some() && !config.extended {
+            bail!("--rates adds columns to the extended report, it needs --extended");
+        }
+        let spills =
+            config.spill_threshold.is_some() || config.on_max_memory == MemoryAction::Spill;
+        if spills && config.spill_file.is_none() {
+            bail!("Spilling deposits needs a file to spill them to, pass it with --spill-file");
+        }
+        match config.command {
+            Command::Process | Command::HeldReport | Command::Estimate => {
+                config.input = input.ok_or_else(|| anyhow!("{}", USAGE))?
+            }
+            Command::Replay if config.event_log.is_none() => {
+                bail!("replay needs an event log, pass it with --log")
+            }
+            Command::Replay if input.is_some() => bail!("replay doesn't take an input file"),
+            // Without an input, tuning makes up transactions of its own.
+            Command::Tune => config.input = input.unwrap_or_default(),
+            Command::Diff => match input {
+                Some(old) if !config.diff_against.is_empty() => config.input = old,
+                _ => bail!("Usage: track diff [--format text|json] <old report> <new report>"),
+            },
+            Command::Replay | Command::PrintDefaultConfig => {}
+        }
+        Ok(config)
+    }
+
+    /// Applies every entry of a configuration file like the flag it stands for. Errors, and
+    /// warnings about keys we don't know, name the file and line.
+    fn apply_file(&mut self, path: &str) -> anyhow::Result<()> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|error| anyhow!("Can't read {}: {}", path, error))?;
+        let entries = config_file::parse(&text).map_err(|error| anyhow!("{}: {}", path, error))?;
+        for entry in entries {
+            let at = |error: anyhow::Error| anyhow!("{}: line {}: {}", path, entry.line, error);
+            let Some(args) = entry.args() else {
+                self.warnings.push(format!(
+                    "{}: line {}: unknown key {}, ignored",
+                    path, entry.line, entry.key
+                ));
+                continue;
+            };
+            let mut args = args.map_err(at)?.into_iter();
+            while let Some(arg) = args.next() {
+                self.apply_flag(arg, &mut args, &mut None).map_err(at)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies every `TRACK_*` variable like the flag it stands for, in alphabetical order.
+    /// Errors name the variable.
+    fn apply_env(&mut self, mut env: Vec<(String, String)>) -> anyhow::Result<()> {
+        env.sort();
+        for (name, value) in env {
+            let at = |error: anyhow::Error| anyhow!("{}: {}", name, error);
+            let Some(args) = config_file::env_args(&name, &value) else {
+                self.warnings
+                    .push(format!("unknown variable {}, ignored", name));
+                continue;
+            };
+            let mut args = args.map_err(at)?.into_iter();
+            while let Some(arg) = args.next() {
+                self.apply_flag(arg, &mut args, &mut None).map_err(at)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a flag without a value, as in `--extended`, or turns it off again, as in
+    /// `--extended=false`, which is how the command line overrides a configuration file that
+    /// turns it on. Returns whether the flag is such a switch.
+    fn apply_switch(&mut self, flag: &str, on: bool) -> bool {
+        match flag {
+            "--no-fee-overdraft" => self.policy.fees_may_overdraw = !on,
+            "--disputable-interest" => self.policy.disputable_interest = on,
+            "--disputable-withdrawals" => self.policy.disputable_withdrawals = on,
+            "--locked-fees" => self.policy.locked.charge_fees = on,
+            "--locked-no-interest" => self.policy.locked.accrue_interest = !on,
+            "--locked-no-disputes" => self.policy.locked.disputes = !on,
+            "--integer-amounts" => self.integer_amounts = on,
+            "--no-fast-parse" => self.fast_parse = !on,
+            "--gzip" => self.gzip = on,
+            "--locked-first" => self.locked_first = on,
+            "--summary" => self.summary = on,
+            "--timings" => self.timings = on,
+            "--sort-by-time" => self.sort_by_time = on,
+            "--sorted-by-client" => self.sorted_by_client = on,
+            "--extended" => self.extended = on,
+            "--suppress-empty" => self.suppress_empty = on,
+            "--ordered-accounts" => self.ordered_accounts = on,
+            "--parallel" => self.parallel = on,
+            "--latency" => self.latency = on,
+            "--cross-account-disputes" => self.cross_account_disputes = on,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Applies a single flag, taking its value from `args` if it has one. Anything that isn't
+    /// a flag is the input file.
+    fn apply_flag(
+        &mut self,
+        arg: String,
+        args: &mut impl Iterator<Item = String>,
+        input: &mut Option<String>,
+    ) -> anyhow::Result<()> {
+        let (switch, on) = match arg.split_once('=') {
+            Some((switch, "true")) => (switch, true),
+            Some((switch, "false")) => (switch, false),
+            _ => (arg.as_str(), true),
+        };
+        if self.apply_switch(switch, on) {
+            return Ok(());
+        }
+        match arg.as_str() {
+            "--rounding" => self.rounding = parse_rounding(&value(args, &arg)?)?,
+            "--precision-policy" => {
+                self.precision = match value(args, &arg)?.as_str() {
+                    "round" => PrecisionPolicy::Round,
+                    "truncate" => PrecisionPolicy::Truncate,
+                    "reject" => PrecisionPolicy::Reject,
+                    other => bail!(
+                        "Unknown --precision-policy {:?}, expected round, truncate or reject",
+                        other
+                    ),
+                }
+            }
+            "--negative" => {
+                self.policy.negative = match value(args, &arg)?.as_str() {
+                    "reject" => NegativePolicy::Reject,
+                    "clamp" => NegativePolicy::Clamp,
+                    "allow" => NegativePolicy::Allow,
+                    other => bail!(
+                        "Unknown --negative {:?}, expected reject, clamp or allow",
+                        other
+                    ),
+                }
+            }
+            "--max-amount" => self.max_amount = Some(number(args, &arg)?),
+            "--max-rate" => self.max_rate = Some(number(args, &arg)?),
+            "--max-memory" => self.max_memory = Some(number(args, &arg)?),
+            "--header" => {
+                let header = value(args, &arg)?;
+                match header.split_once(':') {
+                    Some((name, value)) if !name.trim().is_empty() => self
+                        .headers
+                        .push((name.trim().to_string(), value.trim().to_string())),
+                    _ => bail!("Unknown --header {:?}, expected \"Name: value\"", header),
+                }
+            }
+            "--comment" => {
+                self.comment = match value(args, &arg)?.as_str() {
+                    "none" => None,
+                    comment if comment.len() == 1 => Some(comment.as_bytes()[0]),
+                    other => bail!(
+                        "Unknown --comment {:?}, expected a single character or none",
+                        other
+                    ),
+                }
+            }
+            "--read-ahead" => self.read_ahead = number(args, &arg)?,
+            "--deposit-capacity" => self.deposit_capacity = number(args, &arg)?,
+            "--spill-file" => self.spill_file = Some(value(args, &arg)?),
+            "--spill-threshold" => self.spill_threshold = Some(number(args, &arg)?),
+            "--on-max-memory" => {
+                self.on_max_memory = match value(args, &arg)?.as_str() {
+                    "abort" => MemoryAction::Abort,
+                    "compact" => MemoryAction::Compact,
+                    "spill" => MemoryAction::Spill,
+                    other => bail!(
+                        "Unknown --on-max-memory {:?}, expected abort, compact or spill",
+                        other
+                    ),
+                }
+            }
+            "--balance-cap" => self.policy.balance_cap = Some(number(args, &arg)?),
+            "--max-open-disputes" => self.policy.max_open_disputes = Some(number(args, &arg)?),
+            "--redispute-window" => self.policy.redispute_window = Some(number(args, &arg)?),
+            "--disable" => {
+                for name in value(args, &arg)?.split(',').map(str::trim) {
+                    let Some(record_type) = RecordType::ALL
+                        .into_iter()
+                        .find(|record_type| record_type.name() == name)
+                    else {
+                        let names: Vec<&str> =
+                            RecordType::ALL.iter().map(RecordType::name).collect();
+                        bail!(
+                            "Unknown --disable {:?}, expected any of {}",
+                            name,
+                            names.join(", ")
+                        );
+                    };
+                    self.policy.disabled.insert(record_type);
+                }
+            }
+            "--check-tx-order" | "--check-tx-order=client" => {
+                self.check_tx_order = Some(TxOrderScope::Client)
+            }
+            "--check-tx-order=global" => self.check_tx_order = Some(TxOrderScope::Global),
+            "--rejects" => self.rejects = Some(value(args, &arg)?),
+            "--output" | "-o" => self.output = Some(value(args, &arg)?),
+            "--output-per-shard" => self.output_per_shard = Some(value(args, &arg)?),
+            "--locked-output" => self.locked_output = Some(value(args, &arg)?),
+            "--compress" => {
+                self.compress = match value(args, &arg)?.as_str() {
+                    "gzip" => Some(Compression::Gzip),
+                    "none" => Some(Compression::None),
+                    other => bail!("Unknown --compress {:?}, expected gzip or none", other),
+                }
+            }
+            "-q" | "--quiet" => self.verbosity = Verbosity::Quiet,
+            "-v" | "--verbose" => self.verbosity = self.verbosity.louder(),
+            "-vv" => self.verbosity = Verbosity::Debug,
+            "--verbosity" => {
+                self.verbosity = match value(args, &arg)?.as_str() {
+                    "quiet" => Verbosity::Quiet,
+                    "normal" => Verbosity::Normal,
+                    "verbose" => Verbosity::Verbose,
+                    "debug" => Verbosity::Debug,
+                    other => bail!(
+                        "Unknown --verbosity {:?}, expected quiet, normal, verbose or debug",
+                        other
+                    ),
+                }
+            }
+            "--max-ignored" => self.max_ignored = number(args, &arg)?,
+            "--rates" => self.rates = Some(value(args, &arg)?),
+            "--flush" => self.flush = value(args, &arg)?.parse()?,
+            "--sort" => {
+                self.sort = match value(args, &arg)?.as_str() {
+                    "client" => ReportOrder::Client,
+                    "shard" => ReportOrder::Shards,
+                    "total" => ReportOrder::Total,
+                    other => bail!(
+                        "Unknown --sort {:?}, expected client, shard or total",
+                        other
+                    ),
+                }
+            }
+            "--pending" => {
+                self.pending = match value(args, &arg)?.as_str() {
+                    "apply" => PendingAtEnd::Apply,
+                    "report" => PendingAtEnd::Report,
+                    other => bail!("Unknown --pending {:?}, expected apply or report", other),
+                }
+            }
+            "--until-row" => self.until.row = Some(number(args, &arg)?),
+            "--until-time" => self.until.time = Some(number(args, &arg)?),
+            "--only-client" => self.only_client = Some(number(args, &arg)?),
+            "--event-log" | "--log" => self.event_log = Some(value(args, &arg)?),
+            "--from" => self.replay_from = number(args, &arg)?,
+            "--shards" => self.shards = number(args, &arg)?,
+            "--max-shards" => self.max_shards = number(args, &arg)?,
+            "--sample" => self.sample = number(args, &arg)?,
+            "--queue-capacity" => self.queue_capacity = number(args, &arg)?,
+            "--as-of" => self.as_of = Some(number(args, &arg)?),
+            "--validate-balances" => self.validate_balances = Some(value(args, &arg)?),
+            "--webhook-url" => self.webhook_url = Some(value(args, &arg)?),
+            "--format" => {
+                self.diff_format = match value(args, &arg)?.as_str() {
+                    "text" => DiffFormat::Text,
+                    "json" => DiffFormat::Json,
+                    other => bail!("Unknown --format {:?}, expected text or json", other),
+                }
+            }
+            flag if flag.starts_with("--") => bail!("Unknown option: {}", flag),
+            _ if input.is_none() => *input = Some(arg),
+            // The report the first one is compared against.
+            _ if self.command == Command::Diff && self.diff_against.is_empty() => {
+                self.diff_against = arg
+            }
+            _ => bail!("Only one input file can be processed at a time"),
+        }
+        Ok(())
+    }
+}
+
+/// Rows the reader may get ahead of the engine by default, a few megabytes of them. With a
+/// single core there's nothing to overlap and the hand-over is pure overhead, so it's off.
+fn default_read_ahead() -> usize {
+    match std::thread::available_parallelism() {
+        Ok(cores) if cores.get() > 1 => 64 * 1024,
+        _ => 0,
+    }
+}
+
+/// The environment variable naming a configuration file, see [Config::from_env_and_args].
+const CONFIG_VARIABLE: &str = "TRACK_CONFIG";
+
+/// Shards of a run unless told otherwise.
+const DEFAULT_SHARDS: usize = 2;
+
+const USAGE: &str = "\
+Usage: track [options] <transactions.csv>
+       track replay --log <events.ndjson> [--from <offset>] [options]
+       track held-report [--as-of <timestamp>] [options] <transactions.csv>
+       track estimate [options] <transactions.csv>
+       track tune [--max-shards <n>] [--sample <rows>] [options] [<transactions.csv>]
+       track diff [--format text|json] <old report> <new report>
+       track config --print-default
+
+Options:
+  --config <track.toml>         read options from a file, the other flags override it
+  --rounding <strategy>         half-even (default), half-up, half-down, down, up, floor, ceiling
+  --precision-policy <policy>   round (default), truncate or reject amounts with more than four
+                                decimal places
+  --no-fee-overdraft            ignore fees that exceed the available funds
+  --disputable-interest         allow disputing interest like a deposit
+  --disputable-withdrawals      allow disputing withdrawals, a chargeback reverses them
+  --locked-fees                 keep charging fees to locked accounts
+  --locked-no-interest          stop paying interest to locked accounts
+  --locked-no-disputes          reject disputes and resolves of locked accounts
+  --negative <policy>           reject (default), clamp or allow transactions that take funds
+                                below zero
+  --max-amount <amount>         reject transactions moving more than this
+  --balance-cap <amount>        reject deposits that take an account's total beyond this
+  --max-open-disputes <n>       reject disputes beyond n open ones per account
+  --redispute-window <n>        reject disputes of a resolved deposit once n transactions were
+                                applied to the account since the resolve
+  --disable <type,...>          ignore transactions of these types, e.g. dispute,resolve
+  --check-tx-order[=scope]      warn about non-increasing tx ids per client (default) or globally
+  --rejects <file.csv>          also write warnings and rejected transactions to a CSV file
+  -o, --output <file.csv>       write the report to a file rather than stdout
+  --output-per-shard <template> write every shard's accounts to a file of its own, at once,
+                                named after the template with {shard} replaced by its index
+  --compress <gzip|none>        compress the report and rejects files, by default only those
+                                ending in .gz are (gzip feature)
+  -q, --quiet                   print nothing but errors to stderr
+  -v, --verbose                 also print the summary, -vv every ignored transaction as well
+  --verbosity <level>           quiet, normal (default), verbose or debug, like -q, -v and -vv
+  --summary                     print a summary of the run to stderr, with ignored transactions
+                                counted by reason
+  --max-ignored <n>             keep the first n ignored transactions in the run summary
+  --timings                     print the time spent parsing, transacting and writing to stderr,
+                                with the busy time of every shard in parallel mode
+  --sort-by-time                apply transactions in timestamp order
+  --max-rate <n>                feed at most n transactions per second, to test backpressure
+  --no-fast-parse               read the input through serde even with the standard header
+  --comment <char|none>         skip input lines starting with this character (default #)
+  --gzip                        decompress the input even if its name doesn't end in .gz
+                                (gzip feature)
+  --header <name: value>        send a header when the input is an http(s) URL, e.g. a token
+                                (http feature)
+  --read-ahead <rows>           rows parsed ahead of the engine on a thread of their own, 0 for
+                                none (default 65536, none on a single core)
+  --deposit-capacity <n>        room for n deposits in every new account up front
+  --integer-amounts             faster integer arithmetic, for the plain report under the
+                                default policy
+  --max-memory <bytes>          soft limit on the estimated memory the accounts take
+  --on-max-memory <action>      abort (default), compact, forgetting empty inactive accounts, or
+                                spill deposits to the spill file
+  --spill-file <file>           keep deposits that don't fit in memory in this file
+                                (spill-deposits feature)
+  --spill-threshold <n>         spill the older half of an account's deposits once it has more
+                                than n in memory
+  --sorted-by-client            input is sorted by client, report accounts as they complete
+  --extended                    add more columns to the report
+  --rates <rates.csv>           add totals and held funds in a base currency to the extended
+                                report, using the rates (currency,rate) in the file
+  --suppress-empty              leave accounts without funds out of the report
+  --sort <client|shard|total>   order the report by client, shard by shard (default), or by
+                                descending total
+  --locked-first                write the locked accounts before the others
+  --locked-output <file>        write the locked accounts to a file of their own instead
+  --ordered-accounts            keep accounts in client order, for sorting reports of many
+                                accounts in little memory
+  --flush <when>                flush the report at the end, per shard (default), every n rows
+                                or at most every so often: end, shard, <n>, <n>ms or <n>s
+  --pending <apply|report>      apply scheduled transactions left at the end, or list them (default)
+  --until-row <n>               stop after the n-th transaction
+  --until-time <timestamp>      stop at the first transaction after the timestamp
+  --only-client <id>            only apply and report the transactions of one client
+  --event-log <events.ndjson>   append every applied transaction to an event log
+  --shards <n>                  spread the accounts over n shards (default 2)
+  --max-shards <n>              most shards track tune tries (default 32)
+  --sample <rows>               most transactions track tune runs with (default 200000)
+  --parallel                    process every shard on its own thread
+  --latency                     record how long applying every transaction takes, for the
+                                percentiles in the summary
+  --queue-capacity <n>          transactions queued per shard in parallel mode (default 1024)
+  --cross-account-disputes      let disputes refer to deposits of other clients
+  --as-of <timestamp>           date the held report ages disputes against (default now)
+  --validate-balances <file.csv> compare the report against an expected one
+  --format <text|json>          list the differences track diff finds as text (default) or
+                                JSON, one object per line
+  --webhook-url <url>           POST chargebacks and lock changes to a URL (notify feature)
+
+Every option a configuration file can set can also be set with an environment variable,
+see `track config --print-default`. Variables override the file, flags override both. Options
+without a value take =false to turn them off again, e.g. --extended=false.";
+
+/// Takes the value following a flag.
+fn value(args: &mut impl Iterator<Item = String>, flag: &str) -> anyhow::Result<String> {
+    args.next()
+        .ok_or_else(|| anyhow!("{} expects a value", flag))
+}
+
+/// Takes the value following a flag and parses it as a number.
+fn number<T: FromStr>(args: &mut impl Iterator<Item = String>, flag: &str) -> anyhow::Result<T> {
+    let value = value(args, flag)?;
+    value
+        .parse()
+        .map_err(|_| anyhow!("{} expects a number, got {:?}", flag, value))
+}
+
+/// Maps the names accepted on the command line to `rust_decimal`'s rounding strategies.
+pub fn parse_rounding(name: &str) -> anyhow::Result<RoundingStrategy> {
+    Ok(match name {
+        "half-even" | "bankers" => RoundingStrategy::MidpointNearestEven,
+        "half-up" => RoundingStrategy::MidpointAwayFromZero,
+        "half-down" => RoundingStrategy::MidpointTowardZero,
+        "down" => RoundingStrategy::ToZero,
+        "up" => RoundingStrategy::AwayFromZero,
+        "floor" => RoundingStrategy::ToNegativeInfinity,
+        "ceiling" => RoundingStrategy::ToPositiveInfinity,
+        _ => bail!(
+            "Unknown rounding strategy {:?}, expected one of: half-even, half-up, half-down, down, up, floor, ceiling",
+            name
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    /// Without flags we get the original behaviour
+    fn defaults() {
+        let config = Config::from_args(args(&["transactions.csv"])).unwrap();
+        assert_eq!(config.input, "transactions.csv");
+        assert_eq!(config.rounding, RoundingStrategy::MidpointNearestEven);
+    }
+
+    #[test]
+    /// The rounding strategy can be picked from the command line
+    fn rounding_flag() {
+        let config =
+            Config::from_args(args(&["--rounding", "half-up", "transactions.csv"])).unwrap();
+        assert_eq!(config.rounding, RoundingStrategy::MidpointAwayFromZero);
+        assert!(Config::from_args(args(&["--rounding", "sideways", "t.csv"])).is_err());
+    }
+
+    #[test]
+    /// Every part of the account policy can be changed from the command line
+    fn policy_flags() {
+        let config = Config::from_args(args(&["t.csv"])).unwrap();
+        assert_eq!(config.policy, AccountPolicy::default());
+        let config = Config::from_args(args(&[
+            "--no-fee-overdraft",
+            "--disputable-interest",
+            "--disputable-withdrawals",
+            "--locked-fees",
+            "--locked-no-interest",
+            "--locked-no-disputes",
+            "--negative",
+            "clamp",
+            "--disable",
+            "dispute, resolve",
+            "t.csv",
+        ]))
+        .unwrap();
+        let disabled: Vec<RecordType> = config.policy.disabled.iter().collect();
+        assert_eq!(disabled, [RecordType::Dispute, RecordType::Resolve]);
+        assert!(Config::from_args(args(&["--disable", "refund", "t.csv"])).is_err());
+        assert!(!config.policy.fees_may_overdraw);
+        assert!(config.policy.disputable_interest);
+        assert!(config.policy.disputable_withdrawals);
+        assert!(config.policy.locked.charge_fees);
+        assert!(!config.policy.locked.accrue_interest);
+        assert!(!config.policy.locked.disputes);
+        assert_eq!(config.policy.negative, NegativePolicy::Clamp);
+    }
+
+    #[test]
+    /// Over-precise amounts are rounded unless asked otherwise, and truncating ignores
+    /// `--rounding`
+    fn precision_policy_flag() {
+        let config = Config::from_args(args(&["--rounding", "up", "t.csv"])).unwrap();
+        assert_eq!(config.precision, PrecisionPolicy::Round);
+        assert_eq!(config.rounding_strategy(), RoundingStrategy::AwayFromZero);
+        let config = Config::from_args(args(&[
+            "--rounding",
+            "up",
+            "--precision-policy",
+            "truncate",
+            "t.csv",
+        ]))
+        .unwrap();
+        assert_eq!(config.rounding_strategy(), RoundingStrategy::ToZero);
+        let config = Config::from_args(args(&["--precision-policy", "reject", "t.csv"])).unwrap();
+        assert_eq!(config.precision, PrecisionPolicy::Reject);
+        assert!(Config::from_args(args(&["--precision-policy", "exact", "t.csv"])).is_err());
+    }
+
+    /// Writes a configuration file of its own for a test, returning its path.
+    fn config_file(name: &str, text: &str) -> String {
+        let path = std::env::temp_dir().join(format!("track-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    /// Flags win over the configuration file, which wins over the defaults, wherever
+    /// `--config` is on the command line
+    fn config_file_precedence() {
+        let path = config_file(
+            "precedence",
+            "[amounts]\nrounding = \"up\"\nmax_amount = 500\n\n[policy]\nnegative = \"clamp\"\n\
+             fee_overdraft = false\n\n[output]\nextended = true\ncolour = \"blue\"\n",
+        );
+        let config = Config::from_args(args(&["t.csv", "--config", &path])).unwrap();
+        assert_eq!(config.rounding, RoundingStrategy::AwayFromZero);
+        assert_eq!(config.max_amount, Some(Decimal::from(500)));
+        assert_eq!(config.policy.negative, NegativePolicy::Clamp);
+        assert!(!config.policy.fees_may_overdraw);
+        assert!(config.extended);
+        // Untouched by the file
+        assert_eq!(config.precision, PrecisionPolicy::Round);
+        assert_eq!(
+            config.warnings,
+            vec![format!(
+                "{}: line 11: unknown key output.colour, ignored",
+                path
+            )]
+        );
+
+        let config = Config::from_args(args(&[
+            "--rounding",
+            "down",
+            "--config",
+            &path,
+            "--negative",
+            "allow",
+            "t.csv",
+        ]))
+        .unwrap();
+        assert_eq!(config.rounding, RoundingStrategy::ToZero);
+        assert_eq!(config.policy.negative, NegativePolicy::Allow);
+        assert_eq!(config.max_amount, Some(Decimal::from(500)));
+
+        // Flags turn switches the file set back with =false
+        let config = Config::from_args(args(&[
+            "--config",
+            &path,
+            "--extended=false",
+            "--no-fee-overdraft=false",
+            "t.csv",
+        ]))
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!config.extended);
+        assert!(config.policy.fees_may_overdraw);
+    }
+
+    #[test]
+    /// Broken files, and values the flags wouldn't take, are errors naming the line
+    fn malformed_config_file() {
+        let path = config_file("malformed", "[policy]\nnegative = \"clamp\"\nlocked_fees\n");
+        let error = Config::from_args(args(&["--config", &path, "t.csv"])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!("{}: line 3: expected `key = value`", path)
+        );
+        std::fs::write(&path, "[amounts]\n\nrounding = \"sideways\"\n").unwrap();
+        let error = Config::from_args(args(&["--config", &path, "t.csv"])).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with(&format!("{}: line 3: Unknown rounding", path)),
+            "{}",
+            error
+        );
+        std::fs::write(&path, "[policy]\nlocked_fees = \"yes\"\n").unwrap();
+        let error = Config::from_args(args(&["--config", &path, "t.csv"])).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            error.to_string(),
+            format!("{}: line 2: policy.locked_fees expects true or false", path)
+        );
+    }
+
+    /// `std::env` is shared by every test, so the tests that set variables take turns.
+    static ENV: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    /// Parses `flags` with the given variables set, removing them again afterwards.
+    fn with_env(vars: &[(&str, &str)], flags: &[&str]) -> anyhow::Result<Config> {
+        let _turn = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+        let config = Config::from_env_and_args(args(flags));
+        for (name, _) in vars {
+            std::env::remove_var(name);
+        }
+        config
+    }
+
+    #[test]
+    /// Every option can come from the environment
+    fn env_variables() {
+        let vars = [
+            ("TRACK_NEGATIVE", "clamp"),
+            ("TRACK_EXTENDED", "true"),
+            ("TRACK_FEE_OVERDRAFT", "false"),
+            ("TRACK_MAX_AMOUNT", "250.5"),
+            ("TRACK_CHECK_TX_ORDER", "global"),
+            ("TRACK_COLOUR", "blue"),
+        ];
+        let config = with_env(&vars, &["t.csv"]).unwrap();
+        assert_eq!(config.policy.negative, NegativePolicy::Clamp);
+        assert!(config.extended);
+        assert!(!config.policy.fees_may_overdraw);
+        assert_eq!(config.max_amount, Some(Decimal::new(2505, 1)));
+        assert_eq!(config.check_tx_order, Some(TxOrderScope::Global));
+        assert_eq!(
+            config.warnings,
+            vec!["unknown variable TRACK_COLOUR, ignored".to_string()]
+        );
+        // Plain parsing leaves the environment alone.
+        let _turn = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::env::set_var("TRACK_EXTENDED", "true");
+        let config = Config::from_args(args(&["t.csv"]));
+        std::env::remove_var("TRACK_EXTENDED");
+        assert!(!config.unwrap().extended);
+    }
+
+    #[test]
+    /// The environment overrides the configuration file, flags override both
+    fn env_precedence() {
+        let path = config_file(
+            "env",
+            "[amounts]\nrounding = \"up\"\nmax_amount = 500\n[policy]\nnegative = \"clamp\"\n",
+        );
+        let vars = [
+            ("TRACK_CONFIG", path.as_str()),
+            ("TRACK_ROUNDING", "down"),
+            ("TRACK_NEGATIVE", "reject"),
+        ];
+        let config = with_env(&vars, &["--negative", "allow", "t.csv"]).unwrap();
+        assert_eq!(config.max_amount, Some(Decimal::from(500)));
+        assert_eq!(config.rounding, RoundingStrategy::ToZero);
+        assert_eq!(config.policy.negative, NegativePolicy::Allow);
+
+        // --config beats TRACK_CONFIG
+        let other = config_file("env-other", "[amounts]\nmax_amount = 7\n");
+        let config = with_env(&vars, &["--config", &other, "t.csv"]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&other).unwrap();
+        assert_eq!(config.max_amount, Some(Decimal::from(7)));
+        assert_eq!(config.rounding, RoundingStrategy::ToZero);
+
+        // A variable turns off a switch the file turns on
+        let path = config_file("env-switch", "[output]\nsuppress_empty = true\n");
+        let config = with_env(&[("TRACK_CONFIG", path.as_str())], &["t.csv"]).unwrap();
+        assert!(config.suppress_empty);
+        let vars = [
+            ("TRACK_CONFIG", path.as_str()),
+            ("TRACK_SUPPRESS_EMPTY", "false"),
+        ];
+        let config = with_env(&vars, &["t.csv"]).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!config.suppress_empty);
+    }
+
+    #[test]
+    /// Invalid values are errors naming the variable
+    fn invalid_env_variables() {
+        let error = with_env(&[("TRACK_EXTENDED", "yes")], &["t.csv"]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "TRACK_EXTENDED: expected true or false, got \"yes\""
+        );
+        let error = with_env(&[("TRACK_ROUNDING", "sideways")], &["t.csv"]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("TRACK_ROUNDING: Unknown rounding"),
+            "{}",
+            error
+        );
+        let error = with_env(&[("TRACK_QUEUE_CAPACITY", "lots")], &["t.csv"]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "TRACK_QUEUE_CAPACITY: --queue-capacity expects a number, got \"lots\""
+        );
+    }
+
+    #[test]
+    /// `track config --print-default` needs nothing else
+    fn print_default_config() {
+        let config = Config::from_args(args(&["config", "--print-default"])).unwrap();
+        assert_eq!(config.command, Command::PrintDefaultConfig);
+        assert!(Config::from_args(args(&["config"])).is_err());
+    }
+
+    #[test]
+    /// The transaction order check is per client unless asked otherwise
+    fn check_tx_order_flag() {
+        let config = Config::from_args(args(&["--check-tx-order", "t.csv"])).unwrap();
+        assert_eq!(config.check_tx_order, Some(TxOrderScope::Client));
+        let config = Config::from_args(args(&["--check-tx-order=global", "t.csv"])).unwrap();
+        assert_eq!(config.check_tx_order, Some(TxOrderScope::Global));
+    }
+
+    #[test]
+    /// Both boundaries can be given together
+    fn until_flags() {
+        let config = Config::from_args(args(&[
+            "--until-row",
+            "10",
+            "--until-time",
+            "1650000000",
+            "t.csv",
+        ]))
+        .unwrap();
+        assert_eq!(config.until.row, Some(10));
+        assert_eq!(config.until.time, Some(1650000000));
+        assert!(Config::from_args(args(&["--until-row", "ten", "t.csv"])).is_err());
+    }
+
+    #[test]
+    /// Estimating reads the same input file as processing would
+    fn estimate_command() {
+        let config = Config::from_args(args(&["estimate", "--until-row", "10", "t.csv"])).unwrap();
+        assert_eq!(config.command, Command::Estimate);
+        assert_eq!(config.input, "t.csv");
+        assert!(Config::from_args(args(&["estimate"])).is_err());
+    }
+
+    #[test]
+    /// The held report takes an input file and optionally a date
+    fn held_report_command() {
+        let config =
+            Config::from_args(args(&["held-report", "--as-of", "86400", "t.csv"])).unwrap();
+        assert_eq!(config.command, Command::HeldReport);
+        assert_eq!(config.as_of, Some(86400));
+        assert_eq!(config.input, "t.csv");
+        assert!(Config::from_args(args(&["held-report"])).is_err());
+    }
+
+    #[test]
+    /// Comments start with `#` unless another character is given, or none at all
+    fn comment_flag() {
+        assert_eq!(Config::default().comment, Some(b'#'));
+        let config = Config::from_args(args(&["--comment", ";", "t.csv"])).unwrap();
+        assert_eq!(config.comment, Some(b';'));
+        let config = Config::from_args(args(&["--comment", "none", "t.csv"])).unwrap();
+        assert_eq!(config.comment, None);
+        assert!(Config::from_args(args(&["--comment", "//", "t.csv"])).is_err());
+    }
+
+    #[test]
+    /// Any input can be read as gzip, not only one named .gz
+    fn gzip_flag() {
+        assert!(!Config::default().gzip);
+        let config = Config::from_args(args(&["--gzip", "t.csv"])).unwrap();
+        assert!(config.gzip);
+    }
+
+    #[test]
+    /// Headers for a URL input are split into name and value, and must have both parts
+    fn header_flag() {
+        let config = Config::from_args(args(&[
+            "--header",
+            "Authorization: Bearer token",
+            "--header",
+            "X-Empty:",
+            "https://example.com/t.csv",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.headers,
+            vec![
+                ("Authorization".to_string(), "Bearer token".to_string()),
+                ("X-Empty".to_string(), String::new()),
+            ]
+        );
+        assert!(Config::from_args(args(&["--header", "token", "t.csv"])).is_err());
+        assert!(Config::from_args(args(&["--header", ": token", "t.csv"])).is_err());
+    }
+
+    #[test]
+    /// Reading ahead can be turned off, but not given something other than a number of rows
+    fn read_ahead_flag() {
+        assert_eq!(Config::default().read_ahead, default_read_ahead());
+        let config = Config::from_args(args(&["--read-ahead", "0", "t.csv"])).unwrap();
+        assert_eq!(config.read_ahead, 0);
+        assert!(Config::from_args(args(&["--read-ahead", "lots", "t.csv"])).is_err());
+    }
+
+    #[test]
+    /// Spilling deposits needs a file to spill them to, whether accounts spill past a threshold
+    /// or all of them once over --max-memory
+    fn spill_flags() {
+        let config = Config::from_args(args(&[
+            "--spill-file",
+            "d.spill",
+            "--spill-threshold",
+            "1000",
+            "--on-max-memory",
+            "spill",
+            "t.csv",
+        ]))
+        .unwrap();
+        assert_eq!(config.spill_file.as_deref(), Some("d.spill"));
+        assert_eq!(config.spill_threshold, Some(1000));
+        assert_eq!(config.on_max_memory, MemoryAction::Spill);
+        assert!(Config::from_args(args(&["--spill-threshold", "1000", "t.csv"])).is_err());
+        assert!(Config::from_args(args(&["--on-max-memory", "spill", "t.csv"])).is_err());
+    }
+
+    #[test]
+    /// Locked accounts can go first or to a file of their own, but not while accounts are
+    /// written as they complete
+    fn locked_flags() {
+        let config = Config::from_args(args(&["--locked-first", "t.csv"])).unwrap();
+        assert!(config.locked_first);
+        let config = Config::from_args(args(&["--locked-output", "l.csv", "t.csv"])).unwrap();
+        assert_eq!(config.locked_output.as_deref(), Some("l.csv"));
+        for other in ["--sorted-by-client", "--integer-amounts"] {
+            assert!(Config::from_args(args(&["--locked-first", other, "t.csv"])).is_err());
+        }
+    }
+
+    #[test]
+    /// Tuning does without an input, and the shards it suggests can be passed on
+    fn tune_command() {
+        let config =
+            Config::from_args(args(&["tune", "--max-shards", "4", "--sample", "100"])).unwrap();
+        assert_eq!(config.command, Command::Tune);
+        assert_eq!((config.max_shards, config.sample), (4, 100));
+        assert_eq!(config.input, "");
+        assert_eq!(Config::default().shards, 2);
+        let config = Config::from_args(args(&["--shards", "8", "t.csv"])).unwrap();
+        assert_eq!(config.shards, 8);
+        assert!(Config::from_args(args(&["--shards", "0", "t.csv"])).is_err());
+        assert!(Config::from_args(args(&["tune", "--max-shards", "0"])).is_err());
+    }
+
+    #[test]
+    /// A deposit capacity is a plain number, and the integer path has no accounts to give it to
+    fn deposit_capacity_flag() {
+        assert_eq!(Config::default().deposit_capacity, 0);
+        let config = Config::from_args(args(&["--deposit-capacity", "4096", "t.csv"])).unwrap();
+        assert_eq!(config.deposit_capacity, 4096);
+        let error = Config::from_args(args(&[
+            "--integer-amounts",
+            "--deposit-capacity",
+            "16",
+            "t.csv",
+        ]))
+        .unwrap_err();
+        assert!(
+            error.to_string().contains("--deposit-capacity"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    /// Sorting by total needs every account before writing the first
+    fn sort_total_flag() {
+        let config = Config::from_args(args(&["--sort", "total", "t.csv"])).unwrap();
+        assert_eq!(config.sort, ReportOrder::Total);
+        assert!(
+            Config::from_args(args(&["--sort", "total", "--sorted-by-client", "t.csv"])).is_err()
+        );
+        assert!(Config::from_args(args(&["--sort", "totals", "t.csv"])).is_err());
+    }
+
+    #[test]
+    /// Streaming sorted input relies on reading and applying in file order
+    fn sorted_by_client_flag() {
+        let config = Config::from_args(args(&["--sorted-by-client", "t.csv"])).unwrap();
+        assert!(config.sorted_by_client);
+        assert!(Config::from_args(args(&["--sorted-by-client", "--parallel", "t.csv"])).is_err());
+        assert!(
+            Config::from_args(args(&["--sorted-by-client", "--sort-by-time", "t.csv"])).is_err()
+        );
+    }
+
+    #[test]
+    /// Replaying needs a log, but no input file
+    fn replay_command() {
+        let config = Config::from_args(args(&["replay", "--log", "e.ndjson"])).unwrap();
+        assert_eq!(config.command, Command::Replay);
+        assert_eq!(config.event_log.as_deref(), Some("e.ndjson"));
+        assert!(Config::from_args(args(&["replay"])).is_err());
+        assert!(Config::from_args(args(&["replay", "--log", "e.ndjson", "t.csv"])).is_err());
+    }
+
+    #[test]
+    /// Diffing takes two reports, no more and no fewer, and only it takes two files
+    fn diff_command() {
+        let config = Config::from_args(args(&["diff", "--format", "json", "a.csv", "b.csv"]));
+        let config = config.unwrap();
+        assert_eq!(config.command, Command::Diff);
+        assert_eq!(
+            (config.input.as_str(), config.diff_against.as_str()),
+            ("a.csv", "b.csv")
+        );
+        assert_eq!(config.diff_format, DiffFormat::Json);
+        assert_eq!(Config::default().diff_format, DiffFormat::Text);
+        assert!(Config::from_args(args(&["diff", "a.csv"])).is_err());
+        assert!(Config::from_args(args(&["diff", "a.csv", "b.csv", "c.csv"])).is_err());
+        assert!(Config::from_args(args(&["diff", "--format", "csv", "a.csv", "b.csv"])).is_err());
+        assert!(Config::from_args(args(&["a.csv", "b.csv"])).is_err());
+    }
+}
@@ -0,0 +1,664 @@
+//! Configuration files, so that a run can be set up in a reviewable file rather than a long
+//! list of flags. Every key stands for a command line flag, and [crate::config::Config]
+//! applies them before the flags on the command line, which therefore win.
+//!
+//! Only the part of TOML that such a file needs is understood: comments, `[section]` headers
+//! and `key = value` lines, where the value is a string, a number or a boolean.
+//!
+//! Every key can be set from the environment as well, see [env_args], which is handy where
+//! flags are hard to come by, e.g. in containers.
+
+use anyhow::{anyhow, bail};
+
+/// A `key = value` line of a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Where the entry is, counting from one.
+    pub line: usize,
+    /// The key, prefixed with its section, e.g. `policy.negative`.
+    pub key: String,
+    pub value: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Bool(bool),
+    /// Kept as written, so that amounts don't go through a float.
+    Number(String),
+    String(String),
+}
+
+impl Entry {
+    /// The flags that have the same effect as the entry. `None` for keys we don't know.
+    pub fn args(&self) -> Option<anyhow::Result<Vec<String>>> {
+        let key = KEYS.iter().find(|key| key.name == self.key)?;
+        Some(key.args(&self.value))
+    }
+}
+
+/// How a key maps to a flag.
+enum Kind {
+    /// A flag without a value, on when the key has the given value. The other value turns it
+    /// off with `--flag=false`, so that it overrides a file that turns it on.
+    Switch { when: bool },
+    /// A flag followed by the value.
+    Value,
+    /// A flag with the value attached, as in `--flag=value`.
+    Attached,
+}
+
+/// The prefix of the environment variables standing for keys.
+pub const ENV_PREFIX: &str = "TRACK_";
+
+/// A key a configuration file may set.
+struct Key {
+    name: &'static str,
+    flag: &'static str,
+    kind: Kind,
+    /// Shown in the default file, commented out like everything else.
+    default: &'static str,
+    help: &'static str,
+}
+
+impl Key {
+    /// The environment variable standing for the key: the prefix and the key without its
+    /// section, e.g. `TRACK_NEGATIVE` for `policy.negative`.
+    fn variable(&self) -> String {
+        let (_, field) = self.name.split_once('.').unwrap();
+        format!("{}{}", ENV_PREFIX, field.to_uppercase())
+    }
+
+    fn args(&self, value: &Value) -> anyhow::Result<Vec<String>> {
+        match (&self.kind, value) {
+            (Kind::Switch { when }, Value::Bool(value)) => {
+                Ok(vec![format!("{}={}", self.flag, value == when)])
+            }
+            (Kind::Switch { .. }, _) => bail!("{} expects true or false", self.name),
+            (_, Value::Bool(_)) => bail!("{} expects a string or a number", self.name),
+            (Kind::Value, Value::Number(value) | Value::String(value)) => {
+                Ok(vec![self.flag.to_string(), value.clone()])
+            }
+            (Kind::Attached, Value::Number(value) | Value::String(value)) => {
+                Ok(vec![format!("{}={}", self.flag, value)])
+            }
+        }
+    }
+}
+
+/// Every key, in the order of the default file.
+const KEYS: &[Key] = &[
+    Key {
+        name: "amounts.rounding",
+        flag: "--rounding",
+        kind: Kind::Value,
+        default: "\"half-even\"",
+        help: "half-even, half-up, half-down, down, up, floor or ceiling",
+    },
+    Key {
+        name: "amounts.precision_policy",
+        flag: "--precision-policy",
+        kind: Kind::Value,
+        default: "\"round\"",
+        help: "round, truncate or reject amounts with more than four decimal places",
+    },
+    Key {
+        name: "amounts.max_amount",
+        flag: "--max-amount",
+        kind: Kind::Value,
+        default: "1000000",
+        help: "reject transactions moving more than this, no limit by default",
+    },
+    Key {
+        name: "policy.fee_overdraft",
+        flag: "--no-fee-overdraft",
+        kind: Kind::Switch { when: false },
+        default: "true",
+        help: "charge fees even if they exceed the available funds",
+    },
+    Key {
+        name: "policy.disputable_interest",
+        flag: "--disputable-interest",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "allow disputing interest like a deposit",
+    },
+    Key {
+        name: "policy.disputable_withdrawals",
+        flag: "--disputable-withdrawals",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "allow disputing withdrawals, a chargeback reverses them",
+    },
+    Key {
+        name: "policy.locked_fees",
+        flag: "--locked-fees",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "keep charging fees to locked accounts",
+    },
+    Key {
+        name: "policy.locked_interest",
+        flag: "--locked-no-interest",
+        kind: Kind::Switch { when: false },
+        default: "true",
+        help: "keep paying interest to locked accounts",
+    },
+    Key {
+        name: "policy.locked_disputes",
+        flag: "--locked-no-disputes",
+        kind: Kind::Switch { when: false },
+        default: "true",
+        help: "keep taking disputes and resolves of locked accounts",
+    },
+    Key {
+        name: "policy.negative",
+        flag: "--negative",
+        kind: Kind::Value,
+        default: "\"reject\"",
+        help: "reject, clamp or allow transactions that take funds below zero",
+    },
+    Key {
+        name: "policy.balance_cap",
+        flag: "--balance-cap",
+        kind: Kind::Value,
+        default: "1000000",
+        help: "reject deposits that take an account's total beyond this, no cap by default",
+    },
+    Key {
+        name: "policy.max_open_disputes",
+        flag: "--max-open-disputes",
+        kind: Kind::Value,
+        default: "10",
+        help: "reject disputes beyond this many open ones per account, no cap by default",
+    },
+    Key {
+        name: "policy.redispute_window",
+        flag: "--redispute-window",
+        kind: Kind::Value,
+        default: "5",
+        help: "transactions after a resolve within which a deposit can be disputed again",
+    },
+    Key {
+        name: "policy.disabled",
+        flag: "--disable",
+        kind: Kind::Value,
+        default: "\"dispute,resolve,chargeback\"",
+        help: "ignore transactions of these types, none by default",
+    },
+    Key {
+        name: "input.check_tx_order",
+        flag: "--check-tx-order",
+        kind: Kind::Attached,
+        default: "\"client\"",
+        help: "warn about non-increasing tx ids per client or globally, off by default",
+    },
+    Key {
+        name: "input.sort_by_time",
+        flag: "--sort-by-time",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "apply transactions in timestamp order",
+    },
+    Key {
+        name: "input.sorted_by_client",
+        flag: "--sorted-by-client",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "the input is sorted by client, report accounts as they complete",
+    },
+    Key {
+        name: "input.max_rate",
+        flag: "--max-rate",
+        kind: Kind::Value,
+        default: "1000",
+        help: "feed at most this many transactions per second, to test backpressure",
+    },
+    Key {
+        name: "input.pending",
+        flag: "--pending",
+        kind: Kind::Value,
+        default: "\"report\"",
+        help: "apply scheduled transactions left at the end, or report them",
+    },
+    Key {
+        name: "engine.shards",
+        flag: "--shards",
+        kind: Kind::Value,
+        default: "2",
+        help: "shards the accounts are spread over, see track tune",
+    },
+    Key {
+        name: "engine.latency",
+        flag: "--latency",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "record how long applying every transaction takes, for the summary",
+    },
+    Key {
+        name: "engine.parallel",
+        flag: "--parallel",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "process every shard on its own thread",
+    },
+    Key {
+        name: "engine.queue_capacity",
+        flag: "--queue-capacity",
+        kind: Kind::Value,
+        default: "1024",
+        help: "transactions queued per shard in parallel mode",
+    },
+    Key {
+        name: "input.comment",
+        flag: "--comment",
+        kind: Kind::Value,
+        default: "\"#\"",
+        help: "skip input lines starting with this character, or none",
+    },
+    Key {
+        name: "input.gzip",
+        flag: "--gzip",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "decompress the input even if its name doesn't end in .gz",
+    },
+    Key {
+        name: "input.fast_parse",
+        flag: "--no-fast-parse",
+        kind: Kind::Switch { when: false },
+        default: "true",
+        help: "read files with the standard header without serde",
+    },
+    Key {
+        name: "input.read_ahead",
+        flag: "--read-ahead",
+        kind: Kind::Value,
+        default: "65536",
+        help: "rows parsed ahead of the engine on a thread of their own, 0 for none",
+    },
+    Key {
+        name: "engine.deposit_capacity",
+        flag: "--deposit-capacity",
+        kind: Kind::Value,
+        default: "0",
+        help: "room for this many deposits in every new account up front",
+    },
+    Key {
+        name: "engine.integer_amounts",
+        flag: "--integer-amounts",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "faster integer arithmetic, for the plain report under the default policy",
+    },
+    Key {
+        name: "engine.max_memory",
+        flag: "--max-memory",
+        kind: Kind::Value,
+        default: "8589934592",
+        help: "soft limit in bytes on the estimated memory the accounts take",
+    },
+    Key {
+        name: "engine.on_max_memory",
+        flag: "--on-max-memory",
+        kind: Kind::Value,
+        default: "\"abort\"",
+        help: "abort, compact (forget empty inactive accounts) or spill once over the limit",
+    },
+    Key {
+        name: "engine.spill_file",
+        flag: "--spill-file",
+        kind: Kind::Value,
+        default: "\"deposits.spill\"",
+        help: "keep deposits that don't fit in memory in this file",
+    },
+    Key {
+        name: "engine.spill_threshold",
+        flag: "--spill-threshold",
+        kind: Kind::Value,
+        default: "100000",
+        help: "spill the older half of an account's deposits once it has more in memory",
+    },
+    Key {
+        name: "engine.ordered_accounts",
+        flag: "--ordered-accounts",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help:
+            "keep accounts in client order, for sorting reports of many accounts in little memory",
+    },
+    Key {
+        name: "engine.cross_account_disputes",
+        flag: "--cross-account-disputes",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "let disputes refer to deposits of other clients",
+    },
+    Key {
+        name: "output.extended",
+        flag: "--extended",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "add more columns to the report",
+    },
+    Key {
+        name: "output.suppress_empty",
+        flag: "--suppress-empty",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "leave accounts without funds out of the report",
+    },
+    Key {
+        name: "output.sort",
+        flag: "--sort",
+        kind: Kind::Value,
+        default: "\"shard\"",
+        help: "order the report by client, shard by shard, or by descending total",
+    },
+    Key {
+        name: "output.locked_first",
+        flag: "--locked-first",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "write the locked accounts before the others",
+    },
+    Key {
+        name: "output.locked_output",
+        flag: "--locked-output",
+        kind: Kind::Value,
+        default: "\"locked.csv\"",
+        help: "write the locked accounts to a file of their own, none by default",
+    },
+    Key {
+        name: "output.flush",
+        flag: "--flush",
+        kind: Kind::Value,
+        default: "\"shard\"",
+        help: "flush the report at the end, per shard, every n rows, or every <n>ms or <n>s",
+    },
+    Key {
+        name: "output.rates",
+        flag: "--rates",
+        kind: Kind::Value,
+        default: "\"rates.csv\"",
+        help: "add balances in a base currency to the extended report, none by default",
+    },
+    Key {
+        name: "output.rejects",
+        flag: "--rejects",
+        kind: Kind::Value,
+        default: "\"rejects.csv\"",
+        help: "also write warnings and rejected transactions to a CSV file, none by default",
+    },
+    Key {
+        name: "output.verbosity",
+        flag: "--verbosity",
+        kind: Kind::Value,
+        default: "\"normal\"",
+        help: "quiet, normal, verbose or debug, how much to print to stderr",
+    },
+    Key {
+        name: "output.file",
+        flag: "--output",
+        kind: Kind::Value,
+        default: "\"report.csv.gz\"",
+        help: "write the report to a file rather than stdout, gzipped if it ends in .gz",
+    },
+    Key {
+        name: "output.per_shard",
+        flag: "--output-per-shard",
+        kind: Kind::Value,
+        default: "\"out/part-{shard}.csv\"",
+        help: "write every shard's accounts to a file of its own, {shard} is replaced by its index",
+    },
+    Key {
+        name: "output.compress",
+        flag: "--compress",
+        kind: Kind::Value,
+        default: "\"gzip\"",
+        help: "gzip or none, how to compress the report and rejects files, by extension by default",
+    },
+    Key {
+        name: "output.summary",
+        flag: "--summary",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "print a summary of the run to stderr",
+    },
+    Key {
+        name: "output.max_ignored",
+        flag: "--max-ignored",
+        kind: Kind::Value,
+        default: "0",
+        help: "keep the first this many ignored transactions in the run summary",
+    },
+    Key {
+        name: "output.timings",
+        flag: "--timings",
+        kind: Kind::Switch { when: true },
+        default: "false",
+        help: "print the time spent parsing, transacting and writing to stderr",
+    },
+];
+
+/// The flags that have the same effect as setting the environment variable `name` to
+/// `value`. Switches take `true` or `false`, anything else is taken as is. `None` for
+/// variables that don't stand for a key.
+pub fn env_args(name: &str, value: &str) -> Option<anyhow::Result<Vec<String>>> {
+    let key = KEYS.iter().find(|key| key.variable() == name)?;
+    let value = match key.kind {
+        Kind::Switch { .. } => match value.trim() {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            other => return Some(Err(anyhow!("expected true or false, got {:?}", other))),
+        },
+        Kind::Value | Kind::Attached => Value::String(value.to_string()),
+    };
+    Some(key.args(&value))
+}
+
+/// Reads the entries of a configuration file. Errors name the offending line.
+pub fn parse(text: &str) -> anyhow::Result<Vec<Entry>> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut section = String::new();
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name
+                .strip_suffix(']')
+                .map(str::trim)
+                .filter(|name| is_bare_key(name))
+                .ok_or_else(|| anyhow!("line {}: malformed section header", number))?;
+            section = name.to_string();
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected `key = value`", number))?;
+        let key = key.trim();
+        if !is_bare_key(key) {
+            bail!("line {}: malformed key {:?}", number, key);
+        }
+        let value =
+            parse_value(value.trim()).map_err(|error| anyhow!("line {}: {}", number, error))?;
+        let key = match section.as_str() {
+            "" => key.to_string(),
+            section => format!("{}.{}", section, key),
+        };
+        if entries.iter().any(|entry| entry.key == key) {
+            bail!("line {}: {} is set twice", number, key);
+        }
+        entries.push(Entry {
+            line: number,
+            key,
+            value,
+        });
+    }
+    Ok(entries)
+}
+
+/// A file setting every key to its default, commented out, for `track config --print-default`.
+pub fn default_file() -> String {
+    let mut file = String::from(
+        "# Configuration for track, see `track --help`. Every key is optional, and both the\n\
+         # environment variable named after it and flags on the command line override what is\n\
+         # set here. The values shown are the defaults, or examples for options that are off\n\
+         # by default.\n",
+    );
+    let mut section = "";
+    for key in KEYS {
+        let (name, field) = key.name.split_once('.').unwrap();
+        if name != section {
+            section = name;
+            file.push_str(&format!("\n[{}]\n", section));
+        }
+        file.push_str(&format!(
+            "# {} ({})\n# {} = {}\n",
+            key.help,
+            key.variable(),
+            field,
+            key.default
+        ));
+    }
+    file
+}
+
+/// Everything after a `#` that isn't part of a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (index, char) in line.char_indices() {
+        match char {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '_' || char == '-')
+}
+
+fn parse_value(value: &str) -> anyhow::Result<Value> {
+    match value {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    if let Some(quoted) = value.strip_prefix('"') {
+        let mut string = String::new();
+        let mut chars = quoted.chars();
+        while let Some(char) = chars.next() {
+            match char {
+                '"' if chars.as_str().is_empty() => return Ok(Value::String(string)),
+                '"' => bail!("unexpected characters after the string"),
+                '\\' => match chars.next() {
+                    Some('"') => string.push('"'),
+                    Some('\\') => string.push('\\'),
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    other => bail!("unsupported escape sequence \\{}", other.unwrap_or(' ')),
+                },
+                char => string.push(char),
+            }
+        }
+        bail!("unterminated string");
+    }
+    let number = value.replace('_', "");
+    let digits = number.strip_prefix(['+', '-']).unwrap_or(&number);
+    if !digits.is_empty()
+        && digits
+            .chars()
+            .all(|char| char.is_ascii_digit() || char == '.')
+        && digits.chars().filter(|char| *char == '.').count() <= 1
+    {
+        return Ok(Value::Number(number));
+    }
+    bail!(
+        "expected a string, a number, true or false, got {:?}",
+        value
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Sections prefix their keys, and strings, numbers and booleans come out as written
+    fn parse_entries() {
+        let text = "# comment\n\
+                    top = 1_000\n\
+                    \n\
+                    [policy]\n\
+                    negative = \"clamp\" # trailing comment\n\
+                    locked_fees = true\n\
+                    [output]\n\
+                    rates = \"a # b \\\"c\\\".csv\"\n\
+                    max = -12.5\n";
+        let entries = parse(text).unwrap();
+        let values: Vec<(usize, &str, &Value)> = entries
+            .iter()
+            .map(|entry| (entry.line, entry.key.as_str(), &entry.value))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                (2, "top", &Value::Number("1000".to_string())),
+                (5, "policy.negative", &Value::String("clamp".to_string())),
+                (6, "policy.locked_fees", &Value::Bool(true)),
+                (
+                    8,
+                    "output.rates",
+                    &Value::String("a # b \"c\".csv".to_string())
+                ),
+                (9, "output.max", &Value::Number("-12.5".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    /// Errors point at the line that's wrong
+    fn malformed() {
+        let cases = [
+            ("[policy\n", "line 1: malformed section header"),
+            ("a = 1\nb\n", "line 2: expected `key = value`"),
+            ("\n\nname = \"open\n", "line 3: unterminated string"),
+            (
+                "a = yes\n",
+                "line 1: expected a string, a number, true or false, got \"yes\"",
+            ),
+            ("a = 1\n[x]\na = 2\na = 3\n", "line 4: x.a is set twice"),
+        ];
+        for (text, error) in cases {
+            assert_eq!(parse(text).unwrap_err().to_string(), error, "{:?}", text);
+        }
+    }
+
+    #[test]
+    /// The default file sets nothing, and uncommenting it sets every key there is
+    fn default_file_round_trips() {
+        let file = default_file();
+        assert!(parse(&file).unwrap().is_empty());
+        let uncommented: String = file
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(line) if line.contains(" = ") => format!("{}\n", line),
+                _ => format!("{}\n", line),
+            })
+            .collect();
+        let entries = parse(&uncommented).unwrap();
+        assert_eq!(entries.len(), KEYS.len());
+        assert!(entries.iter().all(|entry| entry.args().unwrap().is_ok()));
+    }
+}
@@ -0,0 +1,59 @@
+use crate::transaction::{ClientId, Transaction};
+use std::collections::HashMap;
+
+/// Who made which deposit, for disputes that name a deposit of another account. Some fraud
+/// patterns involve an account disputing funds that went into a different one; with the index
+/// enabled (see [crate::system::ShardedAccountSystem::enable_deposit_index]) such a dispute,
+/// and the resolve or chargeback that follows it, is handed to the account that owns the
+/// deposit, keyed by the owner's client id and the deposit's tx id.
+///
+/// Transactions are routed by client, so the index can't live in a shard: it sits alongside
+/// them and sees every transaction before it's routed. In the threaded engine that means on
+/// the producer's side, behind a lock every producer takes for every transaction, which makes
+/// it a point of contention the sharding otherwise avoids. A deposit is indexed as it's
+/// handed out, not once it's applied. That's safe since a redirected dispute goes into the
+/// owner's queue behind the deposit, but a deposit that the owner's account goes on to ignore
+/// is indexed regardless, and disputes of it are ignored by the owner instead.
+#[derive(Debug, Default, Clone)]
+pub struct DepositIndex {
+    owners: HashMap<u32, ClientId>,
+}
+
+impl DepositIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The client that made deposit `tx`, if it went through the index.
+    pub fn owner(&self, tx: u32) -> Option<ClientId> {
+        self.owners.get(&tx).copied()
+    }
+
+    /// Indexes deposits, and hands disputes, resolves and chargebacks of another client's
+    /// deposit to that client. Everything else passes unchanged. Tx ids are unique, so the
+    /// first deposit with an id owns it.
+    pub fn route(&mut self, mut transaction: Transaction) -> Transaction {
+        match &mut transaction {
+            Transaction::Deposit { client, tx, .. } => {
+                self.owners.entry(*tx).or_insert(*client);
+            }
+            Transaction::Dispute { client, tx, .. }
+            | Transaction::Resolve { client, tx, .. }
+            | Transaction::Chargeback { client, tx, .. } => {
+                if let Some(owner) = self.owner(*tx) {
+                    *client = owner;
+                }
+            }
+            _ => {}
+        }
+        transaction
+    }
+
+    pub fn len(&self) -> usize {
+        self.owners.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.owners.is_empty()
+    }
+}
@@ -0,0 +1,547 @@
+//! Where an account keeps its [DepositState]s, by transaction id.
+//!
+//! Deposits are most of what a long run keeps in memory, and are mostly written rather than
+//! read, so there are a few ways of storing them behind [DepositStore]. [LazyDeposits] keeps
+//! them in a vector while tx ids increase and in a map after that, which is what accounts use.
+//! [MapDeposits] is the plain map. [CompactDeposits] packs a deposit into its tx id, amount and
+//! a byte of flags, kept in a vector sorted by tx id, at a fraction of the memory. The
+//! `map-deposits` and `compact-deposits` features make accounts use one of the latter two, see
+//! [Deposits]. With `spill-deposits`, accounts that outgrow memory keep their older deposits on
+//! disk, see `spill::SpillDeposits`.
+//!
+//! `cargo bench --bench dispute_rates` compares them at realistic dispute rates, with tx ids
+//! increasing and decreasing. With increasing ids the vector came out ahead of the map at
+//! 0.1%, 1% and 10% of the rows being disputes, by more than twice at the lower two, and the
+//! compact store in between. With decreasing ids it moves to the map right away and keeps up
+//! with it, while the compact store, which keeps its vector sorted, falls behind five times.
+
+use crate::account::{DepositState, Map};
+use crate::currency::Currency;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use core::mem::size_of;
+use core::ops::{Deref, DerefMut};
+use rust_decimal::Decimal;
+
+/// What accounts keep their deposits in. If several features are on, spilling wins, then the
+/// compact store.
+#[cfg(not(any(
+    feature = "map-deposits",
+    feature = "compact-deposits",
+    feature = "spill-deposits"
+)))]
+pub type Deposits = LazyDeposits;
+#[cfg(all(
+    feature = "map-deposits",
+    not(any(feature = "compact-deposits", feature = "spill-deposits"))
+))]
+pub type Deposits = MapDeposits;
+#[cfg(all(feature = "compact-deposits", not(feature = "spill-deposits")))]
+pub type Deposits = CompactDeposits;
+#[cfg(feature = "spill-deposits")]
+pub type Deposits = crate::spill::SpillDeposits;
+
+/// The operations [crate::account::AccountState] needs of its deposits. Deposits are handed
+/// out by value, since a compact store has no [DepositState] to point to. Changing one goes
+/// through [DepositStore::get_mut].
+pub trait DepositStore: Debug + Default + Clone + PartialEq {
+    /// A deposit being changed. Changes are in the store once it's dropped.
+    type Entry<'a>: DerefMut<Target = DepositState>
+    where
+        Self: 'a;
+
+    fn get(&self, tx: u32) -> Option<DepositState>;
+    fn get_mut(&mut self, tx: u32) -> Option<Self::Entry<'_>>;
+    /// Stores the deposit, returning the one it replaces if any.
+    fn insert(&mut self, tx: u32, deposit: DepositState) -> Option<DepositState>;
+    fn remove(&mut self, tx: u32) -> Option<DepositState>;
+    fn len(&self) -> usize;
+    /// In no particular order.
+    fn iter(&self) -> impl Iterator<Item = (u32, DepositState)> + '_;
+    /// Roughly how many bytes the deposits take, see
+    /// [crate::account::AccountState::memory_footprint].
+    fn memory_footprint(&self) -> usize;
+
+    /// Makes room for `additional` more deposits up front, so a busy account doesn't grow its
+    /// store a step at a time. Only a hint, stores that can't make room ignore it.
+    fn reserve(&mut self, _additional: usize) {}
+
+    fn contains_key(&self, tx: u32) -> bool {
+        self.get(tx).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Deposits in a [Map], quick at everything but with a hash table's worth of overhead.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MapDeposits(Map<u32, DepositState>);
+
+/// A deposit being changed where it's kept, for stores that keep [DepositState]s as they are.
+pub struct InPlace<'a>(&'a mut DepositState);
+
+impl Deref for InPlace<'_> {
+    type Target = DepositState;
+
+    fn deref(&self) -> &DepositState {
+        self.0
+    }
+}
+
+impl DerefMut for InPlace<'_> {
+    fn deref_mut(&mut self) -> &mut DepositState {
+        self.0
+    }
+}
+
+impl DepositStore for MapDeposits {
+    type Entry<'a> = InPlace<'a>;
+
+    fn get(&self, tx: u32) -> Option<DepositState> {
+        self.0.get(&tx).copied()
+    }
+
+    fn get_mut(&mut self, tx: u32) -> Option<InPlace<'_>> {
+        self.0.get_mut(&tx).map(InPlace)
+    }
+
+    fn insert(&mut self, tx: u32, deposit: DepositState) -> Option<DepositState> {
+        self.0.insert(tx, deposit)
+    }
+
+    fn remove(&mut self, tx: u32) -> Option<DepositState> {
+        self.0.remove(&tx)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u32, DepositState)> + '_ {
+        self.0.iter().map(|(tx, deposit)| (*tx, *deposit))
+    }
+
+    fn contains_key(&self, tx: u32) -> bool {
+        self.0.contains_key(&tx)
+    }
+
+    fn memory_footprint(&self) -> usize {
+        self.0.len() * (size_of::<u32>() + size_of::<DepositState>())
+    }
+
+    /// Without `std`, the map is a `BTreeMap`, which has no room to make.
+    #[cfg(feature = "std")]
+    fn reserve(&mut self, additional: usize) {
+        self.0.reserve(additional);
+    }
+}
+
+/// Deposits in a vector while tx ids come in increasing, and in a [MapDeposits] from the
+/// first one that doesn't.
+///
+/// While tx ids increase, which is the usual case, recording a deposit is a push, the check
+/// for a duplicate of a new deposit one comparison, and looking one up a binary search. Keeping
+/// the vector sorted past an id that goes in ahead of others would move them all along, for
+/// every such insert, so the deposits move to the map for good instead.
+#[derive(Debug, Clone)]
+pub struct LazyDeposits(Lazy);
+
+#[derive(Debug, Clone)]
+enum Lazy {
+    /// Sorted by tx id.
+    Sorted(Vec<(u32, DepositState)>),
+    Map(MapDeposits),
+}
+
+impl Default for LazyDeposits {
+    fn default() -> Self {
+        LazyDeposits(Lazy::Sorted(Vec::new()))
+    }
+}
+
+impl LazyDeposits {
+    /// Whether the deposits are still in the vector.
+    pub fn is_sorted(&self) -> bool {
+        matches!(self.0, Lazy::Sorted(_))
+    }
+}
+
+fn position(entries: &[(u32, DepositState)], tx: u32) -> Result<usize, usize> {
+    match entries.last() {
+        Some((last, _)) if *last < tx => Err(entries.len()),
+        _ => entries.binary_search_by_key(&tx, |(tx, _)| *tx),
+    }
+}
+
+/// The same deposits, wherever they're kept.
+impl PartialEq for LazyDeposits {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(tx, deposit)| other.get(tx) == Some(deposit))
+    }
+}
+
+impl DepositStore for LazyDeposits {
+    type Entry<'a> = InPlace<'a>;
+
+    fn get(&self, tx: u32) -> Option<DepositState> {
+        match &self.0 {
+            Lazy::Sorted(entries) => position(entries, tx).ok().map(|index| entries[index].1),
+            Lazy::Map(map) => map.get(tx),
+        }
+    }
+
+    fn get_mut(&mut self, tx: u32) -> Option<InPlace<'_>> {
+        match &mut self.0 {
+            Lazy::Sorted(entries) => {
+                let index = position(entries, tx).ok()?;
+                Some(InPlace(&mut entries[index].1))
+            }
+            Lazy::Map(map) => map.get_mut(tx),
+        }
+    }
+
+    fn insert(&mut self, tx: u32, deposit: DepositState) -> Option<DepositState> {
+        let entries = match &mut self.0 {
+            Lazy::Sorted(entries) => entries,
+            Lazy::Map(map) => return map.insert(tx, deposit),
+        };
+        match position(entries, tx) {
+            Ok(index) => Some(core::mem::replace(&mut entries[index].1, deposit)),
+            Err(index) if index == entries.len() => {
+                entries.push((tx, deposit));
+                None
+            }
+            Err(_) => {
+                let mut map = MapDeposits::default();
+                map.reserve(entries.len() + 1);
+                for (tx, deposit) in entries.drain(..) {
+                    map.insert(tx, deposit);
+                }
+                map.insert(tx, deposit);
+                self.0 = Lazy::Map(map);
+                None
+            }
+        }
+    }
+
+    fn remove(&mut self, tx: u32) -> Option<DepositState> {
+        match &mut self.0 {
+            Lazy::Sorted(entries) => {
+                let index = position(entries, tx).ok()?;
+                Some(entries.remove(index).1)
+            }
+            Lazy::Map(map) => map.remove(tx),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match &self.0 {
+            Lazy::Sorted(entries) => entries.len(),
+            Lazy::Map(map) => map.len(),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u32, DepositState)> + '_ {
+        let (sorted, map) = match &self.0 {
+            Lazy::Sorted(entries) => (Some(entries.iter().copied()), None),
+            Lazy::Map(map) => (None, Some(map.iter())),
+        };
+        sorted
+            .into_iter()
+            .flatten()
+            .chain(map.into_iter().flatten())
+    }
+
+    fn memory_footprint(&self) -> usize {
+        match &self.0 {
+            Lazy::Sorted(entries) => entries.len() * size_of::<(u32, DepositState)>(),
+            Lazy::Map(map) => map.memory_footprint(),
+        }
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        match &mut self.0 {
+            Lazy::Sorted(entries) => entries.reserve(additional),
+            Lazy::Map(map) => map.reserve(additional),
+        }
+    }
+}
+
+const WITHDRAWAL: u8 = 1;
+const DISPUTE: u8 = 2;
+const CHARGED_BACK: u8 = 4;
+
+/// What doesn't fit in the flags. Most deposits have none of it: they're in the implicit
+/// currency, not under a dispute with a timestamp, charged back in full if at all, and not
+/// resolved under a [crate::policy::AccountPolicy::redispute_window].
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Extra {
+    /// Only for a partial chargeback, a full one is just the flag.
+    chargeback: Option<Decimal>,
+    disputed_at: Option<u64>,
+    currency: Option<Currency>,
+    resolved_at: Option<u64>,
+}
+
+/// Deposits as `(tx, amount, flags)`, sorted by tx id, plus the odd [Extra] on the side.
+///
+/// Lookups are a binary search. Deposits mostly come in with increasing tx ids, which makes
+/// inserting one a push, but one that goes in ahead of many others moves them all along, as
+/// does removing one that isn't the last.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CompactDeposits {
+    entries: Vec<(u32, Decimal, u8)>,
+    extras: BTreeMap<u32, Extra>,
+}
+
+impl CompactDeposits {
+    fn position(&self, tx: u32) -> Result<usize, usize> {
+        self.entries.binary_search_by_key(&tx, |(tx, _, _)| *tx)
+    }
+
+    fn unpack(&self, index: usize) -> DepositState {
+        let (tx, amount, flags) = self.entries[index];
+        let extra = self.extras.get(&tx);
+        DepositState {
+            amount,
+            withdrawal: flags & WITHDRAWAL != 0,
+            dispute: flags & DISPUTE != 0,
+            chargeback: (flags & CHARGED_BACK != 0)
+                .then(|| extra.and_then(|extra| extra.chargeback).unwrap_or(amount)),
+            disputed_at: extra.and_then(|extra| extra.disputed_at),
+            currency: extra.and_then(|extra| extra.currency),
+            resolved_at: extra.and_then(|extra| extra.resolved_at),
+        }
+    }
+
+    /// Packs the deposit into the entry at `index`, which is for the same tx.
+    fn pack(&mut self, index: usize, deposit: &DepositState) {
+        let tx = self.entries[index].0;
+        let flag = |set: bool, flag: u8| if set { flag } else { 0 };
+        let flags = flag(deposit.withdrawal, WITHDRAWAL)
+            | flag(deposit.dispute, DISPUTE)
+            | flag(deposit.chargeback.is_some(), CHARGED_BACK);
+        self.entries[index] = (tx, deposit.amount, flags);
+        let extra = Extra {
+            chargeback: deposit
+                .chargeback
+                .filter(|chargeback| *chargeback != deposit.amount),
+            disputed_at: deposit.disputed_at,
+            currency: deposit.currency,
+            resolved_at: deposit.resolved_at,
+        };
+        // Only what's needed is kept, so that equal deposits make equal stores.
+        if extra.chargeback.is_some()
+            || extra.disputed_at.is_some()
+            || extra.currency.is_some()
+            || extra.resolved_at.is_some()
+        {
+            self.extras.insert(tx, extra);
+        } else {
+            self.extras.remove(&tx);
+        }
+    }
+}
+
+/// A deposit of [CompactDeposits] being changed, packed back in when dropped.
+pub struct CompactEntry<'a> {
+    store: &'a mut CompactDeposits,
+    index: usize,
+    deposit: DepositState,
+}
+
+impl Deref for CompactEntry<'_> {
+    type Target = DepositState;
+
+    fn deref(&self) -> &DepositState {
+        &self.deposit
+    }
+}
+
+impl DerefMut for CompactEntry<'_> {
+    fn deref_mut(&mut self) -> &mut DepositState {
+        &mut self.deposit
+    }
+}
+
+impl Drop for CompactEntry<'_> {
+    fn drop(&mut self) {
+        self.store.pack(self.index, &self.deposit);
+    }
+}
+
+impl DepositStore for CompactDeposits {
+    type Entry<'a> = CompactEntry<'a>;
+
+    fn get(&self, tx: u32) -> Option<DepositState> {
+        self.position(tx).ok().map(|index| self.unpack(index))
+    }
+
+    fn get_mut(&mut self, tx: u32) -> Option<CompactEntry<'_>> {
+        let index = self.position(tx).ok()?;
+        let deposit = self.unpack(index);
+        Some(CompactEntry {
+            store: self,
+            index,
+            deposit,
+        })
+    }
+
+    fn insert(&mut self, tx: u32, deposit: DepositState) -> Option<DepositState> {
+        let (index, replaced) = match self.position(tx) {
+            Ok(index) => (index, Some(self.unpack(index))),
+            Err(index) => {
+                self.entries.insert(index, (tx, Decimal::ZERO, 0));
+                (index, None)
+            }
+        };
+        self.pack(index, &deposit);
+        replaced
+    }
+
+    fn remove(&mut self, tx: u32) -> Option<DepositState> {
+        let index = self.position(tx).ok()?;
+        let deposit = self.unpack(index);
+        self.entries.remove(index);
+        self.extras.remove(&tx);
+        Some(deposit)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u32, DepositState)> + '_ {
+        (0..self.entries.len()).map(|index| (self.entries[index].0, self.unpack(index)))
+    }
+
+    fn memory_footprint(&self) -> usize {
+        self.entries.len() * size_of::<(u32, Decimal, u8)>()
+            + self.extras.len() * (size_of::<u32>() + size_of::<Extra>())
+    }
+
+    /// Only for the entries, extras are rare.
+    fn reserve(&mut self, additional: usize) {
+        self.entries.reserve(additional);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn deposit(amount: i64) -> DepositState {
+        DepositState::new(Decimal::new(amount, 2), None)
+    }
+
+    /// Puts a store through what the dispute arms of [crate::account::AccountState] do to it.
+    fn exercise<S: DepositStore>() {
+        let mut store = S::default();
+        for tx in [5, 1, 9, 3] {
+            assert_eq!(store.insert(tx, deposit(i64::from(tx) * 100)), None);
+        }
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.get(3), Some(deposit(300)));
+        assert!(!store.contains_key(4));
+        assert!(store.get_mut(4).is_none());
+
+        {
+            let mut entry = store.get_mut(9).unwrap();
+            entry.dispute = true;
+            entry.disputed_at = Some(1_000);
+        }
+        let disputed = store.get(9).unwrap();
+        assert!(disputed.disputed());
+        assert_eq!(disputed.disputed_at(), Some(1_000));
+
+        {
+            let mut entry = store.get_mut(9).unwrap();
+            entry.dispute = false;
+            entry.disputed_at = None;
+            entry.chargeback = Some(Decimal::new(400, 2));
+        }
+        assert_eq!(
+            store.get(9).unwrap().charged_back_amount(),
+            Some(Decimal::new(400, 2))
+        );
+        store.get_mut(5).unwrap().chargeback = Some(Decimal::new(500, 2));
+        assert_eq!(
+            store.get(5).unwrap().charged_back_amount(),
+            Some(Decimal::new(500, 2))
+        );
+
+        let currency = Some("EUR".parse().unwrap());
+        let withdrawal = DepositState::withdrawal(Decimal::new(7, 0), currency);
+        assert_eq!(store.insert(1, withdrawal), Some(deposit(100)));
+        assert_eq!(store.get(1), Some(withdrawal));
+
+        assert_eq!(store.remove(3), Some(deposit(300)));
+        assert_eq!(store.remove(3), None);
+        let mut txs: Vec<u32> = store.iter().map(|(tx, _)| tx).collect();
+        txs.sort_unstable();
+        assert_eq!(txs, vec![1, 5, 9]);
+        assert!(store.memory_footprint() > 0);
+    }
+
+    #[test]
+    /// The map does what the dispute arms need.
+    fn map_deposits() {
+        exercise::<MapDeposits>();
+    }
+
+    #[test]
+    /// The compact store does the same.
+    fn compact_deposits() {
+        exercise::<CompactDeposits>();
+    }
+
+    #[test]
+    /// So does the vector, and the map it moves to once a tx id comes in out of order.
+    fn lazy_deposits() {
+        exercise::<LazyDeposits>();
+
+        let mut unsorted = LazyDeposits::default();
+        let mut sorted = LazyDeposits::default();
+        for tx in [1, 3, 2] {
+            unsorted.insert(tx, deposit(i64::from(tx)));
+        }
+        for tx in [1, 2, 3] {
+            sorted.insert(tx, deposit(i64::from(tx)));
+        }
+        assert!(sorted.is_sorted());
+        assert!(!unsorted.is_sorted());
+        assert_eq!(unsorted.get(1), Some(deposit(1)));
+        assert_eq!(unsorted, sorted);
+        assert_eq!(unsorted.insert(2, deposit(20)), Some(deposit(2)));
+        unsorted.get_mut(3).unwrap().dispute = true;
+        assert_eq!(unsorted.get(3).map(|deposit| deposit.dispute), Some(true));
+        assert_eq!(unsorted.remove(1), Some(deposit(1)));
+        assert_eq!(unsorted.len(), 2);
+    }
+
+    #[test]
+    /// Deposits that are the same make stores that are the same, however they got there, and
+    /// plain deposits take nothing on the side.
+    fn compact_deposits_are_canonical() {
+        let mut disputed = CompactDeposits::default();
+        disputed.insert(1, deposit(100));
+        disputed.get_mut(1).unwrap().disputed_at = Some(5);
+        assert_eq!(disputed.extras.len(), 1);
+        disputed.get_mut(1).unwrap().disputed_at = None;
+
+        let mut plain = CompactDeposits::default();
+        plain.insert(1, deposit(100));
+        assert_eq!(disputed, plain);
+        assert!(plain.extras.is_empty());
+        let mut map = MapDeposits::default();
+        map.insert(1, deposit(100));
+        assert!(plain.memory_footprint() < map.memory_footprint());
+    }
+}
@@ -0,0 +1,89 @@
+//! How much memory processing a file will roughly take, worked out from the input alone before
+//! anything is applied. See `track estimate`.
+use crate::account::{AccountState, DepositState};
+use crate::deposits::{DepositStore, Deposits};
+use crate::policy::AccountPolicy;
+use crate::transaction::{ClientId, Transaction};
+use std::collections::HashSet;
+use std::fmt;
+use std::mem::size_of;
+
+/// The accounts and deposits a file will make for, and what they'll take. The figures are
+/// those of [crate::system::ShardedAccountSystem::memory_footprint] for the same accounts and
+/// deposits, so the estimate can be held against [crate::config::Config::max_memory].
+///
+/// Every row that could record a deposit is counted, the estimate is an upper bound in that
+/// respect: deposits that turn out to be ignored, like duplicates or those of locked accounts,
+/// take nothing once processed. Currencies, adjustments and scheduled transactions aren't in
+/// it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    clients: HashSet<ClientId>,
+    deposits: u64,
+}
+
+impl MemoryEstimate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts a transaction of the input, taking `policy` into account for what gets
+    /// recorded.
+    pub fn count(&mut self, transaction: &Transaction, policy: &AccountPolicy) {
+        self.clients.insert(*transaction.id());
+        let recorded = match transaction {
+            Transaction::Deposit { .. } => true,
+            Transaction::Withdrawal { .. } => policy.disputable_withdrawals,
+            Transaction::Interest { .. } => policy.disputable_interest,
+            _ => false,
+        };
+        if recorded {
+            self.deposits += 1;
+        }
+    }
+
+    /// Distinct clients, and so accounts.
+    pub fn clients(&self) -> u64 {
+        self.clients.len() as u64
+    }
+
+    /// Deposits, along with withdrawals and interest where the policy makes them disputable.
+    pub fn deposits(&self) -> u64 {
+        self.deposits
+    }
+
+    /// Bytes the accounts take, without their deposits.
+    pub fn accounts_bytes(&self) -> usize {
+        let account = size_of::<ClientId>() + AccountState::new().memory_footprint();
+        self.clients.len() * account
+    }
+
+    /// Bytes the deposits take, for the store the crate was built with.
+    pub fn deposits_bytes(&self) -> usize {
+        let mut store = Deposits::default();
+        store.insert(0, DepositState::new(Default::default(), None));
+        self.deposits as usize * store.memory_footprint()
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.accounts_bytes() + self.deposits_bytes()
+    }
+}
+
+impl fmt::Display for MemoryEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "accounts: {}, {} bytes",
+            self.clients(),
+            self.accounts_bytes()
+        )?;
+        writeln!(
+            f,
+            "deposits: {}, {} bytes",
+            self.deposits,
+            self.deposits_bytes()
+        )?;
+        write!(f, "estimated memory: {} bytes", self.total_bytes())
+    }
+}
@@ -0,0 +1,210 @@
+use anyhow::{anyhow, bail};
+use csv::Writer;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// When the report is pushed on to whatever it's written to. Flushing often lets a consumer
+/// at the other end of a pipe see rows as they're ready, flushing rarely saves syscalls.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Only once the report is done.
+    AtEnd,
+    /// After every shard of [crate::system::ShardedAccountSystem::write], which is how the
+    /// report has always been written.
+    #[default]
+    PerShard,
+    /// After every this many rows. A row per account and currency, as the report has them.
+    EveryRows(u64),
+    /// After the first row written once this much time has passed since the last flush.
+    Every(Duration),
+}
+
+/// Parses `end`, `shard`, a number of rows, or a duration in milliseconds or seconds, like
+/// `500ms` or `2s`.
+impl FromStr for FlushPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        let number = |number: &str| {
+            number
+                .parse::<u64>()
+                .map_err(|_| anyhow!("Unknown flush policy {:?}", value))
+        };
+        Ok(match value {
+            "end" => FlushPolicy::AtEnd,
+            "shard" => FlushPolicy::PerShard,
+            _ if value.ends_with("ms") => {
+                FlushPolicy::Every(Duration::from_millis(number(&value[..value.len() - 2])?))
+            }
+            _ if value.ends_with('s') => {
+                FlushPolicy::Every(Duration::from_secs(number(&value[..value.len() - 1])?))
+            }
+            _ => match number(value)? {
+                0 => bail!("Flushing every 0 rows makes no sense, use 1 to flush every row"),
+                rows => FlushPolicy::EveryRows(rows),
+            },
+        })
+    }
+}
+
+/// A CSV writer for reports that flushes as its [FlushPolicy] says. Every way of writing the
+/// report goes through one of these, whether it's written all at once or account by account.
+pub struct ReportWriter<W: Write> {
+    writer: Writer<W>,
+    policy: FlushPolicy,
+    /// Rows written since the last flush.
+    unflushed: u64,
+    /// Rows written at all.
+    rows: u64,
+    last_flush: Instant,
+}
+
+impl<W: Write> ReportWriter<W> {
+    pub fn new(writer: W, policy: FlushPolicy) -> Self {
+        Self::from_csv(Writer::from_writer(writer), policy)
+    }
+
+    pub fn from_csv(writer: Writer<W>, policy: FlushPolicy) -> Self {
+        ReportWriter {
+            writer,
+            policy,
+            unflushed: 0,
+            rows: 0,
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn policy(&self) -> FlushPolicy {
+        self.policy
+    }
+
+    /// Writes a row, and flushes if that's due.
+    pub(crate) fn serialize<T: Serialize>(&mut self, row: T) -> io::Result<()> {
+        self.writer.serialize(row)?;
+        self.unflushed += 1;
+        self.rows += 1;
+        let due = match self.policy {
+            FlushPolicy::AtEnd | FlushPolicy::PerShard => false,
+            FlushPolicy::EveryRows(rows) => self.unflushed >= rows,
+            FlushPolicy::Every(interval) => self.last_flush.elapsed() >= interval,
+        };
+        if due {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Writes the header on its own, for a report without rows. Once a row is written, that
+    /// comes with the header anyway.
+    pub(crate) fn write_header(&mut self, columns: &[&str]) -> io::Result<()> {
+        debug_assert_eq!(self.rows, 0);
+        self.writer.write_record(columns)?;
+        Ok(())
+    }
+
+    /// A shard is written out, which is when [FlushPolicy::PerShard] flushes.
+    pub(crate) fn end_shard(&mut self) -> io::Result<()> {
+        match self.policy {
+            FlushPolicy::PerShard => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flushes whatever the policy, which is what finishing a report takes.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.unflushed = 0;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    /// Flushes and hands back the underlying writer.
+    pub fn into_inner(self) -> io::Result<W> {
+        self.writer
+            .into_inner()
+            .map_err(|error| io::Error::new(error.error().kind(), error.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::{ReportOptions, ShardedAccountSystem};
+    use crate::transaction::Transaction;
+    use rust_decimal::Decimal;
+
+    /// Counts how often it's flushed, and throws everything written away.
+    #[derive(Default)]
+    struct CountingWriter {
+        flushes: usize,
+    }
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// Every policy flushes as often as it says, plus once at the end
+    fn flush_counts() {
+        let mut system = ShardedAccountSystem::new(4);
+        for (client, tx) in (0..10).zip(0..) {
+            system.transact(Transaction::Deposit {
+                client,
+                tx,
+                amount: Decimal::ONE,
+                currency: None,
+                timestamp: None,
+            });
+        }
+        let flushes = |policy| {
+            let mut writer = ReportWriter::new(CountingWriter::default(), policy);
+            system.write(&mut writer, ReportOptions::default()).unwrap();
+            writer.flush().unwrap();
+            writer.into_inner().unwrap().flushes
+        };
+        // Into_inner flushes once more.
+        assert_eq!(flushes(FlushPolicy::AtEnd), 2);
+        assert_eq!(flushes(FlushPolicy::PerShard), 4 + 2);
+        assert_eq!(flushes(FlushPolicy::EveryRows(3)), 3 + 2);
+        assert_eq!(flushes(FlushPolicy::EveryRows(1)), 10 + 2);
+        assert_eq!(flushes(FlushPolicy::Every(Duration::ZERO)), 10 + 2);
+        assert_eq!(flushes(FlushPolicy::Every(Duration::from_secs(60))), 2);
+    }
+
+    #[test]
+    /// Policies are given as words, row counts or durations
+    fn parse() {
+        assert_eq!("end".parse::<FlushPolicy>().unwrap(), FlushPolicy::AtEnd);
+        assert_eq!(
+            "shard".parse::<FlushPolicy>().unwrap(),
+            FlushPolicy::PerShard
+        );
+        assert_eq!(
+            "1000".parse::<FlushPolicy>().unwrap(),
+            FlushPolicy::EveryRows(1000)
+        );
+        assert_eq!(
+            "250ms".parse::<FlushPolicy>().unwrap(),
+            FlushPolicy::Every(Duration::from_millis(250))
+        );
+        assert_eq!(
+            "2s".parse::<FlushPolicy>().unwrap(),
+            FlushPolicy::Every(Duration::from_secs(2))
+        );
+        assert!("0".parse::<FlushPolicy>().is_err());
+        assert!("often".parse::<FlushPolicy>().is_err());
+    }
+}
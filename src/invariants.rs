@@ -0,0 +1,313 @@
+use crate::account::{AccountState, Balance};
+use crate::currency::Currency;
+use rust_decimal::prelude::Zero;
+use rust_decimal::Decimal;
+
+/// A transaction broke the invariants of an account in a currency: something ended up held
+/// below zero, or held without a dispute to hold it for, or the available funds dropped below
+/// zero or further below it. What happens next is up to the [crate::policy::NegativePolicy].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub currency: Option<Currency>,
+    /// The funds as the transaction left them, before the policy had its say.
+    pub balance: Balance,
+}
+
+/// Whether going from `before` to `after` keeps to the invariants. An account a fee has
+/// overdrawn is negative already, so it only may not get any worse.
+pub(crate) fn holds(before: &Balance, after: &Balance) -> bool {
+    after.held >= Decimal::zero() && after.available() >= floor(before)
+}
+
+/// Whether the funds `account` holds are backed by disputes. Adding up what every disputed
+/// transaction holds would take going through all the deposits on every transaction, so this
+/// only catches funds held while not a single dispute is open, which is where a hold that no
+/// resolve or chargeback is going to release ends up.
+pub(crate) fn backed(account: &AccountState, balance: &Balance) -> bool {
+    account.disputed > 0 || balance.held.is_zero()
+}
+
+/// The first currency, the implicit one first, in which `after` breaks the invariants given
+/// what the currencies looked like before the transaction.
+pub(crate) fn check(
+    before: impl Fn(Option<Currency>) -> Balance,
+    after: &AccountState,
+) -> Option<Violation> {
+    let currencies = after.currencies.keys().copied().map(Some);
+    core::iter::once(None)
+        .chain(currencies)
+        .map(|currency| (currency, after.balance(currency)))
+        .find(|(currency, balance)| !holds(&before(*currency), balance) || !backed(after, balance))
+        .map(|(currency, balance)| Violation { currency, balance })
+}
+
+/// Brings funds back within the invariants: nothing is held below zero, or at all without
+/// `disputes` open, then less is held until enough is available, and only if that isn't
+/// enough the shortfall is written off.
+pub(crate) fn clamp(held: &mut Decimal, total: &mut Decimal, before: &Balance, disputes: bool) {
+    let floor = floor(before);
+    *held = match disputes {
+        true => (*held).max(Decimal::zero()),
+        false => Decimal::zero(),
+    };
+    let shortfall = floor.saturating_sub(total.saturating_sub(*held));
+    if shortfall > Decimal::zero() {
+        let released = shortfall.min(*held);
+        *held -= released;
+        *total = total.saturating_add(shortfall - released);
+    }
+}
+
+/// The lowest the available funds may go.
+fn floor(before: &Balance) -> Decimal {
+    before.available().min(Decimal::zero())
+}
+
+/// Every sequence here used to take the available funds below zero.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::{IgnoreReason, RejectReason, TransactionOutcome};
+    use crate::policy::{AccountPolicy, NegativePolicy};
+    use crate::transaction::Transaction;
+
+    fn deposit(tx: u32, amount: u32) -> Transaction {
+        Transaction::Deposit {
+            client: 0,
+            tx,
+            amount: Decimal::from(amount),
+            currency: None,
+            timestamp: None,
+        }
+    }
+
+    fn withdrawal(tx: u32, amount: u32) -> Transaction {
+        Transaction::Withdrawal {
+            client: 0,
+            tx,
+            amount: Decimal::from(amount),
+            currency: None,
+            timestamp: None,
+        }
+    }
+
+    fn fee(tx: u32, amount: u32) -> Transaction {
+        Transaction::Fee {
+            client: 0,
+            tx,
+            amount: Decimal::from(amount),
+            currency: None,
+            timestamp: None,
+        }
+    }
+
+    fn dispute(tx: u32) -> Transaction {
+        Transaction::Dispute {
+            client: 0,
+            tx,
+            timestamp: None,
+        }
+    }
+
+    fn resolve(tx: u32) -> Transaction {
+        Transaction::Resolve {
+            client: 0,
+            tx,
+            amount: None,
+            timestamp: None,
+        }
+    }
+
+    fn chargeback(tx: u32) -> Transaction {
+        Transaction::Chargeback {
+            client: 0,
+            tx,
+            amount: None,
+            timestamp: None,
+        }
+    }
+
+    /// What the last of a sequence of transactions came to.
+    struct Run {
+        outcome: TransactionOutcome,
+        violation: Option<Violation>,
+        /// The account before and after the last transaction.
+        before: AccountState,
+        after: AccountState,
+    }
+
+    /// Applies all but the last transaction, which mustn't break anything, then the last one
+    /// under the given policy.
+    fn run(transactions: Vec<Transaction>, negative: NegativePolicy) -> Run {
+        let policy = AccountPolicy {
+            negative,
+            ..AccountPolicy::default()
+        };
+        let mut state = AccountState::new();
+        let (last, setup) = transactions.split_last().unwrap();
+        for transaction in setup {
+            let (_, violation) = state.transact_checked(transaction.clone(), &policy);
+            assert_eq!(violation, None, "{:?}", transaction);
+        }
+        let before = state.clone();
+        let (outcome, violation) = state.transact_checked(last.clone(), &policy);
+        Run {
+            outcome,
+            violation,
+            before,
+            after: state,
+        }
+    }
+
+    fn balance(held: i64, total: i64) -> Balance {
+        Balance {
+            held: Decimal::from(held),
+            total: Decimal::from(total),
+            payout: Decimal::zero(),
+        }
+    }
+
+    /// Each known sequence, with the balance it used to leave behind.
+    fn sequences() -> Vec<(&'static str, Vec<Transaction>, Balance)> {
+        vec![
+            (
+                "dispute after withdrawal",
+                vec![deposit(1, 100), withdrawal(2, 80), dispute(1)],
+                balance(100, 20),
+            ),
+            (
+                "dispute of an overdrawn account",
+                vec![deposit(1, 10), fee(2, 15), dispute(1)],
+                balance(10, -5),
+            ),
+        ]
+    }
+
+    #[test]
+    /// Rejecting leaves the account exactly as it was
+    fn rejects() {
+        for (name, transactions, broken) in sequences() {
+            let run = run(transactions, NegativePolicy::Reject);
+            let rejected = TransactionOutcome::Rejected(RejectReason::NegativeBalance);
+            assert_eq!(run.outcome, rejected, "{}", name);
+            assert_eq!(run.violation.map(|v| v.balance), Some(broken), "{}", name);
+            assert_eq!(run.after, run.before, "{}", name);
+        }
+    }
+
+    #[test]
+    /// Clamping applies the transaction, but never lets the funds break the invariants
+    fn clamps() {
+        let expected = [balance(20, 20), balance(0, -5)];
+        for ((name, transactions, broken), expected) in sequences().into_iter().zip(expected) {
+            let run = run(transactions, NegativePolicy::Clamp);
+            assert_eq!(run.outcome, TransactionOutcome::Applied, "{}", name);
+            assert_eq!(run.violation.map(|v| v.balance), Some(broken), "{}", name);
+            assert_eq!(run.after.balance(None), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    /// Allowing keeps the old behaviour, but the violation is still reported
+    fn allows() {
+        for (name, transactions, broken) in sequences() {
+            let run = run(transactions, NegativePolicy::Allow);
+            assert_eq!(run.outcome, TransactionOutcome::Applied, "{}", name);
+            assert_eq!(run.violation.map(|v| v.balance), Some(broken), "{}", name);
+            assert_eq!(run.after.balance(None), broken, "{}", name);
+        }
+    }
+
+    #[test]
+    /// A chargeback of funds that were withdrawn while the dispute was in the making never
+    /// gets that far: the dispute is rejected, so there is nothing to charge back
+    fn chargeback_of_spent_funds() {
+        let policy = AccountPolicy::default();
+        let mut state = AccountState::new();
+        state.transact_with(deposit(1, 100), &policy);
+        state.transact_with(withdrawal(2, 80), &policy);
+        let rejected = TransactionOutcome::Rejected(RejectReason::NegativeBalance);
+        assert_eq!(state.transact_with(dispute(1), &policy), rejected);
+        let outcome = state.transact_with(chargeback(1), &policy);
+        assert_eq!(
+            outcome,
+            TransactionOutcome::Ignored(IgnoreReason::NotDisputed)
+        );
+        assert_eq!(state.balance(None), balance(0, 20));
+    }
+
+    #[test]
+    /// Disputing a deposit twice holds its funds once, so that settling the dispute releases
+    /// everything it held
+    fn double_dispute() {
+        for settle in [resolve(1), chargeback(1)] {
+            let mut state = AccountState::new();
+            for transaction in [deposit(1, 10), deposit(2, 100), dispute(1)] {
+                state.transact(transaction);
+            }
+            let held = state.clone();
+            let outcome = state.transact(dispute(1));
+            let ignored = TransactionOutcome::Ignored(IgnoreReason::AlreadyDisputed);
+            assert_eq!(outcome, ignored);
+            assert_eq!(state, held);
+            assert_eq!(state.transact(settle), TransactionOutcome::Applied);
+            assert_eq!(state.held, Decimal::zero());
+        }
+    }
+
+    #[test]
+    /// A resolve without a dispute releases nothing, whether for the whole deposit or less,
+    /// and even when funds may go negative
+    fn resolve_without_dispute() {
+        let policy = AccountPolicy {
+            negative: NegativePolicy::Allow,
+            ..AccountPolicy::default()
+        };
+        let for_less = Transaction::Resolve {
+            client: 0,
+            tx: 1,
+            amount: Some(Decimal::from(40)),
+            timestamp: None,
+        };
+        for resolve in [resolve(1), for_less] {
+            let mut state = AccountState::new();
+            state.transact_with(deposit(1, 100), &policy);
+            let (outcome, violation) = state.transact_checked(resolve, &policy);
+            let ignored = TransactionOutcome::Ignored(IgnoreReason::NotDisputed);
+            assert_eq!((outcome, violation), (ignored, None));
+            assert_eq!(state.balance(None), balance(0, 100));
+        }
+    }
+
+    #[test]
+    /// Funds held without an open dispute are a violation, whatever they add up to
+    fn unbacked_hold() {
+        let mut state = AccountState::new();
+        state.transact(deposit(1, 100));
+        let before = state.balance(None);
+        state.held = Decimal::from(10);
+        let violation = check(|_| before, &state).map(|violation| violation.balance);
+        assert_eq!(violation, Some(balance(10, 100)));
+        let (mut held, mut total) = (state.held, state.total);
+        clamp(&mut held, &mut total, &before, false);
+        assert_eq!((held, total), (Decimal::zero(), Decimal::from(100)));
+
+        state.held = Decimal::zero();
+        assert_eq!(state.transact(dispute(1)), TransactionOutcome::Applied);
+        let before = state.balance(None);
+        assert_eq!(check(|_| before, &state), None);
+    }
+
+    #[test]
+    /// Fees may overdraw an account when the policy says so, and anything that makes up for
+    /// part of the overdraft is fine too
+    fn fee_overdraft() {
+        let run = run(
+            vec![deposit(1, 10), fee(2, 15), deposit(3, 2)],
+            NegativePolicy::Reject,
+        );
+        assert_eq!(run.outcome, TransactionOutcome::Applied);
+        assert_eq!(run.violation, None);
+        assert_eq!(run.after.balance(None), balance(0, -3));
+    }
+}
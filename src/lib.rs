@@ -0,0 +1,101 @@
+//! The account logic (accounts, transactions, policies and observers) only needs `alloc`, so
+//! it builds without the default `std` feature, e.g. for embedded or WASM users. Everything
+//! else, from sharding to reading and writing CSV files, needs `std`.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod account;
+#[cfg(feature = "std")]
+mod account_map;
+#[cfg(feature = "std")]
+pub mod aging;
+#[cfg(feature = "std")]
+pub mod audit;
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod config_file;
+pub mod currency;
+#[cfg(feature = "std")]
+pub mod deposit_index;
+pub mod deposits;
+#[cfg(feature = "std")]
+pub mod estimate;
+#[cfg(feature = "std")]
+pub mod fast_input;
+#[cfg(feature = "std")]
+pub mod flush;
+pub mod invariants;
+#[cfg(feature = "std")]
+pub mod metrics;
+#[cfg(feature = "std")]
+pub mod minor;
+#[cfg(feature = "notify")]
+pub mod notify;
+pub mod observer;
+#[cfg(feature = "std")]
+pub mod ordering;
+#[cfg(feature = "std")]
+pub mod parallel;
+#[cfg(feature = "std")]
+mod pipeline;
+pub mod policy;
+#[cfg(feature = "std")]
+pub mod rates;
+#[cfg(feature = "std")]
+mod read_ahead;
+#[cfg(feature = "std")]
+pub mod reconcile;
+#[cfg(feature = "std")]
+pub mod rejects;
+#[cfg(feature = "std")]
+pub mod replay;
+#[cfg(feature = "std")]
+pub mod report_diff;
+#[cfg(feature = "std")]
+pub mod reporter;
+#[cfg(feature = "std")]
+pub mod sink;
+#[cfg(feature = "std")]
+pub mod source;
+#[cfg(feature = "spill-deposits")]
+pub mod spill;
+#[cfg(feature = "std")]
+pub mod summary;
+#[cfg(feature = "std")]
+pub mod system;
+#[cfg(feature = "std")]
+pub mod throttle;
+pub mod transaction;
+#[cfg(feature = "std")]
+pub mod tune;
+
+#[cfg(feature = "std")]
+pub use pipeline::*;
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    #[test]
+    /// The account logic builds without std, using a target directory of its own so as not to
+    /// wait for the build this test is part of
+    fn builds_without_std() {
+        let manifest = env!("CARGO_MANIFEST_DIR");
+        let output = Command::new(env!("CARGO"))
+            .args(["check", "--lib", "--offline", "--no-default-features"])
+            .arg("--target-dir")
+            .arg(format!("{}/target/no_std", manifest))
+            .current_dir(manifest)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+}
@@ -1,55 +1,53 @@
-mod account;
-mod system;
-mod transaction;
+use std::env;
+use std::process::ExitCode;
+use track::config::Config;
+use track::reporter::Reporter;
 
-use crate::system::ShardedAccountSystem;
-use crate::transaction::Transaction;
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::io::BufReader;
-use std::{env, io};
-
-#[derive(Debug, Deserialize)]
-struct Input {
-    #[serde(rename = "type")]
-    type_: String,
-    client: u16,
-    tx: u32,
-    // Since we want to manage a specific precision, we are going to use the decimal
-    // crate to ease our workload.
-    amount: Option<Decimal>,
-}
-
-#[derive(Serialize)]
-struct Output {
-    pub client: u16,
-    #[serde(with = "rust_decimal::serde::float")]
-    pub available: Decimal,
-    #[serde(with = "rust_decimal::serde::float")]
-    pub held: Decimal,
-    #[serde(with = "rust_decimal::serde::float")]
-    pub total: Decimal,
-    pub locked: bool,
+fn main() -> ExitCode {
+    // Like diff(1), `track diff` exits with 1 if the reports differ, and 2 if it can't tell.
+    let trouble = match env::args().nth(1).as_deref() {
+        Some("diff") => 2,
+        _ => 1,
+    };
+    match run() {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            ExitCode::from(trouble)
+        }
+    }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    let filepath = &args[1]; // Only works with the specific API requirements mentioned in the problem statement
-    let file = File::open(filepath.as_str())?;
-    let reader = BufReader::new(file);
-
-    let mut rdr = csv::Reader::from_reader(reader);
-    // We're hard coding the number of shards because the problem statement API defines
-    // a very strict API and does not mention any other inputs (such as shards).
-    let mut system = ShardedAccountSystem::new(2);
-    let mut wtr = csv::Writer::from_writer(io::stdout());
-
-    for result in rdr.deserialize() {
-        let record: Input = result?;
-        system.transact(record.try_into()?);
+fn run() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    // Only works with the specific API requirements mentioned in the problem statement,
+    // plus a handful of optional flags that default to the original behaviour.
+    let config = Config::from_env_and_args(env::args().skip(1))?;
+    // Stdout is for the report alone, everything else goes through here.
+    let reporter = Reporter::new(config.verbosity);
+    for warning in config.warnings.iter() {
+        reporter.warn(warning);
+    }
+    let summary = track::run(&config)?;
+    if config.summary {
+        reporter.info(&summary);
+    } else {
+        reporter.verbose(&summary);
+    }
+    if let Some(timings) = summary.timings {
+        reporter.info(timings);
+    }
+    let mismatches = summary.balance_mismatches.unwrap_or_default();
+    for mismatch in mismatches.iter() {
+        reporter.info(mismatch);
+    }
+    if !mismatches.is_empty() {
+        return Err(format!("{} balance mismatches", mismatches.len()).into());
+    }
+    match summary.differences {
+        Some(differences) if differences > 0 => {
+            reporter.verbose(format!("{} differences between the reports", differences));
+            Ok(ExitCode::from(1))
+        }
+        _ => Ok(ExitCode::SUCCESS),
     }
-
-    system.write(&mut wtr)?;
-    Ok(())
 }
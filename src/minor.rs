@@ -0,0 +1,259 @@
+use crate::account::{IgnoreReason, RejectReason, TransactionOutcome};
+use crate::flush::ReportWriter;
+use crate::transaction::{ClientId, Transaction};
+use crate::Output;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// The four decimal places amounts are kept to, see [crate::Input::is_too_precise].
+const SCALE: u32 = 4;
+
+/// An amount in ten-thousandths, the smallest unit we keep. Fits amounts, and balances, of up
+/// to about 922 trillion either way, see [MinorAccount::transact] for what happens beyond.
+pub type Minor = i64;
+
+/// `amount` in minor units, `None` if it has more than four decimal places or doesn't fit.
+pub fn to_minor(amount: Decimal) -> Option<Minor> {
+    if amount.scale() > SCALE {
+        return None;
+    }
+    let mut scaled = amount;
+    scaled.rescale(SCALE);
+    i64::try_from(scaled.mantissa()).ok()
+}
+
+pub fn from_minor(amount: Minor) -> Decimal {
+    Decimal::new(amount, SCALE)
+}
+
+/// A deposit, as far as [MinorAccount] needs to know.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct MinorDeposit {
+    amount: Minor,
+    dispute: bool,
+    charged_back: bool,
+}
+
+/// The integer take on [crate::account::AccountState], for inputs that only have deposits,
+/// withdrawals, disputes, resolves and full chargebacks in the implicit currency, applied
+/// under the default [crate::policy::AccountPolicy]. Arithmetic on `i64` is a lot cheaper
+/// than on [Decimal], and that's all there is to this: the outcomes are the same, as long as
+/// the balances stay within the range of [Minor].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MinorAccount {
+    pub held: Minor,
+    pub total: Minor,
+    pub chargebacks: u32,
+    deposits: HashMap<u32, MinorDeposit>,
+}
+
+impl MinorAccount {
+    pub fn available(&self) -> Minor {
+        self.total - self.held
+    }
+
+    pub fn locked(&self) -> bool {
+        self.chargebacks > 0
+    }
+
+    /// Like [crate::account::AccountState::transact], in minor units. Anything other than the
+    /// five basic kinds, or a partial resolve or chargeback, panics, see [MinorAccount::supports].
+    ///
+    /// A transaction that would take a balance beyond the range of [Minor] is rejected as an
+    /// [RejectReason::Overflow], leaving the account as it was. [Decimal] goes a lot further,
+    /// so that's where the outcomes stop being the same.
+    pub fn transact(&mut self, transaction: &Transaction) -> TransactionOutcome {
+        let tx = transaction.tx();
+        let before = (
+            self.held,
+            self.total,
+            self.chargebacks,
+            self.deposits.get(&tx).copied(),
+        );
+        let floor = self.available().min(0);
+        let outcome = match self.apply(transaction) {
+            // The default negative policy: nothing may end up held below zero, and the
+            // available funds may not drop below zero, or further below it.
+            TransactionOutcome::Applied => match self.total.checked_sub(self.held) {
+                None => TransactionOutcome::Rejected(RejectReason::Overflow),
+                Some(available) if self.held < 0 || available < floor => {
+                    TransactionOutcome::Rejected(RejectReason::NegativeBalance)
+                }
+                Some(_) => TransactionOutcome::Applied,
+            },
+            outcome => outcome,
+        };
+        if let TransactionOutcome::Rejected(_) = outcome {
+            let deposit;
+            (self.held, self.total, self.chargebacks, deposit) = before;
+            match deposit {
+                Some(deposit) => self.deposits.insert(tx, deposit),
+                None => self.deposits.remove(&tx),
+            };
+        }
+        outcome
+    }
+
+    /// Whether [MinorAccount::transact] can take the transaction, and its amount fits.
+    pub fn supports(transaction: &Transaction) -> bool {
+        match transaction {
+            Transaction::Deposit {
+                amount, currency, ..
+            }
+            | Transaction::Withdrawal {
+                amount, currency, ..
+            } => currency.is_none() && to_minor(*amount).is_some(),
+            Transaction::Dispute { .. } => true,
+            Transaction::Resolve { amount, .. } | Transaction::Chargeback { amount, .. } => {
+                amount.is_none()
+            }
+            _ => false,
+        }
+    }
+
+    fn apply(&mut self, transaction: &Transaction) -> TransactionOutcome {
+        const OVERFLOW: TransactionOutcome = TransactionOutcome::Rejected(RejectReason::Overflow);
+        let amount = || transaction.amount().and_then(to_minor).unwrap();
+        match *transaction {
+            Transaction::Deposit { tx, .. } => {
+                if self.locked() {
+                    return TransactionOutcome::Ignored(IgnoreReason::Locked);
+                }
+                if self.deposits.contains_key(&tx) {
+                    return TransactionOutcome::Ignored(IgnoreReason::DuplicateTx);
+                }
+                let Some(total) = self.total.checked_add(amount()) else {
+                    return OVERFLOW;
+                };
+                self.total = total;
+                let deposit = MinorDeposit {
+                    amount: amount(),
+                    dispute: false,
+                    charged_back: false,
+                };
+                self.deposits.insert(tx, deposit);
+            }
+            Transaction::Withdrawal { .. } => {
+                if self.locked() {
+                    return TransactionOutcome::Ignored(IgnoreReason::Locked);
+                }
+                if self.available() < amount() {
+                    return TransactionOutcome::Ignored(IgnoreReason::InsufficientFunds);
+                }
+                let Some(total) = self.total.checked_sub(amount()) else {
+                    return OVERFLOW;
+                };
+                self.total = total;
+            }
+            Transaction::Dispute { tx, .. } => {
+                let Some(deposit) = self.deposits.get_mut(&tx) else {
+                    return TransactionOutcome::Ignored(IgnoreReason::UnknownTx);
+                };
+                if deposit.charged_back {
+                    return TransactionOutcome::Ignored(IgnoreReason::ChargedBack);
+                }
+                if deposit.dispute {
+                    return TransactionOutcome::Ignored(IgnoreReason::AlreadyDisputed);
+                }
+                deposit.dispute = true;
+                let Some(held) = self.held.checked_add(deposit.amount) else {
+                    return OVERFLOW;
+                };
+                self.held = held;
+            }
+            Transaction::Resolve { tx, .. } => {
+                let Some(deposit) = self.deposits.get_mut(&tx) else {
+                    return TransactionOutcome::Ignored(IgnoreReason::UnknownTx);
+                };
+                if !deposit.dispute {
+                    return TransactionOutcome::Ignored(IgnoreReason::NotDisputed);
+                }
+                deposit.dispute = false;
+                let Some(held) = self.held.checked_sub(deposit.amount) else {
+                    return OVERFLOW;
+                };
+                self.held = held;
+            }
+            Transaction::Chargeback { tx, .. } => match self.deposits.get_mut(&tx) {
+                Some(deposit) if deposit.dispute => {
+                    if deposit.amount <= 0 {
+                        return TransactionOutcome::Ignored(IgnoreReason::InvalidAmount);
+                    }
+                    deposit.dispute = false;
+                    deposit.charged_back = true;
+                    let (Some(held), Some(total)) = (
+                        self.held.checked_sub(deposit.amount),
+                        self.total.checked_sub(deposit.amount),
+                    ) else {
+                        return OVERFLOW;
+                    };
+                    (self.held, self.total) = (held, total);
+                    self.chargebacks += 1;
+                }
+                Some(_) => return TransactionOutcome::Ignored(IgnoreReason::NotDisputed),
+                None => return TransactionOutcome::Ignored(IgnoreReason::UnknownTx),
+            },
+            _ => panic!("{:?} isn't supported in minor units", transaction.kind()),
+        }
+        TransactionOutcome::Applied
+    }
+}
+
+/// [MinorAccount]s by client, in a single shard: this is about arithmetic, not threads.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MinorAccountSystem {
+    accounts: HashMap<ClientId, MinorAccount>,
+}
+
+impl MinorAccountSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See [MinorAccount::transact]. Like [crate::system::AccountSystem::transact], any
+    /// transaction of a client we don't know opens an account, even one that is ignored.
+    pub fn transact(&mut self, transaction: &Transaction) -> TransactionOutcome {
+        let client = *transaction.id();
+        self.accounts
+            .entry(client)
+            .or_default()
+            .transact(transaction)
+    }
+
+    pub fn account(&self, client: ClientId) -> Option<&MinorAccount> {
+        self.accounts.get(&client)
+    }
+
+    /// Roughly how many bytes the accounts take, in the spirit of
+    /// [crate::account::AccountState::memory_footprint].
+    pub fn memory_footprint(&self) -> usize {
+        use std::mem::size_of;
+        self.accounts
+            .values()
+            .map(|account| {
+                size_of::<ClientId>()
+                    + size_of::<MinorAccount>()
+                    + account.deposits.len() * (size_of::<u32>() + size_of::<MinorDeposit>())
+            })
+            .sum()
+    }
+
+    /// Writes the plain report in client order, converting the amounts back at the last
+    /// moment.
+    pub fn write<W: Write>(&self, writer: &mut ReportWriter<W>) -> std::io::Result<()> {
+        let mut clients: Vec<&ClientId> = self.accounts.keys().collect();
+        clients.sort_unstable();
+        for client in clients {
+            let account = &self.accounts[client];
+            writer.serialize(Output {
+                client: *client,
+                available: from_minor(account.available()),
+                held: from_minor(account.held),
+                total: from_minor(account.total),
+                locked: account.locked(),
+            })?;
+        }
+        Ok(())
+    }
+}
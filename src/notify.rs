@@ -0,0 +1,407 @@
+use crate::account::AccountState;
+use crate::deposits::DepositStore;
+use crate::observer::AccountObserver;
+use crate::reporter::Reporter;
+use crate::transaction::{ClientId, Transaction};
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// What gets POSTed to the webhook, as JSON. Amounts are strings so that receivers don't lose
+/// precision to floating point.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Notification {
+    /// One of `chargeback`, `locked` or `unlocked`.
+    pub event: &'static str,
+    pub client: ClientId,
+    /// The transaction that triggered the event. Freezing an account has none.
+    pub tx: Option<u32>,
+    /// The amount that was charged back, for chargebacks.
+    #[serde(serialize_with = "optional_decimal")]
+    pub amount: Option<Decimal>,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub available: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub held: Decimal,
+    #[serde(with = "rust_decimal::serde::str")]
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+fn optional_decimal<S: Serializer>(
+    value: &Option<Decimal>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.serialize_some(&value.to_string()),
+        None => serializer.serialize_none(),
+    }
+}
+
+impl Notification {
+    fn new(
+        event: &'static str,
+        client: ClientId,
+        cause: Option<&Transaction>,
+        account: &AccountState,
+    ) -> Self {
+        let tx = cause.map(Transaction::tx);
+        let amount = match cause {
+            Some(Transaction::Chargeback { tx, .. }) => account
+                .deposits
+                .get(*tx)
+                .and_then(|deposit| deposit.charged_back_amount()),
+            _ => None,
+        };
+        Notification {
+            event,
+            client,
+            tx,
+            amount,
+            available: account.available(),
+            held: account.held,
+            total: account.total,
+            locked: account.locked(),
+        }
+    }
+}
+
+/// How hard to try delivering notifications.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebhookOptions {
+    /// Notifications waiting for delivery. Once full, new ones are dropped (and counted as
+    /// failed) rather than holding up transactions.
+    pub queue_capacity: usize,
+    /// Attempts per notification, including the first one.
+    pub max_attempts: u32,
+    /// Wait before the first retry. Doubles with every further retry.
+    pub initial_backoff: Duration,
+    /// Per-request timeout.
+    pub timeout: Duration,
+}
+
+impl Default for WebhookOptions {
+    fn default() -> Self {
+        WebhookOptions {
+            queue_capacity: 256,
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Counters reported by [WebhookNotifier::finish].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct WebhookStats {
+    pub delivered: u64,
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    delivered: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// An [AccountObserver] that POSTs a [Notification] to a URL whenever a chargeback is applied
+/// or an account gets locked or unlocked.
+///
+/// Delivery happens on a background thread, so a slow or unreachable endpoint never holds up
+/// the transactions themselves. Failed requests are retried with exponential backoff; what
+/// still fails after that is reported as a warning and counted.
+pub struct WebhookNotifier {
+    sender: Mutex<Option<SyncSender<Notification>>>,
+    worker: Mutex<Option<JoinHandle<()>>>,
+    counters: Arc<Counters>,
+    reporter: Reporter,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: &str) -> Self {
+        Self::with_options(url, WebhookOptions::default())
+    }
+
+    pub fn with_options(url: &str, options: WebhookOptions) -> Self {
+        Self::with_reporter(url, options, Reporter::default())
+    }
+
+    /// Reports failed deliveries to `reporter` rather than straight to stderr.
+    pub fn with_reporter(url: &str, options: WebhookOptions, reporter: Reporter) -> Self {
+        let worker_reporter = reporter.clone();
+        let (sender, receiver) = sync_channel::<Notification>(options.queue_capacity);
+        let counters = Arc::new(Counters::default());
+        let worker_counters = counters.clone();
+        let url = url.to_string();
+        let worker = thread::spawn(move || {
+            let agent = ureq::AgentBuilder::new().timeout(options.timeout).build();
+            for notification in receiver {
+                match deliver(&agent, &url, &notification, &options) {
+                    Ok(()) => worker_counters.delivered.fetch_add(1, Ordering::Relaxed),
+                    Err(error) => {
+                        worker_reporter.warn(format_args!(
+                            "webhook: giving up on {} notification for client {}: {}",
+                            notification.event, notification.client, error
+                        ));
+                        worker_counters.failed.fetch_add(1, Ordering::Relaxed)
+                    }
+                };
+            }
+        });
+        WebhookNotifier {
+            sender: Mutex::new(Some(sender)),
+            worker: Mutex::new(Some(worker)),
+            counters,
+            reporter,
+        }
+    }
+
+    fn notify(&self, notification: Notification) {
+        let sender = self.sender.lock().unwrap();
+        let Some(sender) = sender.as_ref() else {
+            return;
+        };
+        if let Err(TrySendError::Full(notification) | TrySendError::Disconnected(notification)) =
+            sender.try_send(notification)
+        {
+            self.reporter.warn(format_args!(
+                "webhook: queue full, dropping {} notification for client {}",
+                notification.event, notification.client
+            ));
+            self.counters.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Stops accepting notifications, waits for the queued ones to be delivered (or to fail)
+    /// and returns the counters.
+    pub fn finish(&self) -> WebhookStats {
+        self.sender.lock().unwrap().take();
+        if let Some(worker) = self.worker.lock().unwrap().take() {
+            worker.join().expect("webhook worker panicked");
+        }
+        WebhookStats {
+            delivered: self.counters.delivered.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn deliver(
+    agent: &ureq::Agent,
+    url: &str,
+    notification: &Notification,
+    options: &WebhookOptions,
+) -> anyhow::Result<()> {
+    let body = serde_json::to_string(notification)?;
+    let mut backoff = options.initial_backoff;
+    let mut attempt = 1;
+    loop {
+        let result = agent
+            .post(url)
+            .set("Content-Type", "application/json")
+            .send_string(&body);
+        match result {
+            Ok(_) => return Ok(()),
+            Err(error) if attempt >= options.max_attempts => return Err(error.into()),
+            Err(_) => {
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+impl AccountObserver for WebhookNotifier {
+    fn on_applied(&self, client: ClientId, transaction: &Transaction, account: &AccountState) {
+        if let Transaction::Chargeback { .. } = transaction {
+            self.notify(Notification::new(
+                "chargeback",
+                client,
+                Some(transaction),
+                account,
+            ));
+        }
+    }
+
+    fn on_locked(&self, client: ClientId, cause: Option<&Transaction>, account: &AccountState) {
+        self.notify(Notification::new("locked", client, cause, account));
+    }
+
+    fn on_unlocked(&self, client: ClientId, cause: Option<&Transaction>, account: &AccountState) {
+        self.notify(Notification::new("unlocked", client, cause, account));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::ShardedAccountSystem;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// A tiny HTTP server that answers the first `failures` requests with a 500 and everything
+    /// after that with a 200, handing the request bodies back once it has seen `expected` of
+    /// them.
+    fn server(expected: usize, failures: usize) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            let mut requests = 0;
+            while bodies.len() < expected {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                requests += 1;
+                let status = if requests <= failures {
+                    "500 Internal Server Error"
+                } else {
+                    bodies.push(String::from_utf8(body).unwrap());
+                    "200 OK"
+                };
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+            }
+            bodies
+        });
+        (url, handle)
+    }
+
+    fn options() -> WebhookOptions {
+        WebhookOptions {
+            initial_backoff: Duration::from_millis(1),
+            ..WebhookOptions::default()
+        }
+    }
+
+    #[test]
+    /// A chargeback produces a chargeback and a lock notification, freezing and unfreezing an
+    /// account one each
+    fn posts_notifications() {
+        let (url, server) = server(4, 0);
+        let notifier = Arc::new(WebhookNotifier::with_options(&url, options()));
+        let mut system = ShardedAccountSystem::with_observer(2, notifier.clone());
+        system.transact(Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Decimal::from(100),
+            currency: None,
+            timestamp: None,
+        });
+        system.transact(Transaction::Deposit {
+            client: 2,
+            tx: 2,
+            amount: Decimal::from(20),
+            currency: None,
+            timestamp: None,
+        });
+        system.transact(Transaction::Dispute {
+            client: 1,
+            tx: 1,
+            timestamp: None,
+        });
+        system.transact(Transaction::Chargeback {
+            client: 1,
+            tx: 1,
+            amount: None,
+            timestamp: None,
+        });
+        assert_eq!(
+            notifier.finish(),
+            WebhookStats {
+                delivered: 2,
+                failed: 0
+            }
+        );
+
+        let notifier = Arc::new(WebhookNotifier::with_options(&url, options()));
+        let mut system = ShardedAccountSystem::with_observer(1, notifier.clone());
+        system.transact(Transaction::Deposit {
+            client: 2,
+            tx: 2,
+            amount: Decimal::from(20),
+            currency: None,
+            timestamp: None,
+        });
+        system.freeze_all();
+        system.unfreeze_all();
+        assert_eq!(
+            notifier.finish(),
+            WebhookStats {
+                delivered: 2,
+                failed: 0
+            }
+        );
+
+        let bodies = server.join().unwrap();
+        assert_eq!(
+            bodies,
+            vec![
+                r#"{"event":"chargeback","client":1,"tx":1,"amount":"100","available":"0","held":"0","total":"0","locked":true}"#,
+                r#"{"event":"locked","client":1,"tx":1,"amount":"100","available":"0","held":"0","total":"0","locked":true}"#,
+                r#"{"event":"locked","client":2,"tx":null,"amount":null,"available":"20","held":"0","total":"20","locked":true}"#,
+                r#"{"event":"unlocked","client":2,"tx":null,"amount":null,"available":"20","held":"0","total":"20","locked":false}"#,
+            ]
+        );
+    }
+
+    #[test]
+    /// Failed deliveries are retried, and counted once retries run out
+    fn retries_and_failures() {
+        let (url, server) = server(1, 2);
+        let notifier = WebhookNotifier::with_options(&url, options());
+        let account = AccountState::new();
+        notifier.on_locked(3, None, &account);
+        assert_eq!(
+            notifier.finish(),
+            WebhookStats {
+                delivered: 1,
+                failed: 0
+            }
+        );
+        assert_eq!(server.join().unwrap().len(), 1);
+
+        // Nobody listens on this port any more
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        drop(listener);
+        let notifier = WebhookNotifier::with_options(
+            &url,
+            WebhookOptions {
+                max_attempts: 2,
+                ..options()
+            },
+        );
+        notifier.on_locked(3, None, &account);
+        assert_eq!(
+            notifier.finish(),
+            WebhookStats {
+                delivered: 0,
+                failed: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use crate::account::{AccountState, RejectReason};
+use crate::invariants::Violation;
+use crate::transaction::{ClientId, Transaction};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Hooks for side effects such as notifications or metrics, without having to touch the account
+/// logic itself. Every method has an empty default, so an implementation only picks the events
+/// it is interested in.
+///
+/// Observers are shared between shards, which may run on different threads (see
+/// [crate::parallel::ParallelAccountSystem]), hence `&self` and the `Send + Sync` bound. Use
+/// interior mutability to keep state. Calls for a single client always come in the order the
+/// transactions were applied; calls for different clients may interleave.
+pub trait AccountObserver: Send + Sync {
+    /// The transaction changed the account, which is passed in its new state.
+    fn on_applied(&self, _client: ClientId, _transaction: &Transaction, _account: &AccountState) {}
+
+    /// The transaction was not valid for the account and did not change it.
+    fn on_ignored(&self, _client: ClientId, _transaction: &Transaction, _account: &AccountState) {}
+
+    /// The transaction was rejected for `reason` and did not change the account. Rejections
+    /// are ignored transactions that deserve attention, so unless an observer tells them
+    /// apart, they go to `on_ignored`.
+    fn on_rejected(
+        &self,
+        client: ClientId,
+        transaction: &Transaction,
+        _reason: RejectReason,
+        account: &AccountState,
+    ) {
+        self.on_ignored(client, transaction, account)
+    }
+
+    /// The transaction broke the invariants of the account. Called before `on_applied` or
+    /// `on_ignored`, with the account in whatever state the
+    /// [crate::policy::NegativePolicy] left it.
+    fn on_violation(
+        &self,
+        _client: ClientId,
+        _transaction: &Transaction,
+        _violation: &Violation,
+        _account: &AccountState,
+    ) {
+    }
+
+    /// The account went from unlocked to locked, be it by a chargeback or by freezing it.
+    /// `cause` is the transaction that locked it, if it was one.
+    fn on_locked(&self, _client: ClientId, _cause: Option<&Transaction>, _account: &AccountState) {}
+
+    /// The account went from locked to unlocked. `cause` is the transaction that unlocked it,
+    /// if it was one.
+    fn on_unlocked(
+        &self,
+        _client: ClientId,
+        _cause: Option<&Transaction>,
+        _account: &AccountState,
+    ) {
+    }
+}
+
+/// The default observer, which does nothing at all.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NoopObserver;
+
+impl AccountObserver for NoopObserver {}
+
+/// The observers registered on a system, each told about everything in the order they were
+/// registered. With none registered, telling them is a check of an empty list rather than a
+/// call through a trait object.
+#[derive(Clone, Default)]
+pub struct Observers(Vec<Arc<dyn AccountObserver>>);
+
+impl Observers {
+    pub fn push(&mut self, observer: Arc<dyn AccountObserver>) {
+        self.0.push(observer);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl AccountObserver for Observers {
+    fn on_applied(&self, client: ClientId, transaction: &Transaction, account: &AccountState) {
+        for observer in &self.0 {
+            observer.on_applied(client, transaction, account);
+        }
+    }
+
+    fn on_ignored(&self, client: ClientId, transaction: &Transaction, account: &AccountState) {
+        for observer in &self.0 {
+            observer.on_ignored(client, transaction, account);
+        }
+    }
+
+    fn on_rejected(
+        &self,
+        client: ClientId,
+        transaction: &Transaction,
+        reason: RejectReason,
+        account: &AccountState,
+    ) {
+        for observer in &self.0 {
+            observer.on_rejected(client, transaction, reason, account);
+        }
+    }
+
+    fn on_violation(
+        &self,
+        client: ClientId,
+        transaction: &Transaction,
+        violation: &Violation,
+        account: &AccountState,
+    ) {
+        for observer in &self.0 {
+            observer.on_violation(client, transaction, violation, account);
+        }
+    }
+
+    fn on_locked(&self, client: ClientId, cause: Option<&Transaction>, account: &AccountState) {
+        for observer in &self.0 {
+            observer.on_locked(client, cause, account);
+        }
+    }
+
+    fn on_unlocked(&self, client: ClientId, cause: Option<&Transaction>, account: &AccountState) {
+        for observer in &self.0 {
+            observer.on_unlocked(client, cause, account);
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use crate::transaction::{ClientId, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Whether transaction ids are expected to increase per client or across the whole file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TxOrderScope {
+    Client,
+    Global,
+}
+
+/// A fund-moving transaction whose id is not above the highest id seen so far in its scope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxOrderAnomaly {
+    pub client: ClientId,
+    pub tx: u32,
+    pub highest: u32,
+}
+
+impl fmt::Display for TxOrderAnomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.tx == self.highest {
+            write!(f, "transaction id {} was reused", self.tx)
+        } else {
+            write!(
+                f,
+                "transaction id {} arrived after the higher id {}",
+                self.tx, self.highest
+            )
+        }
+    }
+}
+
+/// Our producers guarantee that transaction ids are unique and increasing, so anything else
+/// hints at upstream corruption. This only keeps track of what it has seen and reports
+/// deviations; whether to apply the transaction anyway is up to the caller.
+///
+/// Disputes, resolves and chargebacks refer back to earlier transactions, so only deposits,
+/// withdrawals, fees and interest are checked.
+pub struct TxOrderCheck {
+    scope: TxOrderScope,
+    highest: HashMap<ClientId, u32>,
+}
+
+impl TxOrderCheck {
+    pub fn new(scope: TxOrderScope) -> Self {
+        TxOrderCheck {
+            scope,
+            highest: HashMap::new(),
+        }
+    }
+
+    /// Records the transaction and returns an anomaly if its id is smaller than or equal to
+    /// the highest one seen before it.
+    pub fn check(&mut self, transaction: &Transaction) -> Option<TxOrderAnomaly> {
+        let (client, tx) = match transaction {
+            Transaction::Deposit { client, tx, .. }
+            | Transaction::Withdrawal { client, tx, .. }
+            | Transaction::Fee { client, tx, .. }
+            | Transaction::Interest { client, tx, .. } => (*client, *tx),
+            _ => return None,
+        };
+        // In global scope every client shares the same slot.
+        let key = match self.scope {
+            TxOrderScope::Client => client,
+            TxOrderScope::Global => 0,
+        };
+        match self.highest.get_mut(&key) {
+            Some(highest) if tx <= *highest => Some(TxOrderAnomaly {
+                client,
+                tx,
+                highest: *highest,
+            }),
+            Some(highest) => {
+                *highest = tx;
+                None
+            }
+            None => {
+                self.highest.insert(key, tx);
+                None
+            }
+        }
+    }
+}
+
+/// Where to stop applying transactions, for investigations that need the accounts as they
+/// were at some point in the past. Both boundaries are inclusive: row N is applied and N+1 isn't,
+/// transactions stamped exactly at the given time are applied and the first one after it stops
+/// processing. Rows without a timestamp never trigger the time boundary.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Until {
+    pub row: Option<u64>,
+    pub time: Option<u64>,
+}
+
+impl Until {
+    /// Whether `transaction`, being the `row`-th one (counting from 1), lies past the boundary.
+    pub fn reached(&self, row: u64, transaction: &Transaction) -> bool {
+        let past_row = self.row.is_some_and(|until| row > until);
+        let past_time = match (self.time, transaction.timestamp()) {
+            (Some(until), Some(timestamp)) => timestamp > until,
+            _ => false,
+        };
+        past_row || past_time
+    }
+}
+
+/// Orders transactions by their timestamp for feeds that arrive out of order. The sort is
+/// stable, and a row without a timestamp is treated as happening at the same time as the row
+/// before it, so such rows stay right where they were in the file relative to their neighbours.
+pub fn sort_by_time<T: AsRef<Transaction>>(transactions: Vec<T>) -> Vec<T> {
+    let mut last = 0;
+    let mut keyed: Vec<(u64, T)> = transactions
+        .into_iter()
+        .map(|transaction| {
+            last = transaction.as_ref().timestamp().unwrap_or(last);
+            (last, transaction)
+        })
+        .collect();
+    keyed.sort_by_key(|(timestamp, _)| *timestamp);
+    keyed
+        .into_iter()
+        .map(|(_, transaction)| transaction)
+        .collect()
+}
+
+/// A row of a client whose run ended earlier, see [ClientRuns].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ClientReappeared {
+    pub client: ClientId,
+    /// The client whose run the row interrupts.
+    pub current: ClientId,
+}
+
+impl fmt::Display for ClientReappeared {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the input is not sorted by client: client {} appears again after client {}",
+            self.client, self.current
+        )
+    }
+}
+
+impl std::error::Error for ClientReappeared {}
+
+/// Follows input that is sorted (or at least grouped) by client: all transactions of a client
+/// have to come in one contiguous run. That's what allows finalizing an account as soon as the
+/// next client shows up, since nothing can touch it afterwards.
+#[derive(Debug, Default)]
+pub struct ClientRuns {
+    current: Option<ClientId>,
+    finished: HashSet<ClientId>,
+}
+
+impl ClientRuns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves on to `client` and returns the client whose run just ended, if any. Fails if
+    /// `client` had its run already, i.e. the input is not grouped by client. The current run
+    /// carries on then, as if the row wasn't there.
+    pub fn next(&mut self, client: ClientId) -> Result<Option<ClientId>, ClientReappeared> {
+        match self.current {
+            Some(current) if current == client => Ok(None),
+            _ if self.finished.contains(&client) => Err(ClientReappeared {
+                client,
+                current: self.current.unwrap_or_default(),
+            }),
+            previous => {
+                if let Some(previous) = previous {
+                    self.finished.insert(previous);
+                }
+                self.current = Some(client);
+                Ok(previous)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::system::ShardedAccountSystem;
+    use rust_decimal::Decimal;
+
+    fn deposit(client: ClientId, tx: u32) -> Transaction {
+        Transaction::Deposit {
+            client,
+            tx,
+            amount: Decimal::from(10),
+            currency: None,
+            timestamp: None,
+        }
+    }
+
+    fn deposit_at(tx: u32, timestamp: u64) -> Transaction {
+        Transaction::Deposit {
+            client: 1,
+            tx,
+            amount: Decimal::from(10),
+            currency: None,
+            timestamp: Some(timestamp),
+        }
+    }
+
+    #[test]
+    /// Increasing ids never produce a warning
+    fn in_order() {
+        let mut check = TxOrderCheck::new(TxOrderScope::Client);
+        assert_eq!(check.check(&deposit(1, 1)), None);
+        assert_eq!(check.check(&deposit(1, 2)), None);
+        assert_eq!(check.check(&deposit(2, 1)), None);
+        assert_eq!(
+            check.check(&Transaction::Dispute {
+                client: 1,
+                tx: 1,
+                timestamp: None
+            }),
+            None
+        );
+    }
+
+    #[test]
+    /// A smaller id after a larger one is reported against the highest id seen
+    fn out_of_order() {
+        let mut check = TxOrderCheck::new(TxOrderScope::Client);
+        check.check(&deposit(1, 5));
+        let anomaly = check.check(&deposit(1, 3)).unwrap();
+        assert_eq!(
+            anomaly,
+            TxOrderAnomaly {
+                client: 1,
+                tx: 3,
+                highest: 5
+            }
+        );
+        // The highest id is not lowered by the anomaly
+        assert!(check.check(&deposit(1, 4)).is_some());
+        assert_eq!(check.check(&deposit(1, 6)), None);
+    }
+
+    #[test]
+    /// Reusing an id is an anomaly too
+    fn equal_id() {
+        let mut check = TxOrderCheck::new(TxOrderScope::Client);
+        check.check(&deposit(1, 5));
+        let anomaly = check.check(&deposit(1, 5)).unwrap();
+        assert_eq!(anomaly.to_string(), "transaction id 5 was reused");
+    }
+
+    #[test]
+    /// A dispute that was written before its deposit is moved after it
+    fn sort_dispute_after_deposit() {
+        let transactions = vec![
+            Transaction::Dispute {
+                client: 1,
+                tx: 1,
+                timestamp: Some(20),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Decimal::from(10),
+                currency: None,
+                timestamp: Some(10),
+            },
+        ];
+        let sorted = sort_by_time(transactions.clone());
+        assert_eq!(
+            sorted,
+            vec![transactions[1].clone(), transactions[0].clone()]
+        );
+
+        let mut system = ShardedAccountSystem::new(2);
+        for transaction in sorted {
+            system.transact(transaction);
+        }
+        assert_eq!(system.account(1).unwrap().held, Decimal::from(10));
+    }
+
+    #[test]
+    /// Rows without a timestamp stick to the row before them
+    fn sort_keeps_untimestamped_rows_in_place() {
+        let transactions = vec![
+            deposit_at(1, 30),
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: Decimal::from(10),
+                currency: None,
+                timestamp: None,
+            },
+            deposit_at(3, 10),
+        ];
+        let sorted = sort_by_time(transactions.clone());
+        let ids: Vec<u32> = sorted
+            .iter()
+            .map(|transaction| match transaction {
+                Transaction::Deposit { tx, .. } => *tx,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(ids, vec![3, 1, 2]);
+    }
+
+    #[test]
+    /// In global scope ids have to increase across clients as well
+    fn global_scope() {
+        let mut check = TxOrderCheck::new(TxOrderScope::Global);
+        check.check(&deposit(1, 5));
+        assert!(check.check(&deposit(2, 4)).is_some());
+        let mut check = TxOrderCheck::new(TxOrderScope::Client);
+        check.check(&deposit(1, 5));
+        assert_eq!(check.check(&deposit(2, 4)), None);
+    }
+
+    #[test]
+    /// Every change of client ends a run, and a client can't come back
+    fn client_runs() {
+        let mut runs = ClientRuns::new();
+        assert_eq!(runs.next(3).unwrap(), None);
+        assert_eq!(runs.next(3).unwrap(), None);
+        assert_eq!(runs.next(1).unwrap(), Some(3));
+        assert_eq!(runs.next(2).unwrap(), Some(1));
+        assert_eq!(
+            runs.next(3).unwrap_err().to_string(),
+            "the input is not sorted by client: client 3 appears again after client 2"
+        );
+    }
+}
@@ -0,0 +1,715 @@
+use crate::account::{AccountState, RejectReason, TransactionOutcome};
+use crate::batch::{BatchOptions, BatchReport};
+use crate::deposit_index::DepositIndex;
+use crate::metrics::ShardMetrics;
+use crate::system::{AccountSystem, ShardedAccountSystem};
+use crate::transaction::{ClientId, Transaction};
+use hashring::HashRing;
+use std::sync::atomic::{AtomicIsize, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Where a worker sends the outcome of a transaction that is part of a batch, along with the
+/// transaction's position in the batch.
+type Reply = (usize, Sender<(usize, TransactionOutcome)>);
+
+/// What the workers get handed.
+enum Job {
+    Transact(Transaction, Option<Reply>),
+    Schedule(u64, Transaction),
+    Advance(u64),
+}
+
+impl Job {
+    /// The transaction of a job that couldn't be handed over, there is one whenever that happens.
+    fn into_transaction(self) -> Transaction {
+        match self {
+            Job::Transact(transaction, _) | Job::Schedule(_, transaction) => transaction,
+            Job::Advance(_) => unreachable!("moving the clock never hands anything back"),
+        }
+    }
+}
+
+/// How full a shard's queue is, see [ParallelAccountSystem::queue_depths].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct QueueDepth {
+    /// Jobs waiting for the worker right now.
+    pub queued: usize,
+    /// The most there ever were. A shard whose peak is at the capacity held up the producers
+    /// at some point, which is what a hot client looks like.
+    pub peak: usize,
+}
+
+/// Counts the jobs a shard's queue holds. Producers count a job once it's in, the worker once
+/// it's out, so the count may dip below zero for a moment and, while the worker is picking up a
+/// job from a full queue, be one above the capacity.
+#[derive(Default)]
+struct Gauge {
+    queued: AtomicIsize,
+    peak: AtomicUsize,
+    /// Nanoseconds the worker spent on jobs.
+    busy: AtomicU64,
+    /// Transactions queued for the shard, see [ShardMetrics::routed].
+    routed: AtomicU64,
+}
+
+impl Gauge {
+    fn queued(&self) {
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak
+            .fetch_max(queued.max(0) as usize, Ordering::Relaxed);
+    }
+
+    fn routed(&self) {
+        self.routed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn picked_up(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn worked(&self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.busy.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn depth(&self) -> QueueDepth {
+        QueueDepth {
+            queued: self.queued.load(Ordering::Relaxed).max(0) as usize,
+            peak: self.peak.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The threaded take on [ShardedAccountSystem]: every shard gets a worker thread of its own and
+/// transactions are handed to it over a channel. A client always maps to the same shard and
+/// channels are FIFO, so the order of transactions per client is preserved, which is all we need.
+///
+/// The channels are bounded. If a shard falls behind, its queue fills up and
+/// [ParallelAccountSystem::transact] blocks until the worker has caught up, instead of buffering
+/// the rest of the input in memory.
+///
+/// A long-running service shares the system between its producers and calls
+/// [ParallelAccountSystem::shutdown] once it's asked to stop.
+pub struct ParallelAccountSystem {
+    ring: HashRing<usize>,
+    // Producers only read, shutting down takes the senders away, which is what closes the
+    // channels. Empty from then on.
+    senders: RwLock<Vec<SyncSender<Job>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    // Workers only hold the lock while applying a single transaction, which leaves room for
+    // reading accounts in between.
+    shards: Vec<Arc<Mutex<AccountSystem>>>,
+    gauges: Vec<Arc<Gauge>>,
+    // Every shard needs to see the clock move, so we keep track of it here to only tell them
+    // when it actually does.
+    clock: Mutex<Option<u64>>,
+    // Taken over from the system, see the concurrency notes on [DepositIndex].
+    deposits: Option<Mutex<DepositIndex>>,
+}
+
+impl ParallelAccountSystem {
+    /// How many transactions may be waiting for a shard before producers have to wait. Big
+    /// enough to smooth over bursts, small enough for the memory to not matter.
+    pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+    /// Starts a worker for each shard of `system`, using the default queue capacity.
+    pub fn new(system: ShardedAccountSystem) -> Self {
+        Self::with_queue_capacity(system, Self::DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// Starts a worker for each shard of `system`, allowing `capacity` transactions to queue up
+    /// per shard. A capacity of zero makes every hand-over wait for the worker to pick it up.
+    pub fn with_queue_capacity(mut system: ShardedAccountSystem, capacity: usize) -> Self {
+        let clock = system.clock();
+        let deposits = system.take_deposit_index().map(Mutex::new);
+        let (ring, systems) = system.into_parts();
+        let mut senders = Vec::new();
+        let mut workers = Vec::new();
+        let mut shards = Vec::new();
+        let mut gauges = Vec::new();
+        for system in systems {
+            let (sender, receiver) = sync_channel::<Job>(capacity);
+            let shard = Arc::new(Mutex::new(system));
+            let worker_shard = shard.clone();
+            let gauge = Arc::new(Gauge::default());
+            let worker_gauge = gauge.clone();
+            workers.push(thread::spawn(move || {
+                for job in receiver {
+                    worker_gauge.picked_up();
+                    let start = Instant::now();
+                    let mut shard = worker_shard.lock().unwrap();
+                    match job {
+                        Job::Transact(transaction, reply) => {
+                            let outcome = shard.transact(transaction);
+                            if let Some((index, reply)) = reply {
+                                // The batch only goes away once every outcome is in.
+                                let _ = reply.send((index, outcome));
+                            }
+                        }
+                        Job::Schedule(effective, transaction) => {
+                            shard.schedule(effective, transaction)
+                        }
+                        Job::Advance(now) => shard.advance(now),
+                    }
+                    worker_gauge.worked(start.elapsed());
+                }
+            }));
+            senders.push(sender);
+            shards.push(shard);
+            gauges.push(gauge);
+        }
+        ParallelAccountSystem {
+            ring,
+            senders: RwLock::new(senders),
+            workers: Mutex::new(workers),
+            shards,
+            gauges,
+            clock: Mutex::new(clock),
+            deposits,
+        }
+    }
+
+    fn shard(&self, client: ClientId) -> Option<usize> {
+        self.ring.get(&client.to_be_bytes()).copied()
+    }
+
+    /// See [DepositIndex::route].
+    fn route(&self, transaction: Transaction) -> Transaction {
+        match &self.deposits {
+            Some(deposits) => deposits.lock().unwrap().route(transaction),
+            None => transaction,
+        }
+    }
+
+    /// Queues the job for the shard, waiting for room if necessary. Hands the job back once the
+    /// system is shut down.
+    fn send(&self, shard: usize, job: Job) -> Result<(), Job> {
+        let senders = self.senders.read().unwrap();
+        match senders.get(shard) {
+            Some(sender) => {
+                let transaction = matches!(job, Job::Transact(..));
+                sender.send(job).expect("shard worker stopped unexpectedly");
+                self.gauges[shard].queued();
+                if transaction {
+                    self.gauges[shard].routed();
+                }
+                Ok(())
+            }
+            None => Err(job),
+        }
+    }
+
+    /// Hands the transaction to its shard, waiting for room in the queue if necessary. Like
+    /// [ShardedAccountSystem::transact], a timestamp moves the clock forward first. Once the
+    /// system is shut down, the transaction is handed back instead.
+    pub fn transact(&self, transaction: Transaction) -> Result<(), Transaction> {
+        if let Some(timestamp) = transaction.timestamp() {
+            self.advance(timestamp);
+        }
+        let transaction = self.route(transaction);
+        match self.shard(*transaction.id()) {
+            Some(shard) => self
+                .send(shard, Job::Transact(transaction, None))
+                .map_err(Job::into_transaction),
+            None => Ok(()),
+        }
+    }
+
+    /// See [ShardedAccountSystem::transact_all].
+    pub fn transact_all<I: IntoIterator<Item = Transaction>>(&self, iter: I) -> BatchReport {
+        self.transact_all_with(iter, BatchOptions::default())
+    }
+
+    /// See [ShardedAccountSystem::transact_all_with]. Transactions are dispatched to their
+    /// shards without waiting for one to be applied before handing out the next, so the shards
+    /// work through the batch side by side. Returns once all of it has been applied.
+    ///
+    /// Transactions that come after a shutdown are rejected, see [RejectReason::ShutDown].
+    pub fn transact_all_with<I: IntoIterator<Item = Transaction>>(
+        &self,
+        iter: I,
+        options: BatchOptions,
+    ) -> BatchReport {
+        let (reply, outcomes) = channel();
+        let mut report = BatchReport::default();
+        for (index, transaction) in iter.into_iter().enumerate() {
+            if let Some(timestamp) = transaction.timestamp() {
+                self.advance(timestamp);
+            }
+            let transaction = self.route(transaction);
+            let reason = match self.shard(*transaction.id()) {
+                Some(shard) => {
+                    let job = Job::Transact(transaction, Some((index, reply.clone())));
+                    self.send(shard, job).err().map(|_| RejectReason::ShutDown)
+                }
+                None => Some(RejectReason::NoShard),
+            };
+            if let Some(reason) = reason {
+                report.record(index, TransactionOutcome::Rejected(reason), &options);
+            }
+            // Count what's done already, so outcomes don't pile up while we keep dispatching.
+            for (index, outcome) in outcomes.try_iter() {
+                report.record(index, outcome, &options);
+            }
+        }
+        drop(reply);
+        for (index, outcome) in outcomes {
+            report.record(index, outcome, &options);
+        }
+        report
+    }
+
+    /// Like [ParallelAccountSystem::transact], but hands the transaction back instead of waiting
+    /// when the shard's queue is full. Moving the clock forward may still wait, since every
+    /// shard has to hear about it.
+    pub fn try_transact(&self, transaction: Transaction) -> Result<(), Transaction> {
+        if let Some(timestamp) = transaction.timestamp() {
+            self.advance(timestamp);
+        }
+        let transaction = self.route(transaction);
+        let Some(shard) = self.shard(*transaction.id()) else {
+            return Ok(());
+        };
+        let senders = self.senders.read().unwrap();
+        let Some(sender) = senders.get(shard) else {
+            return Err(transaction);
+        };
+        match sender.try_send(Job::Transact(transaction, None)) {
+            Ok(()) => {
+                self.gauges[shard].queued();
+                self.gauges[shard].routed();
+                Ok(())
+            }
+            Err(TrySendError::Full(job)) => Err(job.into_transaction()),
+            Err(TrySendError::Disconnected(_)) => panic!("shard worker stopped unexpectedly"),
+        }
+    }
+
+    /// See [ShardedAccountSystem::schedule]. Once the system is shut down, the transaction is
+    /// handed back instead.
+    pub fn schedule(&self, effective: u64, transaction: Transaction) -> Result<(), Transaction> {
+        if let Some(timestamp) = transaction.timestamp() {
+            self.advance(timestamp);
+        }
+        let transaction = self.route(transaction);
+        match self.shard(*transaction.id()) {
+            Some(shard) => self
+                .send(shard, Job::Schedule(effective, transaction))
+                .map_err(Job::into_transaction),
+            None => Ok(()),
+        }
+    }
+
+    /// See [ShardedAccountSystem::advance]. Does nothing once the system is shut down.
+    pub fn advance(&self, now: u64) {
+        let mut clock = self.clock.lock().unwrap();
+        if clock.is_some_and(|clock| clock >= now) {
+            return;
+        }
+        *clock = Some(now);
+        for (sender, gauge) in self.senders.read().unwrap().iter().zip(&self.gauges) {
+            sender
+                .send(Job::Advance(now))
+                .expect("shard worker stopped unexpectedly");
+            gauge.queued();
+        }
+    }
+
+    /// How full every shard's queue is and has been, in shard order. The queues are bounded
+    /// by the capacity the system was started with, a producer that finds one full waits,
+    /// so no more than that many jobs are ever held per shard. Counts are taken on the side
+    /// and may be off by one in the moment a job changes hands.
+    pub fn queue_depths(&self) -> Vec<QueueDepth> {
+        self.gauges.iter().map(|gauge| gauge.depth()).collect()
+    }
+
+    /// See [ShardedAccountSystem::metrics]. A transaction counts as routed once it's in its
+    /// shard's queue, which producers count on the side, and with its outcome once the worker
+    /// has applied it. So the outcomes can fall behind, by what's queued at most.
+    pub fn metrics(&self) -> Vec<ShardMetrics> {
+        self.shards
+            .iter()
+            .zip(self.gauges.iter())
+            .map(|(shard, gauge)| {
+                let metrics = shard.lock().unwrap().metrics();
+                ShardMetrics {
+                    routed: gauge.routed.load(Ordering::Relaxed),
+                    queued: Some(gauge.depth().queued),
+                    ..metrics
+                }
+            })
+            .collect()
+    }
+
+    /// How long every shard's worker has spent on the jobs it picked up so far, waiting for
+    /// its shard's lock included. Next to each other, they show how evenly the work is spread.
+    pub fn busy_times(&self) -> Vec<Duration> {
+        self.gauges
+            .iter()
+            .map(|gauge| Duration::from_nanos(gauge.busy.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// See [ShardedAccountSystem::memory_footprint]. Transactions still sitting in the queue
+    /// are not in it.
+    pub fn memory_footprint(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().memory_footprint())
+            .sum()
+    }
+
+    /// See [ShardedAccountSystem::compact]. Accounts of transactions still sitting in the
+    /// queue are looked at as they are now.
+    pub fn compact(&self, horizon: u64) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().compact(horizon))
+            .sum()
+    }
+
+    /// See [ShardedAccountSystem::take_spill_error].
+    #[cfg(feature = "spill-deposits")]
+    pub fn take_spill_error(&self) -> Option<std::io::Error> {
+        self.shards
+            .iter()
+            .find_map(|shard| shard.lock().unwrap().take_spill_error())
+    }
+
+    /// See [ShardedAccountSystem::spill_deposits]. Deposits of transactions still sitting in
+    /// the queue aren't there yet.
+    #[cfg(feature = "spill-deposits")]
+    pub fn spill_deposits(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().spill_deposits())
+            .sum()
+    }
+
+    /// The latest timestamp seen so far, if any.
+    pub fn clock(&self) -> Option<u64> {
+        *self.clock.lock().unwrap()
+    }
+
+    /// Gives `f` a look at a client's account. Transactions still sitting in the queue are not
+    /// reflected yet, and the client's shard doesn't apply anything while `f` runs.
+    pub fn with_account<R>(
+        &self,
+        client: ClientId,
+        f: impl FnOnce(Option<&AccountState>) -> R,
+    ) -> R {
+        match self.shard(client) {
+            Some(shard) => f(self.shards[shard].lock().unwrap().account(client)),
+            None => f(None),
+        }
+    }
+
+    /// Stops taking transactions, waits for the queued ones to be applied and stops the
+    /// workers, then hands back a copy of the resulting accounts. Producers that are waiting for
+    /// room in a queue get to hand over their transaction first, anything after that is handed
+    /// back to them, see [ParallelAccountSystem::transact].
+    ///
+    /// The accounts can still be looked at afterwards. Shutting down again returns the same
+    /// accounts.
+    pub fn shutdown(&self) -> ShardedAccountSystem {
+        self.stop();
+        let systems = self
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().clone())
+            .collect();
+        let mut system = ShardedAccountSystem::from_shards(systems);
+        system.set_deposit_index(self.deposits());
+        system
+    }
+
+    /// Whether [ParallelAccountSystem::shutdown] was called.
+    pub fn is_shut_down(&self) -> bool {
+        self.senders.read().unwrap().is_empty()
+    }
+
+    /// Closes the channels, which ends the workers once they've worked through their queues, and
+    /// waits for them.
+    fn stop(&self) {
+        drop(std::mem::take(&mut *self.senders.write().unwrap()));
+        for worker in std::mem::take(&mut *self.workers.lock().unwrap()) {
+            worker.join().expect("shard worker panicked");
+        }
+    }
+
+    /// Waits for every queued transaction to be applied, stops the workers and hands back the
+    /// resulting accounts.
+    pub fn finish(self) -> ShardedAccountSystem {
+        self.stop();
+        let systems = self
+            .shards
+            .into_iter()
+            .map(|shard| match Arc::try_unwrap(shard) {
+                Ok(shard) => shard.into_inner().unwrap(),
+                Err(_) => unreachable!("workers have stopped"),
+            })
+            .collect();
+        let deposits = self.deposits.map(|deposits| deposits.into_inner().unwrap());
+        let mut system = ShardedAccountSystem::from_parts(self.ring, systems);
+        system.set_deposit_index(deposits);
+        system
+    }
+
+    /// A copy of the deposit index, which the system handed back keeps routing with.
+    fn deposits(&self) -> Option<DepositIndex> {
+        self.deposits
+            .as_ref()
+            .map(|deposits| deposits.lock().unwrap().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deposits::DepositStore;
+    use rust_decimal::Decimal;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+
+    fn deposit(client: ClientId, tx: u32) -> Transaction {
+        Transaction::Deposit {
+            client,
+            tx,
+            amount: Decimal::from(10),
+            currency: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    /// Same input, same accounts as the serial system
+    fn matches_serial_system() {
+        let mut serial = ShardedAccountSystem::new(4);
+        let parallel = ParallelAccountSystem::new(ShardedAccountSystem::new(4));
+        for tx in 0..1000 {
+            serial.transact(deposit((tx % 50) as ClientId, tx));
+            parallel
+                .transact(deposit((tx % 50) as ClientId, tx))
+                .unwrap();
+            if tx % 7 == 0 {
+                let dispute = Transaction::Dispute {
+                    client: (tx % 50) as ClientId,
+                    tx,
+                    timestamp: None,
+                };
+                serial.transact(dispute.clone());
+                parallel.transact(dispute).unwrap();
+            }
+        }
+        let parallel = parallel.finish();
+        for client in 0..50 {
+            assert_eq!(serial.account(client), parallel.account(client));
+        }
+    }
+
+    #[test]
+    /// A batch spread over the shards comes back with its outcomes in order
+    fn transact_all_matches_serial() {
+        let transactions: Vec<Transaction> = (0..500)
+            .flat_map(|tx| {
+                let client = (tx % 20) as ClientId;
+                vec![
+                    deposit(client, tx),
+                    Transaction::Withdrawal {
+                        client,
+                        tx: tx + 1000,
+                        amount: Decimal::from(15),
+                        currency: None,
+                        timestamp: None,
+                    },
+                ]
+            })
+            .collect();
+        let options = BatchOptions { max_outcomes: 100 };
+        let mut serial = ShardedAccountSystem::new(4);
+        let expected = serial.transact_all_with(transactions.clone(), options);
+        let parallel = ParallelAccountSystem::with_queue_capacity(ShardedAccountSystem::new(4), 8);
+        let report = parallel.transact_all_with(transactions, options);
+        assert_eq!(report, expected);
+        assert!(report.ignored > 0);
+        assert!(parallel.finish() == serial);
+    }
+
+    #[test]
+    /// The same transactions make for the same digest in either engine and at any shard count,
+    /// and one transaction more for a different one
+    fn state_digest_matches_serial() {
+        let transactions: Vec<Transaction> = (0..2_000)
+            .map(|tx| match tx % 9 {
+                8 => Transaction::Dispute {
+                    client: (tx % 30) as ClientId,
+                    tx: tx - 8,
+                    timestamp: None,
+                },
+                _ => deposit((tx % 30) as ClientId, tx),
+            })
+            .collect();
+        let mut serial = ShardedAccountSystem::new(2);
+        serial.transact_all(transactions.clone());
+        let parallel = ParallelAccountSystem::with_queue_capacity(ShardedAccountSystem::new(4), 8);
+        parallel.transact_all(transactions);
+        let parallel = parallel.finish();
+        assert_eq!(serial.state_digest(), parallel.state_digest());
+        assert_ne!(
+            serial.state_digest(),
+            ShardedAccountSystem::new(2).state_digest()
+        );
+
+        serial.transact(deposit(0, 5_000));
+        assert_ne!(serial.state_digest(), parallel.state_digest());
+    }
+
+    #[test]
+    /// Counted on the workers' threads, the shards' metrics still add up to the batch, and
+    /// come back the same once the system is put back together
+    fn metrics_match_serial() {
+        let transactions: Vec<Transaction> = (0..1_000)
+            .map(|tx| match tx % 4 {
+                3 => Transaction::Withdrawal {
+                    client: (tx % 25) as ClientId,
+                    tx,
+                    amount: Decimal::from(35),
+                    currency: None,
+                    timestamp: None,
+                },
+                _ => deposit((tx % 25) as ClientId, tx),
+            })
+            .collect();
+        let mut serial = ShardedAccountSystem::new(4);
+        serial.transact_all(transactions.clone());
+        let parallel = ParallelAccountSystem::with_queue_capacity(ShardedAccountSystem::new(4), 8);
+        let report = parallel.transact_all(transactions);
+        let metrics = parallel.metrics();
+        let total: ShardMetrics = metrics.iter().sum();
+        assert_eq!(total.routed, 1_000);
+        assert_eq!(
+            (total.applied, total.ignored_total(), total.rejected),
+            (report.applied, report.ignored, report.rejected)
+        );
+        assert_eq!(total.queued, Some(0));
+        let unqueued: Vec<ShardMetrics> = metrics
+            .into_iter()
+            .map(|shard| ShardMetrics {
+                queued: None,
+                ..shard
+            })
+            .collect();
+        assert_eq!(unqueued, serial.metrics());
+        assert_eq!(parallel.finish().metrics(), serial.metrics());
+    }
+
+    #[test]
+    /// With a stalled shard the queue fills up to its capacity and then the producer waits
+    fn producer_blocks_on_full_queue() {
+        let capacity = 2;
+        let system =
+            ParallelAccountSystem::with_queue_capacity(ShardedAccountSystem::new(1), capacity);
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            system.with_account(0, |_| {
+                // The worker can't apply anything while we hold on to the shard, so exactly one
+                // transaction leaves the queue (the one the worker gets stuck with) and the rest
+                // stays there. Retrying a few times gives the worker a chance to pick that one up.
+                let mut accepted = 0;
+                for _ in 0..50 {
+                    match system.try_transact(deposit(0, accepted)) {
+                        Ok(()) => accepted += 1,
+                        Err(_) => thread::sleep(Duration::from_millis(5)),
+                    }
+                }
+                assert_eq!(accepted, capacity as u32 + 1);
+
+                scope.spawn(|| {
+                    system.transact(deposit(0, 100)).unwrap();
+                    done.store(true, Ordering::SeqCst);
+                });
+                thread::sleep(Duration::from_millis(100));
+                assert!(!done.load(Ordering::SeqCst));
+            });
+        });
+        assert!(done.load(Ordering::SeqCst));
+        let system = system.finish();
+        assert!(system.account(0).unwrap().deposits.contains_key(100));
+    }
+
+    #[test]
+    /// Shutting down waits for the queued transactions to be applied, and turns away the ones
+    /// that come after
+    fn shutdown_drains_queues() {
+        let system = ParallelAccountSystem::with_queue_capacity(ShardedAccountSystem::new(1), 64);
+        let accounts = thread::scope(|scope| {
+            // The worker can't apply anything while we hold on to the shard, so the
+            // transactions stay queued until shutting down has begun.
+            let shutdown = system.with_account(0, |_| {
+                for tx in 0..50 {
+                    system.transact(deposit((tx % 5) as ClientId, tx)).unwrap();
+                }
+                let shutdown = scope.spawn(|| system.shutdown());
+                thread::sleep(Duration::from_millis(50));
+                assert!(!shutdown.is_finished());
+                shutdown
+            });
+            shutdown.join().unwrap()
+        });
+        for client in 0..5 {
+            assert_eq!(accounts.account(client).unwrap().deposits.len(), 10);
+        }
+        assert!(system.is_shut_down());
+        assert_eq!(system.transact(deposit(0, 100)), Err(deposit(0, 100)));
+        let report = system.transact_all([deposit(0, 101)]);
+        assert_eq!(report.rejected, 1);
+        assert!(system.shutdown() == accounts);
+        assert!(system.finish() == accounts);
+    }
+
+    #[test]
+    /// With 90% of the rows for one client, its shard's queue fills up and holds up the
+    /// producer, and no queue ever holds more than the capacity, give or take the job
+    /// changing hands
+    fn hot_shard_queue_is_bounded() {
+        const CAPACITY: usize = 8;
+        const SHARDS: usize = 4;
+        let system =
+            ParallelAccountSystem::with_queue_capacity(ShardedAccountSystem::new(SHARDS), CAPACITY);
+        let hot = system.shard(0).unwrap();
+        thread::scope(|scope| {
+            let producer = system.with_account(0, |_| {
+                let producer = scope.spawn(|| {
+                    for tx in 0..2_000 {
+                        let client = if tx % 10 == 0 { 1 + tx % 50 } else { 0 };
+                        system.transact(deposit(client as ClientId, tx)).unwrap();
+                    }
+                });
+                // The worker of the hot shard is stuck on the one it picked up, the queue fills.
+                while system.queue_depths()[hot].queued < CAPACITY {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                thread::sleep(Duration::from_millis(20));
+                assert!(!producer.is_finished());
+                assert_eq!(system.queue_depths()[hot].queued, CAPACITY);
+                producer
+            });
+            producer.join().unwrap();
+        });
+        let depths = system.queue_depths();
+        assert_eq!(depths.len(), SHARDS);
+        assert!(
+            depths.iter().all(|depth| depth.peak <= CAPACITY + 1),
+            "{:?}",
+            depths
+        );
+        let peaks: usize = depths.iter().map(|depth| depth.peak).sum();
+        assert!(peaks <= (CAPACITY + 1) * SHARDS, "{:?}", depths);
+        assert!(depths[hot].peak >= CAPACITY);
+        let accounts = system.finish();
+        assert_eq!(accounts.account(0).unwrap().deposits.len(), 1_800);
+    }
+}
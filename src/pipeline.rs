@@ -0,0 +1,2531 @@
+//! Reading CSV input, running it through the engine and writing the reports, i.e. everything
+//! the command line tool does on top of the account logic.
+
+use crate::account::{AccountState, RejectReason, TransactionOutcome};
+use crate::aging::HeldReport;
+use crate::batch::BatchOptions;
+use crate::config::{
+    Command, Config, DiffFormat, MemoryAction, PendingAtEnd, PrecisionPolicy, ReportOrder,
+};
+use crate::currency::Currency;
+use crate::estimate::MemoryEstimate;
+use crate::fast_input;
+use crate::flush::ReportWriter;
+use crate::metrics::ShardMetrics;
+use crate::minor::{MinorAccount, MinorAccountSystem};
+use crate::ordering::{sort_by_time, ClientRuns, TxOrderCheck};
+use crate::parallel::ParallelAccountSystem;
+use crate::rates::Rates;
+use crate::read_ahead::read_ahead;
+use crate::reconcile::Reconciler;
+use crate::rejects::Rejects;
+use crate::replay::{EventLog, FileLog, Offset};
+use crate::report_diff::Report;
+use crate::reporter::Reporter;
+use crate::sink::Sink;
+use crate::source::{Source, SourceOptions};
+#[cfg(feature = "spill-deposits")]
+use crate::spill::SpillFile;
+use crate::summary::{IgnoredRecord, Phase, RunSummary, Timings};
+use crate::system::{write_account, write_partitioned, ReportOptions, ShardedAccountSystem};
+use crate::throttle::RateLimiter;
+use crate::transaction::{ClientId, RecordType, Transaction};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// One row of the input CSV. The field names are the column names and are part of the file
+/// format, so they won't change. `timestamp` and `effective` are optional columns.
+///
+/// Other services can use this to read and write files the tool understands:
+///
+/// ```
+/// use track::transaction::RecordType;
+/// use track::{Input, Output};
+/// use rust_decimal::Decimal;
+///
+/// let csv = "type,client,tx,amount\ndeposit,1,1,1.5\n";
+/// let mut reader = csv::Reader::from_reader(csv.as_bytes());
+/// let input: Input = reader.deserialize().next().unwrap().unwrap();
+/// assert_eq!(input.type_, RecordType::Deposit);
+/// assert_eq!(input.amount, Some(Decimal::new(15, 1)));
+/// assert_eq!(input.timestamp, None);
+///
+/// let mut writer = csv::Writer::from_writer(Vec::new());
+/// writer
+///     .serialize(Output {
+///         client: 1,
+///         available: Decimal::new(15, 1),
+///         held: Decimal::ZERO,
+///         total: Decimal::new(15, 1),
+///         locked: false,
+///     })
+///     .unwrap();
+/// let written = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+/// assert_eq!(written, "client,available,held,total,locked\n1,1.5,0.0,1.5,false\n");
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Input {
+    /// `deposit`, `withdrawal`, `dispute`, `resolve`, `chargeback`, `fee`, `interest` or
+    /// `close`.
+    #[serde(rename = "type")]
+    pub type_: RecordType,
+    pub client: ClientId,
+    pub tx: u32,
+    /// Required for deposits, withdrawals, fees and interest. For resolves and chargebacks
+    /// it's optional, and is what the client gets back or loses of the deposit respectively.
+    /// Empty otherwise. We keep four
+    /// decimal places, anything beyond that gets rounded.
+    // Since we want to manage a specific precision, we are going to use the decimal
+    // crate to ease our workload.
+    #[serde(deserialize_with = "trimmed_decimal::deserialize")]
+    pub amount: Option<Decimal>,
+    /// Optional, seconds since the Unix epoch.
+    pub timestamp: Option<u64>,
+    /// Optional, seconds since the Unix epoch. Holds the transaction back until then.
+    #[serde(default)]
+    pub effective: Option<u64>,
+    /// Optional three letter code like `USD`. Balances in different currencies are kept apart.
+    /// Without it, everything is in one implicit currency.
+    #[serde(default)]
+    pub currency: Option<Currency>,
+}
+
+/// Amounts as they are found in a CSV cell. Padding such as `" 100.00 "` is fine, whitespace
+/// within the number isn't, and a cell of nothing but whitespace is no amount at all.
+mod trimmed_decimal {
+    use rust_decimal::Decimal;
+    use serde::{de, Deserialize, Deserializer};
+    use std::str::FromStr;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Decimal>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| value.trim().to_owned())
+            .filter(|value| !value.is_empty())
+            .map(|value| Decimal::from_str(&value).map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+/// How many transactions [apply] hands to the engine at once.
+const BATCH_SIZE: usize = 1024;
+
+/// How many rows go by between checks against [Config::max_memory]. Estimating takes a look
+/// at every account, so not too often.
+const MEMORY_CHECK_ROWS: u64 = 64 * 1024;
+
+/// A transaction as read from the input, along with when it takes effect if that's later.
+struct Row {
+    /// Where the row starts in the input, see [csv::Position::line].
+    line: u64,
+    transaction: Transaction,
+    effective: Option<u64>,
+    /// The amount had more than four decimal places, see [Input::is_too_precise].
+    too_precise: bool,
+}
+
+impl AsRef<Transaction> for Row {
+    fn as_ref(&self) -> &Transaction {
+        &self.transaction
+    }
+}
+
+/// Columns every input file needs, followed by the ones it may have.
+const REQUIRED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+const OPTIONAL_COLUMNS: [&str; 3] = ["timestamp", "effective", "currency"];
+
+/// A mislabeled header makes every single row fail to deserialize with a rather confusing
+/// message, so we look at the header first and say exactly what's wrong with it.
+fn check_headers(headers: &csv::StringRecord) -> anyhow::Result<()> {
+    let missing: Vec<&str> = REQUIRED_COLUMNS
+        .iter()
+        .filter(|column| !headers.iter().any(|header| header == **column))
+        .copied()
+        .collect();
+    let unexpected: Vec<&str> = headers
+        .iter()
+        .filter(|header| !REQUIRED_COLUMNS.contains(header) && !OPTIONAL_COLUMNS.contains(header))
+        .collect();
+    if missing.is_empty() && unexpected.is_empty() {
+        return Ok(());
+    }
+    let mut problems = Vec::new();
+    if !missing.is_empty() {
+        problems.push(format!("missing columns: {}", missing.join(", ")));
+    }
+    if !unexpected.is_empty() {
+        problems.push(format!("unexpected columns: {}", unexpected.join(", ")));
+    }
+    anyhow::bail!(
+        "The input header doesn't match the expected {} ({})",
+        REQUIRED_COLUMNS.join(","),
+        problems.join("; ")
+    )
+}
+
+/// One row of the report. Like with [Input], the field names are the column names and won't
+/// change. Amounts are written as plain numbers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Output {
+    pub client: ClientId,
+    #[serde(with = "rust_decimal::serde::float")]
+    pub available: Decimal,
+    #[serde(with = "rust_decimal::serde::float")]
+    pub held: Decimal,
+    #[serde(with = "rust_decimal::serde::float")]
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// Same as [Output], with a few extra columns that are handy when investigating accounts.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExtendedOutput {
+    pub client: ClientId,
+    #[serde(with = "rust_decimal::serde::float")]
+    pub available: Decimal,
+    #[serde(with = "rust_decimal::serde::float")]
+    pub held: Decimal,
+    #[serde(with = "rust_decimal::serde::float")]
+    pub total: Decimal,
+    pub locked: bool,
+    #[serde(with = "rust_decimal::serde::float")]
+    pub optimistic_available: Decimal,
+    pub first_activity: Option<u64>,
+    pub last_activity: Option<u64>,
+    pub closed: bool,
+    pub closed_at: Option<u64>,
+    /// What was paid out when the account was closed. Empty for open accounts.
+    pub payout: Option<Decimal>,
+    /// More is held than there is in total, see [AccountState::deficit].
+    pub deficit: bool,
+}
+
+/// A scheduled transaction that wasn't due by the end of the input.
+#[derive(Serialize)]
+pub(crate) struct PendingOutput {
+    pub effective: u64,
+    #[serde(rename = "type")]
+    pub type_: &'static str,
+    pub client: ClientId,
+    pub tx: u32,
+    // There's no `float` helper for optional amounts, but with the `serde-float` feature that
+    // is what decimals serialize to anyway.
+    pub amount: Option<Decimal>,
+}
+
+/// The CLI can run either flavour of sharded system, this hides which one it is.
+enum Engine {
+    Serial(ShardedAccountSystem),
+    Parallel(ParallelAccountSystem),
+}
+
+impl Engine {
+    fn new(config: &Config, system: ShardedAccountSystem) -> Self {
+        if config.parallel {
+            Engine::Parallel(ParallelAccountSystem::with_queue_capacity(
+                system,
+                config.queue_capacity,
+            ))
+        } else {
+            Engine::Serial(system)
+        }
+    }
+
+    /// Applies the batch of transactions and the lines they were read from, which is left
+    /// empty. Every rejected transaction is counted and reported along with the reason and
+    /// line, which isn't too costly as rejections are rare. Ignored ones are counted by reason,
+    /// and the first `max_ignored` kept in the summary.
+    fn transact_all(
+        &mut self,
+        batch: &mut Vec<(u64, Transaction)>,
+        summary: &mut RunSummary,
+        rejects: &mut Rejects,
+        max_ignored: usize,
+    ) -> csv::Result<()> {
+        let ids: Vec<(u64, ClientId, u32, &'static str)> = batch
+            .iter()
+            .map(|(line, transaction)| {
+                (
+                    *line,
+                    *transaction.id(),
+                    transaction.tx(),
+                    transaction.kind(),
+                )
+            })
+            .collect();
+        let options = BatchOptions {
+            max_outcomes: batch.len(),
+        };
+        let report = match self {
+            Engine::Serial(system) => {
+                system.transact_all_with(batch.drain(..).map(|(_, t)| t), options)
+            }
+            Engine::Parallel(system) => {
+                system.transact_all_with(batch.drain(..).map(|(_, t)| t), options)
+            }
+        };
+        summary.count(&report);
+        for ((line, client, tx, kind), outcome) in ids.into_iter().zip(report.outcomes) {
+            match outcome {
+                TransactionOutcome::Applied => {}
+                TransactionOutcome::Ignored(reason) => {
+                    let record = IgnoredRecord {
+                        client,
+                        tx,
+                        kind,
+                        reason,
+                    };
+                    rejects.ignore(&record);
+                    summary.ignore(record, max_ignored);
+                }
+                TransactionOutcome::Rejected(reason) => rejects.reject(line, client, tx, reason)?,
+            }
+        }
+        self.spill_error()
+    }
+
+    /// Fails with the first error reading or writing the spill file since the last check. A
+    /// deposit that couldn't be read back was treated as unknown.
+    #[cfg(feature = "spill-deposits")]
+    fn spill_error(&self) -> csv::Result<()> {
+        let error = match self {
+            Engine::Serial(system) => system.take_spill_error(),
+            Engine::Parallel(system) => system.take_spill_error(),
+        };
+        error.map_or(Ok(()), |error| Err(error.into()))
+    }
+
+    /// There is no spill file without the feature, see [spill_file].
+    #[cfg(not(feature = "spill-deposits"))]
+    fn spill_error(&self) -> csv::Result<()> {
+        Ok(())
+    }
+
+    fn schedule(&mut self, effective: u64, transaction: Transaction) {
+        match self {
+            Engine::Serial(system) => system.schedule(effective, transaction),
+            Engine::Parallel(system) => system
+                .schedule(effective, transaction)
+                .expect("the engine isn't shut down before it's finished"),
+        }
+    }
+
+    /// Takes a client's account out of the system. Only the serial engine applies transactions
+    /// as they come, which is why [Config::sorted_by_client] rules out the parallel one.
+    fn remove(&mut self, client: ClientId) -> Option<AccountState> {
+        match self {
+            Engine::Serial(system) => system.remove(client),
+            Engine::Parallel(_) => unreachable!("sorted input is processed serially"),
+        }
+    }
+
+    fn memory_footprint(&self) -> usize {
+        match self {
+            Engine::Serial(system) => system.memory_footprint(),
+            Engine::Parallel(system) => system.memory_footprint(),
+        }
+    }
+
+    /// Forgets accounts that hold nothing and weren't active at the latest timestamp.
+    fn compact(&mut self) -> usize {
+        match self {
+            Engine::Serial(system) => system.clock().map_or(0, |now| system.compact(now)),
+            Engine::Parallel(system) => system.clock().map_or(0, |now| system.compact(now)),
+        }
+    }
+
+    /// Moves the deposits that aren't disputed to the spill file.
+    #[cfg(feature = "spill-deposits")]
+    fn spill(&mut self) -> usize {
+        match self {
+            Engine::Serial(system) => system.spill_deposits(),
+            Engine::Parallel(system) => system.spill_deposits(),
+        }
+    }
+
+    /// There is no spill file without the feature, see [spill_file].
+    #[cfg(not(feature = "spill-deposits"))]
+    fn spill(&mut self) -> usize {
+        0
+    }
+
+    /// Checks the estimated memory against [Config::max_memory], doing what
+    /// [Config::on_max_memory] says once it's over.
+    fn check_memory(&mut self, config: &Config, summary: &mut RunSummary) -> anyhow::Result<()> {
+        let Some(max) = config.max_memory else {
+            return Ok(());
+        };
+        let mut footprint = self.memory_footprint();
+        if footprint > max && config.on_max_memory == MemoryAction::Compact {
+            summary.compacted += self.compact() as u64;
+            footprint = self.memory_footprint();
+        }
+        if footprint > max && config.on_max_memory == MemoryAction::Spill {
+            summary.spilled += self.spill() as u64;
+            self.spill_error()?;
+            footprint = self.memory_footprint();
+        }
+        if footprint > max {
+            anyhow::bail!(
+                "The accounts are estimated to take {} bytes after {} rows, more than the {} \
+                 allowed by --max-memory",
+                footprint,
+                summary.rows,
+                max
+            );
+        }
+        Ok(())
+    }
+
+    /// How full the shards' queues got, for the parallel engine.
+    fn queue_peaks(&self) -> Option<Vec<usize>> {
+        match self {
+            Engine::Serial(_) => None,
+            Engine::Parallel(system) => Some(
+                system
+                    .queue_depths()
+                    .iter()
+                    .map(|depth| depth.peak)
+                    .collect(),
+            ),
+        }
+    }
+
+    fn metrics(&self) -> Vec<ShardMetrics> {
+        match self {
+            Engine::Serial(system) => system.metrics(),
+            Engine::Parallel(system) => system.metrics(),
+        }
+    }
+
+    /// How long the shards spent applying transactions, for the parallel engine.
+    fn busy_times(&self) -> Option<Vec<Duration>> {
+        match self {
+            Engine::Serial(_) => None,
+            Engine::Parallel(system) => Some(system.busy_times()),
+        }
+    }
+
+    /// Waits for outstanding work and returns the accounts.
+    fn finish(self) -> ShardedAccountSystem {
+        match self {
+            Engine::Serial(system) => system,
+            Engine::Parallel(system) => system.finish(),
+        }
+    }
+}
+
+/// Opens the input, decompressing and fetching it as the [Config] says.
+fn open_input(config: &Config, path: &str) -> anyhow::Result<Source> {
+    let options = SourceOptions {
+        gzip: config.gzip,
+        headers: config.headers.clone(),
+    };
+    Source::open(path, &options)
+}
+
+/// Reads the transactions file named in the [Config], applies every transaction in order
+/// and writes the resulting account states to stdout, or the output file if there is one.
+/// When replaying, the transactions come from the event log instead.
+pub fn run(config: &Config) -> anyhow::Result<RunSummary> {
+    let output = || match config.output.as_deref() {
+        Some(path) => Sink::create(path, config.compress),
+        None => Ok(Sink::stdout()),
+    };
+    let summary = match config.command {
+        Command::Process => {
+            let input = open_input(config, &config.input)?;
+            let mut output = output()?;
+            let summary = process(config, input, &mut output)?;
+            output.finish()?;
+            summary
+        }
+        Command::Replay => {
+            let mut output = output()?;
+            let summary = replay(config, &mut output)?;
+            output.finish()?;
+            summary
+        }
+        Command::HeldReport => {
+            let input = open_input(config, &config.input)?;
+            let mut output = output()?;
+            let summary = held_report(config, input, &mut output)?;
+            output.finish()?;
+            summary
+        }
+        Command::Estimate => {
+            let input = open_input(config, &config.input)?;
+            let mut output = output()?;
+            let summary = estimate(config, input, &mut output)?;
+            output.finish()?;
+            summary
+        }
+        Command::Tune => {
+            let mut output = output()?;
+            let summary = match config.input.as_str() {
+                "" => tune(config, None::<Source>, &mut output)?,
+                input => tune(config, Some(open_input(config, input)?), &mut output)?,
+            };
+            output.finish()?;
+            summary
+        }
+        Command::Diff => {
+            let old = open_input(config, &config.input)?;
+            let new = open_input(config, &config.diff_against)?;
+            let mut output = output()?;
+            let summary = diff(config, old, new, &mut output)?;
+            output.finish()?;
+            summary
+        }
+        Command::PrintDefaultConfig => {
+            print!("{}", crate::config_file::default_file());
+            return Ok(RunSummary::default());
+        }
+    };
+    log::info!(
+        "run finished: {} rows, {} applied, {} ignored, {} rejected",
+        summary.rows,
+        summary.applied,
+        summary.ignored,
+        summary.rejected
+    );
+    Ok(summary)
+}
+
+/// Rebuilds the accounts from the event log named in the [Config] and writes the report.
+pub fn replay<W: Write>(config: &Config, writer: W) -> anyhow::Result<RunSummary> {
+    let path = config
+        .event_log
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("replay needs an event log"))?;
+    let mut log = FileLog::open(path)?;
+    let mut system = ShardedAccountSystem::new(config.shards);
+    system.set_policy(config.policy);
+    system.set_deposit_capacity(config.deposit_capacity);
+    if config.ordered_accounts {
+        system.enable_ordered_accounts();
+    }
+    let end = system.replay_log(&mut log, Offset(config.replay_from))?;
+    let rates = config.rates.as_deref().map(Rates::open).transpose()?;
+    let options = ReportOptions {
+        currencies: system
+            .accounts()
+            .any(|(_, account)| !account.currencies.is_empty()),
+        rates: rates.as_ref(),
+        ..config.report_options()
+    };
+    options.check_rates(system.accounts().map(|(_, account)| account))?;
+    let mut wtr = ReportWriter::new(writer, config.flush);
+    write_report(config, &system, &mut wtr, options)?;
+    Ok(RunSummary {
+        rows: end.0 - config.replay_from.min(end.0),
+        ..RunSummary::default()
+    })
+}
+
+/// Does the actual work for [run], reading CSV from any reader and writing the report to any
+/// writer, which is mostly useful for testing.
+pub fn process<R: Read + Send, W: Write>(
+    config: &Config,
+    reader: R,
+    mut writer: W,
+) -> anyhow::Result<RunSummary> {
+    if config.integer_amounts {
+        return process_minor(config, reader, writer);
+    }
+    let mut rdr = csv_reader(config, reader);
+    let rates = config.rates.as_deref().map(Rates::open).transpose()?;
+    let options = ReportOptions {
+        currencies: rdr.headers()?.iter().any(|header| header == "currency"),
+        rates: rates.as_ref(),
+        ..config.report_options()
+    };
+    let mut reconciler = match config.validate_balances.as_deref() {
+        Some(path) => Some(Reconciler::new(BufReader::new(File::open(path)?), options)?),
+        None => None,
+    };
+    let mut wtr = ReportWriter::new(&mut writer, config.flush);
+    // With client-sorted input, accounts are written as they complete. Whatever is still in
+    // the system at the end gets written after that.
+    let mut finalize = |client, account: AccountState| -> anyhow::Result<()> {
+        if let Some(reconciler) = reconciler.as_mut() {
+            reconciler.check(client, &account);
+        }
+        options.check_rates([&account])?;
+        write_account(&mut wtr, client, &account, options)?;
+        Ok(())
+    };
+    let (system, mut summary) = apply(config, rdr, Some(&mut finalize))?;
+    options.check_rates(system.accounts().map(|(_, account)| account))?;
+    Timings::time(&mut summary.timings, Phase::Writing, || {
+        write_report(config, &system, &mut wtr, options)
+    })?;
+    if let Some(mut reconciler) = reconciler {
+        for (client, account) in system.accounts() {
+            reconciler.check(client, account);
+        }
+        summary.balance_mismatches = Some(reconciler.finish());
+    }
+    drop(wtr);
+    Timings::time(&mut summary.timings, Phase::Writing, || {
+        write_pending(&system, &mut writer)
+    })?;
+    Ok(summary)
+}
+
+/// [process] with [Config::integer_amounts]: the same report for the plain input it takes,
+/// using [MinorAccountSystem] rather than the engine. Anything beyond deposits, withdrawals,
+/// disputes, resolves and full chargebacks in the implicit currency stops the run, and so does
+/// a balance beyond the range of [crate::minor::Minor]. There is no falling back to decimals
+/// half way through.
+fn process_minor<R: Read + Send, W: Write>(
+    config: &Config,
+    reader: R,
+    writer: W,
+) -> anyhow::Result<RunSummary> {
+    let mut rdr = csv_reader(config, reader);
+    check_headers(rdr.headers()?)?;
+    let rejects_file = config
+        .rejects
+        .as_deref()
+        .map(|path| Sink::create(path, config.compress))
+        .transpose()?;
+    let mut rejects = Rejects::new(rejects_file, Reporter::new(config.verbosity));
+    let mut limiter = config.max_rate.map(RateLimiter::new);
+    let mut system = MinorAccountSystem::new();
+    let mut summary = RunSummary::default();
+    for record in inputs(config, rdr)? {
+        if let Some(limiter) = limiter.as_mut() {
+            limiter.wait();
+        }
+        let (line, record) = record?;
+        if record.effective.is_some() {
+            anyhow::bail!("Scheduled transactions can't be processed with --integer-amounts");
+        }
+        let too_precise = record.is_too_precise();
+        let transaction = record
+            .into_transaction(config.rounding_strategy())
+            .map_err(|error| anyhow::anyhow!("line {}: {}", line, error))?;
+        if config.until.reached(summary.rows + 1, &transaction) {
+            break;
+        }
+        summary.rows += 1;
+        if too_precise {
+            if config.precision == PrecisionPolicy::Reject {
+                summary.rejected += 1;
+                summary.too_precise += 1;
+                let reason = "amount has more than four decimal places";
+                rejects.reject(line, *transaction.id(), transaction.tx(), reason)?;
+                continue;
+            }
+            summary.rounded += 1;
+        }
+        if let Some(max) = config
+            .max_amount
+            .filter(|max| transaction.amount().is_some_and(|amount| amount > *max))
+        {
+            summary.rejected += 1;
+            let reason = format!("amount exceeds the maximum of {}", max);
+            rejects.reject(line, *transaction.id(), transaction.tx(), reason)?;
+            continue;
+        }
+        if !MinorAccount::supports(&transaction) {
+            anyhow::bail!(
+                "line {}: {} of client {}, tx {} can't be processed with --integer-amounts",
+                line,
+                transaction.kind(),
+                transaction.id(),
+                transaction.tx()
+            );
+        }
+        match system.transact(&transaction) {
+            TransactionOutcome::Applied => summary.applied += 1,
+            TransactionOutcome::Ignored(reason) => {
+                summary.ignored += 1;
+                let record = IgnoredRecord {
+                    client: *transaction.id(),
+                    tx: transaction.tx(),
+                    kind: transaction.kind(),
+                    reason,
+                };
+                rejects.ignore(&record);
+                summary.ignore(record, config.max_ignored);
+            }
+            // Decimals wouldn't have overflowed, the report would differ.
+            TransactionOutcome::Rejected(RejectReason::Overflow) => {
+                anyhow::bail!(
+                    "line {}: {} of client {}, tx {} takes the balance beyond what \
+                     --integer-amounts can hold",
+                    line,
+                    transaction.kind(),
+                    transaction.id(),
+                    transaction.tx()
+                );
+            }
+            TransactionOutcome::Rejected(reason) => {
+                summary.rejected += 1;
+                rejects.reject(line, *transaction.id(), transaction.tx(), reason)?;
+            }
+        }
+    }
+    rejects.finish()?;
+    let mut wtr = ReportWriter::new(writer, config.flush);
+    system.write(&mut wtr)?;
+    wtr.flush()?;
+    summary.memory_footprint = system.memory_footprint();
+    Ok(summary)
+}
+
+/// Writes the report to `wtr`, or to a file per shard if [Config::output_per_shard] asks for
+/// that, and flushes it. [Config::locked_first] and [Config::locked_output] put the locked
+/// accounts first or elsewhere.
+fn write_report<W: Write>(
+    config: &Config,
+    system: &ShardedAccountSystem,
+    wtr: &mut ReportWriter<W>,
+    options: ReportOptions,
+) -> anyhow::Result<()> {
+    if config.locked_first || config.locked_output.is_some() {
+        let accounts: Box<dyn Iterator<Item = (ClientId, &AccountState)>> = match config.sort {
+            ReportOrder::Shards => Box::new(system.accounts()),
+            ReportOrder::Client => Box::new(system.sorted_accounts()),
+            ReportOrder::Total => Box::new(system.accounts_by_total().into_iter()),
+        };
+        let mut locked = match config.locked_output.as_deref() {
+            Some(path) => Some(ReportWriter::new(
+                Sink::create(path, config.compress)?,
+                config.flush,
+            )),
+            None => None,
+        };
+        write_partitioned(accounts, wtr, locked.as_mut(), options)?;
+        wtr.flush()?;
+        if let Some(locked) = locked {
+            locked.into_inner()?.finish()?;
+        }
+        return Ok(());
+    }
+    let Some(template) = config.output_per_shard.as_deref() else {
+        match config.sort {
+            ReportOrder::Shards => system.write(wtr, options)?,
+            ReportOrder::Client => system.write_sorted(wtr, options)?,
+            ReportOrder::Total => system.write_by_total(wtr, options)?,
+        }
+        wtr.flush()?;
+        return Ok(());
+    };
+    let mut writers = (0..system.shard_count())
+        .map(|shard| {
+            let path = template.replace("{shard}", &shard.to_string());
+            let sink = Sink::create(&path, config.compress)?;
+            Ok(ReportWriter::new(sink, config.flush))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    system.write_shards(&mut writers, options)?;
+    for writer in writers {
+        writer.into_inner()?.finish()?;
+    }
+    Ok(())
+}
+
+/// Scheduled transactions that didn't become due, as a second CSV table following an empty
+/// line. Nothing at all is written if there are none.
+fn write_pending<W: Write>(system: &ShardedAccountSystem, mut writer: W) -> anyhow::Result<()> {
+    let pending = system.pending();
+    if pending.is_empty() {
+        return Ok(());
+    }
+    writer.write_all(b"\n")?;
+    let mut wtr = csv::Writer::from_writer(writer);
+    for (effective, transaction) in pending {
+        wtr.serialize(PendingOutput {
+            effective,
+            type_: transaction.kind(),
+            client: *transaction.id(),
+            tx: transaction.tx(),
+            amount: transaction.amount(),
+        })?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Applies the transactions like [process] does, but reports on the funds held by open
+/// disputes instead of on the accounts, see [HeldReport].
+pub fn held_report<R: Read + Send, W: Write>(
+    config: &Config,
+    reader: R,
+    writer: W,
+) -> anyhow::Result<RunSummary> {
+    let (system, mut summary) = apply(config, csv_reader(config, reader), None)?;
+    let as_of = match config.as_of {
+        Some(as_of) => as_of,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    Timings::time(&mut summary.timings, Phase::Writing, || {
+        HeldReport::new(&system, as_of).write(writer)
+    })?;
+    Ok(summary)
+}
+
+/// Reads the transactions without applying them and writes a [MemoryEstimate] of what
+/// processing them would take. Rows that can't be read stop it the way they'd stop processing,
+/// and [Config::until] and [Config::only_client] are respected.
+pub fn estimate<R: Read + Send, W: Write>(
+    config: &Config,
+    reader: R,
+    mut writer: W,
+) -> anyhow::Result<RunSummary> {
+    let mut rdr = csv_reader(config, reader);
+    check_headers(rdr.headers()?)?;
+    let mut estimate = MemoryEstimate::new();
+    let mut summary = RunSummary::default();
+    for record in inputs(config, rdr)? {
+        let (line, record) = record?;
+        let transaction = record
+            .into_transaction(config.rounding_strategy())
+            .map_err(|error| anyhow::anyhow!("line {}: {}", line, error))?;
+        if config.until.reached(summary.rows + 1, &transaction) {
+            break;
+        }
+        summary.rows += 1;
+        estimate.count(&transaction, &config.policy);
+    }
+    writeln!(writer, "{}", estimate)?;
+    summary.memory_footprint = estimate.total_bytes();
+    Ok(summary)
+}
+
+/// Compares two account reports, CSV or JSON, and writes how they differ, see
+/// [Report::diff]. The differences are counted in [RunSummary::differences].
+pub fn diff<R: Read, S: Read, W: Write>(
+    config: &Config,
+    mut old: R,
+    mut new: S,
+    mut writer: W,
+) -> anyhow::Result<RunSummary> {
+    let read = |reader: &mut dyn Read, path: &str| {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        Report::parse(&text).map_err(|error| anyhow::anyhow!("{}: {}", path, error))
+    };
+    let old = read(&mut old, &config.input)?;
+    let new = read(&mut new, &config.diff_against)?;
+    let differences = old.diff(&new);
+    for difference in differences.iter() {
+        match config.diff_format {
+            DiffFormat::Text => writeln!(writer, "{}", difference)?,
+            DiffFormat::Json => {
+                serde_json::to_writer(&mut writer, difference)?;
+                writeln!(writer)?;
+            }
+        }
+    }
+    Ok(RunSummary {
+        rows: (old.len() + new.len()) as u64,
+        differences: Some(differences.len() as u64),
+        ..RunSummary::default()
+    })
+}
+
+/// Runs the first [Config::sample] transactions of the input at growing shard counts and
+/// writes how long each took, see [crate::tune::tune]. Without an input, the transactions are
+/// made up.
+pub fn tune<R: Read + Send, W: Write>(
+    config: &Config,
+    reader: Option<R>,
+    mut writer: W,
+) -> anyhow::Result<RunSummary> {
+    let transactions = match reader {
+        Some(reader) => {
+            let mut rdr = csv_reader(config, reader);
+            check_headers(rdr.headers()?)?;
+            let mut transactions = Vec::new();
+            for record in inputs(config, rdr)?.take(config.sample) {
+                let (line, record) = record?;
+                let transaction = record
+                    .into_transaction(config.rounding_strategy())
+                    .map_err(|error| anyhow::anyhow!("line {}: {}", line, error))?;
+                transactions.push(transaction);
+            }
+            transactions
+        }
+        None => crate::tune::synthetic(config.sample, 10_000, 1),
+    };
+    let tuning = crate::tune::tune(
+        &transactions,
+        config.max_shards,
+        config.policy,
+        config.queue_capacity,
+    )?;
+    writeln!(writer, "{}", tuning)?;
+    Ok(RunSummary {
+        rows: transactions.len() as u64,
+        ..RunSummary::default()
+    })
+}
+
+/// A CSV reader for the input, skipping the lines that start with [Config::comment].
+fn csv_reader<R: Read>(config: &Config, reader: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new()
+        .comment(config.comment)
+        .from_reader(reader)
+}
+
+/// Rows of the input, each with the line it starts on.
+type Inputs<'a> = Box<dyn Iterator<Item = anyhow::Result<(u64, Input)>> + Send + 'a>;
+
+/// The rows of the input, with the lines they start on. Files with the standard header are
+/// read by [fast_input::inputs] unless [Config::fast_parse] is off, others through serde.
+fn inputs<'a, R: Read + Send + 'a>(
+    config: &'a Config,
+    mut rdr: csv::Reader<R>,
+) -> anyhow::Result<Inputs<'a>> {
+    let headers = rdr.headers()?.clone();
+    // Filtering by client happens before deserializing, which the fast path does anyway.
+    if config.fast_parse && config.only_client.is_none() && fast_input::is_standard(&headers) {
+        let inputs = fast_input::inputs(rdr)?;
+        return Ok(Box::new(inputs.map(|result| Ok(result?))));
+    }
+    // There is one, check_headers made sure of that.
+    let client_column = headers
+        .iter()
+        .position(|header| header == "client")
+        .unwrap();
+    // Rows of other clients are skipped before they're deserialized, which is where the time
+    // goes.
+    let inputs = rdr
+        .into_records()
+        .filter(move |record| match (config.only_client, record) {
+            (Some(client), Ok(record)) => record[client_column].trim().parse() == Ok(client),
+            _ => true,
+        })
+        .map(move |result| {
+            let record = result?;
+            let line = record.position().map_or(0, csv::Position::line);
+            Ok((line, record.deserialize(Some(&headers))?))
+        });
+    Ok(Box::new(inputs))
+}
+
+/// Reads the transactions and applies them, along with everything the [Config] asks for on
+/// the side. Writing a report is up to the caller.
+///
+/// With [Config::sorted_by_client], accounts that can't change anymore are taken out of the
+/// system and handed to `finalize` right away, if given. Without `finalize` the input is only
+/// checked for being sorted.
+fn apply<R: Read + Send>(
+    config: &Config,
+    mut rdr: csv::Reader<R>,
+    finalize: Option<&mut dyn FnMut(ClientId, AccountState) -> anyhow::Result<()>>,
+) -> anyhow::Result<(ShardedAccountSystem, RunSummary)> {
+    check_headers(rdr.headers()?)?;
+    let rows = inputs(config, rdr)?.map(|result| {
+        // Errors of csv say where they happened, those of the conversion need telling.
+        let (line, record) = result?;
+        let effective = record.effective;
+        let too_precise = record.is_too_precise();
+        let transaction = record
+            .into_transaction(config.rounding_strategy())
+            .map_err(|error| anyhow::anyhow!("line {}: {}", line, error))?;
+        Ok(Row {
+            line,
+            transaction,
+            effective,
+            too_precise,
+        })
+    });
+    if config.read_ahead == 0 {
+        return transact_rows(config, Box::new(rows), finalize);
+    }
+    std::thread::scope(|scope| {
+        let rows = read_ahead(scope, rows, config.read_ahead);
+        transact_rows(config, Box::new(rows), finalize)
+    })
+}
+
+/// The part of [apply] that runs on the engine's thread, taking the rows from wherever they
+/// were read.
+fn transact_rows(
+    config: &Config,
+    rows: Box<dyn Iterator<Item = anyhow::Result<Row>> + '_>,
+    mut finalize: Option<&mut dyn FnMut(ClientId, AccountState) -> anyhow::Result<()>>,
+) -> anyhow::Result<(ShardedAccountSystem, RunSummary)> {
+    let reporter = Reporter::new(config.verbosity);
+    #[cfg(feature = "spill-deposits")]
+    let spill = spill_file(config)?;
+    #[cfg(not(feature = "spill-deposits"))]
+    spill_file(config)?;
+    let webhook = webhook(config, &reporter)?;
+    let mut system = match &webhook {
+        Some(notifier) => ShardedAccountSystem::with_observer(config.shards, notifier.clone()),
+        None => ShardedAccountSystem::new(config.shards),
+    };
+    system.set_policy(config.policy);
+    system.set_deposit_capacity(config.deposit_capacity);
+    if config.ordered_accounts {
+        system.enable_ordered_accounts();
+    }
+    if config.latency {
+        system.enable_latency();
+    }
+    if config.cross_account_disputes {
+        system.enable_deposit_index();
+    }
+    // Accounts that are created empty only to be compacted away again aren't worth it
+    if config.on_max_memory == MemoryAction::Compact {
+        system.skip_unknown_references();
+    }
+    #[cfg(feature = "spill-deposits")]
+    if let Some(file) = spill {
+        system.set_spill_file(file);
+    }
+    let mut system = Engine::new(config, system);
+    let rejects_file = config
+        .rejects
+        .as_deref()
+        .map(|path| Sink::create(path, config.compress))
+        .transpose()?;
+    let mut rejects = Rejects::new(rejects_file, reporter);
+    let mut tx_order = config.check_tx_order.map(TxOrderCheck::new);
+    let mut event_log = config.event_log.as_deref().map(FileLog::open).transpose()?;
+    let mut client_runs = config.sorted_by_client.then(ClientRuns::new);
+    let mut summary = RunSummary {
+        sort_violations: client_runs.as_ref().map(|_| 0),
+        ..RunSummary::default()
+    };
+    let mut timings = config.timings.then(Timings::default);
+
+    // Sorting needs to see every row before the first one can be applied, so in that case we
+    // buffer the whole file. Otherwise we stream.
+    let mut rows: Box<dyn Iterator<Item = anyhow::Result<Row>> + '_> = if config.sort_by_time {
+        let sorted = Timings::time(&mut timings, Phase::Parsing, || {
+            rows.collect::<anyhow::Result<Vec<_>>>().map(sort_by_time)
+        })?;
+        Box::new(sorted.into_iter().map(Ok))
+    } else {
+        rows
+    };
+
+    // Transactions are applied in batches. Whatever needs to happen in between two transactions,
+    // like finalizing an account or scheduling a transaction, applies the batch so far first.
+    // Throttled input is handed over as it comes, the engine should feel the pace.
+    let mut limiter = config.max_rate.map(RateLimiter::new);
+    let batch_size = if limiter.is_some() { 1 } else { BATCH_SIZE };
+    let mut batch = Vec::with_capacity(batch_size);
+    while let Some(row) = Timings::time(&mut timings, Phase::Parsing, || rows.next()) {
+        if let Some(limiter) = limiter.as_mut() {
+            limiter.wait();
+        }
+        let Row {
+            line,
+            transaction,
+            effective,
+            too_precise,
+        } = row?;
+        if config.until.reached(summary.rows + 1, &transaction) {
+            break;
+        }
+        summary.rows += 1;
+        if config.max_memory.is_some() && summary.rows.is_multiple_of(MEMORY_CHECK_ROWS) {
+            Timings::time(&mut timings, Phase::Transacting, || {
+                system.transact_all(&mut batch, &mut summary, &mut rejects, config.max_ignored)?;
+                system.check_memory(config, &mut summary)
+            })?;
+        }
+        let finished = match client_runs
+            .as_mut()
+            .map(|runs| runs.next(*transaction.id()))
+        {
+            Some(Ok(finished)) => finished,
+            // The account is gone already, and going on with a fresh one would put a second
+            // row for the client in the report.
+            Some(Err(violation)) => {
+                summary.rejected += 1;
+                *summary.sort_violations.get_or_insert(0) += 1;
+                rejects.reject(line, violation.client, transaction.tx(), violation)?;
+                continue;
+            }
+            None => None,
+        };
+        if let (Some(client), Some(finalize)) = (finished, finalize.as_mut()) {
+            Timings::time(&mut timings, Phase::Transacting, || {
+                system.transact_all(&mut batch, &mut summary, &mut rejects, config.max_ignored)
+            })?;
+            if let Some(account) = system.remove(client) {
+                Timings::time(&mut timings, Phase::Writing, || finalize(client, account))?;
+            }
+        }
+        if too_precise {
+            if config.precision == PrecisionPolicy::Reject {
+                summary.rejected += 1;
+                summary.too_precise += 1;
+                let reason = "amount has more than four decimal places";
+                rejects.reject(line, *transaction.id(), transaction.tx(), reason)?;
+                continue;
+            }
+            summary.rounded += 1;
+        }
+        if let Some(max) = config
+            .max_amount
+            .filter(|max| transaction.amount().is_some_and(|amount| amount > *max))
+        {
+            summary.rejected += 1;
+            let reason = format!("amount exceeds the maximum of {}", max);
+            rejects.reject(line, *transaction.id(), transaction.tx(), reason)?;
+            continue;
+        }
+        if let Some(anomaly) = tx_order
+            .as_mut()
+            .and_then(|check| check.check(&transaction))
+        {
+            // Anomalies are only reported, the transaction still goes through.
+            summary.tx_order_anomalies += 1;
+            rejects.warn(line, anomaly.client, anomaly.tx, &anomaly)?;
+        }
+        match effective {
+            // A finalized account must not change anymore, and the event log has no notion of
+            // scheduling, so replaying it would apply the transaction right away.
+            Some(_) if client_runs.is_some() => {
+                anyhow::bail!("Scheduled transactions can't be combined with --sorted-by-client")
+            }
+            Some(_) if event_log.is_some() => {
+                anyhow::bail!("Scheduled transactions can't be recorded in an event log")
+            }
+            Some(effective) => Timings::time(&mut timings, Phase::Transacting, || {
+                system.transact_all(&mut batch, &mut summary, &mut rejects, config.max_ignored)?;
+                system.schedule(effective, transaction);
+                csv::Result::Ok(())
+            })?,
+            None => {
+                if let Some(log) = event_log.as_mut() {
+                    log.append(&transaction)?;
+                }
+                batch.push((line, transaction));
+                if batch.len() == batch_size {
+                    Timings::time(&mut timings, Phase::Transacting, || {
+                        system.transact_all(
+                            &mut batch,
+                            &mut summary,
+                            &mut rejects,
+                            config.max_ignored,
+                        )
+                    })?;
+                }
+            }
+        }
+    }
+    let mut shard_busy = None;
+    let system = Timings::time(&mut timings, Phase::Transacting, || {
+        system.transact_all(&mut batch, &mut summary, &mut rejects, config.max_ignored)?;
+        system.check_memory(config, &mut summary)?;
+        summary.queue_peaks = system.queue_peaks();
+        shard_busy = system.busy_times();
+        summary.shards = system.metrics();
+        let mut system = system.finish();
+        if config.pending == PendingAtEnd::Apply {
+            system.apply_pending();
+        }
+        anyhow::Ok(system)
+    })?;
+    rejects.finish()?;
+    if let Some(log) = event_log.as_mut() {
+        log.flush()?;
+    }
+    #[cfg(feature = "notify")]
+    if let Some(notifier) = webhook {
+        summary.webhook_failures = Some(notifier.finish().failed);
+    }
+    summary.timings = timings.map(|timings| Timings {
+        rows: summary.rows,
+        shard_busy,
+        ..timings
+    });
+    summary.memory_footprint = system.memory_footprint();
+    Ok((system, summary))
+}
+
+/// Creates the spill file, see [crate::spill::SpillFile].
+#[cfg(feature = "spill-deposits")]
+fn spill_file(config: &Config) -> anyhow::Result<Option<std::sync::Arc<SpillFile>>> {
+    let Some(path) = config.spill_file.as_deref() else {
+        return Ok(None);
+    };
+    let file = SpillFile::create(path, config.spill_threshold)
+        .map_err(|error| anyhow::anyhow!("{}: {}", path, error))?;
+    Ok(Some(file))
+}
+
+/// Without the `spill-deposits` feature deposits stay in memory, so asking for a spill file is
+/// an error.
+#[cfg(not(feature = "spill-deposits"))]
+fn spill_file(config: &Config) -> anyhow::Result<()> {
+    match config.spill_file {
+        Some(_) => {
+            anyhow::bail!("--spill-file needs track to be built with the spill-deposits feature")
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(feature = "notify")]
+fn webhook(
+    config: &Config,
+    reporter: &Reporter,
+) -> anyhow::Result<Option<std::sync::Arc<crate::notify::WebhookNotifier>>> {
+    use crate::notify::{WebhookNotifier, WebhookOptions};
+    Ok(config.webhook_url.as_deref().map(|url| {
+        let options = WebhookOptions::default();
+        std::sync::Arc::new(WebhookNotifier::with_reporter(
+            url,
+            options,
+            reporter.clone(),
+        ))
+    }))
+}
+
+/// Without the `notify` feature there is nothing to send webhooks with, so asking for one is
+/// an error rather than something to silently ignore.
+#[cfg(not(feature = "notify"))]
+fn webhook(
+    config: &Config,
+    _reporter: &Reporter,
+) -> anyhow::Result<Option<std::sync::Arc<crate::observer::NoopObserver>>> {
+    match config.webhook_url {
+        Some(_) => anyhow::bail!("--webhook-url needs track to be built with the notify feature"),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::IgnoreReason;
+    use crate::ordering::Until;
+    use crate::reconcile::Mismatch;
+    use std::num::NonZeroU32;
+
+    const INPUT: &str = "type,client,tx,amount,timestamp
+deposit,1,1,10.0,100
+deposit,2,2,20.0,200
+dispute,1,1,,300
+withdrawal,2,3,5.0,400
+chargeback,1,1,,500
+";
+
+    /// Runs the input through the engine and returns the report lines, sorted since accounts
+    /// come out in no particular order.
+    fn report(config: &Config, input: &str) -> Vec<String> {
+        let mut output = Vec::new();
+        process(config, input.as_bytes(), &mut output).unwrap();
+        let mut lines: Vec<String> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    /// The header plus the first `rows` rows of [INPUT].
+    fn truncated(rows: usize) -> String {
+        INPUT
+            .lines()
+            .take(rows + 1)
+            .map(|line| format!("{}\n", line))
+            .collect()
+    }
+
+    #[test]
+    /// Replaying the recorded event log reports exactly what processing the CSV did
+    fn replay_matches_processing() {
+        let path = std::env::temp_dir().join(format!("track-replay-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = Config {
+            event_log: Some(path.to_str().unwrap().to_string()),
+            extended: true,
+            ..Config::default()
+        };
+        let mut processed = Vec::new();
+        process(&config, INPUT.as_bytes(), &mut processed).unwrap();
+        let mut replayed = Vec::new();
+        replay(&config, &mut replayed).unwrap();
+        assert_eq!(processed, replayed);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    /// Timings are only collected when asked for, and then cover all three phases
+    fn timings() {
+        let summary = process(&Config::default(), INPUT.as_bytes(), Vec::new()).unwrap();
+        assert_eq!(summary.timings, None);
+        let config = Config {
+            timings: true,
+            ..Config::default()
+        };
+        let summary = process(&config, INPUT.as_bytes(), Vec::new()).unwrap();
+        let timings = summary.timings.unwrap().to_string();
+        for label in ["parsing: ", "transacting: ", "writing: "] {
+            assert!(timings.contains(label), "{:?} lacks {:?}", timings, label);
+        }
+    }
+
+    #[test]
+    /// Comment lines are skipped wherever they are, by serde and the fast path alike, and are
+    /// data again once comments are off
+    fn comment_lines() {
+        let input = "# hand-maintained
+type,client,tx,amount
+# the first deposit
+deposit,1,1,1.0
+#withdrawal,1,2,5.0
+deposit,1,3,2.0
+# done
+";
+        for fast_parse in [true, false] {
+            let config = Config {
+                fast_parse,
+                ..Config::default()
+            };
+            assert_eq!(
+                report(&config, input),
+                ["1,3.0,0.0,3.0,false", "client,available,held,total,locked"]
+            );
+            let summary = process(&config, input.as_bytes(), Vec::new()).unwrap();
+            assert_eq!((summary.rows, summary.applied), (2, 2));
+        }
+        let config = Config {
+            comment: None,
+            ..Config::default()
+        };
+        assert!(process(&config, input.as_bytes(), Vec::new()).is_err());
+    }
+
+    #[test]
+    /// Latencies are only recorded when asked for, in either engine
+    fn latency() {
+        let summary = process(&Config::default(), INPUT.as_bytes(), Vec::new()).unwrap();
+        assert!(summary.shards.iter().all(|shard| shard.latency.is_none()));
+        for parallel in [false, true] {
+            let config = Config {
+                latency: true,
+                parallel,
+                ..Config::default()
+            };
+            let summary = process(&config, INPUT.as_bytes(), Vec::new()).unwrap();
+            let total: ShardMetrics = summary.shards.iter().sum();
+            assert_eq!(total.latency.unwrap().count(), total.routed);
+            assert!(summary.to_string().contains("apply latency p50: "));
+        }
+    }
+
+    #[test]
+    /// The phases account for nearly all of a run, and in parallel mode every shard's busy time
+    /// is there as well
+    fn timings_cover_the_run() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 0..20_000 {
+            input += &format!("deposit,{},{},1.5\n", tx % 100, tx);
+        }
+        for parallel in [false, true] {
+            let config = Config {
+                timings: true,
+                parallel,
+                read_ahead: 0,
+                ..Config::default()
+            };
+            let start = std::time::Instant::now();
+            let summary = process(&config, input.as_bytes(), Vec::new()).unwrap();
+            let elapsed = start.elapsed();
+            let timings = summary.timings.unwrap();
+            assert_eq!(timings.rows, 20_000);
+            assert!(!timings.parsing.is_zero() && !timings.transacting.is_zero());
+            assert!(!timings.writing.is_zero());
+            assert!(
+                timings.total() <= elapsed && timings.total() >= elapsed / 2,
+                "{:?} of {:?}",
+                timings.total(),
+                elapsed
+            );
+            match timings.shard_busy.as_deref() {
+                Some(busy) => {
+                    assert!(parallel);
+                    assert!(busy.len() == 2 && busy.iter().all(|busy| !busy.is_zero()));
+                }
+                None => assert!(!parallel),
+            }
+            let shown = timings.to_string();
+            assert!(
+                shown.contains("%, ") && shown.contains(" rows/s)"),
+                "{}",
+                shown
+            );
+            assert_eq!(shown.contains("busy per shard: "), parallel, "{}", shown);
+        }
+    }
+
+    #[test]
+    /// Every difference to the expected balances is reported, by client and column
+    fn validate_balances() {
+        let path = std::env::temp_dir().join(format!("track-expected-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "client,available,held,total,locked
+1,0.0,1.0,0.0,false
+3,1.0,0.0,1.0,false
+",
+        )
+        .unwrap();
+        let config = Config {
+            validate_balances: Some(path.to_str().unwrap().to_string()),
+            ..Config::default()
+        };
+        let summary = process(&config, INPUT.as_bytes(), Vec::new()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let field = |field, expected: &str, actual: &str| Mismatch::Field {
+            client: 1,
+            field,
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        };
+        assert_eq!(
+            summary.balance_mismatches.unwrap(),
+            vec![
+                field("held", "1", "0"),
+                field("locked", "false", "true"),
+                Mismatch::Unexpected { client: 2 },
+                Mismatch::Missing { client: 3 },
+            ]
+        );
+    }
+
+    #[test]
+    /// The summary tells how many transactions went through
+    fn summary_counts_outcomes() {
+        let summary = process(&Config::default(), INPUT.as_bytes(), Vec::new()).unwrap();
+        assert_eq!(summary.rows, 5);
+        assert_eq!(
+            (summary.applied, summary.ignored, summary.rejected),
+            (5, 0, 0)
+        );
+        let input = "type,client,tx,amount\nwithdrawal,1,1,1.0\ndeposit,1,2,1.0\n";
+        let summary = process(&Config::default(), input.as_bytes(), Vec::new()).unwrap();
+        assert_eq!((summary.applied, summary.ignored), (1, 1));
+    }
+
+    #[test]
+    /// Every ignored transaction is counted by its reason, and the first few are kept
+    fn ignore_reasons() {
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,1,1,5.0
+withdrawal,1,2,50.0
+dispute,1,9,
+chargeback,1,1,
+dispute,1,1,
+resolve,1,1,20.0
+chargeback,1,1,
+dispute,1,1,
+deposit,1,3,1.0
+dispute,2,1,
+";
+        let config = Config {
+            max_ignored: 3,
+            ..Config::default()
+        };
+        let summary = process(&config, input.as_bytes(), Vec::new()).unwrap();
+        assert_eq!((summary.applied, summary.ignored), (3, 8));
+        let reasons: Vec<(IgnoreReason, u64)> = summary.ignore_reasons.into_iter().collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (IgnoreReason::Locked, 1),
+                (IgnoreReason::DuplicateTx, 1),
+                (IgnoreReason::InsufficientFunds, 1),
+                (IgnoreReason::UnknownTx, 2),
+                (IgnoreReason::ChargedBack, 1),
+                (IgnoreReason::NotDisputed, 1),
+                (IgnoreReason::InvalidAmount, 1),
+            ]
+        );
+        let record = |tx, kind, reason| IgnoredRecord {
+            client: 1,
+            tx,
+            kind,
+            reason,
+        };
+        assert_eq!(
+            summary.ignored_records,
+            vec![
+                record(1, "deposit", IgnoreReason::DuplicateTx),
+                record(2, "withdrawal", IgnoreReason::InsufficientFunds),
+                record(9, "dispute", IgnoreReason::UnknownTx),
+            ]
+        );
+    }
+
+    #[test]
+    /// A wrong header is reported up front, naming the offending columns
+    fn schema_error() {
+        let input = "kind,client,transaction,amount\ndeposit,1,1,1.0\n";
+        let error = process(&Config::default(), input.as_bytes(), Vec::new()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The input header doesn't match the expected type,client,tx,amount \
+             (missing columns: type, tx; unexpected columns: kind, transaction)"
+        );
+        // Optional columns can be left out, and columns may come in any order
+        let input = "client,type,tx,amount\n1,deposit,1,1.0\n";
+        assert!(process(&Config::default(), input.as_bytes(), Vec::new()).is_ok());
+    }
+
+    #[test]
+    /// Amounts may be padded with whitespace, but not have any inside
+    fn amount_whitespace() {
+        let parse = |amount: &str| {
+            let input = format!("type,client,tx,amount\ndeposit,1,1,{}\n", amount);
+            let mut reader = csv::Reader::from_reader(input.as_bytes());
+            reader
+                .deserialize::<Input>()
+                .next()
+                .unwrap()
+                .map(|row| row.amount)
+        };
+        let amount = Some(Decimal::new(10000, 2));
+        assert_eq!(parse(" 100.00 ").unwrap(), amount);
+        assert_eq!(parse("\t100.00").unwrap(), amount);
+        assert_eq!(parse("100.00").unwrap(), amount);
+        assert_eq!(parse("  ").unwrap(), None);
+        assert!(parse("1 00").is_err());
+    }
+
+    #[test]
+    /// The parallel engine produces the very same report
+    fn parallel_matches_serial() {
+        let config = Config {
+            parallel: true,
+            queue_capacity: 1,
+            ..Config::default()
+        };
+        let mut serial = Vec::new();
+        process(&Config::default(), INPUT.as_bytes(), &mut serial).unwrap();
+        let mut parallel = Vec::new();
+        let summary = process(&config, INPUT.as_bytes(), &mut parallel).unwrap();
+        assert_eq!(serial, parallel);
+        let peaks = summary.queue_peaks.unwrap();
+        assert!(
+            peaks.len() == 2 && peaks.iter().all(|peak| *peak <= 2),
+            "{:?}",
+            peaks
+        );
+    }
+
+    #[test]
+    /// Stopping at row N gives the same picture as a file that ends at row N
+    fn until_row_matches_truncated_file() {
+        for rows in 0..=5 {
+            let config = Config {
+                until: Until {
+                    row: Some(rows as u64),
+                    time: None,
+                },
+                ..Config::default()
+            };
+            assert_eq!(
+                report(&config, INPUT),
+                report(&Config::default(), &truncated(rows))
+            );
+        }
+    }
+
+    #[test]
+    /// Stopping at time T includes transactions stamped exactly at T
+    fn until_time_matches_truncated_file() {
+        let config = Config {
+            until: Until {
+                row: None,
+                time: Some(300),
+            },
+            ..Config::default()
+        };
+        assert_eq!(
+            report(&config, INPUT),
+            report(&Config::default(), &truncated(3))
+        );
+        let config = Config {
+            until: Until {
+                row: None,
+                time: Some(299),
+            },
+            ..Config::default()
+        };
+        assert_eq!(
+            report(&config, INPUT),
+            report(&Config::default(), &truncated(2))
+        );
+    }
+
+    #[test]
+    /// A fully withdrawn account only disappears from the report when asked to
+    fn suppress_empty_accounts() {
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,10.0
+deposit,2,3,5.0
+";
+        assert_eq!(
+            report(&Config::default(), input),
+            vec![
+                "1,0.0,0.0,0.0,false",
+                "2,5.0,0.0,5.0,false",
+                "client,available,held,total,locked"
+            ]
+        );
+        let config = Config {
+            suppress_empty: true,
+            ..Config::default()
+        };
+        assert_eq!(
+            report(&config, input),
+            vec!["2,5.0,0.0,5.0,false", "client,available,held,total,locked"]
+        );
+        // Locked accounts are always reported, even without funds
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+dispute,1,1,
+chargeback,1,1,
+";
+        assert_eq!(report(&config, input).len(), 2);
+    }
+
+    #[test]
+    /// With a currency column, every client gets a row per currency
+    fn currencies() {
+        let input = "type,client,tx,amount,currency
+deposit,1,1,10.0,USD
+deposit,1,2,20.0,eur
+deposit,2,3,5.0,
+withdrawal,1,4,15.0,EUR
+dispute,1,1,,
+withdrawal,1,5,1.0,USD
+";
+        assert_eq!(
+            report(&Config::default(), input),
+            vec![
+                "1,0.0,10.0,10.0,false,USD",
+                "1,5.0,0.0,5.0,false,EUR",
+                "2,5.0,0.0,5.0,false,",
+                "client,available,held,total,locked,currency"
+            ]
+        );
+    }
+
+    #[test]
+    /// With rates, the extended report has every balance in the base currency as well, and
+    /// every currency in the report needs a rate
+    fn base_currency() {
+        let path = std::env::temp_dir().join(format!("track-rates-{}.csv", std::process::id()));
+        let input = "type,client,tx,amount,currency
+deposit,1,1,10.0,USD
+deposit,1,2,20.0,eur
+deposit,2,3,5.0,
+withdrawal,1,4,15.0,EUR
+dispute,1,1,,
+deposit,3,5,0.0003,CHF
+";
+        let config = Config::from_args(
+            ["--extended", "--rates", path.to_str().unwrap(), "t.csv"].map(String::from),
+        )
+        .unwrap();
+        std::fs::write(&path, "currency,rate\nUSD,1\nEUR,1.0825\n,0.5\nCHF,1.13\n").unwrap();
+        let base: Vec<String> = report(&config, input)
+            .iter()
+            .map(|line| {
+                let columns: Vec<&str> = line.split(',').collect();
+                format!("{},{}", columns[0], columns[12..].join(","))
+            })
+            .collect();
+        assert_eq!(
+            base,
+            vec![
+                "1,10.0,10.0,USD",
+                "1,5.4125,0.0,EUR",
+                "2,2.5,0.0,",
+                "3,0.000339,0.0,CHF",
+                "client,total_base,held_base,currency"
+            ]
+        );
+
+        std::fs::write(&path, "currency,rate\nUSD,1\n").unwrap();
+        let error = process(&config, input.as_bytes(), Vec::new()).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            error.to_string(),
+            "There are no rates for the implicit currency, CHF, EUR"
+        );
+        assert!(Config::from_args(["--rates", "r.csv", "t.csv"].map(String::from)).is_err());
+    }
+
+    #[test]
+    /// The extended report shows which accounts were closed, when, and what they paid out
+    fn closed_accounts() {
+        let input = "type,client,tx,amount,timestamp
+deposit,1,1,10.0,100
+deposit,2,2,5.0,100
+dispute,1,1,,200
+deposit,1,3,4.0,250
+close,1,4,,300
+deposit,1,5,1.0,400
+chargeback,1,1,,500
+";
+        let config = Config {
+            extended: true,
+            sort: ReportOrder::Client,
+            ..Config::default()
+        };
+        let mut output = Vec::new();
+        let summary = process(&config, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked,optimistic_available,first_activity,last_activity,closed,closed_at,payout,deficit
+1,0.0,0.0,0.0,true,0.0,100,500,true,300,4.0,false
+2,5.0,0.0,5.0,false,5.0,100,100,false,,,false
+"
+        );
+        assert_eq!(summary.rejected, 1);
+    }
+
+    #[test]
+    /// Disputes, resolves and chargebacks of clients without an account leave an empty account
+    /// in the report, unless accounts get compacted anyway
+    fn unknown_client_references() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.0
+dispute,2,1,
+resolve,3,1,
+chargeback,4,1,
+";
+        let config = Config {
+            shards: 1,
+            ordered_accounts: true,
+            ..Config::default()
+        };
+        let mut output = Vec::new();
+        process(&config, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked
+1,1.0,0.0,1.0,false
+2,0.0,0.0,0.0,false
+3,0.0,0.0,0.0,false
+4,0.0,0.0,0.0,false
+"
+        );
+
+        let config = Config {
+            on_max_memory: MemoryAction::Compact,
+            ..config
+        };
+        let mut output = Vec::new();
+        process(&config, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1.0,0.0,1.0,false\n"
+        );
+    }
+
+    #[test]
+    /// Amounts above the maximum are rejected as they are read, and amounts that would
+    /// overflow a balance are rejected by the engine. Either way the run goes on
+    fn huge_amounts() {
+        let max = Decimal::MAX;
+        let input = format!(
+            "type,client,tx,amount
+deposit,1,1,{max}
+deposit,1,2,{max}
+deposit,2,3,100.0
+deposit,2,4,100.0001
+withdrawal,2,5,100.0
+"
+        );
+        let mut output = Vec::new();
+        let summary = process(&Config::default(), input.as_bytes(), &mut output).unwrap();
+        assert_eq!((summary.applied, summary.rejected), (4, 1));
+        let config = Config::from_args(["--max-amount", "100", "t.csv"].map(String::from)).unwrap();
+        let mut output = Vec::new();
+        let summary = process(&config, input.as_bytes(), &mut output).unwrap();
+        assert_eq!((summary.rows, summary.applied, summary.rejected), (5, 2, 3));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n2,0.0,0.0,0.0,false\n"
+        );
+    }
+
+    #[test]
+    /// Transactions at the maximum amount and deposits up to the balance cap go through,
+    /// anything beyond is reported as rejected along with the limit it broke
+    fn limits() {
+        let path = std::env::temp_dir().join(format!("track-limits-{}.csv", std::process::id()));
+        let input = "type,client,tx,amount
+deposit,1,1,99.9999
+deposit,1,2,100.0
+deposit,1,3,100.0001
+withdrawal,1,4,100.0001
+deposit,2,5,100.0
+deposit,2,6,100.0
+deposit,2,7,50.0
+deposit,2,8,0.0001
+";
+        let args = [
+            "--max-amount",
+            "100",
+            "--balance-cap",
+            "250",
+            "--rejects",
+            path.to_str().unwrap(),
+            "t.csv",
+        ];
+        let config = Config::from_args(args.map(String::from)).unwrap();
+        let summary = process(&config, input.as_bytes(), Vec::new()).unwrap();
+        assert_eq!((summary.applied, summary.rejected), (5, 3));
+        let rejects = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            rejects,
+            "level,client,tx,reason,line
+rejected,1,3,amount exceeds the maximum of 100,4
+rejected,1,4,amount exceeds the maximum of 100,5
+rejected,2,8,total would exceed the balance cap of 250,9
+"
+        );
+    }
+
+    #[test]
+    /// Rows that can't be read or converted are reported with the line they're on, blank
+    /// lines and quoted line breaks before them included
+    fn line_numbers() {
+        let input = "type,client,tx,amount
+
+deposit,1,1,\"1.0\"
+deposit,1,2,2.0
+refund,1,3,1.0
+";
+        let error = process(&Config::default(), input.as_bytes(), Vec::new()).unwrap_err();
+        assert!(error.to_string().contains("(line: 5, "), "{}", error);
+
+        let input = "type,client,tx,amount
+deposit,1,1,\"1.0
+\"
+withdrawal,1,2,
+";
+        let error = process(&Config::default(), input.as_bytes(), Vec::new()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 4: An amount needs to be specified for withdraw."
+        );
+
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,one,2,1.0\n";
+        let error = process(&Config::default(), input.as_bytes(), Vec::new()).unwrap_err();
+        assert!(error.to_string().contains("line: 3"), "{}", error);
+    }
+
+    #[test]
+    /// Amounts at the boundary of four decimal places under every precision policy: trailing
+    /// zeros never count as extra precision, anything else is rounded half-even, truncated or
+    /// rejected
+    fn precision_policies() {
+        let input = "type,client,tx,amount
+deposit,1,1,1.0000
+deposit,2,2,1.00000
+deposit,3,3,1.00004
+deposit,4,4,1.00005
+deposit,5,5,1.00015
+deposit,6,6,1.99995
+deposit,7,7,0.00009
+";
+        // The total of every client's account, in client order.
+        let run = |policy: &str| {
+            let args = ["--precision-policy", policy, "t.csv"];
+            let config = Config::from_args(args.map(String::from)).unwrap();
+            let mut output = Vec::new();
+            let summary = process(&config, input.as_bytes(), &mut output).unwrap();
+            let mut totals: Vec<(ClientId, String)> = String::from_utf8(output)
+                .unwrap()
+                .lines()
+                .skip(1)
+                .map(|line| {
+                    let columns: Vec<&str> = line.split(',').collect();
+                    (columns[0].parse().unwrap(), columns[3].to_string())
+                })
+                .collect();
+            totals.sort();
+            let totals: Vec<String> = totals.into_iter().map(|(_, total)| total).collect();
+            (
+                totals,
+                summary.rounded,
+                summary.too_precise,
+                summary.rejected,
+            )
+        };
+        let totals = |totals: &[&str]| totals.iter().map(|total| total.to_string()).collect();
+        assert_eq!(
+            run("round"),
+            (
+                totals(&["1.0", "1.0", "1.0", "1.0", "1.0002", "2.0", "0.0001"]),
+                5,
+                0,
+                0
+            )
+        );
+        assert_eq!(
+            run("truncate"),
+            (
+                totals(&["1.0", "1.0", "1.0", "1.0", "1.0001", "1.9999", "0.0"]),
+                5,
+                0,
+                0
+            )
+        );
+        assert_eq!(run("reject"), (totals(&["1.0", "1.0"]), 0, 5, 5));
+    }
+
+    #[test]
+    #[cfg(any(feature = "client-id-u32", feature = "client-id-u64"))]
+    /// Widened client ids go all the way through, from parsing over routing to the report
+    fn wide_client_ids() {
+        let input = "type,client,tx,amount
+deposit,65536,1,10.0
+deposit,4000000000,2,20.0
+withdrawal,65536,3,4.0
+deposit,0,4,1.0
+";
+        assert_eq!(
+            report(&Config::default(), input),
+            vec![
+                "0,1.0,0.0,1.0,false",
+                "4000000000,20.0,0.0,20.0,false",
+                "65536,6.0,0.0,6.0,false",
+                "client,available,held,total,locked"
+            ]
+        );
+        let config = Config::from_args(["--only-client", "65536", "t.csv"].map(String::from));
+        assert_eq!(report(&config.unwrap(), input).len(), 2);
+    }
+
+    #[test]
+    /// Filtering on a client only reports that client's account
+    fn only_client() {
+        let config = Config::from_args(["--only-client", "2", "t.csv"].map(String::from)).unwrap();
+        let mut output = Vec::new();
+        let summary = process(&config, INPUT.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n2,15.0,0.0,15.0,false\n"
+        );
+        assert_eq!(summary.rows, 2);
+    }
+
+    #[test]
+    /// The extended report flags accounts that hold more than they have
+    fn deficit() {
+        let input = "type,client,tx,amount
+deposit,1,1,10.0
+withdrawal,1,2,10.0
+dispute,1,1,
+deposit,2,3,5.0
+";
+        let mut config =
+            Config::from_args(["--negative", "allow", "t.csv"].map(String::from)).unwrap();
+        config.extended = true;
+        config.sort = ReportOrder::Client;
+        let mut output = Vec::new();
+        process(&config, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let rows: Vec<&str> = output.lines().skip(1).collect();
+        assert_eq!(
+            rows,
+            vec![
+                "1,-10.0,10.0,0.0,false,0.0,,,false,,,true",
+                "2,5.0,0.0,5.0,false,5.0,,,false,,,false"
+            ]
+        );
+    }
+
+    #[test]
+    /// With client-sorted input every account is written as soon as the next client shows up,
+    /// so the system never holds more than the current account
+    fn sorted_by_client_streams_accounts() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for client in 0..100 {
+            input += &format!("deposit,{},{},2.0\n", client, client * 2);
+            input += &format!("withdrawal,{},{},1.0\n", client, client * 2 + 1);
+        }
+        let config = Config {
+            sorted_by_client: true,
+            ..Config::default()
+        };
+        let mut finalized = Vec::new();
+        let mut finalize = |client, account: AccountState| -> anyhow::Result<()> {
+            assert_eq!(account.total, Decimal::ONE);
+            finalized.push(client);
+            Ok(())
+        };
+        let (system, _) = apply(
+            &config,
+            csv::Reader::from_reader(input.as_bytes()),
+            Some(&mut finalize),
+        )
+        .unwrap();
+        assert_eq!(finalized, (0..99).collect::<Vec<ClientId>>());
+        assert_eq!(system.accounts().count(), 1);
+
+        assert_eq!(report(&config, &input), report(&Config::default(), &input));
+    }
+
+    #[test]
+    /// A client that comes back after its account was finalized is reported and its row
+    /// rejected, the account stays as it was written
+    fn sorted_by_client_violation() {
+        let config = Config {
+            sorted_by_client: true,
+            ..Config::default()
+        };
+        let unsorted = "type,client,tx,amount
+deposit,1,1,1.0
+deposit,2,2,1.0
+deposit,1,3,5.0
+deposit,2,4,1.0
+";
+        assert_eq!(
+            report(&config, unsorted),
+            vec![
+                "1,1.0,0.0,1.0,false",
+                "2,2.0,0.0,2.0,false",
+                "client,available,held,total,locked"
+            ]
+        );
+        let summary = process(&config, unsorted.as_bytes(), Vec::new()).unwrap();
+        assert_eq!(summary.sort_violations, Some(1));
+        assert_eq!((summary.applied, summary.rejected), (3, 1));
+        assert!(summary
+            .to_string()
+            .ends_with("rows of finalized clients: 1"));
+        let summary = process(&Config::default(), unsorted.as_bytes(), Vec::new()).unwrap();
+        assert_eq!(summary.sort_violations, None);
+    }
+
+    #[test]
+    /// Scheduled rows wait for the clock, and what's left at the end is either listed or applied
+    fn scheduled_rows() {
+        let input = "type,client,tx,amount,timestamp,effective
+deposit,1,1,10.0,100,
+deposit,1,2,5.0,100,300
+withdrawal,1,3,12.0,200,
+deposit,2,4,1.0,200,250
+deposit,2,5,1.0,300,
+deposit,2,6,7.0,300,900
+";
+        let config = Config {
+            sort: ReportOrder::Client,
+            ..Config::default()
+        };
+        let mut output = Vec::new();
+        process(&config, input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked
+1,15.0,0.0,15.0,false
+2,2.0,0.0,2.0,false
+
+effective,type,client,tx,amount
+900,deposit,2,6,7.0
+"
+        );
+
+        let config = Config {
+            pending: PendingAtEnd::Apply,
+            ..Config::default()
+        };
+        assert_eq!(
+            report(&config, input),
+            vec![
+                "1,15.0,0.0,15.0,false",
+                "2,9.0,0.0,9.0,false",
+                "client,available,held,total,locked"
+            ]
+        );
+        let config = Config {
+            parallel: true,
+            ..config
+        };
+        assert_eq!(
+            report(&config, input),
+            report(
+                &Config {
+                    pending: PendingAtEnd::Apply,
+                    ..Config::default()
+                },
+                input
+            )
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    /// A gzipped report and rejects file decompress to exactly what's written uncompressed
+    fn gzip_output() {
+        use crate::sink::Compression;
+        let dir = std::env::temp_dir();
+        let path = |name: &str| {
+            let path = dir.join(format!("track-gzip-{}-{}", std::process::id(), name));
+            path.to_str().unwrap().to_string()
+        };
+        let input = path("input.csv");
+        // A single account, so the report comes out the same every time.
+        std::fs::write(
+            &input,
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5000.0\nwithdrawal,1,3,2.5\n",
+        )
+        .unwrap();
+        let run = |output: &str, rejects: &str, compress| {
+            let config = Config {
+                input: input.clone(),
+                output: Some(path(output)),
+                rejects: Some(path(rejects)),
+                compress,
+                max_amount: Some(Decimal::from(1000)),
+                ..Config::default()
+            };
+            super::run(&config).unwrap();
+        };
+        let read = |name: &str| std::fs::read(path(name)).unwrap();
+        let gunzip = |name: &str| {
+            let mut plain = Vec::new();
+            flate2::read::GzDecoder::new(read(name).as_slice())
+                .read_to_end(&mut plain)
+                .unwrap();
+            plain
+        };
+
+        run("report.csv", "rejects.csv", None);
+        run("report.csv.gz", "rejects.csv.gz", None);
+        run("report.gzip", "rejects.gzip", Some(Compression::Gzip));
+        run(
+            "report.plain.gz",
+            "rejects.plain.gz",
+            Some(Compression::None),
+        );
+
+        let report = read("report.csv");
+        let rejects = read("rejects.csv");
+        assert!(String::from_utf8_lossy(&rejects).contains("rejected,1,2,"));
+        assert_eq!(gunzip("report.csv.gz"), report);
+        assert_eq!(gunzip("rejects.csv.gz"), rejects);
+        assert_eq!(gunzip("report.gzip"), report);
+        assert_eq!(gunzip("rejects.gzip"), rejects);
+        assert_eq!(read("report.plain.gz"), report);
+        assert_eq!(read("rejects.plain.gz"), rejects);
+        for name in [
+            "input.csv",
+            "report.csv",
+            "rejects.csv",
+            "report.csv.gz",
+            "rejects.csv.gz",
+            "report.gzip",
+            "rejects.gzip",
+            "report.plain.gz",
+            "rejects.plain.gz",
+        ] {
+            std::fs::remove_file(path(name)).unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    /// A gzipped input, named .gz or read with --gzip, gives the report its plain text does
+    fn gzip_input() {
+        use std::io::Write;
+        let path = |name: &str| {
+            let path =
+                std::env::temp_dir().join(format!("track-gunzip-{}-{}", std::process::id(), name));
+            path.to_str().unwrap().to_string()
+        };
+        let csv = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\nwithdrawal,1,3,2.5\n\
+            dispute,2,2,\n";
+        std::fs::write(path("input.csv"), csv).unwrap();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(csv.as_bytes()).unwrap();
+        let gzipped = gzip.finish().unwrap();
+        std::fs::write(path("input.csv.gz"), &gzipped).unwrap();
+        std::fs::write(path("input.bin"), &gzipped).unwrap();
+
+        let report = |input: &str, gzip| {
+            let config = Config {
+                input: path(input),
+                output: Some(path("report.csv")),
+                gzip,
+                ..Config::default()
+            };
+            let summary = super::run(&config).unwrap();
+            let mut lines: Vec<String> = std::fs::read_to_string(path("report.csv"))
+                .unwrap()
+                .lines()
+                .map(str::to_string)
+                .collect();
+            lines.sort();
+            (summary.rows, lines)
+        };
+        let plain = report("input.csv", false);
+        assert_eq!(plain.0, 4);
+        assert_eq!(report("input.csv.gz", false), plain);
+        assert_eq!(report("input.bin", true), plain);
+        assert!(super::run(&Config {
+            input: path("input.bin"),
+            output: Some(path("report.csv")),
+            ..Config::default()
+        })
+        .is_err());
+        for name in ["input.csv", "input.csv.gz", "input.bin", "report.csv"] {
+            std::fs::remove_file(path(name)).unwrap();
+        }
+    }
+
+    #[test]
+    /// The parts of a per-shard report make up the single-file report, a header each
+    fn output_per_shard() {
+        let template = std::env::temp_dir()
+            .join(format!("track-part-{}-{{shard}}.csv", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let mut input = String::from("type,client,tx,amount\n");
+        for (client, tx) in (0..20).zip(1..) {
+            input.push_str(&format!("deposit,{},{},{}.5\n", client, tx, tx));
+        }
+        for parallel in [false, true] {
+            let config = Config {
+                parallel,
+                output_per_shard: Some(template.clone()),
+                ..Config::default()
+            };
+            let mut output = Vec::new();
+            process(&config, input.as_bytes(), &mut output).unwrap();
+            assert!(output.is_empty());
+
+            let mut lines = Vec::new();
+            for shard in 0..2 {
+                let path = template.replace("{shard}", &shard.to_string());
+                let part = std::fs::read_to_string(&path).unwrap();
+                std::fs::remove_file(&path).unwrap();
+                let mut part = part.lines().map(String::from);
+                assert_eq!(part.next().unwrap(), "client,available,held,total,locked");
+                lines.extend(part);
+            }
+            lines.push("client,available,held,total,locked".to_string());
+            lines.sort();
+            assert_eq!(lines, report(&Config::default(), &input));
+        }
+    }
+
+    #[test]
+    /// A burst of transactions takes as long as the rate limit says it must
+    fn max_rate() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=11 {
+            input.push_str(&format!("deposit,1,{},1.0\n", tx));
+        }
+        let config = Config {
+            max_rate: NonZeroU32::new(50),
+            ..Config::default()
+        };
+        let start = std::time::Instant::now();
+        assert_eq!(report(&config, &input), report(&Config::default(), &input));
+        // The first goes right away, ten more at 20ms each.
+        assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+    }
+
+    #[test]
+    /// Crossing --max-memory stops the run, unless compacting gets it back below the limit
+    fn max_memory() {
+        let mut input = String::from("type,client,tx,amount,timestamp\n");
+        for (client, tx) in (1..=10).zip(1..) {
+            input.push_str(&format!("withdrawal,{},{},1.0,{}\n", client, tx, tx));
+        }
+        let deposit = "deposit,100,100,5.0,100\n";
+        input.push_str(deposit);
+        let alone = format!("type,client,tx,amount,timestamp\n{}", deposit);
+        let limit = process(&Config::default(), alone.as_bytes(), Vec::new())
+            .unwrap()
+            .memory_footprint;
+
+        let summary = process(&Config::default(), input.as_bytes(), Vec::new()).unwrap();
+        assert!(summary.memory_footprint > limit);
+        let config = Config {
+            max_memory: Some(limit),
+            ..Config::default()
+        };
+        let error = process(&config, input.as_bytes(), Vec::new()).unwrap_err();
+        assert!(
+            error.to_string().contains("allowed by --max-memory"),
+            "{}",
+            error
+        );
+
+        let config = Config {
+            on_max_memory: MemoryAction::Compact,
+            ..config
+        };
+        let mut output = Vec::new();
+        let summary = process(&config, input.as_bytes(), &mut output).unwrap();
+        assert_eq!((summary.compacted, summary.memory_footprint), (10, limit));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n100,5.0,0.0,5.0,false\n"
+        );
+    }
+
+    #[test]
+    /// Integer arithmetic comes to the same report and counts as decimals on input it takes,
+    /// and refuses what it doesn't.
+    fn integer_amounts() {
+        let input = "type,client,tx,amount
+deposit,1,1,10.5
+deposit,1,2,2.25
+withdrawal,1,3,20.0
+dispute,1,1,
+resolve,1,1,
+dispute,1,2,
+chargeback,1,2,
+deposit,1,4,1.0
+deposit,2,5,3.0
+withdrawal,2,6,1.0001
+dispute,2,5,
+dispute,3,7,
+deposit,3,8,0.125
+dispute,3,8,
+deposit,5,10,2.0
+dispute,5,10,
+dispute,5,10,
+resolve,5,10,
+resolve,5,10,
+dispute,6,11,
+";
+        let integer = Config {
+            integer_amounts: true,
+            ..Config::default()
+        };
+        assert_eq!(report(&integer, input), report(&Config::default(), input));
+        let summary = process(&integer, input.as_bytes(), Vec::new()).unwrap();
+        let decimal = process(&Config::default(), input.as_bytes(), Vec::new()).unwrap();
+        assert_eq!(
+            (summary.applied, summary.ignored, summary.rejected),
+            (decimal.applied, decimal.ignored, decimal.rejected)
+        );
+        assert_eq!(summary.ignore_reasons, decimal.ignore_reasons);
+
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\nfee,1,2,0.5\n";
+        let error = process(&integer, input.as_bytes(), Vec::new()).unwrap_err();
+        assert!(error.to_string().contains("line 3: fee"), "{}", error);
+    }
+
+    #[test]
+    /// Balances beyond about 922 trillion don't fit integer arithmetic, which stops the run
+    /// rather than report anything other than decimals would
+    fn integer_amounts_overflow() {
+        let integer = Config {
+            integer_amounts: true,
+            ..Config::default()
+        };
+        let input = "type,client,tx,amount
+deposit,1,1,900000000000000
+withdrawal,1,2,0.0001
+";
+        assert_eq!(report(&integer, input), report(&Config::default(), input));
+
+        let input = "type,client,tx,amount
+deposit,1,1,900000000000000
+deposit,1,2,900000000000000
+";
+        let error = process(&integer, input.as_bytes(), Vec::new()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 3: deposit of client 1, tx 2 takes the balance beyond what --integer-amounts \
+             can hold"
+        );
+        let summary = process(&Config::default(), input.as_bytes(), Vec::new()).unwrap();
+        assert_eq!((summary.applied, summary.rejected), (2, 0));
+    }
+
+    /// Inputs with the standard header, including what the fast path leaves to serde.
+    const CORPUS: [&str; 10] = [
+        "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,20.0
+dispute,1,1,
+withdrawal,2,3,5.0
+chargeback,1,1,
+deposit,1,4,3.0
+",
+        "type,client,tx,amount
+deposit,1,1,1.12345
+withdrawal,1,2, 0.5 
+dispute,1,1,
+resolve,1,1,
+fee,1,3,0.25
+interest,1,4,1
+close,1,5,
+deposit,65535,6,1e2
+",
+        "type,client,tx,amount
+deposit,1,1,5.0
+withdrawal,1,2,6.0
+resolve,1,1,
+chargeback,1,9,
+dispute,3,1,
+",
+        "type,client,tx,amount\ndeposit,+1,1,1.0\n",
+        "type,client,tx,amount\ndeposit, 1,1,1.0\n",
+        "type,client,tx,amount\ndeposit,70000,1,1.0\n",
+        "type,client,tx,amount\nDeposit,1,1,1.0\n",
+        "type,client,tx,amount\ndeposit,1,1,\n",
+        "type,client,tx,amount\ndeposit,1,1,abc\n",
+        "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1\n",
+    ];
+
+    #[test]
+    /// Reading without serde comes to the same reports, summaries and errors as with it
+    fn fast_parse_matches_serde() {
+        let run = |config: &Config, input: &str| {
+            let mut output = Vec::new();
+            process(config, input.as_bytes(), &mut output)
+                .map(|summary| (String::from_utf8(output).unwrap(), summary))
+                .map_err(|error| error.to_string())
+        };
+        for input in CORPUS {
+            for integer_amounts in [false, true] {
+                let fast = Config {
+                    integer_amounts,
+                    ..Config::default()
+                };
+                let serde = Config {
+                    fast_parse: false,
+                    ..fast.clone()
+                };
+                assert_eq!(run(&fast, input), run(&serde, input), "{}", input);
+            }
+        }
+        assert!(run(&Config::default(), CORPUS[0]).is_ok());
+        assert!(run(&Config::default(), CORPUS[8]).is_err());
+    }
+
+    #[test]
+    /// Rows read on a thread of their own come to the same as read on the engine's, and
+    /// errors still say which line they're on, however far ahead the reader is
+    fn read_ahead() {
+        let run = |read_ahead: usize, input: &str| {
+            let config = Config {
+                read_ahead,
+                ..Config::default()
+            };
+            let mut output = Vec::new();
+            process(&config, input.as_bytes(), &mut output)
+                .map(|summary| (String::from_utf8(output).unwrap(), summary))
+                .map_err(|error| error.to_string())
+        };
+        for input in CORPUS {
+            assert_eq!(run(0, input), run(1, input), "{}", input);
+            assert_eq!(run(0, input), run(64 * 1024, input), "{}", input);
+        }
+        let rows = |bad: &str| {
+            let mut input = String::from("type,client,tx,amount\n");
+            for tx in 1..5_000 {
+                input.push_str(&format!("deposit,{},{},1.0\n", tx % 7, tx));
+            }
+            input.push_str(bad);
+            input.push_str("deposit,1,6000,1.0\n");
+            input
+        };
+        let conversion = rows("withdrawal,1,5000,\n");
+        let csv = rows("deposit,one,5000,1.0\n");
+        for read_ahead in [0, 10, 4096] {
+            let error = run(read_ahead, &conversion).unwrap_err();
+            assert_eq!(
+                error,
+                "line 5001: An amount needs to be specified for withdraw."
+            );
+            let error = run(read_ahead, &csv).unwrap_err();
+            assert!(error.contains("line: 5001"), "{}", error);
+        }
+    }
+
+    #[test]
+    /// The estimate made before processing comes close to the footprint of the accounts
+    /// processing leaves, and counts only deposits that get recorded
+    fn estimate_memory() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=2_000 {
+            let type_ = if tx % 4 == 0 { "withdrawal" } else { "deposit" };
+            input.push_str(&format!("{},{},{},1.0\n", type_, tx % 50, tx));
+        }
+        input.push_str("dispute,1,1,\nresolve,1,1,\n");
+        let mut output = Vec::new();
+        let estimated = estimate(&Config::default(), input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("accounts: 50, "), "{}", output);
+        assert!(output.contains("\ndeposits: 1500, "), "{}", output);
+        assert_eq!(estimated.rows, 2_002);
+
+        let processed = process(&Config::default(), input.as_bytes(), Vec::new()).unwrap();
+        let (estimated, actual) = (estimated.memory_footprint, processed.memory_footprint);
+        assert!(
+            estimated <= actual && actual < estimated * 11 / 10,
+            "estimated {}, actual {}",
+            estimated,
+            actual
+        );
+    }
+
+    #[test]
+    /// A report compared against itself makes no difference, against that of changed input
+    /// one for every column and client that changed
+    fn diff_reports() {
+        let report = |input: &str| {
+            let mut output = Vec::new();
+            process(&Config::default(), input.as_bytes(), &mut output).unwrap();
+            output
+        };
+        let old =
+            report("type,client,tx,amount\ndeposit,1,1,0.1\ndeposit,1,2,0.2\ndeposit,2,3,5\n");
+        let new =
+            report("type,client,tx,amount\ndeposit,1,1,0.1\ndeposit,1,2,0.3\ndeposit,3,3,5\n");
+        let mut output = Vec::new();
+        let summary = diff(&Config::default(), &old[..], &old[..], &mut output).unwrap();
+        assert_eq!((summary.rows, summary.differences), (4, Some(0)));
+        assert!(output.is_empty());
+
+        let config = Config {
+            diff_format: DiffFormat::Json,
+            ..Config::default()
+        };
+        let summary = diff(&config, &old[..], &new[..], &mut output).unwrap();
+        assert_eq!(summary.differences, Some(4));
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            concat!(
+                r#"{"kind":"field","client":1,"field":"available","expected":"0.3","actual":"0.4"}"#,
+                "\n",
+                r#"{"kind":"field","client":1,"field":"total","expected":"0.3","actual":"0.4"}"#,
+                "\n",
+                r#"{"kind":"missing","client":2}"#,
+                "\n",
+                r#"{"kind":"unexpected","client":3}"#,
+                "\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use crate::transaction::RecordType;
+use rust_decimal::Decimal;
+
+/// Rules that differ between institutions. The defaults match how the engine always behaved,
+/// plus our own policy for fees and interest, except that funds no longer go negative, see
+/// [NegativePolicy].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AccountPolicy {
+    /// Fees are charged even if that takes the available funds below zero. Without this, a
+    /// fee larger than what's available is ignored, like a withdrawal would be.
+    pub fees_may_overdraw: bool,
+    /// Interest is recorded like a deposit and can be disputed and charged back like one.
+    /// Fees are debits, which never hold any funds, so they can't be disputed either way.
+    pub disputable_interest: bool,
+    /// Withdrawals are recorded and can be disputed and charged back, which reverses them,
+    /// see [crate::account::DepositState::is_withdrawal]. Without this, disputes of
+    /// withdrawals are ignored, and withdrawals cost no memory once applied.
+    pub disputable_withdrawals: bool,
+    /// What a locked account still accepts.
+    pub locked: LockedPolicy,
+    /// How many applied transactions every account remembers for
+    /// [crate::account::AccountState::undo_last]. Each one costs memory for every account, so
+    /// nothing is remembered by default.
+    pub undo_depth: usize,
+    /// What happens when a transaction would take the available funds of a currency below
+    /// zero, or further below it. Fees are exempt as long as `fees_may_overdraw` allows them
+    /// to overdraw, which is the one way an account is meant to go negative.
+    pub negative: NegativePolicy,
+    /// Deposits that would take the total of an account beyond this, in the currency of the
+    /// deposit, are rejected. Interest isn't held to it.
+    pub balance_cap: Option<Decimal>,
+    /// Disputes beyond this many open ones per account are rejected, until one of them is
+    /// resolved or charged back. Disputing a deposit that is disputed already is ignored, see
+    /// [crate::account::IgnoreReason::AlreadyDisputed], so it doesn't count.
+    pub max_open_disputes: Option<u32>,
+    /// A resolved deposit can only be disputed again while fewer than this many transactions
+    /// were applied to the account since the resolve. Later disputes are rejected. Without a
+    /// window, deposits can be disputed again any time.
+    pub redispute_window: Option<u32>,
+    /// Transactions of these types are ignored as
+    /// [crate::account::IgnoreReason::Disabled], before they reach an account. A deployment
+    /// that only moves funds can turn disputes off altogether this way.
+    pub disabled: RecordTypes,
+}
+
+impl Default for AccountPolicy {
+    fn default() -> Self {
+        AccountPolicy {
+            fees_may_overdraw: true,
+            disputable_interest: false,
+            disputable_withdrawals: false,
+            locked: LockedPolicy::default(),
+            undo_depth: 0,
+            negative: NegativePolicy::default(),
+            balance_cap: None,
+            max_open_disputes: None,
+            redispute_window: None,
+            disabled: RecordTypes::default(),
+        }
+    }
+}
+
+/// A set of transaction types, see [AccountPolicy::disabled]. Empty by default.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct RecordTypes(u8);
+
+impl RecordTypes {
+    pub fn insert(&mut self, record_type: RecordType) {
+        self.0 |= 1 << record_type as u8;
+    }
+
+    pub fn contains(&self, record_type: RecordType) -> bool {
+        self.0 & (1 << record_type as u8) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The types in the set, in the order of [RecordType::ALL].
+    pub fn iter(&self) -> impl Iterator<Item = RecordType> + '_ {
+        RecordType::ALL
+            .into_iter()
+            .filter(|record_type| self.contains(*record_type))
+    }
+}
+
+/// Deposits and withdrawals are never applied to a locked account. The adjustments we post
+/// ourselves are a different matter, and so are disputes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LockedPolicy {
+    /// Locked accounts keep accruing interest.
+    pub accrue_interest: bool,
+    /// Locked accounts are still charged fees.
+    pub charge_fees: bool,
+    /// Deposits of locked accounts can still be disputed, and disputes resolved. Some
+    /// institutions freeze all activity on a locked account instead, then both are rejected.
+    /// Open disputes can still be charged back either way.
+    pub disputes: bool,
+}
+
+impl Default for LockedPolicy {
+    fn default() -> Self {
+        LockedPolicy {
+            accrue_interest: true,
+            charge_fees: false,
+            disputes: true,
+        }
+    }
+}
+
+/// How to deal with a transaction that breaks the invariants of an account: nothing may be
+/// held below zero, and the available funds may not drop below zero (or further below it, for
+/// an account a fee has overdrawn). A dispute of a deposit that has already been withdrawn, a
+/// resolve without a dispute and a chargeback of spent funds are the usual suspects. See
+/// [crate::invariants].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum NegativePolicy {
+    /// The account stays as it was and the transaction is rejected.
+    #[default]
+    Reject,
+    /// The transaction is applied, but the currency it broke is clamped: less is held until
+    /// nothing more is held than there is in total, and a negative total is written off.
+    ///
+    /// A dispute of a deposit of 100 that 60 were withdrawn from since holds the 40 that are
+    /// left, and none of it is available until the dispute is resolved.
+    Clamp,
+    /// The transaction is applied as is, like the engine always used to. The same dispute
+    /// holds all of the 100, and leaves -60 available.
+    Allow,
+}
@@ -0,0 +1,100 @@
+use crate::currency::Currency;
+use anyhow::bail;
+use rust_decimal::Decimal;
+use serde::Deserialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+/// A row of the rates file.
+#[derive(Deserialize)]
+struct Rate {
+    /// Left blank for the implicit currency of inputs without a `currency` column.
+    currency: Option<Currency>,
+    #[serde(with = "rust_decimal::serde::str")]
+    rate: Decimal,
+}
+
+/// Exchange rates into a base currency, for reporting only: the balances themselves stay in
+/// their own currency. Read from a CSV file with a `currency` and a `rate` column, where a
+/// balance of one unit of `currency` is worth `rate` units of the base currency.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Rates {
+    rates: HashMap<Option<Currency>, Decimal>,
+}
+
+impl Rates {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Reads the rates, which mustn't be negative or list a currency twice.
+    pub fn from_reader<R: Read>(reader: R) -> anyhow::Result<Self> {
+        let mut rates = HashMap::new();
+        let mut rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in rdr.deserialize() {
+            let Rate { currency, rate } = row?;
+            if rate.is_sign_negative() {
+                bail!("The rate for {} is negative", name(currency));
+            }
+            if rates.insert(currency, rate).is_some() {
+                bail!("The rates list {} more than once", name(currency));
+            }
+        }
+        Ok(Rates { rates })
+    }
+
+    /// `amount` in the base currency, unrounded. `None` if there is no rate for `currency`,
+    /// or the result doesn't fit.
+    pub fn convert(&self, currency: Option<Currency>, amount: Decimal) -> Option<Decimal> {
+        self.rates.get(&currency)?.checked_mul(amount)
+    }
+
+    /// Fails with a list of every currency in `currencies` that there is no rate for.
+    pub fn check(
+        &self,
+        currencies: impl IntoIterator<Item = Option<Currency>>,
+    ) -> anyhow::Result<()> {
+        let uncovered: BTreeSet<Option<Currency>> = currencies
+            .into_iter()
+            .filter(|currency| !self.rates.contains_key(currency))
+            .collect();
+        if !uncovered.is_empty() {
+            let names: Vec<String> = uncovered.into_iter().map(name).collect();
+            bail!("There are no rates for {}", names.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// How a currency is called in error messages.
+fn name(currency: Option<Currency>) -> String {
+    match currency {
+        Some(currency) => currency.to_string(),
+        None => "the implicit currency".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Every currency but the ones listed is reported missing, the implicit one included
+    fn missing_rates() {
+        let rates = Rates::from_reader("currency,rate\nusd,1\nEUR,1.08\n".as_bytes()).unwrap();
+        let currencies = ["GBP", "USD", "CHF", "GBP"].map(|code| Some(code.parse().unwrap()));
+        let error = rates.check(currencies).unwrap_err();
+        assert_eq!(error.to_string(), "There are no rates for CHF, GBP");
+        let error = rates.check([None]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "There are no rates for the implicit currency"
+        );
+        assert!(rates.check(["usd".parse().ok()]).is_ok());
+        assert!(Rates::from_reader("currency,rate\nUSD,1\nusd,2\n".as_bytes()).is_err());
+        assert!(Rates::from_reader("currency,rate\nUSD,-1\n".as_bytes()).is_err());
+    }
+}
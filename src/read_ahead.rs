@@ -0,0 +1,75 @@
+use std::sync::mpsc::sync_channel;
+use std::thread::Scope;
+
+/// How many rows the reader hands over at a time. Going through the channel row by row would
+/// cost about as much as the parsing the thread takes off the engine.
+const CHUNK: usize = 1024;
+
+/// Runs `rows` on a thread of `scope`, so reading and parsing the input overlaps with
+/// applying it, and returns what the thread produces, in order. The thread gets up to
+/// `capacity` rows ahead, in chunks of up to [CHUNK], and then waits for the engine to catch
+/// up. A capacity of zero is taken as one.
+///
+/// The thread stops after the first error, which comes through as is and so still says where
+/// it happened, or once the returned iterator is dropped. Should it panic instead, the rows
+/// just end early, and the panic is raised again when `scope` ends.
+pub(crate) fn read_ahead<'scope, T, I>(
+    scope: &'scope Scope<'scope, '_>,
+    rows: I,
+    capacity: usize,
+) -> impl Iterator<Item = anyhow::Result<T>> + 'scope
+where
+    T: Send + 'scope,
+    I: Iterator<Item = anyhow::Result<T>> + Send + 'scope,
+{
+    let chunk = capacity.clamp(1, CHUNK);
+    let (sender, receiver) = sync_channel::<Vec<anyhow::Result<T>>>(capacity.max(1) / chunk);
+    scope.spawn(move || {
+        let mut rows = rows;
+        let mut failed = false;
+        while !failed {
+            let mut read = Vec::with_capacity(chunk);
+            for row in rows.by_ref() {
+                failed = row.is_err();
+                read.push(row);
+                if failed || read.len() == chunk {
+                    break;
+                }
+            }
+            // Sending only fails once the engine is gone, which leaves nothing to read for.
+            if read.is_empty() || sender.send(read).is_err() {
+                return;
+            }
+        }
+    });
+    receiver.into_iter().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Rows come through in order over many chunks, and nothing is read past an error
+    fn stops_after_error() {
+        let read = std::sync::atomic::AtomicUsize::new(0);
+        let rows = (0..5_000).map(|n| {
+            read.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            match n {
+                3_000 => Err(anyhow::anyhow!("line {}: broken", n)),
+                n => Ok(n),
+            }
+        });
+        let received: Vec<_> = std::thread::scope(|scope| read_ahead(scope, rows, 100).collect());
+        assert_eq!(received.len(), 3_001);
+        assert!(received[..3_000]
+            .iter()
+            .zip(0..)
+            .all(|(row, n)| *row.as_ref().unwrap() == n));
+        assert_eq!(
+            received[3_000].as_ref().unwrap_err().to_string(),
+            "line 3000: broken"
+        );
+        assert_eq!(read.load(std::sync::atomic::Ordering::Relaxed), 3_001);
+    }
+}
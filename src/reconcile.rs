@@ -0,0 +1,103 @@
+use crate::account::AccountState;
+use crate::report_diff::{Report, Row};
+use crate::system::ReportOptions;
+use crate::transaction::ClientId;
+use serde::Serialize;
+use std::fmt;
+use std::io::Read;
+
+/// A way in which the computed accounts, or a report, differ from the expected report, see
+/// [Reconciler] and [Report::diff]. Serializes as an object tagged with its `kind`, for
+/// `track diff --format json`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Mismatch {
+    /// The expected report has the client, the computed one doesn't.
+    Missing { client: ClientId },
+    /// The computed report has the client, the expected one doesn't.
+    Unexpected { client: ClientId },
+    /// Both have the client, but one of its columns differs.
+    Field {
+        client: ClientId,
+        field: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Mismatch {
+    pub fn client(&self) -> ClientId {
+        match self {
+            Mismatch::Missing { client } | Mismatch::Unexpected { client } => *client,
+            Mismatch::Field { client, .. } => *client,
+        }
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mismatch::Missing { client } => {
+                write!(f, "client {}: expected, but not reported", client)
+            }
+            Mismatch::Unexpected { client } => {
+                write!(f, "client {}: reported, but not expected", client)
+            }
+            Mismatch::Field {
+                client,
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "client {}: {} expected {}, got {}",
+                client, field, expected, actual
+            ),
+        }
+    }
+}
+
+/// Compares accounts against a known-good report, one account at a time, so that accounts can
+/// be checked as they are written. Only the implicit currency is compared.
+pub struct Reconciler {
+    expected: Report,
+    /// See [ReportOptions::suppress_empty], the only option that matters when comparing.
+    suppress_empty: bool,
+    mismatches: Vec<Mismatch>,
+}
+
+impl Reconciler {
+    /// Reads the expected report, in CSV as [crate::process] writes it or in JSON, see
+    /// [Report::parse]. Accounts the [ReportOptions] leave out of the report count as not
+    /// reported.
+    pub fn new<R: Read>(mut expected: R, options: ReportOptions) -> anyhow::Result<Self> {
+        let mut text = String::new();
+        expected.read_to_string(&mut text)?;
+        Ok(Reconciler {
+            expected: Report::parse(&text)?,
+            suppress_empty: options.suppress_empty,
+            mismatches: Vec::new(),
+        })
+    }
+
+    /// Compares a computed account against what was expected for the client.
+    pub fn check(&mut self, client: ClientId, account: &AccountState) {
+        if self.suppress_empty && account.is_empty() {
+            return;
+        }
+        match self.expected.remove(client) {
+            Some(expected) => expected.compare(client, &Row::of(account), &mut self.mismatches),
+            None => self.mismatches.push(Mismatch::Unexpected { client }),
+        }
+    }
+
+    /// Every mismatch found, by client. Expected clients that were never checked are missing.
+    pub fn finish(mut self) -> Vec<Mismatch> {
+        let missing = self.expected.into_clients();
+        self.mismatches
+            .extend(missing.map(|client| Mismatch::Missing { client }));
+        // Stable, so the fields of a client stay in column order.
+        self.mismatches.sort_by_key(Mismatch::client);
+        self.mismatches
+    }
+}
@@ -0,0 +1,102 @@
+use crate::reporter::Reporter;
+use crate::sink::Sink;
+use crate::summary::IgnoredRecord;
+use crate::transaction::ClientId;
+use csv::Writer;
+use serde::Serialize;
+use std::fmt::Display;
+
+#[derive(Serialize)]
+struct RejectRecord<'a> {
+    level: &'a str,
+    client: ClientId,
+    tx: u32,
+    reason: &'a str,
+    /// Where the row starts in the input, counting from 1 like editors do.
+    line: u64,
+}
+
+/// Everything that did not go as expected for a transaction is reported here. Reports go to
+/// the [Reporter] as warnings, and additionally to a CSV file when one was asked for, so that
+/// they can be processed further without scraping logs. The file gets them whatever the
+/// verbosity.
+pub struct Rejects {
+    file: Option<Writer<Sink>>,
+    reporter: Reporter,
+}
+
+impl Rejects {
+    /// Writes reports to the reporter and, if a sink is given, as CSV to that sink.
+    pub fn new(file: Option<Sink>, reporter: Reporter) -> Self {
+        Rejects {
+            file: file.map(Writer::from_writer),
+            reporter,
+        }
+    }
+
+    /// The transaction was still applied, but someone should have a look at it.
+    pub fn warn(
+        &mut self,
+        line: u64,
+        client: ClientId,
+        tx: u32,
+        reason: impl Display,
+    ) -> csv::Result<()> {
+        self.report("warning", line, client, tx, &reason.to_string())
+    }
+
+    /// The engine ignored the transaction. That's routine, so it only shows with `-vv` and
+    /// never makes it to the file.
+    pub fn ignore(&mut self, record: &IgnoredRecord) {
+        self.reporter.debug(format_args!(
+            "ignored: client {}, tx {}, {}: {}",
+            record.client, record.tx, record.kind, record.reason
+        ));
+    }
+
+    /// The transaction was refused before it got to the engine.
+    pub fn reject(
+        &mut self,
+        line: u64,
+        client: ClientId,
+        tx: u32,
+        reason: impl Display,
+    ) -> csv::Result<()> {
+        self.report("rejected", line, client, tx, &reason.to_string())
+    }
+
+    fn report(
+        &mut self,
+        level: &str,
+        line: u64,
+        client: ClientId,
+        tx: u32,
+        reason: &str,
+    ) -> csv::Result<()> {
+        self.reporter.info(format_args!(
+            "{}: line {}, client {}, tx {}: {}",
+            level, line, client, tx, reason
+        ));
+        if let Some(file) = self.file.as_mut() {
+            file.serialize(RejectRecord {
+                level,
+                client,
+                tx,
+                reason,
+                line,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Writes out what's buffered and completes the file, see [Sink::finish].
+    pub fn finish(self) -> std::io::Result<()> {
+        match self.file {
+            Some(file) => file
+                .into_inner()
+                .map_err(|error| std::io::Error::new(error.error().kind(), error.to_string()))?
+                .finish(),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::transaction::Transaction;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Position of an event in an [EventLog], counting from zero.
+#[derive(Debug, Default, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Offset(pub u64);
+
+/// Treating the stream of transactions as the source of truth means we can throw the account
+/// states away at any point and rebuild them by replaying the log, see
+/// [crate::system::ShardedAccountSystem::replay_log]. This only works because applying a
+/// transaction depends on nothing but the current state and the transaction itself.
+pub trait EventLog {
+    /// Adds a transaction to the end of the log and returns its offset.
+    fn append(&mut self, transaction: &Transaction) -> anyhow::Result<Offset>;
+
+    /// Iterates over the transactions in the order they were appended, starting at `from`.
+    fn iter_from(
+        &mut self,
+        from: Offset,
+    ) -> anyhow::Result<Box<dyn Iterator<Item = anyhow::Result<Transaction>> + '_>>;
+
+    /// Makes sure everything appended so far has reached the underlying storage.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the events in a vector. Handy for tests and short-lived systems.
+#[derive(Debug, Default)]
+pub struct MemoryLog {
+    events: Vec<Transaction>,
+}
+
+impl MemoryLog {
+    pub fn new() -> Self {
+        MemoryLog { events: Vec::new() }
+    }
+}
+
+impl EventLog for MemoryLog {
+    fn append(&mut self, transaction: &Transaction) -> anyhow::Result<Offset> {
+        self.events.push(transaction.clone());
+        Ok(Offset(self.events.len() as u64 - 1))
+    }
+
+    fn iter_from(
+        &mut self,
+        from: Offset,
+    ) -> anyhow::Result<Box<dyn Iterator<Item = anyhow::Result<Transaction>> + '_>> {
+        Ok(Box::new(
+            self.events.iter().skip(from.0 as usize).cloned().map(Ok),
+        ))
+    }
+}
+
+/// Stores one JSON encoded transaction per line (NDJSON). Amounts are written as strings so
+/// that nothing gets lost to floating point on the way through.
+pub struct FileLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    len: u64,
+}
+
+impl FileLog {
+    /// Opens the log at `path`, creating it if needed. New events are appended after the
+    /// existing ones.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let len = BufReader::new(File::open(&path)?).lines().count() as u64;
+        Ok(FileLog {
+            path,
+            writer: BufWriter::new(file),
+            len,
+        })
+    }
+}
+
+impl EventLog for FileLog {
+    fn append(&mut self, transaction: &Transaction) -> anyhow::Result<Offset> {
+        serde_json::to_writer(&mut self.writer, transaction)?;
+        self.writer.write_all(b"\n")?;
+        self.len += 1;
+        Ok(Offset(self.len - 1))
+    }
+
+    fn iter_from(
+        &mut self,
+        from: Offset,
+    ) -> anyhow::Result<Box<dyn Iterator<Item = anyhow::Result<Transaction>> + '_>> {
+        self.flush()?;
+        let reader = BufReader::new(File::open(&self.path)?);
+        Ok(Box::new(
+            reader
+                .lines()
+                .skip(from.0 as usize)
+                .map(|line| Ok(serde_json::from_str(&line?)?)),
+        ))
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flush::{FlushPolicy, ReportWriter};
+    use crate::system::{ReportOptions, ShardedAccountSystem};
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+
+    fn transactions() -> Vec<Transaction> {
+        let mut transactions = Vec::new();
+        for (client, tx) in (0..20).zip((0..).step_by(10)) {
+            transactions.push(Transaction::Deposit {
+                client,
+                tx,
+                amount: Decimal::from_str("10.1234").unwrap(),
+                currency: None,
+                timestamp: Some(tx as u64),
+            });
+            transactions.push(Transaction::Withdrawal {
+                client,
+                tx: tx + 1,
+                amount: Decimal::from(3),
+                currency: None,
+                timestamp: None,
+            });
+            if client % 3 == 0 {
+                transactions.push(Transaction::Dispute {
+                    client,
+                    tx,
+                    timestamp: None,
+                });
+            }
+            if client % 6 == 0 {
+                transactions.push(Transaction::Chargeback {
+                    client,
+                    tx,
+                    amount: None,
+                    timestamp: None,
+                });
+            }
+        }
+        transactions
+    }
+
+    fn snapshot(system: &ShardedAccountSystem) -> Vec<u8> {
+        let mut writer = ReportWriter::new(Vec::new(), FlushPolicy::default());
+        let options = ReportOptions {
+            extended: true,
+            ..ReportOptions::default()
+        };
+        system.write(&mut writer, options).unwrap();
+        writer.into_inner().unwrap()
+    }
+
+    fn replayed(log: &mut impl EventLog) -> ShardedAccountSystem {
+        let mut system = ShardedAccountSystem::new(2);
+        system.replay_log(log, Offset(0)).unwrap();
+        system
+    }
+
+    #[test]
+    /// Replaying a log twice gives exactly the same accounts and report
+    fn replay_is_deterministic() {
+        let mut log = MemoryLog::new();
+        let mut original = ShardedAccountSystem::new(2);
+        for transaction in transactions() {
+            log.append(&transaction).unwrap();
+            original.transact(transaction);
+        }
+
+        let first = replayed(&mut log);
+        let second = replayed(&mut log);
+        assert_eq!(snapshot(&first), snapshot(&second));
+        assert_eq!(snapshot(&first), snapshot(&original));
+        for client in 0..20 {
+            assert_eq!(first.account(client), original.account(client));
+        }
+    }
+
+    #[test]
+    /// The file log hands back exactly what was appended, also across reopening
+    fn file_log_round_trip() {
+        let path = std::env::temp_dir().join(format!("track-events-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let transactions = transactions();
+        let (head, tail) = transactions.split_at(10);
+        {
+            let mut log = FileLog::open(&path).unwrap();
+            for transaction in head {
+                log.append(transaction).unwrap();
+            }
+        }
+        let mut log = FileLog::open(&path).unwrap();
+        for transaction in tail {
+            log.append(transaction).unwrap();
+        }
+        let read = log
+            .iter_from(Offset(0))
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read, transactions);
+
+        let mut system = ShardedAccountSystem::new(2);
+        let next = system.replay_log(&mut log, Offset(10)).unwrap();
+        assert_eq!(next, Offset(transactions.len() as u64));
+        assert!(system.account(0).is_none());
+
+        assert_eq!(snapshot(&replayed(&mut log)), snapshot(&replayed(&mut log)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,399 @@
+//! Comparing two account reports, e.g. those of a run before and after changing the engine,
+//! see [Report::diff]. Reports are CSV, as [crate::process] writes them, or JSON.
+
+use crate::account::AccountState;
+use crate::reconcile::Mismatch;
+use crate::transaction::ClientId;
+use anyhow::{anyhow, bail};
+use csv::StringRecord;
+use rust_decimal::Decimal;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// How far apart, relative to their size, two amounts may be and still count as the same when
+/// one of them went through an `f64`. A few dozen ulps, so only rounding is forgiven.
+const TOLERANCE: Decimal = Decimal::from_parts(1, 0, 0, false, 14);
+
+/// The most significant digits an `f64` gives back unchanged. Writing a decimal with no more
+/// than these as a float and reading it back gives the same decimal.
+const FLOAT_DIGITS: usize = 15;
+
+/// An amount as a report gives it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Amount {
+    value: Decimal,
+    /// Whether the amount is written as it is. One that went through an `f64` on the way, as
+    /// the amounts of the report do, may be off in the last digits.
+    exact: bool,
+}
+
+impl Amount {
+    /// Reads a CSV field. The report writes amounts as floats, but a float that reads the same
+    /// as the decimal it came from is as good as the decimal. Only one with more significant
+    /// digits than that, or in scientific notation, may have lost something.
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let text = text.trim();
+        let digits = text
+            .chars()
+            .filter(char::is_ascii_digit)
+            .skip_while(|digit| *digit == '0')
+            .count();
+        let scientific = text.contains(['e', 'E']);
+        Ok(Amount {
+            value: decimal(text)?,
+            exact: !scientific && digits <= FLOAT_DIGITS,
+        })
+    }
+
+    /// Reads a JSON value: a string is a decimal as it is, a number went through an `f64`.
+    fn from_json(value: &Value) -> anyhow::Result<Self> {
+        match value {
+            Value::String(text) => Ok(Amount {
+                value: decimal(text)?,
+                exact: true,
+            }),
+            Value::Number(number) => Ok(Amount {
+                value: decimal(&number.to_string())?,
+                exact: false,
+            }),
+            _ => bail!("{} is not an amount", value),
+        }
+    }
+
+    /// Exact amounts are the same if they're equal, others if they're within [TOLERANCE] of
+    /// each other.
+    fn matches(&self, other: &Amount) -> bool {
+        if self.exact && other.exact {
+            return self.value == other.value;
+        }
+        let Some(difference) = self.value.checked_sub(other.value) else {
+            return false;
+        };
+        difference.abs() <= self.value.abs().max(other.value.abs()) * TOLERANCE
+    }
+}
+
+fn decimal(text: &str) -> anyhow::Result<Decimal> {
+    let parsed = match text.contains(['e', 'E']) {
+        true => Decimal::from_scientific(text),
+        false => Decimal::from_str(text),
+    };
+    parsed.map_err(|_| anyhow!("{} is not an amount", text))
+}
+
+/// The columns of a report that are compared. Any others, e.g. those of the extended report,
+/// are ignored.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Row {
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+impl Row {
+    /// The row the report would have for `account`, with its amounts as they are.
+    pub(crate) fn of(account: &AccountState) -> Self {
+        let exact = |value| Amount { value, exact: true };
+        Row {
+            available: exact(account.available()),
+            held: exact(account.held),
+            total: exact(account.total),
+            locked: account.locked(),
+        }
+    }
+
+    /// Adds every column in which `actual` differs from this row to `mismatches`, in the order
+    /// of the report.
+    pub(crate) fn compare(&self, client: ClientId, actual: &Row, mismatches: &mut Vec<Mismatch>) {
+        let amounts = [
+            ("available", self.available, actual.available),
+            ("held", self.held, actual.held),
+            ("total", self.total, actual.total),
+        ];
+        for (field, expected, actual) in amounts {
+            if !expected.matches(&actual) {
+                mismatches.push(Mismatch::Field {
+                    client,
+                    field,
+                    expected: expected.value.normalize().to_string(),
+                    actual: actual.value.normalize().to_string(),
+                });
+            }
+        }
+        if self.locked != actual.locked {
+            mismatches.push(Mismatch::Field {
+                client,
+                field: "locked",
+                expected: self.locked.to_string(),
+                actual: actual.locked.to_string(),
+            });
+        }
+    }
+}
+
+/// The accounts of a report, by client. Only the implicit currency is compared, rows of the
+/// report for other currencies are left out.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Report {
+    rows: BTreeMap<ClientId, Row>,
+}
+
+impl Report {
+    /// Reads a report in CSV, or in JSON if it starts like JSON does: either an array of
+    /// objects, or objects one after the other as in NDJSON.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        match text.trim_start().starts_with(['[', '{']) {
+            true => Self::from_json(text),
+            false => Self::from_csv(text),
+        }
+    }
+
+    /// Reads a report in CSV. A table following an empty line, like the pending scheduled
+    /// transactions, isn't part of it.
+    fn from_csv(text: &str) -> anyhow::Result<Self> {
+        let table = text.split("\n\n").next().unwrap_or_default();
+        let mut rdr = csv::Reader::from_reader(table.as_bytes());
+        let headers = rdr.headers()?.clone();
+        let column = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header.trim() == name)
+                .ok_or_else(|| anyhow!("The report has no {} column", name))
+        };
+        let [client, available, held, total, locked] =
+            ["client", "available", "held", "total", "locked"].map(column);
+        let (client, available, held, total, locked) =
+            (client?, available?, held?, total?, locked?);
+        let currency = headers
+            .iter()
+            .position(|header| header.trim() == "currency");
+        let parse = |record: &StringRecord| -> anyhow::Result<Option<(ClientId, Row)>> {
+            let field = |index: usize| record.get(index).unwrap_or_default().trim();
+            if currency.is_some_and(|index| !field(index).is_empty()) {
+                return Ok(None);
+            }
+            let row = Row {
+                available: Amount::parse(field(available))?,
+                held: Amount::parse(field(held))?,
+                total: Amount::parse(field(total))?,
+                locked: field(locked).parse()?,
+            };
+            Ok(Some((field(client).parse()?, row)))
+        };
+        let mut report = Report::default();
+        for (line, record) in rdr.records().enumerate() {
+            let row = parse(&record?).map_err(|error| anyhow!("line {}: {}", line + 2, error))?;
+            if let Some(row) = row {
+                report.insert(row)?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Reads a report in JSON, where amounts are either numbers or strings.
+    fn from_json(text: &str) -> anyhow::Result<Self> {
+        let mut objects = Vec::new();
+        for value in serde_json::Deserializer::from_str(text).into_iter::<Value>() {
+            match value? {
+                Value::Array(values) => objects.extend(values),
+                value => objects.push(value),
+            }
+        }
+        let mut report = Report::default();
+        for object in objects {
+            let field = |name: &str| {
+                object
+                    .get(name)
+                    .ok_or_else(|| anyhow!("{} has no {}", object, name))
+            };
+            if object
+                .get("currency")
+                .is_some_and(|currency| !currency.is_null() && currency != "")
+            {
+                continue;
+            }
+            let client = field("client")?;
+            let client: ClientId = serde_json::from_value(client.clone())
+                .map_err(|_| anyhow!("{} is not a client id", client))?;
+            let row = Row {
+                available: Amount::from_json(field("available")?)?,
+                held: Amount::from_json(field("held")?)?,
+                total: Amount::from_json(field("total")?)?,
+                locked: field("locked")?
+                    .as_bool()
+                    .ok_or_else(|| anyhow!("{} has no locked true or false", object))?,
+            };
+            report.insert((client, row))?;
+        }
+        Ok(report)
+    }
+
+    fn insert(&mut self, (client, row): (ClientId, Row)) -> anyhow::Result<()> {
+        if self.rows.insert(client, row).is_some() {
+            bail!("The report lists client {} more than once", client);
+        }
+        Ok(())
+    }
+
+    /// Number of accounts in the report.
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Takes the row of `client` out of the report.
+    pub(crate) fn remove(&mut self, client: ClientId) -> Option<Row> {
+        self.rows.remove(&client)
+    }
+
+    /// The clients in the report, in order.
+    pub(crate) fn into_clients(self) -> impl Iterator<Item = ClientId> {
+        self.rows.into_keys()
+    }
+
+    /// Every way in which `new` differs from this report, by client, and the columns of a
+    /// client in the order of the report. This report is what's expected, `new` the actual
+    /// accounts.
+    pub fn diff(&self, new: &Report) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        let mut clients: Vec<&ClientId> = self.rows.keys().chain(new.rows.keys()).collect();
+        clients.sort_unstable();
+        clients.dedup();
+        for client in clients.into_iter().copied() {
+            match (self.rows.get(&client), new.rows.get(&client)) {
+                (Some(old), Some(new)) => old.compare(client, new, &mut mismatches),
+                (Some(_), None) => mismatches.push(Mismatch::Missing { client }),
+                _ => mismatches.push(Mismatch::Unexpected { client }),
+            }
+        }
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSV: &str = "\
+client,available,held,total,locked
+1,1.5,0.0,1.5,false
+2,0.3,0.1,0.4,true
+";
+
+    fn diff(old: &str, new: &str) -> Vec<Mismatch> {
+        Report::parse(old)
+            .unwrap()
+            .diff(&Report::parse(new).unwrap())
+    }
+
+    #[test]
+    /// The same accounts make no difference, whether in CSV, a JSON array or NDJSON, with
+    /// amounts as floats off by rounding or as decimal strings, and with extra columns
+    fn identical() {
+        let json = r#"[
+            {"client": 2, "available": 0.30000000000000004, "held": 0.1, "total": 0.4, "locked": true},
+            {"client": 1, "available": "1.50", "held": "0", "total": "1.5", "locked": false}
+        ]"#;
+        let ndjson = concat!(
+            r#"{"client":1,"available":1.5,"held":0,"total":1.5,"locked":false}"#,
+            "\n",
+            r#"{"client":2,"available":0.3,"held":0.1,"total":0.4,"locked":true}"#,
+            "\n",
+        );
+        let extended = "\
+client,available,held,total,locked,closed,currency
+1,1.5,0.0,1.5,false,false,
+1,9.0,0.0,9.0,false,false,EUR
+2,3e-1,0.1,0.4,true,false,
+
+effective,type,client,tx,amount
+5,deposit,3,9,1.0
+";
+        for other in [CSV, json, ndjson, extended] {
+            assert_eq!(diff(CSV, other), []);
+            assert_eq!(diff(other, CSV), []);
+        }
+        assert_eq!(Report::parse(extended).unwrap().len(), 2);
+    }
+
+    #[test]
+    /// Every column that differs is a difference of its own. Decimal strings are compared
+    /// exactly, floats with enough digits to have been rounded with a tolerance
+    fn differing() {
+        let new = "\
+client,available,held,total,locked
+1,1.5001,0.0,1.5001,false
+2,0.3,0.1,0.4,false
+";
+        assert_eq!(
+            diff(CSV, new),
+            [
+                Mismatch::Field {
+                    client: 1,
+                    field: "available",
+                    expected: "1.5".to_string(),
+                    actual: "1.5001".to_string(),
+                },
+                Mismatch::Field {
+                    client: 1,
+                    field: "total",
+                    expected: "1.5".to_string(),
+                    actual: "1.5001".to_string(),
+                },
+                Mismatch::Field {
+                    client: 2,
+                    field: "locked",
+                    expected: "true".to_string(),
+                    actual: "false".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            diff(CSV, new)[0].to_string(),
+            "client 1: available expected 1.5, got 1.5001"
+        );
+
+        // The float lost the last digits of the exact amount, which is only rounding.
+        let exact = r#"{"client":1,"available":"12345678901234.5678","held":"0","total":"12345678901234.5678","locked":false}"#;
+        let float =
+            "client,available,held,total,locked\n1,12345678901234.568,0,12345678901234.568,false\n";
+        assert_eq!(diff(exact, float), []);
+        // Both exact, the same digits aren't enough.
+        let rounded = r#"{"client":1,"available":"12345678901234.568","held":"0","total":"12345678901234.568","locked":false}"#;
+        assert_eq!(diff(exact, rounded).len(), 2);
+    }
+
+    #[test]
+    /// Clients in only one of the reports are differences, listed with the others in client
+    /// order
+    fn disjoint_clients() {
+        let new = "\
+client,available,held,total,locked
+2,0.3,0.1,0.4,true
+3,0,0,0,false
+";
+        let differences = diff(CSV, new);
+        assert_eq!(
+            differences,
+            [
+                Mismatch::Missing { client: 1 },
+                Mismatch::Unexpected { client: 3 },
+            ]
+        );
+        assert_eq!(
+            differences[1].to_string(),
+            "client 3: reported, but not expected"
+        );
+        assert_eq!(
+            serde_json::to_string(&differences[0]).unwrap(),
+            r#"{"kind":"missing","client":1}"#
+        );
+        assert!(Report::parse("client,available\n1,2\n").is_err());
+        assert!(Report::parse(&format!("{}1,0,0,0,false\n", CSV)).is_err());
+    }
+}
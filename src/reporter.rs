@@ -0,0 +1,147 @@
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// How much of what a run has to say ends up on stderr, see [Reporter].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    /// Nothing at all. Errors still make the run fail.
+    Quiet,
+    /// Warnings, rejected transactions and whatever was asked for, like the summary.
+    #[default]
+    Normal,
+    /// The summary as well, asked for or not.
+    Verbose,
+    /// Every ignored transaction on top.
+    Debug,
+}
+
+impl Verbosity {
+    /// One level up, for every `-v`. There is nothing beyond [Verbosity::Debug].
+    pub fn louder(self) -> Self {
+        match self {
+            Verbosity::Quiet | Verbosity::Normal => Verbosity::Verbose,
+            Verbosity::Verbose | Verbosity::Debug => Verbosity::Debug,
+        }
+    }
+}
+
+/// The one place human-facing output goes through. The report is data and goes to stdout,
+/// everything else comes here and goes to stderr, as far as the [Verbosity] lets it.
+///
+/// Cloning is cheap, clones share the same writer so their lines don't interleave.
+#[derive(Clone)]
+pub struct Reporter {
+    verbosity: Verbosity,
+    writer: Arc<Mutex<dyn Write + Send>>,
+}
+
+impl Default for Reporter {
+    fn default() -> Self {
+        Self::new(Verbosity::default())
+    }
+}
+
+impl Reporter {
+    /// Writes to stderr.
+    pub fn new(verbosity: Verbosity) -> Self {
+        Self::with_writer(verbosity, io::stderr())
+    }
+
+    pub fn with_writer(verbosity: Verbosity, writer: impl Write + Send + 'static) -> Self {
+        Reporter {
+            verbosity,
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        self.verbosity
+    }
+
+    /// Something the user should know about, prefixed with `warning: `.
+    pub fn warn(&self, message: impl Display) {
+        self.write(Verbosity::Normal, format_args!("warning: {}", message));
+    }
+
+    /// Something the user asked for.
+    pub fn info(&self, message: impl Display) {
+        self.write(Verbosity::Normal, message);
+    }
+
+    /// Only shown with `-v` or more.
+    pub fn verbose(&self, message: impl Display) {
+        self.write(Verbosity::Verbose, message);
+    }
+
+    /// Only shown with `-vv`.
+    pub fn debug(&self, message: impl Display) {
+        self.write(Verbosity::Debug, message);
+    }
+
+    /// Whether messages at `level` are shown, for callers that would rather not build a
+    /// message that goes nowhere.
+    pub fn enabled(&self, level: Verbosity) -> bool {
+        level <= self.verbosity
+    }
+
+    /// A failure to write to stderr is nothing we could report anywhere, so it's dropped.
+    fn write(&self, level: Verbosity, message: impl Display) {
+        if self.enabled(level) {
+            let _ = writeln!(self.writer.lock().unwrap(), "{}", message);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A writer the test can read back from.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    /// Every level shows its own messages and those of the levels below
+    fn levels() {
+        let levels = [
+            Verbosity::Quiet,
+            Verbosity::Normal,
+            Verbosity::Verbose,
+            Verbosity::Debug,
+        ];
+        let shown: Vec<String> = levels
+            .into_iter()
+            .map(|verbosity| {
+                let buffer = Buffer::default();
+                let reporter = Reporter::with_writer(verbosity, buffer.clone());
+                reporter.warn("w");
+                reporter.info("i");
+                reporter.verbose("v");
+                reporter.debug("d");
+                let output = buffer.0.lock().unwrap().clone();
+                String::from_utf8(output).unwrap()
+            })
+            .collect();
+        assert_eq!(
+            shown,
+            [
+                "",
+                "warning: w\ni\n",
+                "warning: w\ni\nv\n",
+                "warning: w\ni\nv\nd\n"
+            ]
+        );
+        assert_eq!(Verbosity::Normal.louder().louder(), Verbosity::Debug);
+    }
+}
@@ -0,0 +1,96 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Stdout, Write};
+
+/// How a file is compressed.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Needs the `gzip` feature.
+    Gzip,
+}
+
+impl Compression {
+    /// What a file is compressed with, going by its extension: `.gz` files are gzipped.
+    pub fn detect(path: &str) -> Self {
+        match path.ends_with(".gz") {
+            true => Compression::Gzip,
+            false => Compression::None,
+        }
+    }
+}
+
+/// Where the report or a side file (rejects, audit log) goes: stdout, or a file that may be
+/// compressed. Compression is the sink's business, so whatever writes to one doesn't have to
+/// care.
+///
+/// A compressed file is only complete once [Sink::finish] wrote the end of the stream.
+pub struct Sink {
+    kind: Kind,
+}
+
+enum Kind {
+    Stdout(Stdout),
+    File(BufWriter<File>),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<BufWriter<File>>),
+}
+
+impl Sink {
+    pub fn stdout() -> Self {
+        Sink {
+            kind: Kind::Stdout(io::stdout()),
+        }
+    }
+
+    /// Creates the file at `path`, compressed as `compression` says, or as its extension
+    /// suggests if that's `None`, see [Compression::detect].
+    pub fn create(path: &str, compression: Option<Compression>) -> anyhow::Result<Self> {
+        let compression = compression.unwrap_or_else(|| Compression::detect(path));
+        let file = BufWriter::new(File::create(path)?);
+        let kind = match compression {
+            Compression::None => Kind::File(file),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Kind::Gzip(flate2::write::GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )),
+            #[cfg(not(feature = "gzip"))]
+            Compression::Gzip => anyhow::bail!(
+                "Can't compress {}, track needs to be built with the gzip feature for that",
+                path
+            ),
+        };
+        Ok(Sink { kind })
+    }
+
+    /// Writes whatever is buffered and, for a compressed file, the end of the stream.
+    pub fn finish(self) -> io::Result<()> {
+        match self.kind {
+            Kind::Stdout(mut stdout) => stdout.flush(),
+            Kind::File(mut file) => file.flush(),
+            #[cfg(feature = "gzip")]
+            Kind::Gzip(gzip) => gzip.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.kind {
+            Kind::Stdout(stdout) => stdout.write(buf),
+            Kind::File(file) => file.write(buf),
+            #[cfg(feature = "gzip")]
+            Kind::Gzip(gzip) => gzip.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.kind {
+            Kind::Stdout(stdout) => stdout.flush(),
+            Kind::File(file) => file.flush(),
+            #[cfg(feature = "gzip")]
+            Kind::Gzip(gzip) => gzip.flush(),
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::sink::Compression;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+
+/// Where the transactions are read from: a file or, with the `http` feature, an `http(s)://`
+/// URL, either of which may be gzipped. The counterpart of [crate::sink::Sink], so whatever
+/// reads the input gets plain CSV either way.
+pub struct Source {
+    reader: Box<dyn Read + Send>,
+}
+
+/// How the input is read, see [Source::open].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SourceOptions {
+    /// Decompress the input whatever its name, rather than only one ending in `.gz`.
+    pub gzip: bool,
+    /// Extra headers sent along when the input is a URL, e.g. for an auth token.
+    pub headers: Vec<(String, String)>,
+}
+
+impl Source {
+    /// Opens the file at `path`, or fetches it if it's an `http://` or `https://` URL. It's
+    /// decompressed if [SourceOptions::gzip] says so or its name ends in `.gz`, see
+    /// [Compression::detect]. A server that gzips the response on the way is taken care of
+    /// regardless.
+    pub fn open(path: &str, options: &SourceOptions) -> anyhow::Result<Self> {
+        let (reader, name): (Box<dyn BufRead + Send>, &str) = match is_url(path) {
+            true => (fetch(path, &options.headers)?, url_path(path)),
+            false => (Box::new(BufReader::new(File::open(path)?)), path),
+        };
+        let compression = match options.gzip {
+            true => Compression::Gzip,
+            false => Compression::detect(name),
+        };
+        let reader: Box<dyn Read + Send> = match compression {
+            Compression::None => reader,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Box::new(flate2::bufread::MultiGzDecoder::new(reader)),
+            #[cfg(not(feature = "gzip"))]
+            Compression::Gzip => anyhow::bail!(
+                "Can't decompress {}, track needs to be built with the gzip feature for that",
+                path
+            ),
+        };
+        Ok(Source { reader })
+    }
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// The part of a URL that tells what it points to, without the query or fragment.
+fn url_path(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
+
+/// Streams the body of a GET, following redirects. Anything but a 2xx fails with the status
+/// and the start of what the server said.
+#[cfg(feature = "http")]
+fn fetch(url: &str, headers: &[(String, String)]) -> anyhow::Result<Box<dyn BufRead + Send>> {
+    let mut request = ureq::get(url);
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    match request.call() {
+        Ok(response) => Ok(Box::new(BufReader::new(response.into_reader()))),
+        Err(ureq::Error::Status(status, response)) => {
+            let text = response.status_text().to_string();
+            let mut body = String::new();
+            // Whatever the body is, the status is what matters, so a body that isn't text
+            // doesn't get in the way of reporting it.
+            let _ = response.into_reader().take(200).read_to_string(&mut body);
+            anyhow::bail!("{} answered {} {}: {}", url, status, text, body.trim())
+        }
+        Err(error) => Err(anyhow::anyhow!("Can't fetch {}: {}", url, error)),
+    }
+}
+
+/// Without the `http` feature there is nothing to fetch a URL with.
+#[cfg(not(feature = "http"))]
+fn fetch(url: &str, _headers: &[(String, String)]) -> anyhow::Result<Box<dyn BufRead + Send>> {
+    anyhow::bail!(
+        "Can't fetch {}, track needs to be built with the http feature for that",
+        url
+    )
+}
+
+#[cfg(all(test, feature = "http", feature = "gzip"))]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    const CSV: &str = "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,2.5\n";
+
+    fn gzipped() -> Vec<u8> {
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(CSV.as_bytes()).unwrap();
+        gzip.finish().unwrap()
+    }
+
+    /// A tiny HTTP server that answers `requests` requests by their path and hands back the
+    /// head of each request it got.
+    fn server(requests: usize) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut heads = Vec::new();
+            for _ in 0..requests {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim_end().is_empty() {
+                        break;
+                    }
+                    head.push_str(&line);
+                }
+                let path = head.split(' ').nth(1).unwrap().to_string();
+                let (status, headers, body) = match path.as_str() {
+                    "/plain.csv" => ("200 OK", "", CSV.as_bytes().to_vec()),
+                    "/encoded.csv" => ("200 OK", "Content-Encoding: gzip\r\n", gzipped()),
+                    "/data.csv.gz?version=2" => ("200 OK", "", gzipped()),
+                    "/moved" => ("302 Found", "Location: /plain.csv\r\n", Vec::new()),
+                    _ => ("404 Not Found", "", b"no such file".to_vec()),
+                };
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    headers,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+                heads.push(head);
+            }
+            heads
+        });
+        (url, handle)
+    }
+
+    fn read(path: &str, options: &SourceOptions) -> String {
+        let mut text = String::new();
+        Source::open(path, options)
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    }
+
+    #[test]
+    /// A URL reads like the file it serves, plain, gzipped on the way or gzipped as it is,
+    /// and after a redirect. The headers asked for are sent along
+    fn reads_urls() {
+        let path = std::env::temp_dir().join(format!("track-source-{}.csv", std::process::id()));
+        std::fs::write(&path, CSV).unwrap();
+        let file = read(path.to_str().unwrap(), &SourceOptions::default());
+        std::fs::remove_file(&path).unwrap();
+
+        let (url, server) = server(5);
+        let options = SourceOptions {
+            headers: vec![("X-Api-Key".to_string(), "secret".to_string())],
+            ..SourceOptions::default()
+        };
+        assert_eq!(read(&format!("{}/plain.csv", url), &options), file);
+        assert_eq!(read(&format!("{}/encoded.csv", url), &options), file);
+        assert_eq!(
+            read(&format!("{}/data.csv.gz?version=2", url), &options),
+            file
+        );
+        assert_eq!(read(&format!("{}/moved", url), &options), file);
+        let heads = server.join().unwrap();
+        assert_eq!(heads.len(), 5);
+        assert!(heads
+            .iter()
+            .all(|head| head.contains("x-api-key: secret") || head.contains("X-Api-Key: secret")));
+    }
+
+    #[test]
+    /// A status other than 2xx fails with the status and the body
+    fn fails_on_errors() {
+        let (url, server) = server(1);
+        let error = Source::open(&format!("{}/missing.csv", url), &SourceOptions::default())
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .ends_with("answered 404 Not Found: no such file"));
+        server.join().unwrap();
+    }
+}
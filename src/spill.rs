@@ -0,0 +1,343 @@
+//! Deposits that don't all fit in memory. With the `spill-deposits` feature, accounts keep
+//! their deposits in a [SpillDeposits]: the recent ones in memory, the older ones in a spill
+//! file shared by every account, from where a dispute fetches them back.
+//!
+//! The spill file is an append-only log of fixed-size records, and every store keeps the
+//! offsets of its spilled deposits in memory. A deposit that's fetched back stays in memory,
+//! and its record in the file is dead from then on. The system hands its [SpillFile] to the
+//! stores of its accounts, see [crate::system::AccountSystem::set_spill_file]. A store without
+//! one spills nothing.
+//!
+//! `cargo bench --bench spill --features spill-deposits` measures what looking up a spilled
+//! deposit costs compared to one in memory.
+
+use crate::account::DepositState;
+use crate::currency::Currency;
+use crate::deposits::{DepositStore, InPlace, MapDeposits};
+use rust_decimal::Decimal;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Where spilled deposits go, and how many deposits an account keeps in memory. Shared by the
+/// stores that spill to it.
+#[derive(Debug)]
+pub struct SpillFile {
+    path: PathBuf,
+    /// The file and its length, which is where the next record goes.
+    file: Mutex<(File, u64)>,
+    /// `usize::MAX` for no limit, spilling is then up to [SpillDeposits::spill].
+    threshold: usize,
+    /// The first error reading or writing the file. [DepositStore] has no way of reporting
+    /// one, so it's kept for [SpillFile::take_error].
+    error: Mutex<Option<io::Error>>,
+}
+
+impl SpillFile {
+    /// Spills deposits to the file at `path`, which is created or emptied. Accounts with more
+    /// than `threshold` deposits in memory spill the older half of them, without a threshold
+    /// only [SpillDeposits::spill] does.
+    pub fn create(path: impl AsRef<Path>, threshold: Option<usize>) -> io::Result<Arc<Self>> {
+        let path = path.as_ref();
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Arc::new(SpillFile {
+            path: path.to_path_buf(),
+            file: Mutex::new((file, 0)),
+            threshold: threshold.unwrap_or(usize::MAX),
+            error: Mutex::new(None),
+        }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The first error reading or writing the file since the last call, if any. A deposit
+    /// that couldn't be read back looked like it wasn't there, so the accounts can't be
+    /// trusted after one.
+    pub fn take_error(&self) -> Option<io::Error> {
+        self.error.lock().unwrap().take()
+    }
+
+    /// Keeps `error`, unless there is one already.
+    fn fail(&self, error: io::Error) {
+        let error = io::Error::new(error.kind(), format!("{}: {}", self.path.display(), error));
+        self.error.lock().unwrap().get_or_insert(error);
+    }
+
+    /// Reads the record at `offset` back.
+    fn read(&self, offset: u64) -> io::Result<(u32, DepositState)> {
+        let mut file = self.file.lock().unwrap();
+        let mut record = [0; RECORD];
+        file.0.seek(SeekFrom::Start(offset))?;
+        file.0.read_exact(&mut record)?;
+        Ok(decode(&record))
+    }
+
+    /// Appends the deposits to the file, returning the offset of each.
+    fn write(&self, deposits: &[(u32, DepositState)]) -> io::Result<Vec<u64>> {
+        let mut file = self.file.lock().unwrap();
+        let (handle, len) = &mut *file;
+        let start = *len;
+        let mut records = Vec::with_capacity(deposits.len() * RECORD);
+        for (tx, deposit) in deposits {
+            records.extend_from_slice(&encode(*tx, deposit));
+        }
+        handle.seek(SeekFrom::Start(start))?;
+        handle.write_all(&records)?;
+        *len += records.len() as u64;
+        Ok((0..deposits.len())
+            .map(|index| start + (index * RECORD) as u64)
+            .collect())
+    }
+}
+
+/// The size of a record: tx id, amount, flags, then what may or may not be there.
+const RECORD: usize = 4 + 16 + 1 + 16 + 8 + 3 + 8;
+
+const WITHDRAWAL: u8 = 1;
+const DISPUTE: u8 = 2;
+const CHARGEBACK: u8 = 4;
+const DISPUTED_AT: u8 = 8;
+const CURRENCY: u8 = 16;
+const RESOLVED_AT: u8 = 32;
+
+fn encode(tx: u32, deposit: &DepositState) -> [u8; RECORD] {
+    let flag = |set: bool, flag: u8| if set { flag } else { 0 };
+    let flags = flag(deposit.withdrawal, WITHDRAWAL)
+        | flag(deposit.dispute, DISPUTE)
+        | flag(deposit.chargeback.is_some(), CHARGEBACK)
+        | flag(deposit.disputed_at.is_some(), DISPUTED_AT)
+        | flag(deposit.currency.is_some(), CURRENCY)
+        | flag(deposit.resolved_at.is_some(), RESOLVED_AT);
+    let mut record = [0; RECORD];
+    record[..4].copy_from_slice(&tx.to_le_bytes());
+    record[4..20].copy_from_slice(&deposit.amount.serialize());
+    record[20] = flags;
+    let chargeback = deposit.chargeback.unwrap_or_default();
+    record[21..37].copy_from_slice(&chargeback.serialize());
+    record[37..45].copy_from_slice(&deposit.disputed_at.unwrap_or_default().to_le_bytes());
+    if let Some(currency) = deposit.currency {
+        record[45..48].copy_from_slice(currency.as_str().as_bytes());
+    }
+    record[48..].copy_from_slice(&deposit.resolved_at.unwrap_or_default().to_le_bytes());
+    record
+}
+
+fn decode(record: &[u8; RECORD]) -> (u32, DepositState) {
+    let tx = u32::from_le_bytes(record[..4].try_into().unwrap());
+    let decimal = |bytes: &[u8]| Decimal::deserialize(bytes.try_into().unwrap());
+    let number = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+    let flags = record[20];
+    let set = |flag: u8| flags & flag != 0;
+    let currency = set(CURRENCY).then(|| {
+        let code = std::str::from_utf8(&record[45..48]).expect("spilled currency code");
+        code.parse::<Currency>().expect("spilled currency code")
+    });
+    let deposit = DepositState {
+        amount: decimal(&record[4..20]),
+        withdrawal: set(WITHDRAWAL),
+        dispute: set(DISPUTE),
+        chargeback: set(CHARGEBACK).then(|| decimal(&record[21..37])),
+        disputed_at: set(DISPUTED_AT).then(|| number(&record[37..45])),
+        currency,
+        resolved_at: set(RESOLVED_AT).then(|| number(&record[48..])),
+    };
+    (tx, deposit)
+}
+
+/// Deposits in memory, until there are more than the threshold of its [SpillFile], and then
+/// in the spill file. Deposits under dispute stay in memory, they're about to be looked at
+/// again. Whichever tier a deposit is in, the store behaves the same.
+///
+/// A deposit that can't be read back from the spill file is as good as not there, and the
+/// error is kept in the file, see [SpillFile::take_error]. One that can't be written stays
+/// in memory.
+#[derive(Debug, Default, Clone)]
+pub struct SpillDeposits {
+    hot: MapDeposits,
+    /// Offsets of the spilled deposits in the spill file, by tx id.
+    cold: BTreeMap<u32, u64>,
+    file: Option<Arc<SpillFile>>,
+}
+
+impl SpillDeposits {
+    /// Spills to `file` from now on, unless the store has a spill file already.
+    pub fn attach(&mut self, file: &Arc<SpillFile>) {
+        if self.file.is_none() {
+            self.file = Some(file.clone());
+        }
+    }
+
+    /// How many deposits are in the spill file.
+    pub fn spilled(&self) -> usize {
+        self.cold.len()
+    }
+
+    /// Spills every deposit in memory that isn't under dispute, returning how many. Nothing is
+    /// spilled without a spill file, see [SpillDeposits::attach].
+    pub fn spill(&mut self) -> usize {
+        self.spill_down_to(0)
+    }
+
+    /// Spills the deposits with the lowest tx ids, which are usually the oldest ones, until
+    /// no more than `keep` are left in memory or only disputed ones are.
+    fn spill_down_to(&mut self, keep: usize) -> usize {
+        let Some(file) = self.file.as_ref().filter(|_| self.hot.len() > keep) else {
+            return 0;
+        };
+        let mut cold: Vec<(u32, DepositState)> = self
+            .hot
+            .iter()
+            .filter(|(_, deposit)| !deposit.dispute)
+            .collect();
+        cold.sort_unstable_by_key(|(tx, _)| *tx);
+        cold.truncate(self.hot.len() - keep);
+        let offsets = match file.write(&cold) {
+            Ok(offsets) => offsets,
+            Err(error) => {
+                file.fail(error);
+                return 0;
+            }
+        };
+        for ((tx, _), offset) in cold.iter().zip(offsets) {
+            self.hot.remove(*tx);
+            self.cold.insert(*tx, offset);
+        }
+        cold.len()
+    }
+
+    /// Reads the deposit spilled at `offset`.
+    fn read(&self, offset: u64) -> Option<(u32, DepositState)> {
+        let file = self.file.as_ref()?;
+        file.read(offset).map_err(|error| file.fail(error)).ok()
+    }
+
+    /// Takes a deposit out of the spill file, if it's there and can be read.
+    fn fetch(&mut self, tx: u32) -> Option<DepositState> {
+        let (spilled, deposit) = self.read(*self.cold.get(&tx)?)?;
+        debug_assert_eq!(spilled, tx);
+        self.cold.remove(&tx);
+        Some(deposit)
+    }
+}
+
+/// The same deposits, wherever they're kept.
+impl PartialEq for SpillDeposits {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(tx, deposit)| other.get(tx) == Some(deposit))
+    }
+}
+
+impl DepositStore for SpillDeposits {
+    type Entry<'a> = InPlace<'a>;
+
+    fn get(&self, tx: u32) -> Option<DepositState> {
+        match self.hot.get(tx) {
+            Some(deposit) => Some(deposit),
+            None => self.read(*self.cold.get(&tx)?).map(|(_, deposit)| deposit),
+        }
+    }
+
+    /// A spilled deposit is fetched back into memory, it's about to change.
+    fn get_mut(&mut self, tx: u32) -> Option<InPlace<'_>> {
+        if let Some(deposit) = self.fetch(tx) {
+            self.hot.insert(tx, deposit);
+        }
+        self.hot.get_mut(tx)
+    }
+
+    fn insert(&mut self, tx: u32, deposit: DepositState) -> Option<DepositState> {
+        let spilled = self.fetch(tx);
+        let replaced = self.hot.insert(tx, deposit).or(spilled);
+        let threshold = self.file.as_ref().map_or(usize::MAX, |file| file.threshold);
+        if self.hot.len() > threshold {
+            self.spill_down_to(threshold / 2);
+        }
+        replaced
+    }
+
+    fn remove(&mut self, tx: u32) -> Option<DepositState> {
+        self.hot.remove(tx).or_else(|| self.fetch(tx))
+    }
+
+    fn len(&self) -> usize {
+        self.hot.len() + self.cold.len()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (u32, DepositState)> + '_ {
+        let cold = self.cold.values().filter_map(|offset| self.read(*offset));
+        self.hot.iter().chain(cold)
+    }
+
+    /// The spilled deposits only take their offset in memory.
+    fn memory_footprint(&self) -> usize {
+        self.hot.memory_footprint() + self.cold.len() * size_of::<(u32, u64)>()
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.hot.reserve(additional);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// A record reads back as the deposit that was written, whatever it holds
+    fn records() {
+        let plain = DepositState::new(Decimal::new(12345, 2), None);
+        let mut busy = DepositState::withdrawal(Decimal::MAX, "EUR".parse().ok());
+        busy.dispute = true;
+        busy.chargeback = Some(Decimal::new(-1, 28));
+        busy.disputed_at = Some(0);
+        busy.resolved_at = Some(u64::MAX);
+        for (tx, deposit) in [(0, plain), (u32::MAX, busy)] {
+            assert_eq!(decode(&encode(tx, &deposit)), (tx, deposit));
+        }
+    }
+
+    #[test]
+    /// A spill file that can't be read anymore keeps the error for the system to report, and
+    /// its deposits stay in the store's books rather than vanish
+    fn read_errors() {
+        let path = std::env::temp_dir().join(format!("track-spill-error-{}", std::process::id()));
+        let file = SpillFile::create(&path, None).unwrap();
+        let mut store = SpillDeposits::default();
+        store.attach(&file);
+        let deposit = DepositState::new(Decimal::ONE, None);
+        store.insert(1, deposit);
+        assert_eq!(store.spill(), 1);
+        assert_eq!(store.get(1), Some(deposit));
+        assert!(file.take_error().is_none());
+
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        assert_eq!(store.get(1), None);
+        assert!(store.get_mut(1).is_none());
+        assert_eq!((store.spilled(), store.len()), (1, 1));
+        let error = file.take_error().unwrap();
+        assert!(
+            error.to_string().starts_with(&path.display().to_string()),
+            "{}",
+            error
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
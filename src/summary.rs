@@ -0,0 +1,243 @@
+use crate::account::IgnoreReason;
+use crate::batch::BatchReport;
+use crate::metrics::ShardMetrics;
+use crate::reconcile::Mismatch;
+use crate::transaction::ClientId;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Counters collected while processing a file. `run` hands this back so the caller can decide
+/// whether (and where) to print it.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunSummary {
+    /// Number of input rows that were read.
+    pub rows: u64,
+    /// What came of the transactions, see [BatchReport]. Scheduled transactions are not
+    /// counted.
+    pub applied: u64,
+    pub ignored: u64,
+    pub rejected: u64,
+    /// How many transactions were ignored for every reason. Reasons that didn't come up are
+    /// left out.
+    pub ignore_reasons: BTreeMap<IgnoreReason, u64>,
+    /// The first [crate::config::Config::max_ignored] ignored transactions, in the order they
+    /// were applied.
+    pub ignored_records: Vec<IgnoredRecord>,
+    /// Rows whose amount had more than four decimal places and was rounded or truncated, see
+    /// [crate::config::PrecisionPolicy].
+    pub rounded: u64,
+    /// Rows rejected for the same reason. They are counted as rejected as well.
+    pub too_precise: u64,
+    /// Deposits and withdrawals whose transaction id did not increase, see [crate::ordering].
+    pub tx_order_anomalies: u64,
+    /// Rows of clients whose accounts were finalized already, if the input was expected to be
+    /// sorted by client. They are counted as rejected as well.
+    pub sort_violations: Option<u64>,
+    /// Roughly how many bytes the accounts took at the end, see
+    /// [crate::system::ShardedAccountSystem::memory_footprint].
+    pub memory_footprint: usize,
+    /// Accounts forgotten to stay below [crate::config::Config::max_memory].
+    pub compacted: u64,
+    /// Deposits moved to the spill file to stay below [crate::config::Config::max_memory]. Those
+    /// spilled for [crate::config::Config::spill_threshold] aren't counted.
+    pub spilled: u64,
+    /// The most transactions every shard's queue held at once, in parallel mode. A shard at
+    /// [crate::config::Config::queue_capacity] held up reading, see
+    /// [crate::parallel::QueueDepth].
+    pub queue_peaks: Option<Vec<usize>>,
+    /// What every shard did, see [crate::system::ShardedAccountSystem::metrics]. Rows turned
+    /// away before reaching a shard, like those too precise, aren't in it.
+    pub shards: Vec<ShardMetrics>,
+    /// Webhook notifications that could not be delivered, if a webhook was configured.
+    pub webhook_failures: Option<u64>,
+    /// How the accounts differ from the expected balances, if
+    /// [crate::config::Config::validate_balances] asked for a comparison.
+    pub balance_mismatches: Option<Vec<Mismatch>>,
+    /// How many differences `track diff` found between the reports, see
+    /// [crate::report_diff::Report::diff].
+    pub differences: Option<u64>,
+    /// Where the time went, if [crate::config::Config::timings] asked for it. Not part of the
+    /// summary's own output.
+    pub timings: Option<Timings>,
+}
+
+impl RunSummary {
+    /// Adds the outcomes of a batch to the counters.
+    pub fn count(&mut self, report: &BatchReport) {
+        self.applied += report.applied;
+        self.ignored += report.ignored;
+        self.rejected += report.rejected;
+    }
+
+    /// Counts an ignored transaction by its reason, and keeps it if there are fewer than `max`
+    /// already.
+    pub(crate) fn ignore(&mut self, record: IgnoredRecord, max: usize) {
+        *self.ignore_reasons.entry(record.reason).or_default() += 1;
+        if self.ignored_records.len() < max {
+            self.ignored_records.push(record);
+        }
+    }
+}
+
+/// A transaction that was ignored, see [RunSummary::ignored_records].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IgnoredRecord {
+    pub client: ClientId,
+    pub tx: u32,
+    /// The transaction type, as used in the input.
+    pub kind: &'static str,
+    pub reason: IgnoreReason,
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "rows processed: {}", self.rows)?;
+        writeln!(
+            f,
+            "transactions applied: {}, ignored: {}, rejected: {}",
+            self.applied, self.ignored, self.rejected
+        )?;
+        for (reason, count) in &self.ignore_reasons {
+            writeln!(f, "  ignored as {}: {}", reason, count)?;
+        }
+        writeln!(
+            f,
+            "amounts rounded: {}, rejected as too precise: {}",
+            self.rounded, self.too_precise
+        )?;
+        writeln!(f, "transaction id anomalies: {}", self.tx_order_anomalies)?;
+        write!(f, "estimated memory: {} bytes", self.memory_footprint)?;
+        if self.compacted > 0 {
+            write!(f, ", {} accounts compacted to stay in it", self.compacted)?;
+        }
+        if self.spilled > 0 {
+            write!(
+                f,
+                ", {} deposits spilled to disk to stay in it",
+                self.spilled
+            )?;
+        }
+        if !self.shards.is_empty() {
+            let routed: Vec<String> = self
+                .shards
+                .iter()
+                .map(|shard| shard.routed.to_string())
+                .collect();
+            write!(f, "\ntransactions per shard: {}", routed.join(", "))?;
+            let total: ShardMetrics = self.shards.iter().sum();
+            if let Some(latency) = total.latency.filter(|latency| latency.count() > 0) {
+                let [p50, p95, p99] = [50.0, 95.0, 99.0].map(|p| latency.percentile(p).unwrap());
+                write!(
+                    f,
+                    "\napply latency p50: {:?}, p95: {:?}, p99: {:?}, max: {:?}",
+                    p50,
+                    p95,
+                    p99,
+                    latency.max()
+                )?;
+            }
+        }
+        if let Some(violations) = self.sort_violations {
+            write!(f, "\nrows of finalized clients: {}", violations)?;
+        }
+        if let Some(peaks) = &self.queue_peaks {
+            let peaks: Vec<String> = peaks.iter().map(usize::to_string).collect();
+            write!(f, "\npeak queue per shard: {}", peaks.join(", "))?;
+        }
+        if let Some(failures) = self.webhook_failures {
+            write!(f, "\nwebhook failures: {}", failures)?;
+        }
+        if let Some(mismatches) = &self.balance_mismatches {
+            write!(f, "\nbalance mismatches: {}", mismatches.len())?;
+        }
+        if let Some(differences) = self.differences {
+            write!(f, "\nreport differences: {}", differences)?;
+        }
+        Ok(())
+    }
+}
+
+/// The stages a run spends its time in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Phase {
+    /// Reading and parsing the input, including sorting it if asked to. With
+    /// [crate::config::Config::read_ahead], only the time spent waiting for the reader.
+    Parsing,
+    /// Applying transactions, including waiting for the shards in parallel mode. There, this
+    /// is routing transactions and handing them over, and the shards' own time applying them
+    /// is in [Timings::shard_busy].
+    Transacting,
+    /// Writing the report.
+    Writing,
+}
+
+/// Wall-clock time spent in every [Phase] of a run, measured on the thread driving it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Timings {
+    pub parsing: Duration,
+    pub transacting: Duration,
+    pub writing: Duration,
+    /// Input rows of the run, which make for the rate of every phase.
+    pub rows: u64,
+    /// How long every shard spent applying transactions, in parallel mode. Shards that spent
+    /// much longer than others got more than their share of the work.
+    pub shard_busy: Option<Vec<Duration>>,
+}
+
+impl Timings {
+    /// Runs `f` and adds the time it took to `phase`. Without timings this is just a call to
+    /// `f`, so there is nothing to pay for when they're off.
+    pub(crate) fn time<T>(timings: &mut Option<Timings>, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let Some(timings) = timings else {
+            return f();
+        };
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        match phase {
+            Phase::Parsing => timings.parsing += elapsed,
+            Phase::Transacting => timings.transacting += elapsed,
+            Phase::Writing => timings.writing += elapsed,
+        }
+        result
+    }
+}
+
+impl Timings {
+    /// Time spent in all phases together.
+    pub fn total(&self) -> Duration {
+        self.parsing + self.transacting + self.writing
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.total().as_secs_f64();
+        let phases = [
+            ("parsing", self.parsing),
+            ("transacting", self.transacting),
+            ("writing", self.writing),
+        ];
+        for (index, (phase, elapsed)) in phases.into_iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            let seconds = elapsed.as_secs_f64();
+            let share = match total > 0.0 {
+                true => seconds / total * 100.0,
+                false => 0.0,
+            };
+            write!(f, "{}: {:?} ({:.0}%", phase, elapsed, share)?;
+            if seconds > 0.0 {
+                write!(f, ", {:.0} rows/s", self.rows as f64 / seconds)?;
+            }
+            write!(f, ")")?;
+        }
+        if let Some(busy) = &self.shard_busy {
+            let busy: Vec<String> = busy.iter().map(|busy| format!("{:?}", busy)).collect();
+            write!(f, "\nbusy per shard: {}", busy.join(", "))?;
+        }
+        Ok(())
+    }
+}
@@ -1,58 +1,837 @@
-use crate::account::AccountState;
-use crate::transaction::Transaction;
-use crate::Output;
-use csv::Writer;
+use crate::account::{AccountState, Balance, IgnoreReason, RejectReason, TransactionOutcome};
+use crate::account_map::AccountMap;
+use crate::audit::AuditLog;
+use crate::batch::{BatchOptions, BatchReport};
+use crate::currency::Currency;
+use crate::deposit_index::DepositIndex;
+use crate::flush::ReportWriter;
+use crate::metrics::{LatencyHistogram, ShardMetrics};
+use crate::observer::{AccountObserver, Observers};
+use crate::policy::AccountPolicy;
+use crate::rates::Rates;
+use crate::replay::{EventLog, Offset};
+#[cfg(feature = "spill-deposits")]
+use crate::spill::SpillFile;
+use crate::transaction::{ClientId, Transaction};
+use crate::{ExtendedOutput, Output};
 use hashring::HashRing;
-use std::collections::HashMap;
-use std::io::Stdout;
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Knobs for [AccountSystem::write] and [ShardedAccountSystem::write].
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct ReportOptions<'a> {
+    /// Add the optimistic available funds and the activity window to every row.
+    pub extended: bool,
+    /// Leave out accounts with nothing in them, see [AccountState::is_empty].
+    pub suppress_empty: bool,
+    /// Write a row per client and currency, with the currency in a column of its own at the
+    /// end. Without it, only the implicit currency is reported.
+    pub currencies: bool,
+    /// Add every balance's total and held funds in the base currency to the extended report.
+    /// Every currency reported needs a rate, see [ReportOptions::check_rates].
+    pub rates: Option<&'a Rates>,
+}
+
+impl ReportOptions<'_> {
+    /// Fails with a list of the currencies that the accounts are going to be reported in but
+    /// [ReportOptions::rates] has no rate for, if converting at all.
+    pub fn check_rates<'a>(
+        &self,
+        accounts: impl IntoIterator<Item = &'a AccountState>,
+    ) -> anyhow::Result<()> {
+        match self.rates {
+            Some(rates) if self.extended => rates.check(
+                accounts
+                    .into_iter()
+                    .filter(|account| !(self.suppress_empty && account.is_empty()))
+                    .flat_map(|account| reported_balances(account, self))
+                    .map(|(currency, _)| currency),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The columns [ReportOptions::rates] adds to a row.
+#[derive(Serialize)]
+struct BaseColumns {
+    #[serde(with = "rust_decimal::serde::float")]
+    total_base: Decimal,
+    #[serde(with = "rust_decimal::serde::float")]
+    held_base: Decimal,
+}
+
+/// The header of a report written with the options, for reports that have no rows to
+/// derive it from.
+fn report_columns(options: &ReportOptions) -> Vec<&'static str> {
+    let mut columns = vec!["client", "available", "held", "total", "locked"];
+    if options.extended {
+        columns.extend([
+            "optimistic_available",
+            "first_activity",
+            "last_activity",
+            "closed",
+            "closed_at",
+            "payout",
+            "deficit",
+        ]);
+        if options.rates.is_some() {
+            columns.extend(["total_base", "held_base"]);
+        }
+    }
+    if options.currencies {
+        columns.push("currency");
+    }
+    columns
+}
+
+/// The column [ReportOptions::currencies] adds to a row.
+#[derive(Serialize)]
+struct CurrencyColumn {
+    currency: Option<Currency>,
+}
+
+/// A point [AccountSystem::rollback] can return to, see [AccountSystem::savepoint].
+#[derive(Debug, PartialEq, Eq)]
+pub struct Savepoint(usize);
 
 /// Think of this as a database (or rather a key-value store) that can be used to
 /// store more than one [AccountState].
 /// One can call [AccountSystem::transact] to run a specific transaction for a given
 /// user account.
+#[derive(Clone)]
 pub struct AccountSystem {
     /// A HashMap is probably the best structure for in-memory calculation
     /// because we need to frequently look for accounts using the ID.
     /// This will yield a constant time lookup, which is probably the best we can do.
-    accounts: HashMap<u16, AccountState>,
+    /// Unless the accounts are needed in order, see [AccountSystem::enable_ordered_accounts].
+    accounts: AccountMap,
+    observers: Observers,
+    policy: AccountPolicy,
+    /// Scheduled transactions keyed by their effective time and then by the order they were
+    /// scheduled in, which is also the order they get applied in.
+    pending: BTreeMap<(u64, u64), Transaction>,
+    scheduled: u64,
+    /// The latest time [AccountSystem::advance] was called with.
+    clock: Option<u64>,
+    /// Open savepoints, innermost last. Each one keeps the accounts touched since it was
+    /// taken, as they were back then. `None` stands for an account that didn't exist yet.
+    savepoints: Vec<HashMap<ClientId, Option<AccountState>>>,
+    /// Deposits every new account has room for from the start, see
+    /// [AccountSystem::set_deposit_capacity].
+    deposit_capacity: usize,
+    /// Whether disputes and the like for clients without an account are ignored without
+    /// creating one, see [AccountSystem::skip_unknown_references].
+    skip_unknown: bool,
+    /// What came of the transactions so far, see [AccountSystem::metrics].
+    metrics: ShardMetrics,
+    /// Where the accounts spill their deposits to, see [AccountSystem::set_spill_file].
+    #[cfg(feature = "spill-deposits")]
+    spill: Option<Arc<SpillFile>>,
+}
+
+/// Two systems are equal if they hold the same accounts. Observers, policies and scheduled
+/// transactions don't count.
+impl PartialEq for AccountSystem {
+    fn eq(&self, other: &Self) -> bool {
+        self.accounts == other.accounts
+    }
+}
+
+impl Default for AccountSystem {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl AccountSystem {
     /// Nothing fancy. Just a nice-to-have constructor.
     pub fn new() -> Self {
         AccountSystem {
-            accounts: HashMap::new(),
+            accounts: AccountMap::default(),
+            observers: Observers::default(),
+            policy: AccountPolicy::default(),
+            pending: BTreeMap::new(),
+            scheduled: 0,
+            clock: None,
+            savepoints: Vec::new(),
+            deposit_capacity: 0,
+            skip_unknown: false,
+            metrics: ShardMetrics::default(),
+            #[cfg(feature = "spill-deposits")]
+            spill: None,
         }
     }
 
+    /// Same as [AccountSystem::new], but tells `observer` about everything that happens.
+    pub fn with_observer(observer: Arc<dyn AccountObserver>) -> Self {
+        let mut system = Self::new();
+        system.add_observer(observer);
+        system
+    }
+
+    /// Tells `observer` about everything that happens from now on, after the observers
+    /// registered before it.
+    pub fn add_observer(&mut self, observer: Arc<dyn AccountObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Applies every transaction from now on under `policy`.
+    pub fn set_policy(&mut self, policy: AccountPolicy) {
+        self.policy = policy;
+    }
+
+    /// Gives every account created from now on room for `deposits` deposits, see
+    /// [AccountState::with_deposit_capacity]. Worth it when clients make many deposits each,
+    /// a waste of memory when most make a few.
+    pub fn set_deposit_capacity(&mut self, deposits: usize) {
+        self.deposit_capacity = deposits;
+    }
+
     /// Let's apply a transaction to an account in our register.
     /// If such an account does not exist, we initialise an empty account.
-    pub fn transact(&mut self, transaction: Transaction) {
-        if let Some(account) = self.accounts.get_mut(transaction.id()) {
-            account.transact(transaction)
-        } else {
-            let id = *transaction.id();
-            let mut account = AccountState::new();
-            account.transact(transaction);
-            self.accounts.insert(id, account);
-        }
-    }
-
-    /// We simply write the CSV content out to write-buffer based on the current account state
-    pub fn write(&self, writer: &mut Writer<Stdout>) -> std::io::Result<()> {
-        for (client, account) in self.accounts.iter() {
-            writer.serialize(Output {
-                client: *client,
-                available: account.available(),
-                held: account.held,
-                total: account.total,
-                locked: account.locked(),
-            })?;
+    pub fn transact(&mut self, transaction: Transaction) -> TransactionOutcome {
+        self.transact_audited(transaction, None)
+    }
+
+    /// [AccountSystem::transact], recording the outcome in `audit` if there is one.
+    fn transact_audited(
+        &mut self,
+        transaction: Transaction,
+        audit: Option<&mut AuditLog>,
+    ) -> TransactionOutcome {
+        let start = self.metrics.latency.is_some().then(Instant::now);
+        let outcome = self.transact_unaudited(&transaction);
+        self.metrics.count(outcome);
+        if let (Some(start), Some(latency)) = (start, self.metrics.latency.as_mut()) {
+            latency.record(start.elapsed());
+        }
+        if let Some(audit) = audit {
+            audit.record(&transaction, outcome);
+        }
+        outcome
+    }
+
+    fn transact_unaudited(&mut self, transaction: &Transaction) -> TransactionOutcome {
+        let client = *transaction.id();
+        // Disabled types don't get to create an account either.
+        if self.policy.disabled.contains(transaction.record_type()) {
+            let empty = AccountState::new();
+            let account = self.accounts.get(&client).unwrap_or(&empty);
+            self.observers.on_ignored(client, transaction, account);
+            return TransactionOutcome::Ignored(IgnoreReason::Disabled);
+        }
+        // Disputes and the like refer to a deposit, which an account we don't know yet can't
+        // have. No need to create an empty account just to ignore them, if asked not to.
+        if self.skip_unknown
+            && !self.accounts.contains_key(&client)
+            && matches!(
+                transaction,
+                Transaction::Dispute { .. }
+                    | Transaction::Resolve { .. }
+                    | Transaction::Chargeback { .. }
+            )
+        {
+            log::info!(
+                "client {}: {} of unknown tx {} ignored, the client has no account",
+                client,
+                transaction.kind(),
+                transaction.tx()
+            );
+            self.observers
+                .on_ignored(client, transaction, &AccountState::new());
+            return TransactionOutcome::Ignored(IgnoreReason::UnknownTx);
+        }
+        self.preserve(client);
+        // Only worth the extra lookup if anyone is listening.
+        if log::log_enabled!(log::Level::Debug) && !self.accounts.contains_key(&client) {
+            log::debug!("client {}: account created", client);
+        }
+        let capacity = self.deposit_capacity;
+        let account = self
+            .accounts
+            .get_or_insert_with(client, || AccountState::with_deposit_capacity(capacity));
+        #[cfg(feature = "spill-deposits")]
+        if let Some(file) = &self.spill {
+            account.deposits.attach(file);
+        }
+        let was_locked = account.locked();
+        // Transactions are small and free of heap allocations, so cloning one for the
+        // observer's sake is cheap.
+        let (outcome, violation) = account.transact_checked(transaction.clone(), &self.policy);
+        if let Some(violation) = violation {
+            self.observers
+                .on_violation(client, transaction, &violation, account);
+        }
+        match outcome {
+            TransactionOutcome::Applied => self.observers.on_applied(client, transaction, account),
+            TransactionOutcome::Ignored(reason) => {
+                log::debug!(
+                    "client {}: {} {} ignored as {}",
+                    client,
+                    transaction.kind(),
+                    transaction.tx(),
+                    reason
+                );
+                self.observers.on_ignored(client, transaction, account)
+            }
+            TransactionOutcome::Rejected(reason) => {
+                log::warn!(
+                    "client {}: {} {} rejected, {}",
+                    client,
+                    transaction.kind(),
+                    transaction.tx(),
+                    reason
+                );
+                self.observers
+                    .on_rejected(client, transaction, reason, account)
+            }
+        }
+        let cause = Some(transaction);
+        notify_lock_change(&self.observers, client, cause, was_locked, account);
+        outcome
+    }
+
+    /// Records how long applying every transaction from now on takes, in
+    /// [ShardMetrics::latency]. That's two reads of the clock per transaction, which batch runs
+    /// are better off without.
+    pub fn enable_latency(&mut self) {
+        self.metrics
+            .latency
+            .get_or_insert_with(LatencyHistogram::new);
+    }
+
+    /// What came of the transactions this system got, and how many accounts it holds.
+    /// Scheduled transactions count once they're applied.
+    pub fn metrics(&self) -> ShardMetrics {
+        ShardMetrics {
+            accounts: self.accounts.len(),
+            ..self.metrics.clone()
+        }
+    }
+
+    /// Reverts the last transaction applied to the client's account, see
+    /// [AccountState::undo_last]. Returns whether there was one to revert.
+    pub fn undo_last(&mut self, client: ClientId) -> bool {
+        if !self.accounts.contains_key(&client) {
+            return false;
+        }
+        self.preserve(client);
+        let account = self.accounts.get_mut(&client).unwrap();
+        let was_locked = account.locked();
+        let undone = account.undo_last();
+        notify_lock_change(&self.observers, client, None, was_locked, account);
+        undone
+    }
+
+    /// Holds `transaction` back until the clock reaches `effective`, see
+    /// [AccountSystem::advance]. Transactions that are due already are applied right away.
+    pub fn schedule(&mut self, effective: u64, transaction: Transaction) {
+        self.schedule_audited(effective, transaction, None)
+    }
+
+    fn schedule_audited(
+        &mut self,
+        effective: u64,
+        transaction: Transaction,
+        audit: Option<&mut AuditLog>,
+    ) {
+        if self.clock.is_some_and(|clock| clock >= effective) {
+            self.transact_audited(transaction, audit);
+            return;
+        }
+        self.pending
+            .insert((effective, self.scheduled), transaction);
+        self.scheduled += 1;
+    }
+
+    /// Moves the clock forward to `now` and applies every scheduled transaction that has become
+    /// due, i.e. is effective at or before `now`. They are applied in order of their effective
+    /// time; those effective at the same time in the order they were scheduled.
+    pub fn advance(&mut self, now: u64) {
+        self.advance_audited(now, None)
+    }
+
+    fn advance_audited(&mut self, now: u64, mut audit: Option<&mut AuditLog>) {
+        if self.clock.is_some_and(|clock| clock >= now) {
+            return;
+        }
+        self.clock = Some(now);
+        while let Some(entry) = self.pending.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let transaction = entry.remove();
+            self.transact_audited(transaction, audit.as_deref_mut());
+        }
+    }
+
+    /// Applies every scheduled transaction, due or not, in the order [AccountSystem::advance]
+    /// would. The clock stays where it is.
+    pub fn apply_pending(&mut self) {
+        self.apply_pending_audited(None)
+    }
+
+    fn apply_pending_audited(&mut self, mut audit: Option<&mut AuditLog>) {
+        while let Some((_, transaction)) = self.pending.pop_first() {
+            self.transact_audited(transaction, audit.as_deref_mut());
+        }
+    }
+
+    /// Scheduled transactions that are not due yet, with their effective time, in the order
+    /// they are going to be applied.
+    pub fn pending(&self) -> impl Iterator<Item = (u64, &Transaction)> {
+        self.pending
+            .iter()
+            .map(|((effective, _), transaction)| (*effective, transaction))
+    }
+
+    /// Looks up the current state of a client's account, if we've seen that client at all.
+    pub fn account(&self, client: ClientId) -> Option<&AccountState> {
+        self.accounts.get(&client)
+    }
+
+    /// Takes a client's account out of the system, e.g. once it has been reported on.
+    pub fn remove(&mut self, client: ClientId) -> Option<AccountState> {
+        self.preserve(client);
+        self.accounts.remove(&client)
+    }
+
+    /// Every account in this system, in no particular order.
+    pub fn accounts(&self) -> impl Iterator<Item = (ClientId, &AccountState)> {
+        self.accounts
+            .iter()
+            .map(|(client, account)| (*client, account))
+    }
+
+    /// Roughly how many bytes the accounts and scheduled transactions take, see
+    /// [AccountState::memory_footprint].
+    pub fn memory_footprint(&self) -> usize {
+        let accounts: usize = self
+            .accounts
+            .iter()
+            .map(|(_, account)| std::mem::size_of::<ClientId>() + account.memory_footprint())
+            .sum();
+        let pending = std::mem::size_of::<((u64, u64), Transaction)>() * self.pending.len();
+        std::mem::size_of::<Self>() + accounts + pending
+    }
+
+    /// Every account in this system, in client order. Unless the accounts are kept in order,
+    /// that takes sorting the ids of this shard's accounts first.
+    pub fn sorted_accounts(&self) -> Box<dyn Iterator<Item = (ClientId, &AccountState)> + '_> {
+        self.accounts.sorted()
+    }
+
+    /// Ignores disputes, resolves and chargebacks for clients without an account from now on,
+    /// rather than creating an empty account for them first. They'd be ignored either way,
+    /// but the empty accounts show up in the report, so this changes the output. Meant for
+    /// long running processes that [AccountSystem::compact] their accounts anyway.
+    pub fn skip_unknown_references(&mut self) {
+        self.skip_unknown = true;
+    }
+
+    /// Keeps the accounts in client order from now on, in a BTreeMap rather than a HashMap.
+    /// Lookups get slower, but [AccountSystem::sorted_accounts] no longer has to sort.
+    pub fn enable_ordered_accounts(&mut self) {
+        self.accounts.make_ordered();
+    }
+
+    /// Removes the accounts that hold nothing and haven't been active since `horizon`, see
+    /// [AccountState::is_disposable]. Returns how many were removed.
+    pub fn compact(&mut self, horizon: u64) -> usize {
+        let disposable: Vec<ClientId> = self
+            .accounts
+            .iter()
+            .filter(|(_, account)| account.is_disposable(horizon))
+            .map(|(client, _)| *client)
+            .collect();
+        for client in disposable.iter() {
+            self.remove(*client);
+        }
+        disposable.len()
+    }
+
+    /// Lets every account, those there are and those to come, spill its deposits to `file`,
+    /// see [crate::spill::SpillDeposits].
+    #[cfg(feature = "spill-deposits")]
+    pub fn set_spill_file(&mut self, file: Arc<SpillFile>) {
+        let clients: Vec<ClientId> = self.accounts.keys().copied().collect();
+        for client in clients {
+            if let Some(account) = self.accounts.get_mut(&client) {
+                account.deposits.attach(&file);
+            }
+        }
+        self.spill = Some(file);
+    }
+
+    /// The first error reading or writing the spill file since the last call, see
+    /// [SpillFile::take_error].
+    #[cfg(feature = "spill-deposits")]
+    pub fn take_spill_error(&self) -> Option<std::io::Error> {
+        self.spill.as_ref().and_then(|file| file.take_error())
+    }
+
+    /// Moves every deposit that isn't under dispute to the spill file, see
+    /// [crate::spill::SpillDeposits::spill]. Returns how many.
+    #[cfg(feature = "spill-deposits")]
+    pub fn spill_deposits(&mut self) -> usize {
+        let clients: Vec<ClientId> = self.accounts.keys().copied().collect();
+        let mut spilled = 0;
+        for client in clients {
+            if let Some(account) = self.accounts.get_mut(&client) {
+                spilled += account.deposits.spill();
+            }
+        }
+        spilled
+    }
+
+    /// Administratively locks every account we know of. Chargebacks are left untouched.
+    pub fn freeze_all(&mut self) {
+        let clients: Vec<ClientId> = self.accounts.keys().copied().collect();
+        for client in clients {
+            self.set_frozen(client, true);
+        }
+    }
+
+    /// Lifts the administrative lock again. Accounts locked due to a chargeback stay locked.
+    pub fn unfreeze_all(&mut self) {
+        let clients: Vec<ClientId> = self.accounts.keys().copied().collect();
+        for client in clients {
+            self.set_frozen(client, false);
+        }
+    }
+
+    /// Administratively locks the client's account, see [AccountState::frozen]. Returns
+    /// whether the client has an account, one that doesn't exist isn't created for this.
+    pub fn freeze(&mut self, client: ClientId) -> bool {
+        self.set_frozen(client, true)
+    }
+
+    /// Lifts the administrative lock of the client's account. A chargeback keeps it locked.
+    /// Returns whether the client has an account.
+    pub fn unfreeze(&mut self, client: ClientId) -> bool {
+        self.set_frozen(client, false)
+    }
+
+    fn set_frozen(&mut self, client: ClientId, frozen: bool) -> bool {
+        if !self.accounts.contains_key(&client) {
+            return false;
+        }
+        self.preserve(client);
+        let account = self.accounts.get_mut(&client).unwrap();
+        let was_locked = account.locked();
+        account.frozen = frozen;
+        notify_lock_change(&self.observers, client, None, was_locked, account);
+        true
+    }
+
+    /// Marks the current state of the accounts so that [AccountSystem::rollback] can return to
+    /// it. Nothing is copied up front: an account is copied the first time it changes after
+    /// the savepoint, so accounts that aren't touched cost nothing. Savepoints nest.
+    ///
+    /// Only accounts are covered. Scheduled transactions and the clock stay as they are.
+    pub fn savepoint(&mut self) -> Savepoint {
+        self.savepoints.push(HashMap::new());
+        Savepoint(self.savepoints.len() - 1)
+    }
+
+    /// Returns every account to where it was when `savepoint` was taken. Savepoints taken
+    /// after it are rolled back too, and all of them are gone afterwards.
+    pub fn rollback(&mut self, savepoint: Savepoint) {
+        assert!(
+            savepoint.0 < self.savepoints.len(),
+            "savepoint is no longer open"
+        );
+        // Innermost first, so that the older copies taken by outer savepoints win.
+        while self.savepoints.len() > savepoint.0 {
+            let preserved = self.savepoints.pop().unwrap();
+            for (client, account) in preserved {
+                let was_locked = self.accounts.get(&client).is_some_and(AccountState::locked);
+                match account {
+                    Some(account) => {
+                        self.accounts.insert(client, account);
+                        let account = &self.accounts[&client];
+                        notify_lock_change(&self.observers, client, None, was_locked, account);
+                    }
+                    None => {
+                        self.accounts.remove(&client);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Keeps the changes made since `savepoint` and forgets about it, along with any
+    /// savepoint taken after it. An enclosing savepoint can still roll them back.
+    pub fn commit(&mut self, savepoint: Savepoint) {
+        assert!(
+            savepoint.0 < self.savepoints.len(),
+            "savepoint is no longer open"
+        );
+        while self.savepoints.len() > savepoint.0 {
+            let preserved = self.savepoints.pop().unwrap();
+            if let Some(outer) = self.savepoints.last_mut() {
+                for (client, account) in preserved {
+                    outer.entry(client).or_insert(account);
+                }
+            }
+        }
+    }
+
+    /// Copies the account into the innermost savepoint before it changes for the first time.
+    fn preserve(&mut self, client: ClientId) {
+        if let Some(savepoint) = self.savepoints.last_mut() {
+            savepoint
+                .entry(client)
+                .or_insert_with(|| self.accounts.get(&client).cloned());
+        }
+    }
+
+    /// We simply write the CSV content out to write-buffer based on the current account state.
+    /// The [ReportOptions] decide which accounts and columns make it into the report.
+    ///
+    /// Accounts are written in client order. HashMap iteration order changes from run to run,
+    /// and we want the same input to always produce the same report.
+    pub fn write<W: Write>(
+        &self,
+        writer: &mut ReportWriter<W>,
+        options: ReportOptions,
+    ) -> std::io::Result<()> {
+        for (client, account) in self.sorted_accounts() {
+            write_account(writer, client, account, options)?;
         }
         Ok(())
     }
 }
 
+/// Writes a single row of the report, unless the [ReportOptions] leave the account out.
+pub(crate) fn write_account<W: Write>(
+    writer: &mut ReportWriter<W>,
+    client: ClientId,
+    account: &AccountState,
+    options: ReportOptions,
+) -> std::io::Result<()> {
+    if options.suppress_empty && account.is_empty() {
+        return Ok(());
+    }
+    for (currency, balance) in reported_balances(account, &options) {
+        write_balance(writer, client, account, currency, balance, options)?;
+    }
+    Ok(())
+}
+
+/// 64-bit FNV-1a, see [ShardedAccountSystem::state_digest].
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Writes the locked accounts among `accounts` before the others, each group in the order the
+/// accounts come in, so a locked account is found without reading the whole report. With a
+/// writer for `locked`, they go there instead and the others to `writer`, in a single pass.
+/// That file gets the header even if no account is locked, so it can be loaded on its own.
+/// Without one, the active accounts wait in a list until the locked ones are written.
+pub fn write_partitioned<'a, W: Write, L: Write>(
+    accounts: impl IntoIterator<Item = (ClientId, &'a AccountState)>,
+    writer: &mut ReportWriter<W>,
+    locked: Option<&mut ReportWriter<L>>,
+    options: ReportOptions,
+) -> std::io::Result<()> {
+    let mut active = Vec::new();
+    match locked {
+        Some(locked) => {
+            for (client, account) in accounts {
+                match account.locked() {
+                    true => write_account(locked, client, account, options)?,
+                    false => write_account(writer, client, account, options)?,
+                }
+            }
+            if locked.rows() == 0 {
+                locked.write_header(&report_columns(&options))?;
+            }
+            locked.flush()?;
+        }
+        None => {
+            for (client, account) in accounts {
+                match account.locked() {
+                    true => write_account(writer, client, account, options)?,
+                    false => active.push((client, account)),
+                }
+            }
+        }
+    }
+    for (client, account) in active {
+        write_account(writer, client, account, options)?;
+    }
+    Ok(())
+}
+
+/// The balances of the account the report has a row for: only the implicit currency unless
+/// [ReportOptions::currencies] asks for all of them.
+fn reported_balances<'a>(
+    account: &'a AccountState,
+    options: &ReportOptions,
+) -> impl Iterator<Item = (Option<Currency>, Balance)> + 'a {
+    let currencies = options.currencies;
+    let implicit = (!currencies).then(|| (None, account.balance(None)));
+    implicit
+        .into_iter()
+        .chain(account.balances().filter(move |_| currencies))
+}
+
+/// Writes one row for a balance of the account, with the currency column if the
+/// [ReportOptions] ask for it.
+fn write_balance<W: Write>(
+    writer: &mut ReportWriter<W>,
+    client: ClientId,
+    account: &AccountState,
+    currency: Option<Currency>,
+    balance: Balance,
+    options: ReportOptions,
+) -> std::io::Result<()> {
+    let column = options.currencies.then_some(CurrencyColumn { currency });
+    if options.extended {
+        let row = ExtendedOutput {
+            client,
+            available: balance.available(),
+            held: balance.held,
+            total: balance.total,
+            locked: account.locked(),
+            optimistic_available: balance.total,
+            first_activity: account.first_activity,
+            last_activity: account.last_activity,
+            closed: account.closed,
+            closed_at: account.closed_at,
+            payout: account.closed.then_some(balance.payout),
+            deficit: balance.deficit(),
+        };
+        match options.rates {
+            Some(rates) => {
+                let convert = |amount| {
+                    rates.convert(currency, amount).ok_or_else(|| {
+                        std::io::Error::other(format!(
+                            "Can't convert {} {} to the base currency",
+                            amount,
+                            currency
+                                .map(|currency| currency.to_string())
+                                .unwrap_or_default()
+                        ))
+                    })
+                };
+                let base = BaseColumns {
+                    total_base: convert(balance.total)?,
+                    held_base: convert(balance.held)?,
+                };
+                serialize_row(writer, (row, base), column)
+            }
+            None => serialize_row(writer, row, column),
+        }
+    } else {
+        let row = Output {
+            client,
+            available: balance.available(),
+            held: balance.held,
+            total: balance.total,
+            locked: account.locked(),
+        };
+        serialize_row(writer, row, column)
+    }
+}
+
+fn serialize_row<W: Write, T: Serialize>(
+    writer: &mut ReportWriter<W>,
+    row: T,
+    currency: Option<CurrencyColumn>,
+) -> std::io::Result<()> {
+    match currency {
+        Some(currency) => writer.serialize((row, currency))?,
+        None => writer.serialize(row)?,
+    }
+    Ok(())
+}
+
+fn notify_lock_change(
+    observer: &Observers,
+    client: ClientId,
+    cause: Option<&Transaction>,
+    was_locked: bool,
+    account: &AccountState,
+) {
+    match (was_locked, account.locked()) {
+        (false, true) => observer.on_locked(client, cause, account),
+        (true, false) => observer.on_unlocked(client, cause, account),
+        _ => {}
+    }
+}
+
+/// See [ShardedAccountSystem::sorted_accounts].
+pub struct SortedAccounts<'a> {
+    shards: Vec<Box<dyn Iterator<Item = (ClientId, &'a AccountState)> + 'a>>,
+    /// The next account of every shard that has any left.
+    heads: BinaryHeap<Reverse<Head<'a>>>,
+}
+
+/// Ordered by client alone, a client only ever lives in one shard.
+struct Head<'a> {
+    client: ClientId,
+    shard: usize,
+    account: &'a AccountState,
+}
+
+impl PartialEq for Head<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.client == other.client
+    }
+}
+
+impl Eq for Head<'_> {}
+
+impl PartialOrd for Head<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.client.cmp(&other.client)
+    }
+}
+
+impl<'a> Iterator for SortedAccounts<'a> {
+    type Item = (ClientId, &'a AccountState);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse(head) = self.heads.pop()?;
+        if let Some((client, account)) = self.shards[head.shard].next() {
+            self.heads.push(Reverse(Head {
+                client,
+                shard: head.shard,
+                account,
+            }));
+        }
+        Some((head.client, head.account))
+    }
+}
+
 /// The problem statement calls for consideration for a real-world case where the input
 /// can be streamed and tasks executed more efficiently.
 /// The good thing about working with multiple objects (users) is that we can shard them
@@ -65,6 +844,28 @@ impl AccountSystem {
 pub struct ShardedAccountSystem {
     ring: HashRing<usize>,
     systems: Vec<AccountSystem>,
+    /// Only kept once asked for, see [ShardedAccountSystem::enable_audit_log].
+    audit: Option<AuditLog>,
+    /// Only kept once asked for, see [ShardedAccountSystem::enable_deposit_index].
+    deposits: Option<DepositIndex>,
+}
+
+/// The ring can't be cloned, but building one from the same shards gives the same routing.
+impl Clone for ShardedAccountSystem {
+    fn clone(&self) -> Self {
+        let mut clone = ShardedAccountSystem::from_shards(self.systems.clone());
+        clone.audit = self.audit.clone();
+        clone.deposits = self.deposits.clone();
+        clone
+    }
+}
+
+/// Two systems are equal if they hold the same accounts, however they are spread over shards.
+/// How they got there, i.e. their audit logs, doesn't matter.
+impl PartialEq for ShardedAccountSystem {
+    fn eq(&self, other: &Self) -> bool {
+        self.diff(other).is_empty()
+    }
 }
 
 impl ShardedAccountSystem {
@@ -78,32 +879,1850 @@ impl ShardedAccountSystem {
             systems.push(AccountSystem::new());
             ring.add(shard);
         }
-        ShardedAccountSystem { ring, systems }
+        ShardedAccountSystem::from_parts(ring, systems)
+    }
+
+    /// Same as [ShardedAccountSystem::new], with every shard reporting to the same observer.
+    pub fn with_observer(shards: usize, observer: Arc<dyn AccountObserver>) -> Self {
+        let mut system = Self::new(shards);
+        system.add_observer(observer);
+        system
+    }
+
+    /// Tells `observer` about everything that happens in any shard from now on, after the
+    /// observers registered before it.
+    pub fn add_observer(&mut self, observer: Arc<dyn AccountObserver>) {
+        for system in self.systems.iter_mut() {
+            system.add_observer(observer.clone());
+        }
+    }
+
+    /// Starts recording every transaction from now on in an [AuditLog]. Enabling it again
+    /// keeps the entries so far.
+    pub fn enable_audit_log(&mut self) {
+        self.audit.get_or_insert_with(AuditLog::new);
+    }
+
+    /// The audit log, if [ShardedAccountSystem::enable_audit_log] was called.
+    pub fn audit_log(&self) -> Option<&AuditLog> {
+        self.audit.as_ref()
+    }
+
+    /// Lets disputes, resolves and chargebacks name a deposit of another client from now on,
+    /// see [DepositIndex]. Only deposits made from now on can be referred to that way.
+    pub fn enable_deposit_index(&mut self) {
+        self.deposits.get_or_insert_with(DepositIndex::new);
+    }
+
+    /// The deposit index, if [ShardedAccountSystem::enable_deposit_index] was called.
+    pub fn deposit_index(&self) -> Option<&DepositIndex> {
+        self.deposits.as_ref()
+    }
+
+    /// Takes the deposit index out, for an engine that routes transactions itself.
+    pub(crate) fn take_deposit_index(&mut self) -> Option<DepositIndex> {
+        self.deposits.take()
+    }
+
+    pub(crate) fn set_deposit_index(&mut self, deposits: Option<DepositIndex>) {
+        self.deposits = deposits;
+    }
+
+    /// Records how long applying a transaction takes in every shard from now on, see
+    /// [AccountSystem::enable_latency]. In parallel mode, that's from the shard's worker
+    /// taking it off the queue.
+    pub fn enable_latency(&mut self) {
+        for system in self.systems.iter_mut() {
+            system.enable_latency();
+        }
+    }
+
+    /// Keeps every shard's accounts in client order from now on, see
+    /// [AccountSystem::enable_ordered_accounts]. That's what lets
+    /// [ShardedAccountSystem::write_sorted] get by with memory for a handful of accounts,
+    /// however many there are.
+    pub fn enable_ordered_accounts(&mut self) {
+        for system in self.systems.iter_mut() {
+            system.enable_ordered_accounts();
+        }
+    }
+
+    /// Ignores references to clients without an account in every shard from now on, see
+    /// [AccountSystem::skip_unknown_references].
+    pub fn skip_unknown_references(&mut self) {
+        for system in self.systems.iter_mut() {
+            system.skip_unknown_references();
+        }
+    }
+
+    /// Applies every transaction from now on under `policy`, in every shard.
+    pub fn set_policy(&mut self, policy: AccountPolicy) {
+        for system in self.systems.iter_mut() {
+            system.set_policy(policy);
+        }
+    }
+
+    /// Gives every account created from now on room for `deposits` deposits, in every shard.
+    pub fn set_deposit_capacity(&mut self, deposits: usize) {
+        for system in self.systems.iter_mut() {
+            system.set_deposit_capacity(deposits);
+        }
+    }
+
+    /// Spreads the accounts over a different number of shards, e.g. to make use of more
+    /// threads. Every account moves to the shard the new ring routes it to, with all of its
+    /// deposits, its scheduled transactions and whatever open savepoints kept of it. Policy,
+    /// observer, clock, deposit capacity and spill file stay the same, and so do recording
+    /// latencies and skipping unknown references.
+    pub fn rebalance(&mut self, shards: usize) {
+        assert!(shards > 0, "accounts need at least one shard to live in");
+        let template = self.systems.first();
+        let observers = template.map(|system| system.observers.clone());
+        let policy = template.map_or_else(AccountPolicy::default, |system| system.policy);
+        let clock = template.and_then(|system| system.clock);
+        let deposit_capacity = template.map_or(0, |system| system.deposit_capacity);
+        let skip_unknown = template.is_some_and(|system| system.skip_unknown);
+        #[cfg(feature = "spill-deposits")]
+        let spill = template.and_then(|system| system.spill.clone());
+        let depth = template.map_or(0, |system| system.savepoints.len());
+        let ordered = template.is_some_and(|system| system.accounts.is_ordered());
+        let latency = template.is_some_and(|system| system.metrics.latency.is_some());
+        let mut rebalanced = Self::new(shards);
+        rebalanced.audit = self.audit.take();
+        rebalanced.deposits = self.deposits.take();
+        for system in rebalanced.systems.iter_mut() {
+            system.policy = policy;
+            system.observers = observers.clone().unwrap_or_default();
+            system.clock = clock;
+            system.deposit_capacity = deposit_capacity;
+            system.skip_unknown = skip_unknown;
+            #[cfg(feature = "spill-deposits")]
+            {
+                system.spill = spill.clone();
+            }
+            system.savepoints = vec![HashMap::new(); depth];
+            if ordered {
+                system.enable_ordered_accounts();
+            }
+            if latency {
+                system.enable_latency();
+            }
+        }
+        for system in std::mem::take(&mut self.systems) {
+            for (client, account) in system.accounts {
+                rebalanced
+                    .shard_mut(client)
+                    .accounts
+                    .insert(client, account);
+            }
+            // A client's transactions all come from the same shard, in order, so they stay in
+            // the order they were scheduled in.
+            for ((effective, _), transaction) in system.pending {
+                let target = rebalanced.shard_mut(*transaction.id());
+                target
+                    .pending
+                    .insert((effective, target.scheduled), transaction);
+                target.scheduled += 1;
+            }
+            for (level, preserved) in system.savepoints.into_iter().enumerate() {
+                for (client, account) in preserved {
+                    rebalanced.shard_mut(client).savepoints[level].insert(client, account);
+                }
+            }
+        }
+        *self = rebalanced;
+    }
+
+    /// The shard a client is routed to. Only for systems with at least one shard.
+    fn shard_mut(&mut self, client: ClientId) -> &mut AccountSystem {
+        let shard = self.shard_of(client).unwrap();
+        &mut self.systems[shard]
+    }
+
+    /// The index of the shard a client is routed to, whether or not it has an account yet.
+    /// `None` only for a system without shards.
+    pub fn shard_of(&self, client: ClientId) -> Option<usize> {
+        self.ring.get(&client.to_be_bytes()).copied()
+    }
+
+    /// Takes the system apart so that the shards can be moved onto their own threads.
+    pub(crate) fn into_parts(self) -> (HashRing<usize>, Vec<AccountSystem>) {
+        (self.ring, self.systems)
+    }
+
+    /// Puts the shards back together with a ring of their own, which routes the same way as
+    /// any other ring for as many shards.
+    pub(crate) fn from_shards(systems: Vec<AccountSystem>) -> Self {
+        let mut ring = HashRing::new();
+        for shard in 0..systems.len() {
+            ring.add(shard);
+        }
+        ShardedAccountSystem::from_parts(ring, systems)
+    }
+
+    pub(crate) fn from_parts(ring: HashRing<usize>, systems: Vec<AccountSystem>) -> Self {
+        ShardedAccountSystem {
+            ring,
+            systems,
+            audit: None,
+            deposits: None,
+        }
     }
 
     /// This could very well be executed in parallel with individual account-systems executing
     /// their tasks using a Tokio Task and using a channel to dispatch information to them.
-    /// Unfortunately, I did not have time to implement that, so I stopped at "simulating"
-    /// similar behaviour using a sync API.
+    /// [crate::parallel::ParallelAccountSystem] does that with plain threads, while this one
+    /// stays the simple, "simulated" take using a sync API.
     /// Of course, in a real-world application, the entire point of sharded-transaction systems is
     /// lost without an async API, and I would have done this differently had this been a production
     /// application or if I had had more time.
-    pub fn transact(&mut self, transaction: Transaction) {
+    ///
+    /// A transaction with a timestamp moves the clock forward first, so anything scheduled to
+    /// become effective by then is applied before it.
+    pub fn transact(&mut self, transaction: Transaction) -> TransactionOutcome {
+        if let Some(timestamp) = transaction.timestamp() {
+            self.advance(timestamp);
+        }
+        let transaction = self.route(transaction);
+        let id = *transaction.id();
+        match self.ring.get(&id.to_be_bytes()) {
+            Some(shard) => self.systems[*shard].transact_audited(transaction, self.audit.as_mut()),
+            None => {
+                let outcome = TransactionOutcome::Rejected(RejectReason::NoShard);
+                if let Some(audit) = self.audit.as_mut() {
+                    audit.record(&transaction, outcome);
+                }
+                outcome
+            }
+        }
+    }
+
+    /// Holds `transaction` back until the clock reaches `effective`. There is no wall time
+    /// involved: the clock is driven by the timestamps of the transactions, so that the same
+    /// input always has the same effect. A timestamp on `transaction` itself moves the clock
+    /// as well.
+    pub fn schedule(&mut self, effective: u64, transaction: Transaction) {
+        if let Some(timestamp) = transaction.timestamp() {
+            self.advance(timestamp);
+        }
+        let transaction = self.route(transaction);
         let id = *transaction.id();
         if let Some(shard) = self.ring.get(&id.to_be_bytes()) {
-            self.systems[*shard].transact(transaction);
+            self.systems[*shard].schedule_audited(effective, transaction, self.audit.as_mut());
+        }
+    }
+
+    /// Hands the transaction to the account it's about, see [DepositIndex::route].
+    fn route(&mut self, transaction: Transaction) -> Transaction {
+        match self.deposits.as_mut() {
+            Some(deposits) => deposits.route(transaction),
+            None => transaction,
+        }
+    }
+
+    /// The latest timestamp seen so far, if any.
+    pub fn clock(&self) -> Option<u64> {
+        self.systems.first().and_then(|system| system.clock)
+    }
+
+    /// Moves the clock forward to `now` in every shard, applying what has become due. Moving
+    /// it backwards does nothing.
+    pub fn advance(&mut self, now: u64) {
+        for system in self.systems.iter_mut() {
+            system.advance_audited(now, self.audit.as_mut());
         }
     }
 
+    /// Applies every scheduled transaction, due or not.
+    pub fn apply_pending(&mut self) {
+        for system in self.systems.iter_mut() {
+            system.apply_pending_audited(self.audit.as_mut());
+        }
+    }
+
+    /// Scheduled transactions that are not due yet, with their effective time, ordered by
+    /// effective time and client. A client's transactions effective at the same time stay in
+    /// the order they were scheduled.
+    pub fn pending(&self) -> Vec<(u64, &Transaction)> {
+        let mut pending: Vec<(u64, &Transaction)> = self
+            .systems
+            .iter()
+            .flat_map(AccountSystem::pending)
+            .collect();
+        pending.sort_by_key(|(effective, transaction)| (*effective, *transaction.id()));
+        pending
+    }
+
+    /// Applies every transaction and reports what came of them in one go. Outcomes of the
+    /// individual transactions are not kept, see [ShardedAccountSystem::transact_all_with].
+    pub fn transact_all<I: IntoIterator<Item = Transaction>>(&mut self, iter: I) -> BatchReport {
+        self.transact_all_with(iter, BatchOptions::default())
+    }
+
+    /// [ShardedAccountSystem::transact_all], keeping as many individual outcomes as `options`
+    /// allow.
+    pub fn transact_all_with<I: IntoIterator<Item = Transaction>>(
+        &mut self,
+        iter: I,
+        options: BatchOptions,
+    ) -> BatchReport {
+        let mut report = BatchReport::default();
+        for (index, transaction) in iter.into_iter().enumerate() {
+            let outcome = self.transact(transaction);
+            report.record(index, outcome, &options);
+        }
+        report
+    }
+
+    /// Applies every transaction and returns the outcome of each, in the order of
+    /// `transactions`. The counterpart of [ShardedAccountSystem::transact_all] for callers that
+    /// report on every transaction rather than on the batch.
+    pub fn transact_each(&mut self, transactions: &[Transaction]) -> Vec<TransactionOutcome> {
+        let options = BatchOptions {
+            max_outcomes: transactions.len(),
+        };
+        self.transact_all_with(transactions.iter().cloned(), options)
+            .outcomes
+    }
+
+    /// Applies transactions in order until `stop` returns true for one of them. That transaction
+    /// and everything after it is left alone. Returns how many transactions were applied.
+    pub fn transact_until<I, P>(&mut self, transactions: I, mut stop: P) -> usize
+    where
+        I: IntoIterator<Item = Transaction>,
+        P: FnMut(&Transaction) -> bool,
+    {
+        let mut applied = 0;
+        for transaction in transactions {
+            if stop(&transaction) {
+                break;
+            }
+            self.transact(transaction);
+            applied += 1;
+        }
+        applied
+    }
+
+    /// Applies `transactions` on top of the current state. Paired with [Clone] and
+    /// [ShardedAccountSystem::diff], this allows re-running a batch against a snapshot and
+    /// comparing the outcome with what one expected.
+    pub fn replay(&mut self, transactions: impl IntoIterator<Item = Transaction>) {
+        for transaction in transactions {
+            self.transact(transaction);
+        }
+    }
+
+    /// Rebuilds state by applying every transaction in the log from `from` onwards. Since
+    /// applying a transaction doesn't depend on anything but the account and the transaction,
+    /// replaying the same log always produces the same accounts. Returns the offset following
+    /// the last replayed transaction, from where one can continue later on.
+    pub fn replay_log<L: EventLog + ?Sized>(
+        &mut self,
+        log: &mut L,
+        from: Offset,
+    ) -> anyhow::Result<Offset> {
+        let mut next = from;
+        for transaction in log.iter_from(from)? {
+            self.transact(transaction?);
+            next.0 += 1;
+        }
+        Ok(next)
+    }
+
+    /// Finds the account of a client by asking the shard it is routed to.
+    pub fn account(&self, client: ClientId) -> Option<&AccountState> {
+        self.systems[self.shard_of(client)?].account(client)
+    }
+
+    /// Takes a client's account out of the shard it is routed to.
+    pub fn remove(&mut self, client: ClientId) -> Option<AccountState> {
+        let shard = self.ring.get(&client.to_be_bytes())?;
+        self.systems[*shard].remove(client)
+    }
+
+    /// Takes a savepoint in every shard, see [AccountSystem::savepoint].
+    pub fn savepoint(&mut self) -> Savepoint {
+        let depth = self
+            .systems
+            .first()
+            .map_or(0, |system| system.savepoints.len());
+        for system in self.systems.iter_mut() {
+            system.savepoint();
+        }
+        Savepoint(depth)
+    }
+
+    /// Rolls every shard back to `savepoint`, see [AccountSystem::rollback].
+    pub fn rollback(&mut self, savepoint: Savepoint) {
+        for system in self.systems.iter_mut() {
+            system.rollback(Savepoint(savepoint.0));
+        }
+    }
+
+    /// Commits `savepoint` in every shard, see [AccountSystem::commit].
+    pub fn commit(&mut self, savepoint: Savepoint) {
+        for system in self.systems.iter_mut() {
+            system.commit(Savepoint(savepoint.0));
+        }
+    }
+
+    /// Reverts the last transaction applied to the client's account in the shard it is routed
+    /// to, see [AccountState::undo_last].
+    pub fn undo_last(&mut self, client: ClientId) -> bool {
+        match self.ring.get(&client.to_be_bytes()) {
+            Some(shard) => self.systems[*shard].undo_last(client),
+            None => false,
+        }
+    }
+
+    /// Roughly how many bytes the shards take, see [AccountSystem::memory_footprint].
+    pub fn memory_footprint(&self) -> usize {
+        self.systems
+            .iter()
+            .map(AccountSystem::memory_footprint)
+            .sum()
+    }
+
+    /// Removes empty, inactive accounts from every shard, see [AccountSystem::compact].
+    /// Returns how many were removed. Long running processes should call this now and then.
+    pub fn compact(&mut self, horizon: u64) -> usize {
+        self.systems
+            .iter_mut()
+            .map(|system| system.compact(horizon))
+            .sum()
+    }
+
+    /// Lets the accounts of every shard spill to `file`, see [AccountSystem::set_spill_file].
+    #[cfg(feature = "spill-deposits")]
+    pub fn set_spill_file(&mut self, file: Arc<SpillFile>) {
+        for system in self.systems.iter_mut() {
+            system.set_spill_file(file.clone());
+        }
+    }
+
+    /// See [AccountSystem::take_spill_error].
+    #[cfg(feature = "spill-deposits")]
+    pub fn take_spill_error(&self) -> Option<std::io::Error> {
+        self.systems
+            .iter()
+            .find_map(AccountSystem::take_spill_error)
+    }
+
+    /// See [AccountSystem::spill_deposits].
+    #[cfg(feature = "spill-deposits")]
+    pub fn spill_deposits(&mut self) -> usize {
+        self.systems
+            .iter_mut()
+            .map(AccountSystem::spill_deposits)
+            .sum()
+    }
+
+    /// Every account across all shards, in no particular order.
+    pub fn accounts(&self) -> impl Iterator<Item = (ClientId, &AccountState)> {
+        self.systems.iter().flat_map(AccountSystem::accounts)
+    }
+
+    /// The accounts `predicate` holds for, across all shards, in ascending order of client.
+    /// Meant for ad-hoc queries, like which accounts are locked or hold funds.
+    pub fn filter(
+        &self,
+        mut predicate: impl FnMut(ClientId, &AccountState) -> bool,
+    ) -> Vec<(ClientId, &AccountState)> {
+        let mut accounts: Vec<(ClientId, &AccountState)> = self
+            .accounts()
+            .filter(|(client, account)| predicate(*client, account))
+            .collect();
+        accounts.sort_unstable_by_key(|(client, _)| *client);
+        accounts
+    }
+
+    /// A checksum of every account's balances, taken in client order, so two systems holding
+    /// the same balances have the same digest however many shards they have and whichever
+    /// engine filled them. Cheaper to compare or log than [ShardedAccountSystem::diff].
+    ///
+    /// Held, total and paid out funds in every currency go into it, along with whether the
+    /// account is locked or closed. Timestamps and deposits don't, and neither does the scale
+    /// of an amount: `1.5` and `1.50` are the same. The hash is FNV-1a, which is the same on
+    /// every platform for builds with the same [ClientId] width, but no defence against
+    /// someone forging a collision.
+    pub fn state_digest(&self) -> u64 {
+        let mut digest = Fnv1a::default();
+        for (client, account) in self.sorted_accounts() {
+            digest.write(&client.to_le_bytes());
+            for (currency, balance) in account.balances() {
+                match currency {
+                    None => digest.write(&[0]),
+                    Some(currency) => {
+                        digest.write(&[1]);
+                        digest.write(currency.as_str().as_bytes());
+                    }
+                }
+                for amount in [balance.held, balance.total, balance.payout] {
+                    digest.write(&amount.normalize().serialize());
+                }
+            }
+            digest.write(&[u8::from(account.locked()), u8::from(account.closed)]);
+        }
+        digest.0
+    }
+
+    /// Clients whose accounts differ between the two systems, including those that only one
+    /// of them knows about, in ascending order.
+    pub fn diff(&self, other: &ShardedAccountSystem) -> Vec<ClientId> {
+        let mut clients: Vec<ClientId> = self
+            .accounts()
+            .filter(|(client, account)| other.account(*client) != Some(*account))
+            .map(|(client, _)| client)
+            .chain(
+                other
+                    .accounts()
+                    .filter(|(client, _)| self.account(*client).is_none())
+                    .map(|(client, _)| client),
+            )
+            .collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    /// Meant for incidents: freezes every account that exists right now, across all shards.
+    /// Accounts created afterwards are not frozen.
+    pub fn freeze_all(&mut self) {
+        for system in self.systems.iter_mut() {
+            system.freeze_all();
+        }
+    }
+
+    /// Undoes [ShardedAccountSystem::freeze_all] without touching chargeback-driven locks.
+    pub fn unfreeze_all(&mut self) {
+        for system in self.systems.iter_mut() {
+            system.unfreeze_all();
+        }
+    }
+
+    /// Freezes the client's account in its shard, see [AccountSystem::freeze]. Returns whether
+    /// the client has an account.
+    pub fn freeze(&mut self, client: ClientId) -> bool {
+        !self.systems.is_empty() && self.shard_mut(client).freeze(client)
+    }
+
+    /// Unfreezes the client's account in its shard, see [AccountSystem::unfreeze]. Returns
+    /// whether the client has an account.
+    pub fn unfreeze(&mut self, client: ClientId) -> bool {
+        !self.systems.is_empty() && self.shard_mut(client).unfreeze(client)
+    }
+
     /// While we're calling the same write function as that of contained [AccountSystem],
     /// we flush the buffer after every shard in case they start getting too big.
     /// Of course, this is not very likely for our application because everything is in memory
-    /// nevertheless, but it's definitely nice to consider that for extreme cases.
-    pub fn write(&self, writer: &mut Writer<Stdout>) -> std::io::Result<()> {
+    /// nevertheless, but it's definitely nice to consider that for extreme cases. The writer's
+    /// [crate::flush::FlushPolicy] may say otherwise, see [ReportWriter].
+    pub fn write<W: Write>(
+        &self,
+        writer: &mut ReportWriter<W>,
+        options: ReportOptions,
+    ) -> std::io::Result<()> {
         for system in self.systems.iter() {
-            system.write(writer)?;
-            writer.flush()?;
+            system.write(writer, options)?;
+            writer.end_shard()?;
+        }
+        Ok(())
+    }
+
+    /// Every account across all shards, in client order. Each shard hands out its own in
+    /// order, see [AccountSystem::sorted_accounts], and they're merged as they go with a heap
+    /// holding one account per shard.
+    pub fn sorted_accounts(&self) -> SortedAccounts<'_> {
+        let mut shards: Vec<_> = self
+            .systems
+            .iter()
+            .map(|system| system.sorted_accounts())
+            .collect();
+        let heads = shards
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(shard, accounts)| {
+                let (client, account) = accounts.next()?;
+                Some(Reverse(Head {
+                    client,
+                    shard,
+                    account,
+                }))
+            })
+            .collect();
+        SortedAccounts { shards, heads }
+    }
+
+    /// Writes the report in client order across all shards, streaming the accounts out of
+    /// [ShardedAccountSystem::sorted_accounts] rather than sorting the whole report first.
+    /// There are no shards to speak of in the output, so [crate::flush::FlushPolicy::PerShard]
+    /// leaves flushing to the end.
+    pub fn write_sorted<W: Write>(
+        &self,
+        writer: &mut ReportWriter<W>,
+        options: ReportOptions,
+    ) -> std::io::Result<()> {
+        for (client, account) in self.sorted_accounts() {
+            write_account(writer, client, account, options)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the report by total in the implicit currency, largest first. The sort is stable
+    /// over [ShardedAccountSystem::sorted_accounts], so accounts with the same total stay in
+    /// client order. Unlike the other orders this needs every account at hand before the first
+    /// one is written.
+    pub fn write_by_total<W: Write>(
+        &self,
+        writer: &mut ReportWriter<W>,
+        options: ReportOptions,
+    ) -> std::io::Result<()> {
+        for (client, account) in self.accounts_by_total() {
+            write_account(writer, client, account, options)?;
         }
         Ok(())
     }
+
+    /// Every account across all shards in the order of [ShardedAccountSystem::write_by_total].
+    pub fn accounts_by_total(&self) -> Vec<(ClientId, &AccountState)> {
+        let mut accounts: Vec<_> = self.sorted_accounts().collect();
+        accounts.sort_by_key(|(_, account)| std::cmp::Reverse(account.total));
+        accounts
+    }
+
+    /// Every dispute still open in any account as `(client, tx, amount)`, by client and then
+    /// transaction id, see [AccountState::open_disputes]. The amount is that of the disputed
+    /// deposit.
+    pub fn open_disputes(&self) -> Vec<(ClientId, u32, Decimal)> {
+        let mut disputes: Vec<_> = self
+            .sorted_accounts()
+            .flat_map(|(client, account)| {
+                account
+                    .open_disputes()
+                    .map(move |(tx, deposit)| (client, tx, deposit.amount()))
+            })
+            .collect();
+        disputes.sort_unstable_by_key(|(client, tx, _)| (*client, *tx));
+        disputes
+    }
+
+    /// What every shard has done, in shard order, see [AccountSystem::metrics]. Summed up,
+    /// they're the totals of the whole system. Rebalancing starts the counts over.
+    pub fn metrics(&self) -> Vec<ShardMetrics> {
+        self.systems.iter().map(AccountSystem::metrics).collect()
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.systems.len()
+    }
+
+    /// Writes every shard to a writer of its own, all at the same time on a thread per
+    /// shard, and flushes them. There has to be a writer for every shard, see
+    /// [ShardedAccountSystem::shard_count]. Every part starts with the header, even if its
+    /// shard has no accounts to report, so each can be loaded on its own.
+    pub fn write_shards<W: Write + Send>(
+        &self,
+        writers: &mut [ReportWriter<W>],
+        options: ReportOptions,
+    ) -> std::io::Result<()> {
+        assert_eq!(writers.len(), self.systems.len(), "a writer per shard");
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = self
+                .systems
+                .iter()
+                .zip(writers.iter_mut())
+                .map(|(system, writer)| {
+                    scope.spawn(move || {
+                        system.write(writer, options)?;
+                        if writer.rows() == 0 {
+                            writer.write_header(&report_columns(&options))?;
+                        }
+                        writer.flush()
+                    })
+                })
+                .collect();
+            threads
+                .into_iter()
+                .try_for_each(|thread| thread.join().expect("writing a shard panicked"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditEntry;
+    use crate::parallel::ParallelAccountSystem;
+    use crate::transaction::RecordType;
+    use rust_decimal::Decimal;
+    use std::sync::Mutex;
+
+    fn deposit(client: ClientId, tx: u32, amount: u32) -> Transaction {
+        Transaction::Deposit {
+            client,
+            tx,
+            amount: Decimal::from(amount),
+            currency: None,
+            timestamp: None,
+        }
+    }
+
+    fn withdrawal(client: ClientId, tx: u32, amount: u32) -> Transaction {
+        Transaction::Withdrawal {
+            client,
+            tx,
+            amount: Decimal::from(amount),
+            currency: None,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    /// The shards' counters add up to what came of the whole batch, reasons and latencies and
+    /// all
+    fn metrics_add_up() {
+        let mut system = ShardedAccountSystem::new(4);
+        // Ten clients per shard, so every shard has something to count
+        let clients: Vec<ClientId> = (0..4)
+            .flat_map(|shard| {
+                (0..ClientId::MAX)
+                    .filter(|&client| system.shard_of(client) == Some(shard))
+                    .take(10)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let transactions: Vec<Transaction> = (0..600)
+            .map(|tx| {
+                let client = clients[tx as usize % 40];
+                match tx % 3 {
+                    0 => deposit(client, tx, 10),
+                    1 => withdrawal(client, tx, 25),
+                    _ => Transaction::Dispute {
+                        client,
+                        tx: tx + 10_000,
+                        timestamp: None,
+                    },
+                }
+            })
+            .collect();
+        system.enable_latency();
+        let report = system.transact_all_with(transactions, BatchOptions { max_outcomes: 600 });
+        let shards = system.metrics();
+        assert_eq!(shards.len(), 4);
+        assert!(shards.iter().all(|shard| shard.routed > 0));
+        let total: ShardMetrics = shards.iter().sum();
+        assert_eq!(total.routed, 600);
+        assert_eq!(
+            (total.applied, total.ignored_total(), total.rejected),
+            (report.applied, report.ignored, report.rejected)
+        );
+        let mut reasons = BTreeMap::new();
+        for outcome in &report.outcomes {
+            if let TransactionOutcome::Ignored(reason) = outcome {
+                *reasons.entry(*reason).or_default() += 1;
+            }
+        }
+        assert_eq!(total.ignored, reasons);
+        assert_eq!(total.accounts, 40);
+        assert_eq!(total.queued, None);
+        assert_eq!(total.latency.map(|latency| latency.count()), Some(600));
+    }
+
+    #[test]
+    /// Each outcome is that of the transaction at the same position, whichever shard it went to
+    fn transact_each() {
+        let transactions = [
+            deposit(1, 1, 100),
+            deposit(2, 2, 50),
+            withdrawal(1, 3, 500),
+            deposit(3, 2, 10),
+            deposit(2, 2, 10),
+            Transaction::Dispute {
+                client: 3,
+                tx: 9,
+                timestamp: None,
+            },
+            withdrawal(2, 4, 20),
+        ];
+        let mut system = ShardedAccountSystem::new(3);
+        let outcomes = system.transact_each(&transactions);
+        assert_eq!(
+            outcomes,
+            [
+                TransactionOutcome::Applied,
+                TransactionOutcome::Applied,
+                TransactionOutcome::Ignored(IgnoreReason::InsufficientFunds),
+                TransactionOutcome::Applied,
+                TransactionOutcome::Ignored(IgnoreReason::DuplicateTx),
+                TransactionOutcome::Ignored(IgnoreReason::UnknownTx),
+                TransactionOutcome::Applied,
+            ]
+        );
+        assert!(system.transact_each(&[]).is_empty());
+    }
+
+    #[test]
+    /// Rebalancing keeps every account as it was, and later transactions find them
+    fn rebalance() {
+        let dispute = |client: ClientId, tx| Transaction::Dispute {
+            client,
+            tx,
+            timestamp: None,
+        };
+        let before: Vec<Transaction> = (0..16)
+            .zip((0..).step_by(2))
+            .flat_map(|(client, tx)| [deposit(client, tx, 100), deposit(client, tx + 1, 50)])
+            .collect();
+        let after: Vec<Transaction> = (0..16)
+            .zip((0..).step_by(2))
+            .flat_map(|(client, tx)| [dispute(client, tx + 1), withdrawal(client, tx + 100, 100)])
+            .collect();
+        let mut system = ShardedAccountSystem::new(2);
+        system.transact_all(before.clone());
+        let snapshot = system.clone();
+        system.rebalance(4);
+        assert!(system.diff(&snapshot).is_empty());
+        for client in 0..16 {
+            assert_eq!(system.account(client), snapshot.account(client));
+        }
+        assert!(system.systems.iter().all(|shard| shard.accounts.len() < 16));
+        // The deposits made before can be disputed, and what's left withdrawn.
+        let report = system.transact_all(after.clone());
+        assert_eq!(report.applied, 32);
+        assert_eq!(report.rejected, 0);
+        let mut expected = ShardedAccountSystem::new(4);
+        expected.transact_all(before.into_iter().chain(after));
+        assert!(system.diff(&expected).is_empty());
+    }
+
+    #[test]
+    /// Every transaction gets exactly one audit entry, scheduled ones once they're applied,
+    /// numbered in the order they were applied
+    fn audit_log() {
+        let mut system = ShardedAccountSystem::new(4);
+        system.transact(deposit(9, 0, 100));
+        assert!(system.audit_log().is_none());
+        system.enable_audit_log();
+        let mut transactions: Vec<Transaction> = (0..8)
+            .zip((1..).step_by(2))
+            .flat_map(|(client, tx)| [deposit(client, tx, 100), withdrawal(client, tx + 1, 150)])
+            .collect();
+        system.schedule(10, deposit(9, 100, 5));
+        system.transact_all(transactions.clone());
+        system.advance(10);
+        transactions.push(deposit(9, 100, 5));
+
+        let audit = system.audit_log().unwrap();
+        assert_eq!(audit.len(), transactions.len());
+        let entries: Vec<&AuditEntry> = audit.iter().collect();
+        assert!(entries
+            .iter()
+            .enumerate()
+            .all(|(i, entry)| entry.seq == i as u64));
+        for (entry, transaction) in entries.iter().zip(&transactions) {
+            assert_eq!(
+                (entry.client, entry.tx),
+                (*transaction.id(), transaction.tx())
+            );
+            assert_eq!(entry.kind, transaction.kind());
+        }
+        assert_eq!(entries[0].outcome, TransactionOutcome::Applied);
+        assert_eq!(
+            entries[1].outcome,
+            TransactionOutcome::Ignored(IgnoreReason::InsufficientFunds)
+        );
+
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        audit.write(&mut wtr).unwrap();
+        let csv = String::from_utf8(wtr.into_inner().unwrap()).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[..3],
+            [
+                "seq,client,tx,type,outcome",
+                "0,0,1,deposit,applied",
+                "1,0,2,withdrawal,ignored: insufficient_funds",
+            ]
+        );
+        assert_eq!(lines[17], "16,9,100,deposit,applied");
+    }
+
+    #[test]
+    /// Freezing blocks withdrawals until the accounts are unfrozen again
+    fn freeze_and_unfreeze_all() {
+        let mut system = ShardedAccountSystem::new(2);
+        for (client, tx) in (0..4).zip(0..) {
+            system.transact(deposit(client, tx, 100));
+        }
+        system.freeze_all();
+        for (client, tx) in (0..4).zip(10..) {
+            assert!(system.account(client).unwrap().locked());
+            system.transact(withdrawal(client, tx, 50));
+            assert_eq!(system.account(client).unwrap().total, Decimal::from(100));
+        }
+
+        system.unfreeze_all();
+        for (client, tx) in (0..4).zip(20..) {
+            assert!(!system.account(client).unwrap().locked());
+            system.transact(withdrawal(client, tx, 50));
+            assert_eq!(system.account(client).unwrap().total, Decimal::from(50));
+        }
+    }
+
+    #[test]
+    /// Freezing one account blocks its withdrawals only, until it's unfrozen again. Unfreezing
+    /// leaves a chargeback's lock alone, and clients without an account stay without one
+    fn freeze_and_unfreeze_one() {
+        let mut system = ShardedAccountSystem::new(4);
+        for tx in 1..=3 {
+            system.transact(deposit(tx as ClientId, tx, 100));
+        }
+        assert!(system.freeze(2));
+        system.transact(withdrawal(1, 10, 30));
+        system.transact(withdrawal(2, 11, 30));
+        assert_eq!(system.account(1).unwrap().total, Decimal::from(70));
+        assert_eq!(system.account(2).unwrap().total, Decimal::from(100));
+        assert!(system.account(2).unwrap().locked());
+
+        assert!(system.unfreeze(2));
+        system.transact(withdrawal(2, 12, 30));
+        assert_eq!(system.account(2).unwrap().total, Decimal::from(70));
+        assert!(!system.account(2).unwrap().locked());
+
+        system.transact(Transaction::Dispute {
+            client: 3,
+            tx: 3,
+            timestamp: None,
+        });
+        system.transact(Transaction::Chargeback {
+            client: 3,
+            tx: 3,
+            amount: None,
+            timestamp: None,
+        });
+        assert!(system.freeze(3) && system.unfreeze(3));
+        assert!(system.account(3).unwrap().locked());
+
+        assert!(!system.freeze(9));
+        assert!(!system.unfreeze(9));
+        assert_eq!(system.account(9), None);
+    }
+
+    #[test]
+    /// A client's account ends up in the shard it's said to be routed to
+    fn shard_of() {
+        assert_eq!(ShardedAccountSystem::new(0).shard_of(1), None);
+        let mut system = ShardedAccountSystem::new(8);
+        for tx in 0..64 {
+            system.transact(deposit(tx as ClientId, tx, 10));
+        }
+        let mut used = std::collections::BTreeSet::new();
+        for client in 0..64 {
+            let shard = system.shard_of(client).unwrap();
+            assert!(system.systems[shard].account(client).is_some());
+            used.insert(shard);
+        }
+        assert!(used.len() > 1);
+    }
+
+    #[test]
+    /// Only disputes that are still open are listed, across shards and in a fixed order
+    fn open_disputes() {
+        let mut system = ShardedAccountSystem::new(4);
+        for (client, tx, amount) in [(2, 1, 10), (1, 2, 20), (2, 3, 30), (1, 4, 40), (3, 5, 50)] {
+            system.transact(deposit(client, tx, amount));
+        }
+        for (client, tx) in [(2, 3), (1, 4), (2, 1), (1, 2)] {
+            system.transact(Transaction::Dispute {
+                client,
+                tx,
+                timestamp: None,
+            });
+        }
+        system.transact(Transaction::Resolve {
+            client: 1,
+            tx: 2,
+            amount: None,
+            timestamp: None,
+        });
+        assert_eq!(
+            system.open_disputes(),
+            vec![
+                (1, 4, Decimal::from(40)),
+                (2, 1, Decimal::from(10)),
+                (2, 3, Decimal::from(30)),
+            ]
+        );
+    }
+
+    #[test]
+    /// Disputes of the same amount are listed by client and transaction id, not in the order
+    /// they were disputed in
+    fn open_disputes_equal_amounts() {
+        let mut system = ShardedAccountSystem::new(4);
+        for (client, tx) in [(2, 9), (1, 8), (2, 4), (2, 6)] {
+            system.transact(deposit(client, tx, 50));
+        }
+        for (client, tx) in [(2, 9), (2, 6), (1, 8), (2, 4)] {
+            system.transact(Transaction::Dispute {
+                client,
+                tx,
+                timestamp: None,
+            });
+        }
+        let fifty = Decimal::from(50);
+        assert_eq!(
+            system.open_disputes(),
+            vec![(1, 8, fifty), (2, 4, fifty), (2, 6, fifty), (2, 9, fifty)]
+        );
+    }
+
+    #[test]
+    /// Unfreezing must not erase the lock caused by a chargeback
+    fn unfreeze_keeps_chargeback_lock() {
+        let mut system = ShardedAccountSystem::new(2);
+        system.transact(deposit(1, 1, 100));
+        system.transact(deposit(2, 2, 100));
+        system.transact(Transaction::Dispute {
+            client: 1,
+            tx: 1,
+            timestamp: None,
+        });
+        system.transact(Transaction::Chargeback {
+            client: 1,
+            tx: 1,
+            amount: None,
+            timestamp: None,
+        });
+
+        system.freeze_all();
+        system.unfreeze_all();
+        assert!(system.account(1).unwrap().locked());
+        assert!(!system.account(2).unwrap().locked());
+    }
+
+    #[test]
+    /// Nothing at or after the first transaction matching the predicate is applied
+    fn transact_until_stops_at_predicate() {
+        let mut system = ShardedAccountSystem::new(2);
+        let transactions = vec![
+            deposit(1, 1, 100),
+            withdrawal(1, 2, 30),
+            deposit(1, 3, 50),
+            deposit(1, 4, 50),
+        ];
+        let applied = system.transact_until(transactions, |transaction| {
+            matches!(transaction, Transaction::Deposit { tx: 3, .. })
+        });
+        assert_eq!(applied, 2);
+        assert_eq!(system.account(1).unwrap().total, Decimal::from(70));
+    }
+
+    #[test]
+    /// An empty batch reports nothing at all
+    fn transact_all_empty() {
+        let mut system = ShardedAccountSystem::new(2);
+        let report = system.transact_all(Vec::new());
+        assert!(report.is_empty());
+        assert_eq!(report, BatchReport::default());
+    }
+
+    #[test]
+    /// Mixed outcomes are counted, and kept individually up to the configured limit
+    fn transact_all_mixed_outcomes() {
+        let transactions = vec![
+            deposit(1, 1, 100),
+            withdrawal(1, 2, 500),
+            deposit(1, 1, 100),
+            withdrawal(1, 3, 50),
+        ];
+        let mut system = ShardedAccountSystem::new(2);
+        let options = BatchOptions { max_outcomes: 3 };
+        let report = system.transact_all_with(transactions.clone(), options);
+        assert_eq!((report.applied, report.ignored, report.rejected), (2, 2, 0));
+        assert_eq!(
+            report.outcomes,
+            vec![
+                TransactionOutcome::Applied,
+                TransactionOutcome::Ignored(IgnoreReason::InsufficientFunds),
+                TransactionOutcome::Ignored(IgnoreReason::DuplicateTx)
+            ]
+        );
+
+        // Same as applying them one by one
+        let mut single = ShardedAccountSystem::new(2);
+        let outcomes: Vec<TransactionOutcome> = transactions
+            .into_iter()
+            .map(|transaction| single.transact(transaction))
+            .collect();
+        assert_eq!(outcomes[..3], report.outcomes[..]);
+        assert!(single == system);
+
+        // Without shards there is nowhere to apply anything
+        let mut empty = ShardedAccountSystem::new(0);
+        let report = empty.transact_all(vec![deposit(1, 1, 100)]);
+        assert_eq!(report.rejected, 1);
+    }
+
+    #[test]
+    /// Replaying a batch on a snapshot can be compared with an expected state built from scratch
+    fn replay_on_snapshot() {
+        let mut system = ShardedAccountSystem::new(2);
+        system.replay(vec![
+            deposit(1, 1, 100),
+            deposit(2, 2, 50),
+            deposit(3, 3, 10),
+        ]);
+        let snapshot = system.clone();
+        assert!(snapshot == system);
+
+        let mut replayed = snapshot.clone();
+        replayed.replay(vec![withdrawal(1, 4, 30), deposit(4, 5, 5)]);
+        assert_eq!(replayed.diff(&snapshot), vec![1, 4]);
+
+        // The expected state doesn't even have to be sharded the same way
+        let mut expected = ShardedAccountSystem::new(3);
+        expected.replay(vec![
+            deposit(1, 1, 70),
+            deposit(2, 2, 50),
+            deposit(3, 3, 10),
+            deposit(4, 5, 5),
+        ]);
+        // Same balances, but client 1 went through a different set of deposits
+        assert_eq!(replayed.diff(&expected), vec![1]);
+        assert_eq!(
+            replayed.account(1).unwrap().total,
+            expected.account(1).unwrap().total
+        );
+        expected.remove(1);
+        expected.replay(vec![deposit(1, 1, 100), withdrawal(1, 4, 30)]);
+        assert!(replayed == expected);
+        assert!(replayed != snapshot);
+    }
+
+    fn at(transaction: Transaction, timestamp: u64) -> Transaction {
+        match transaction {
+            Transaction::Deposit {
+                client,
+                tx,
+                amount,
+                currency,
+                ..
+            } => Transaction::Deposit {
+                client,
+                tx,
+                amount,
+                currency,
+                timestamp: Some(timestamp),
+            },
+            Transaction::Withdrawal {
+                client,
+                tx,
+                amount,
+                currency,
+                ..
+            } => Transaction::Withdrawal {
+                client,
+                tx,
+                amount,
+                currency,
+                timestamp: Some(timestamp),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    /// Scheduled transactions stay out of the balances until the clock, driven by the
+    /// timestamps of other transactions, reaches them
+    fn scheduled_transactions() {
+        let mut system = ShardedAccountSystem::new(2);
+        system.transact(at(deposit(1, 1, 100), 10));
+        system.schedule(50, deposit(1, 2, 20));
+        system.schedule(30, withdrawal(1, 3, 110));
+        system.schedule(30, deposit(2, 4, 5));
+        assert_eq!(system.clock(), Some(10));
+        assert_eq!(system.account(1).unwrap().total, Decimal::from(100));
+        assert!(system.account(2).is_none());
+
+        // At 30 the deposit for client 2 and the withdrawal are due, the latter is applied
+        // before the immediate deposit stamped 30 and fails.
+        system.transact(at(deposit(1, 5, 10), 30));
+        assert_eq!(system.account(1).unwrap().total, Decimal::from(110));
+        assert_eq!(system.account(2).unwrap().total, Decimal::from(5));
+        let pending: Vec<u32> = system.pending().iter().map(|(_, t)| t.tx()).collect();
+        assert_eq!(pending, vec![2]);
+
+        // Scheduling something that's already due applies it right away
+        system.schedule(20, deposit(2, 6, 5));
+        assert_eq!(system.account(2).unwrap().total, Decimal::from(10));
+
+        system.apply_pending();
+        assert_eq!(system.account(1).unwrap().total, Decimal::from(130));
+        assert!(system.pending().is_empty());
+        assert_eq!(system.clock(), Some(30));
+    }
+
+    #[test]
+    /// Transactions effective at the same time are applied in the order they were scheduled
+    fn scheduled_ties_keep_order() {
+        let mut system = ShardedAccountSystem::new(2);
+        system.schedule(10, deposit(1, 1, 100));
+        system.schedule(10, withdrawal(1, 2, 100));
+        system.schedule(5, deposit(1, 3, 1));
+        system.advance(10);
+        // The withdrawal only went through because both deposits came first
+        assert_eq!(system.account(1).unwrap().total, Decimal::from(1));
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Applied(u32),
+        Ignored(u32),
+        Locked(ClientId),
+        Unlocked(ClientId),
+    }
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Event>>);
+
+    impl AccountObserver for Recorder {
+        fn on_applied(&self, _: ClientId, transaction: &Transaction, _: &AccountState) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(Event::Applied(transaction.tx()));
+        }
+
+        fn on_ignored(&self, _: ClientId, transaction: &Transaction, _: &AccountState) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(Event::Ignored(transaction.tx()));
+        }
+
+        fn on_locked(&self, client: ClientId, _: Option<&Transaction>, _: &AccountState) {
+            self.0.lock().unwrap().push(Event::Locked(client));
+        }
+
+        fn on_unlocked(&self, client: ClientId, _: Option<&Transaction>, _: &AccountState) {
+            self.0.lock().unwrap().push(Event::Unlocked(client));
+        }
+    }
+
+    #[test]
+    /// The observer hears about every outcome and lock change, in order
+    fn observer_events() {
+        let recorder = Arc::new(Recorder::default());
+        let mut system = ShardedAccountSystem::with_observer(2, recorder.clone());
+        system.transact(deposit(1, 1, 100));
+        system.transact(Transaction::Dispute {
+            client: 1,
+            tx: 2,
+            timestamp: None,
+        });
+        system.transact(Transaction::Dispute {
+            client: 1,
+            tx: 1,
+            timestamp: None,
+        });
+        system.transact(Transaction::Chargeback {
+            client: 1,
+            tx: 1,
+            amount: None,
+            timestamp: None,
+        });
+        system.transact(withdrawal(1, 3, 10));
+        system.freeze_all();
+        system.unfreeze_all();
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                Event::Applied(1),
+                Event::Ignored(2),
+                Event::Applied(1),
+                Event::Applied(1),
+                Event::Locked(1),
+                Event::Ignored(3),
+            ]
+        );
+    }
+
+    #[test]
+    /// With disputes turned off, a dispute is ignored before it reaches the account, while
+    /// deposits and withdrawals go on as before. Disabled types don't create accounts
+    fn disabled_types() {
+        let mut system = ShardedAccountSystem::new(2);
+        let mut policy = AccountPolicy::default();
+        policy.disabled.insert(RecordType::Dispute);
+        policy.disabled.insert(RecordType::Fee);
+        system.set_policy(policy);
+        assert_eq!(
+            system.transact(deposit(1, 1, 100)),
+            TransactionOutcome::Applied
+        );
+        assert_eq!(
+            system.transact(withdrawal(1, 2, 30)),
+            TransactionOutcome::Applied
+        );
+        let dispute = |client| Transaction::Dispute {
+            client,
+            tx: 1,
+            timestamp: None,
+        };
+        let disabled = TransactionOutcome::Ignored(IgnoreReason::Disabled);
+        assert_eq!(system.transact(dispute(1)), disabled);
+        assert_eq!(system.transact(dispute(2)), disabled);
+        let fee = Transaction::Fee {
+            client: 3,
+            tx: 3,
+            amount: Decimal::ONE,
+            currency: None,
+            timestamp: None,
+        };
+        assert_eq!(system.transact(fee), disabled);
+        let account = system.account(1).unwrap();
+        assert_eq!(
+            (account.held, account.total),
+            (Decimal::ZERO, Decimal::from(70))
+        );
+        assert!(system.account(2).is_none() && system.account(3).is_none());
+    }
+
+    /// What an observer was told about a transaction. Ignoring comes without a reason.
+    #[derive(Debug, PartialEq)]
+    enum Told {
+        Applied(u32),
+        Ignored(u32),
+        Rejected(u32, RejectReason),
+    }
+
+    #[derive(Default)]
+    struct Outcomes(Mutex<Vec<Told>>);
+
+    impl AccountObserver for Outcomes {
+        fn on_applied(&self, _: ClientId, transaction: &Transaction, _: &AccountState) {
+            self.0.lock().unwrap().push(Told::Applied(transaction.tx()));
+        }
+
+        fn on_ignored(&self, _: ClientId, transaction: &Transaction, _: &AccountState) {
+            self.0.lock().unwrap().push(Told::Ignored(transaction.tx()));
+        }
+
+        fn on_rejected(
+            &self,
+            _: ClientId,
+            transaction: &Transaction,
+            reason: RejectReason,
+            _: &AccountState,
+        ) {
+            let told = Told::Rejected(transaction.tx(), reason);
+            self.0.lock().unwrap().push(told);
+        }
+    }
+
+    #[test]
+    /// Observers registered after the fact are each told about every transaction from then
+    /// on, with the outcome it had. Those that don't tell rejections apart hear about them as
+    /// ignored
+    fn observers_see_every_outcome() {
+        let mut system = ShardedAccountSystem::new(2);
+        system.transact(deposit(1, 1, 100));
+        let (first, second) = (Arc::new(Outcomes::default()), Arc::new(Outcomes::default()));
+        let recorder = Arc::new(Recorder::default());
+        system.add_observer(first.clone());
+        system.add_observer(second.clone());
+        system.add_observer(recorder.clone());
+        let transactions = [
+            deposit(2, 2, 50),
+            withdrawal(2, 3, 80),
+            Transaction::Close {
+                client: 2,
+                tx: 4,
+                timestamp: None,
+            },
+            deposit(2, 5, 10),
+            withdrawal(1, 6, 30),
+        ];
+        let mut expected = Vec::new();
+        for transaction in transactions {
+            let tx = transaction.tx();
+            expected.push(match system.transact(transaction) {
+                TransactionOutcome::Applied => Told::Applied(tx),
+                TransactionOutcome::Ignored(_) => Told::Ignored(tx),
+                TransactionOutcome::Rejected(reason) => Told::Rejected(tx, reason),
+            });
+        }
+        assert_eq!(
+            expected,
+            [
+                Told::Applied(2),
+                Told::Ignored(3),
+                Told::Applied(4),
+                Told::Rejected(5, RejectReason::Closed),
+                Told::Applied(6),
+            ]
+        );
+        assert_eq!(*first.0.lock().unwrap(), expected);
+        assert_eq!(*second.0.lock().unwrap(), expected);
+        assert_eq!(recorder.0.lock().unwrap()[3], Event::Ignored(5));
+    }
+
+    #[test]
+    /// Freezing and unfreezing are lock changes too
+    fn observer_freeze_events() {
+        let recorder = Arc::new(Recorder::default());
+        let mut system = ShardedAccountSystem::with_observer(2, recorder.clone());
+        system.transact(deposit(1, 1, 100));
+        system.freeze_all();
+        system.unfreeze_all();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![Event::Applied(1), Event::Locked(1), Event::Unlocked(1)]
+        );
+    }
+
+    #[test]
+    /// Undoing a chargeback unlocks the account again, and the observer hears about it
+    fn undo_chargeback() {
+        let recorder = Arc::new(Recorder::default());
+        let mut system = ShardedAccountSystem::with_observer(2, recorder.clone());
+        system.set_policy(AccountPolicy {
+            undo_depth: 2,
+            ..AccountPolicy::default()
+        });
+        assert!(!system.undo_last(1));
+        system.transact(deposit(1, 1, 100));
+        system.transact(Transaction::Dispute {
+            client: 1,
+            tx: 1,
+            timestamp: None,
+        });
+        let disputed = system.clone();
+        system.transact(Transaction::Chargeback {
+            client: 1,
+            tx: 1,
+            amount: None,
+            timestamp: None,
+        });
+        assert!(system.account(1).unwrap().locked());
+        assert!(system.undo_last(1));
+        assert!(system == disputed);
+        assert!(!system.undo_last(2));
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                Event::Applied(1),
+                Event::Applied(1),
+                Event::Applied(1),
+                Event::Locked(1),
+                Event::Unlocked(1),
+            ]
+        );
+    }
+
+    #[test]
+    /// Rolling back restores the touched accounts exactly, and only those get copied
+    fn savepoint_rollback() {
+        let mut system = ShardedAccountSystem::new(2);
+        for (client, tx) in (0..100).zip(0..) {
+            system.transact(deposit(client, tx, 100));
+        }
+        let before = system.clone();
+
+        let outer = system.savepoint();
+        system.transact(deposit(1, 1000, 5));
+        system.transact(Transaction::Dispute {
+            client: 2,
+            tx: 2,
+            timestamp: None,
+        });
+        let inner = system.savepoint();
+        let middle = system.clone();
+        system.transact(Transaction::Chargeback {
+            client: 2,
+            tx: 2,
+            amount: None,
+            timestamp: None,
+        });
+        system.transact(withdrawal(1, 1001, 50));
+        system.transact(deposit(500, 1002, 7));
+        system.remove(3);
+        let copies = |system: &ShardedAccountSystem| -> Vec<usize> {
+            system
+                .systems
+                .iter()
+                .flat_map(|system| system.savepoints.iter().map(HashMap::len))
+                .collect()
+        };
+        // Clients 1 and 2 for the outer savepoint, 1, 2, 3 and 500 for the inner one.
+        assert_eq!(copies(&system).iter().sum::<usize>(), 6);
+
+        system.rollback(inner);
+        assert!(system == middle);
+        assert!(!system.account(2).unwrap().locked());
+        assert_eq!(system.account(2).unwrap().held, Decimal::from(100));
+        assert!(system.account(500).is_none());
+
+        system.transact(withdrawal(4, 1003, 100));
+        system.rollback(outer);
+        assert!(system == before);
+        assert_eq!(
+            system.account(1).unwrap().deposits,
+            before.account(1).unwrap().deposits
+        );
+        assert!(copies(&system).is_empty());
+    }
+
+    #[test]
+    /// Committing keeps the changes, but an enclosing savepoint can still undo them
+    fn savepoint_commit() {
+        let mut system = ShardedAccountSystem::new(2);
+        system.transact(deposit(1, 1, 100));
+        let before = system.clone();
+
+        let outer = system.savepoint();
+        let inner = system.savepoint();
+        system.transact(deposit(1, 2, 10));
+        system.transact(deposit(2, 3, 10));
+        system.commit(inner);
+        assert_eq!(system.account(1).unwrap().total, Decimal::from(110));
+        system.rollback(outer);
+        assert!(system == before);
+
+        let savepoint = system.savepoint();
+        system.transact(deposit(1, 4, 10));
+        system.commit(savepoint);
+        assert_eq!(system.account(1).unwrap().total, Decimal::from(110));
+        assert!(system
+            .systems
+            .iter()
+            .all(|system| system.savepoints.is_empty()));
+    }
+
+    #[test]
+    /// Only the accounts matching the predicate are returned, whatever shard they're on
+    fn filter() {
+        let mut system = ShardedAccountSystem::new(4);
+        for (client, tx) in (0..12).zip(0..) {
+            system.transact(deposit(client, tx, 10));
+            if client % 3 == 0 {
+                system.transact(Transaction::Dispute {
+                    client,
+                    tx,
+                    timestamp: None,
+                });
+            }
+        }
+        let held = system.filter(|_, account| account.held > Decimal::ZERO);
+        let clients: Vec<ClientId> = held.iter().map(|(client, _)| *client).collect();
+        assert_eq!(clients, [0, 3, 6, 9]);
+        assert!(held
+            .iter()
+            .all(|(_, account)| account.held == Decimal::from(10)));
+        assert!(system.filter(|client, _| client > 100).is_empty());
+    }
+
+    #[test]
+    /// Compaction only removes accounts that hold nothing, owe nothing and have been quiet
+    fn compact() {
+        let mut system = ShardedAccountSystem::new(2);
+        // Deposits can still be disputed, even once everything was withdrawn
+        system.transact(deposit(2, 2, 10));
+        system.transact(withdrawal(2, 3, 10));
+        // Held funds
+        system.transact(deposit(3, 4, 10));
+        system.transact(Transaction::Dispute {
+            client: 3,
+            tx: 4,
+            timestamp: None,
+        });
+        // Locked
+        system.transact(withdrawal(4, 5, 10));
+        system.freeze_all();
+        // Empty and quiet: an account whose only transaction was ignored
+        system.transact(withdrawal(1, 1, 10));
+        // Fees on record
+        system.transact(Transaction::Fee {
+            client: 6,
+            tx: 7,
+            amount: Decimal::from(0),
+            currency: None,
+            timestamp: None,
+        });
+        // Closed
+        system.transact(Transaction::Close {
+            client: 7,
+            tx: 8,
+            timestamp: None,
+        });
+        // Active since the horizon
+        system.transact(at(withdrawal(8, 9, 10), 100));
+        // Quiet for long enough
+        system.transact(at(withdrawal(9, 10, 10), 99));
+
+        assert_eq!(system.compact(100), 2);
+        let mut clients: Vec<ClientId> = system.accounts().map(|(client, _)| client).collect();
+        clients.sort_unstable();
+        assert_eq!(clients, vec![2, 3, 4, 6, 7, 8]);
+        assert_eq!(system.compact(100), 0);
+    }
+
+    #[test]
+    /// Disputes and the like for a client we've never seen create an empty account, unless
+    /// told to skip them
+    fn no_account_for_unknown_dispute() {
+        let dispute = Transaction::Dispute {
+            client: 1,
+            tx: 1,
+            timestamp: None,
+        };
+        let mut system = ShardedAccountSystem::new(2);
+        assert_eq!(
+            system.transact(dispute.clone()),
+            TransactionOutcome::Ignored(IgnoreReason::UnknownTx)
+        );
+        assert_eq!(system.account(1), Some(&AccountState::new()));
+
+        let mut system = ShardedAccountSystem::new(2);
+        system.skip_unknown_references();
+        assert_eq!(
+            system.transact(dispute),
+            TransactionOutcome::Ignored(IgnoreReason::UnknownTx)
+        );
+        assert!(system.account(1).is_none());
+    }
+
+    #[test]
+    /// Shards without accounts still get a part with the header that the others have
+    fn write_shards_headers() {
+        let mut system = ShardedAccountSystem::new(8);
+        system.transact(Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Decimal::ONE,
+            currency: Some("EUR".parse().unwrap()),
+            timestamp: None,
+        });
+        let rates = Rates::from_reader("currency,rate\nEUR,1.1\n".as_bytes()).unwrap();
+        for (extended, currencies, rates) in [
+            (false, false, None),
+            (true, false, None),
+            (true, true, Some(&rates)),
+            (false, true, None),
+        ] {
+            let options = ReportOptions {
+                extended,
+                currencies,
+                rates,
+                ..ReportOptions::default()
+            };
+            let mut writers: Vec<_> = (0..system.shard_count())
+                .map(|_| ReportWriter::new(Vec::new(), Default::default()))
+                .collect();
+            system.write_shards(&mut writers, options).unwrap();
+            let parts: Vec<String> = writers
+                .into_iter()
+                .map(|writer| String::from_utf8(writer.into_inner().unwrap()).unwrap())
+                .collect();
+            let header = |part: &String| part.lines().next().unwrap().to_string();
+            let full = parts.iter().find(|part| part.lines().count() > 1).unwrap();
+            assert_eq!(
+                parts
+                    .iter()
+                    .filter(|part| part.lines().count() == 1)
+                    .count(),
+                7
+            );
+            for part in &parts {
+                assert_eq!(header(part), header(full), "{:?}", options);
+            }
+        }
+    }
+
+    #[test]
+    /// With the deposit index, a dispute naming another client's deposit holds the funds of
+    /// the client that made it, in the serial and the threaded engine alike
+    fn cross_account_dispute() {
+        let dispute = Transaction::Dispute {
+            client: 2,
+            tx: 1,
+            timestamp: None,
+        };
+        let transactions = [deposit(1, 1, 10), deposit(2, 2, 5), dispute];
+
+        let mut system = ShardedAccountSystem::new(4);
+        system.transact_all(transactions.clone());
+        assert_eq!(system.account(1).unwrap().held, Decimal::ZERO);
+
+        let mut system = ShardedAccountSystem::new(4);
+        system.enable_deposit_index();
+        let parallel = ParallelAccountSystem::new(system.clone());
+        system.transact_all(transactions.clone());
+        parallel.transact_all(transactions);
+        let parallel = parallel.finish();
+        for system in [&system, &parallel] {
+            let owner = system.account(1).unwrap();
+            assert_eq!((owner.held, owner.total), (Decimal::TEN, Decimal::TEN));
+            let disputer = system.account(2).unwrap();
+            assert_eq!(
+                (disputer.held, disputer.total),
+                (Decimal::ZERO, Decimal::from(5))
+            );
+        }
+        assert_eq!(parallel.deposit_index().unwrap().owner(1), Some(1));
+    }
+
+    #[test]
+    /// The merged report is the naive one: every account, sorted by client, whether the
+    /// shards keep their accounts in order or not
+    fn write_sorted() {
+        let mut system = ShardedAccountSystem::new(4);
+        for (client, tx) in (0..200).rev().zip(1..) {
+            system.transact(deposit(client, tx, tx));
+        }
+        let mut naive: Vec<(ClientId, &AccountState)> = system.accounts().collect();
+        naive.sort_unstable_by_key(|(client, _)| *client);
+        let mut expected = ReportWriter::new(Vec::new(), Default::default());
+        for (client, account) in naive {
+            write_account(&mut expected, client, account, ReportOptions::default()).unwrap();
+        }
+        let expected = expected.into_inner().unwrap();
+
+        let sorted = |system: &ShardedAccountSystem| {
+            let mut writer = ReportWriter::new(Vec::new(), Default::default());
+            system
+                .write_sorted(&mut writer, ReportOptions::default())
+                .unwrap();
+            writer.into_inner().unwrap()
+        };
+        assert_eq!(sorted(&system), expected);
+        let mut ordered = system.clone();
+        ordered.enable_ordered_accounts();
+        ordered.rebalance(3);
+        assert!(ordered
+            .systems
+            .iter()
+            .all(|shard| shard.accounts.is_ordered()));
+        assert_eq!(sorted(&ordered), expected);
+        assert!(ordered == system);
+    }
+
+    #[test]
+    /// Largest total first across shards, and accounts with the same total in client order
+    fn write_by_total() {
+        let mut system = ShardedAccountSystem::new(4);
+        for (tx, (client, amount)) in [(7, 5), (3, 20), (9, 5), (1, 1), (4, 50), (2, 5)]
+            .into_iter()
+            .enumerate()
+        {
+            system.transact(deposit(client, tx as u32, amount));
+        }
+        let mut writer = ReportWriter::new(Vec::new(), Default::default());
+        system
+            .write_by_total(&mut writer, ReportOptions::default())
+            .unwrap();
+        let report = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let clients: Vec<&str> = report
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert_eq!(clients, ["4", "3", "2", "7", "9", "1"]);
+    }
+
+    #[test]
+    /// Locked accounts come first or go elsewhere, and both groups keep the order they came in
+    fn write_partitioned() {
+        let mut system = ShardedAccountSystem::new(4);
+        for tx in 1..=8 {
+            system.transact(deposit(tx as ClientId, tx, 10));
+        }
+        for tx in [6, 3, 7] {
+            let client = tx as ClientId;
+            system.transact(Transaction::Dispute {
+                client,
+                tx,
+                timestamp: None,
+            });
+            system.transact(Transaction::Chargeback {
+                client,
+                tx,
+                amount: None,
+                timestamp: None,
+            });
+        }
+        let clients = |writer: ReportWriter<Vec<u8>>| {
+            let report = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+            assert!(report.starts_with("client,"), "{:?}", report);
+            report
+                .lines()
+                .skip(1)
+                .map(|line| line.split(',').next().unwrap().parse().unwrap())
+                .collect::<Vec<u32>>()
+        };
+
+        let mut writer = ReportWriter::new(Vec::new(), Default::default());
+        let no_file: Option<&mut ReportWriter<Vec<u8>>> = None;
+        super::write_partitioned(
+            system.sorted_accounts(),
+            &mut writer,
+            no_file,
+            ReportOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(clients(writer), [3, 6, 7, 1, 2, 4, 5, 8]);
+
+        let mut writer = ReportWriter::new(Vec::new(), Default::default());
+        let mut locked = ReportWriter::new(Vec::new(), Default::default());
+        super::write_partitioned(
+            system.sorted_accounts(),
+            &mut writer,
+            Some(&mut locked),
+            ReportOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(clients(writer), [1, 2, 4, 5, 8]);
+        assert_eq!(clients(locked), [3, 6, 7]);
+
+        // Nothing locked still makes for a file with a header.
+        let mut locked = ReportWriter::new(Vec::new(), Default::default());
+        super::write_partitioned(
+            system
+                .sorted_accounts()
+                .filter(|(_, account)| !account.locked()),
+            &mut ReportWriter::new(Vec::new(), Default::default()),
+            Some(&mut locked),
+            ReportOptions::default(),
+        )
+        .unwrap();
+        assert!(clients(locked).is_empty());
+    }
+
+    #[test]
+    /// The estimate grows by the same amount for every account, and for every deposit
+    fn memory_footprint() {
+        let footprint = |accounts: u32, deposits: u32| {
+            let mut system = ShardedAccountSystem::new(4);
+            let mut tx = 0;
+            for client in (0..).take(accounts as usize) {
+                for _ in 0..deposits {
+                    tx += 1;
+                    system.transact(deposit(client, tx, 1));
+                }
+            }
+            system.memory_footprint()
+        };
+        let empty = footprint(0, 0);
+        let account = footprint(1, 1) - empty;
+        let deposit = footprint(1, 2) - footprint(1, 1);
+        assert!(deposit > 0 && account > deposit);
+        for (accounts, deposits) in [(10, 1), (100, 1), (100, 5), (1000, 3)] {
+            assert_eq!(
+                footprint(accounts, deposits),
+                empty + accounts as usize * (account + (deposits as usize - 1) * deposit)
+            );
+        }
+    }
 }
@@ -0,0 +1,38 @@
+use std::num::NonZeroU32;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Paces the input to at most so many transactions per second, to see how the engine copes
+/// with a steady upstream rather than a file it can read as fast as it likes.
+///
+/// Transactions are spaced out evenly, there is no bursting. Waiting sleeps the thread, so a
+/// throttled run doesn't burn a core doing nothing.
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    /// When the next transaction may go. Never further back than the last one that went.
+    next: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: NonZeroU32) -> Self {
+        RateLimiter {
+            interval: Duration::from_secs(1) / per_second.get(),
+            next: None,
+        }
+    }
+
+    /// Waits until the next transaction may go. The first one goes right away. A caller that
+    /// falls behind doesn't get to catch up in a burst afterwards.
+    pub fn wait(&mut self) {
+        let now = Instant::now();
+        let go = match self.next {
+            Some(next) if next > now => {
+                thread::sleep(next - now);
+                next
+            }
+            _ => now,
+        };
+        self.next = Some(go + self.interval);
+    }
+}
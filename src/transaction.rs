@@ -1,85 +1,583 @@
+use crate::currency::Currency;
+#[cfg(feature = "std")]
 use crate::Input;
+#[cfg(feature = "std")]
 use anyhow::bail;
 use rust_decimal::Decimal;
-use std::convert::TryInto;
+#[cfg(feature = "std")]
+use rust_decimal::RoundingStrategy;
+use serde::{Deserialize, Serialize};
+
+/// What identifies a client. Two bytes cover the 65,536 clients the original feed is limited
+/// to; the `client-id-u32` and `client-id-u64` features widen it for larger id spaces, at the
+/// cost of a few bytes per transaction and account. If both are on, the wider one wins.
+#[cfg(not(any(feature = "client-id-u32", feature = "client-id-u64")))]
+pub type ClientId = u16;
+#[cfg(all(feature = "client-id-u32", not(feature = "client-id-u64")))]
+pub type ClientId = u32;
+#[cfg(feature = "client-id-u64")]
+pub type ClientId = u64;
+
+/// The `type` column of the input. Deserializing straight into this spares every row a
+/// string, and a type that isn't one of these fails with the offending text and the ones
+/// there are, e.g. ``unknown variant `refund`, expected one of `deposit`, ...``.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Fee,
+    Interest,
+    Close,
+}
+
+impl RecordType {
+    pub const ALL: [RecordType; 8] = [
+        RecordType::Deposit,
+        RecordType::Withdrawal,
+        RecordType::Dispute,
+        RecordType::Resolve,
+        RecordType::Chargeback,
+        RecordType::Fee,
+        RecordType::Interest,
+        RecordType::Close,
+    ];
+
+    /// The type as used in the input.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordType::Deposit => "deposit",
+            RecordType::Withdrawal => "withdrawal",
+            RecordType::Dispute => "dispute",
+            RecordType::Resolve => "resolve",
+            RecordType::Chargeback => "chargeback",
+            RecordType::Fee => "fee",
+            RecordType::Interest => "interest",
+            RecordType::Close => "close",
+        }
+    }
+}
 
 /// We want to ensure that the incoming transactions are valid and as such it is useful to
 /// wrap them into their own discriminated union for both validation and convenience of
 /// discrimination for further use.
+///
+/// Every variant can carry the moment it happened as seconds since the Unix epoch. Feeds
+/// without a `timestamp` column simply leave it empty.
+///
+/// The serde representation is what ends up in event logs, see [crate::replay].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum Transaction {
     Deposit {
-        client: u16,
+        client: ClientId,
         tx: u32,
+        #[serde(with = "rust_decimal::serde::str")]
         amount: Decimal,
+        /// `None` for the implicit currency of inputs without a `currency` column.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
     },
     Withdrawal {
-        client: u16,
+        client: ClientId,
         tx: u32,
+        #[serde(with = "rust_decimal::serde::str")]
         amount: Decimal,
+        /// `None` for the implicit currency of inputs without a `currency` column.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
     },
     Dispute {
-        client: u16,
+        client: ClientId,
         tx: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
     },
+    /// Settles a dispute in the client's favour. With an `amount`, the client is credited
+    /// only that much of the deposit, e.g. because of a fee, and the rest is taken out of
+    /// the total.
     Resolve {
-        client: u16,
+        client: ClientId,
         tx: u32,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "optional_decimal"
+        )]
+        amount: Option<Decimal>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
     },
+    /// Reverses a disputed deposit. With an `amount`, only that part of the deposit is
+    /// reversed and the rest is released to the client.
     Chargeback {
-        client: u16,
+        client: ClientId,
+        tx: u32,
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "optional_decimal"
+        )]
+        amount: Option<Decimal>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+    },
+    /// A charge we post ourselves. See [crate::policy::AccountPolicy] for how it's applied.
+    Fee {
+        client: ClientId,
+        tx: u32,
+        #[serde(with = "rust_decimal::serde::str")]
+        amount: Decimal,
+        /// `None` for the implicit currency of inputs without a `currency` column.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+    },
+    /// Interest we credit ourselves. See [crate::policy::AccountPolicy] for how it's applied.
+    Interest {
+        client: ClientId,
+        tx: u32,
+        #[serde(with = "rust_decimal::serde::str")]
+        amount: Decimal,
+        /// `None` for the implicit currency of inputs without a `currency` column.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+    },
+    /// Closes the account and pays out the available funds. Locked accounts can't be closed.
+    Close {
+        client: ClientId,
         tx: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
     },
 }
 
 impl Transaction {
-    pub fn id(&self) -> &u16 {
+    pub fn id(&self) -> &ClientId {
         match self {
             Self::Deposit { client, .. } => client,
             Self::Withdrawal { client, .. } => client,
             Self::Dispute { client, .. } => client,
             Self::Resolve { client, .. } => client,
             Self::Chargeback { client, .. } => client,
+            Self::Fee { client, .. } => client,
+            Self::Interest { client, .. } => client,
+            Self::Close { client, .. } => client,
+        }
+    }
+
+    /// The transaction id, or for disputes, resolves and chargebacks the id of the transaction
+    /// they refer to.
+    pub fn tx(&self) -> u32 {
+        match self {
+            Self::Deposit { tx, .. }
+            | Self::Withdrawal { tx, .. }
+            | Self::Dispute { tx, .. }
+            | Self::Resolve { tx, .. }
+            | Self::Chargeback { tx, .. }
+            | Self::Fee { tx, .. }
+            | Self::Interest { tx, .. }
+            | Self::Close { tx, .. } => *tx,
+        }
+    }
+
+    /// The name of the transaction type, as used in the input.
+    pub fn kind(&self) -> &'static str {
+        self.record_type().name()
+    }
+
+    /// The type of the row the transaction is read from.
+    pub fn record_type(&self) -> RecordType {
+        match self {
+            Self::Deposit { .. } => RecordType::Deposit,
+            Self::Withdrawal { .. } => RecordType::Withdrawal,
+            Self::Dispute { .. } => RecordType::Dispute,
+            Self::Resolve { .. } => RecordType::Resolve,
+            Self::Chargeback { .. } => RecordType::Chargeback,
+            Self::Fee { .. } => RecordType::Fee,
+            Self::Interest { .. } => RecordType::Interest,
+            Self::Close { .. } => RecordType::Close,
+        }
+    }
+
+    /// The amount moved by the transaction. Disputes have none of their own, neither do
+    /// resolves and chargebacks that settle the whole deposit.
+    pub fn amount(&self) -> Option<Decimal> {
+        match self {
+            Self::Deposit { amount, .. }
+            | Self::Withdrawal { amount, .. }
+            | Self::Fee { amount, .. }
+            | Self::Interest { amount, .. } => Some(*amount),
+            Self::Resolve { amount, .. } | Self::Chargeback { amount, .. } => *amount,
+            Self::Dispute { .. } | Self::Close { .. } => None,
+        }
+    }
+
+    /// The currency the amount is in. Disputes, resolves and chargebacks refer to a deposit,
+    /// which is where their currency comes from.
+    pub fn currency(&self) -> Option<Currency> {
+        match self {
+            Self::Deposit { currency, .. }
+            | Self::Withdrawal { currency, .. }
+            | Self::Fee { currency, .. }
+            | Self::Interest { currency, .. } => *currency,
+            Self::Dispute { .. }
+            | Self::Resolve { .. }
+            | Self::Chargeback { .. }
+            | Self::Close { .. } => None,
+        }
+    }
+
+    pub fn timestamp(&self) -> Option<u64> {
+        match self {
+            Self::Deposit { timestamp, .. }
+            | Self::Withdrawal { timestamp, .. }
+            | Self::Dispute { timestamp, .. }
+            | Self::Resolve { timestamp, .. }
+            | Self::Chargeback { timestamp, .. }
+            | Self::Fee { timestamp, .. }
+            | Self::Interest { timestamp, .. }
+            | Self::Close { timestamp, .. } => *timestamp,
         }
     }
 }
 
-impl TryInto<Transaction> for Input {
-    type Error = anyhow::Error;
+/// Optional amounts in the same string form [rust_decimal::serde::str] uses for the others.
+mod optional_decimal {
+    use alloc::string::{String, ToString};
+    use core::str::FromStr;
+    use rust_decimal::Decimal;
+    use serde::{de, Deserialize, Deserializer, Serializer};
 
-    fn try_into(self) -> Result<Transaction, Self::Error> {
-        match self.type_.as_str() {
-            "deposit" => Ok(Transaction::Deposit {
+    pub fn serialize<S: Serializer>(
+        value: &Option<Decimal>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.serialize_some(&value.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Decimal>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|value| Decimal::from_str(&value).map_err(de::Error::custom))
+            .transpose()
+    }
+}
+
+impl AsRef<Transaction> for Transaction {
+    fn as_ref(&self) -> &Transaction {
+        self
+    }
+}
+
+#[cfg(feature = "std")]
+impl Input {
+    /// Converts the raw record into a [Transaction], rounding amounts to 4 decimal places
+    /// using the given strategy.
+    pub(crate) fn into_transaction(
+        self,
+        rounding: RoundingStrategy,
+    ) -> anyhow::Result<Transaction> {
+        let amount = self
+            .amount
+            .map(|amount| amount.round_dp_with_strategy(4, rounding)); // Round to 4 decimal places
+        let required = |kind: &str| match amount {
+            Some(amount) => Ok(amount),
+            None => bail!("An amount needs to be specified for {}.", kind),
+        };
+        match self.type_ {
+            RecordType::Deposit => Ok(Transaction::Deposit {
+                client: self.client,
+                tx: self.tx,
+                amount: required("deposit")?,
+                currency: self.currency,
+                timestamp: self.timestamp,
+            }),
+            RecordType::Withdrawal => Ok(Transaction::Withdrawal {
+                client: self.client,
+                tx: self.tx,
+                amount: required("withdraw")?,
+                currency: self.currency,
+                timestamp: self.timestamp,
+            }),
+            RecordType::Dispute => Ok(Transaction::Dispute {
+                client: self.client,
+                tx: self.tx,
+                timestamp: self.timestamp,
+            }),
+            RecordType::Resolve => Ok(Transaction::Resolve {
                 client: self.client,
                 tx: self.tx,
-                amount: self
-                    .amount
-                    .expect("An amount needs to be specified for deposit.")
-                    .round_dp(4), // Round to 4 decimal places
+                amount,
+                timestamp: self.timestamp,
             }),
-            "withdrawal" => Ok(Transaction::Withdrawal {
+            RecordType::Chargeback => Ok(Transaction::Chargeback {
                 client: self.client,
                 tx: self.tx,
-                amount: self
-                    .amount
-                    .expect("An amount needs to be specified for withdraw.")
-                    .round_dp(4), // Round to 4 decimal places
+                amount,
+                timestamp: self.timestamp,
             }),
-            "dispute" => Ok(Transaction::Dispute {
+            RecordType::Fee => Ok(Transaction::Fee {
                 client: self.client,
                 tx: self.tx,
+                amount: required("fee")?,
+                currency: self.currency,
+                timestamp: self.timestamp,
             }),
-            "resolve" => Ok(Transaction::Resolve {
+            RecordType::Interest => Ok(Transaction::Interest {
                 client: self.client,
                 tx: self.tx,
+                amount: required("interest")?,
+                currency: self.currency,
+                timestamp: self.timestamp,
             }),
-            "chargeback" => Ok(Transaction::Chargeback {
+            RecordType::Close => Ok(Transaction::Close {
                 client: self.client,
                 tx: self.tx,
+                timestamp: self.timestamp,
             }),
-            // Based on our handling, this will stop the program. However, IMHO, it should stop because
-            // this probably means something terrible has happened and continuing process is unlikely
-            // to yield correct state in the end.
-            _ => bail!("Following input could not be parsed: {:?}", self),
         }
     }
 }
+
+#[cfg(feature = "std")]
+impl Input {
+    /// Whether the amount has more than four decimal places that rounding would lose. Trailing
+    /// zeros don't count, `1.00000` is as exact as `1.0000`.
+    pub(crate) fn is_too_precise(&self) -> bool {
+        self.amount.is_some_and(|amount| {
+            amount.round_dp_with_strategy(4, RoundingStrategy::ToZero) != amount
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl TryInto<Transaction> for Input {
+    type Error = anyhow::Error;
+
+    /// Uses banker's rounding, which is what `round_dp` does by default.
+    fn try_into(self) -> Result<Transaction, Self::Error> {
+        self.into_transaction(RoundingStrategy::MidpointNearestEven)
+    }
+}
+
+// Parsing is all there is to test here, and that needs std.
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn deposit(amount: &str) -> Input {
+        Input {
+            type_: RecordType::Deposit,
+            client: 0,
+            tx: 0,
+            amount: Some(Decimal::from_str(amount).unwrap()),
+            timestamp: None,
+            effective: None,
+            currency: None,
+        }
+    }
+
+    fn rounded(amount: &str, rounding: RoundingStrategy) -> Decimal {
+        match deposit(amount).into_transaction(rounding).unwrap() {
+            Transaction::Deposit { amount, .. } => amount,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    /// Half-up always moves a midpoint away from zero
+    fn round_half_up() {
+        let strategy = RoundingStrategy::MidpointAwayFromZero;
+        assert_eq!(
+            rounded("0.12345", strategy),
+            Decimal::from_str("0.1235").unwrap()
+        );
+        assert_eq!(
+            rounded("0.12355", strategy),
+            Decimal::from_str("0.1236").unwrap()
+        );
+    }
+
+    #[test]
+    /// Banker's rounding moves a midpoint to the nearest even digit
+    fn round_half_even() {
+        let strategy = RoundingStrategy::MidpointNearestEven;
+        assert_eq!(
+            rounded("0.12345", strategy),
+            Decimal::from_str("0.1234").unwrap()
+        );
+        assert_eq!(
+            rounded("0.12355", strategy),
+            Decimal::from_str("0.1236").unwrap()
+        );
+    }
+
+    #[test]
+    /// The plain conversion keeps the original banker's rounding
+    fn default_rounding_unchanged() {
+        let transaction: Transaction = deposit("0.12345").try_into().unwrap();
+        match transaction {
+            Transaction::Deposit { amount, .. } => {
+                assert_eq!(amount, Decimal::from_str("0.1234").unwrap())
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    /// Fees and interest carry an amount, rounded like every other one
+    fn parse_adjustments() {
+        let input = Input {
+            type_: RecordType::Fee,
+            ..deposit("1.23456")
+        };
+        assert_eq!(
+            input
+                .into_transaction(RoundingStrategy::MidpointNearestEven)
+                .unwrap(),
+            Transaction::Fee {
+                client: 0,
+                tx: 0,
+                amount: Decimal::from_str("1.2346").unwrap(),
+                currency: None,
+                timestamp: None,
+            }
+        );
+        let input = Input {
+            type_: RecordType::Interest,
+            ..deposit("0.5")
+        };
+        assert_eq!(
+            input
+                .into_transaction(RoundingStrategy::MidpointNearestEven)
+                .unwrap(),
+            Transaction::Interest {
+                client: 0,
+                tx: 0,
+                amount: Decimal::from_str("0.5").unwrap(),
+                currency: None,
+                timestamp: None,
+            }
+        );
+    }
+
+    #[test]
+    /// A resolve may come with the amount credited, rounded like the others
+    fn parse_adjusted_resolve() {
+        let input = Input {
+            type_: RecordType::Resolve,
+            ..deposit("95.00005")
+        };
+        let transaction = input
+            .into_transaction(RoundingStrategy::MidpointNearestEven)
+            .unwrap();
+        assert_eq!(
+            transaction.amount(),
+            Some(Decimal::from_str("95.0000").unwrap())
+        );
+        let json = serde_json::to_string(&transaction).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Transaction>(&json).unwrap(),
+            transaction
+        );
+        let plain: Transaction =
+            serde_json::from_str(r#"{"type":"resolve","client":0,"tx":0}"#).unwrap();
+        assert_eq!(plain.amount(), None);
+    }
+
+    #[test]
+    /// A chargeback amount is optional and survives the trip through the event log
+    fn parse_partial_chargeback() {
+        let input = Input {
+            type_: RecordType::Chargeback,
+            ..deposit("30.00005")
+        };
+        let transaction = input
+            .into_transaction(RoundingStrategy::MidpointNearestEven)
+            .unwrap();
+        assert_eq!(
+            transaction,
+            Transaction::Chargeback {
+                client: 0,
+                tx: 0,
+                amount: Some(Decimal::from_str("30.0000").unwrap()),
+                timestamp: None,
+            }
+        );
+        let json = serde_json::to_string(&transaction).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"chargeback","client":0,"tx":0,"amount":"30.0000"}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Transaction>(&json).unwrap(),
+            transaction
+        );
+        let full: Transaction =
+            serde_json::from_str(r#"{"type":"chargeback","client":0,"tx":0}"#).unwrap();
+        assert_eq!(full.amount(), None);
+    }
+
+    #[test]
+    /// Every type reads from its name, and one that isn't known is named in the error
+    fn parse_record_type() {
+        let csv = "type\ndeposit\nwithdrawal\ndispute\nresolve\nchargeback\nfee\ninterest\nclose\n";
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        let types: Vec<RecordType> = reader
+            .deserialize::<(RecordType,)>()
+            .map(|row| row.unwrap().0)
+            .collect();
+        assert_eq!(types.len(), 8);
+        assert_eq!(types[7], RecordType::Close);
+
+        let mut reader = csv::Reader::from_reader("type\nrefund\n".as_bytes());
+        let error = reader
+            .deserialize::<(RecordType,)>()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("unknown variant `refund`"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    /// Closing an account takes no amount
+    fn parse_close() {
+        let input = Input {
+            type_: RecordType::Close,
+            amount: None,
+            timestamp: Some(7),
+            ..deposit("0")
+        };
+        let transaction: Transaction = input.try_into().unwrap();
+        assert_eq!(
+            transaction,
+            Transaction::Close {
+                client: 0,
+                tx: 0,
+                timestamp: Some(7),
+            }
+        );
+        assert_eq!(transaction.kind(), "close");
+        assert_eq!(transaction.amount(), None);
+    }
+}
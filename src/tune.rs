@@ -0,0 +1,211 @@
+//! Running the same transactions at growing shard counts, to find out how many shards are
+//! worth it on this machine. See `track tune`.
+use crate::parallel::ParallelAccountSystem;
+use crate::policy::AccountPolicy;
+use crate::system::ShardedAccountSystem;
+use crate::transaction::{ClientId, Transaction};
+use anyhow::bail;
+use rust_decimal::Decimal;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How one shard count did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TuneRun {
+    pub shards: usize,
+    /// Wall time for applying every transaction, shards on threads of their own.
+    pub elapsed: Duration,
+    /// Transactions of the busiest shard over the mean per shard. One is perfectly even, as
+    /// many as there are shards means one shard got everything.
+    pub imbalance: f64,
+}
+
+/// The runs of [tune], from one shard up.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tuning {
+    pub runs: Vec<TuneRun>,
+}
+
+impl Tuning {
+    /// The fewest shards that come within a tenth of the fastest run. More shards than that
+    /// cost threads and memory for gains that are lost in the noise.
+    pub fn suggested(&self) -> Option<usize> {
+        let fastest = self.runs.iter().map(|run| run.elapsed).min()?;
+        self.runs
+            .iter()
+            .find(|run| run.elapsed <= fastest + fastest / 10)
+            .map(|run| run.shards)
+    }
+}
+
+impl fmt::Display for Tuning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "shards,ms,imbalance")?;
+        for run in &self.runs {
+            writeln!(
+                f,
+                "{},{:.1},{:.2}",
+                run.shards,
+                run.elapsed.as_secs_f64() * 1e3,
+                run.imbalance
+            )?;
+        }
+        match self.suggested() {
+            Some(shards) => write!(f, "suggested: --shards {}", shards),
+            None => write!(f, "suggested: nothing, there were no runs"),
+        }
+    }
+}
+
+/// Applies `transactions` with 1, 2, 4, … shards, up to `max_shards`, and times each run. The
+/// runs have to agree on every balance, a shard count that changes the outcome is a bug and
+/// fails the tuning.
+pub fn tune(
+    transactions: &[Transaction],
+    max_shards: usize,
+    policy: AccountPolicy,
+    queue_capacity: usize,
+) -> anyhow::Result<Tuning> {
+    let mut tuning = Tuning::default();
+    let mut first: Option<ShardedAccountSystem> = None;
+    let counts = std::iter::successors(Some(1usize), |shards| shards.checked_mul(2));
+    for shards in counts.take_while(|shards| *shards <= max_shards.max(1)) {
+        let mut system = ShardedAccountSystem::new(shards);
+        system.set_policy(policy);
+        let mut routed = vec![0u64; shards];
+        for transaction in transactions {
+            routed[system.shard_of(*transaction.id()).unwrap()] += 1;
+        }
+        let start = Instant::now();
+        let parallel = ParallelAccountSystem::with_queue_capacity(system, queue_capacity);
+        parallel.transact_all(transactions.iter().cloned());
+        let system = parallel.finish();
+        let elapsed = start.elapsed();
+        match &first {
+            None => first = Some(system),
+            Some(first) => {
+                let differing = first.diff(&system);
+                if let Some(client) = differing.first() {
+                    bail!(
+                        "{} shards disagree with one about {} accounts, client {} among them",
+                        shards,
+                        differing.len(),
+                        client
+                    );
+                }
+            }
+        }
+        let busiest = routed.iter().copied().max().unwrap_or(0);
+        let total: u64 = routed.iter().sum();
+        let imbalance = match total {
+            0 => 1.0,
+            total => busiest as f64 * shards as f64 / total as f64,
+        };
+        tuning.runs.push(TuneRun {
+            shards,
+            elapsed,
+            imbalance,
+        });
+    }
+    Ok(tuning)
+}
+
+/// Xorshift, deterministic and good enough to spread clients, amounts and disputes. Not for
+/// anything that needs to be unpredictable.
+#[derive(Debug, Clone)]
+pub struct Xorshift(u64);
+
+impl Xorshift {
+    /// The same `seed` makes the same numbers. Zero would only ever make zeros, so it's made
+    /// odd.
+    pub fn new(seed: u64) -> Self {
+        Xorshift(seed | 1)
+    }
+
+    /// A number below `bound`, which mustn't be zero.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 % bound
+    }
+}
+
+/// A made-up workload for when there's no input to tune with: deposits and withdrawals of
+/// `clients` clients, with a dispute, resolve or chargeback now and then. The same `seed` makes
+/// the same transactions.
+pub fn synthetic(rows: usize, clients: u64, seed: u64) -> Vec<Transaction> {
+    let clients = clients.max(1);
+    let mut xorshift = Xorshift::new(seed);
+    let mut random = move |bound: u64| xorshift.below(bound);
+    let mut transactions = Vec::with_capacity(rows);
+    for tx in (0u32..).take(rows) {
+        let client = random(clients) as ClientId;
+        let amount = Decimal::new(random(100_000) as i64 + 1, 2);
+        // Refers back to a recent transaction, which is a deposit more often than not.
+        let earlier = tx.saturating_sub(random(64) as u32 + 1);
+        let transaction = match random(100) {
+            0..=59 => Transaction::Deposit {
+                client,
+                tx,
+                amount,
+                currency: None,
+                timestamp: None,
+            },
+            60..=91 => Transaction::Withdrawal {
+                client,
+                tx,
+                amount,
+                currency: None,
+                timestamp: None,
+            },
+            92..=95 => Transaction::Dispute {
+                client,
+                tx: earlier,
+                timestamp: None,
+            },
+            96..=98 => Transaction::Resolve {
+                client,
+                tx: earlier,
+                amount: None,
+                timestamp: None,
+            },
+            _ => Transaction::Chargeback {
+                client,
+                tx: earlier,
+                amount: None,
+                timestamp: None,
+            },
+        };
+        transactions.push(transaction);
+    }
+    transactions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Every power of two up to the maximum gets a run, the suggestion is one of them, and one
+    /// shard is as even as it gets
+    fn runs_up_to_max_shards() {
+        let transactions = synthetic(5_000, 200, 7);
+        assert_eq!(transactions, synthetic(5_000, 200, 7));
+        let tuning = tune(&transactions, 6, AccountPolicy::default(), 64).unwrap();
+        let shards: Vec<usize> = tuning.runs.iter().map(|run| run.shards).collect();
+        assert_eq!(shards, [1, 2, 4]);
+        assert_eq!(tuning.runs[0].imbalance, 1.0);
+        assert!(tuning
+            .runs
+            .iter()
+            .all(|run| run.imbalance >= 1.0 && run.imbalance <= run.shards as f64));
+        assert!(shards.contains(&tuning.suggested().unwrap()));
+        let table = tuning.to_string();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "shards,ms,imbalance");
+        assert!(lines[1].starts_with("1,"));
+        assert!(lines[4].starts_with("suggested: --shards "));
+    }
+}
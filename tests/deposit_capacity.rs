@@ -0,0 +1,59 @@
+//! An account given room for its deposits up front records them without growing its store
+//! again and again. Allocations are counted process-wide, which is why this is the only test in
+//! here.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use track::account::AccountState;
+use track::transaction::Transaction;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const DEPOSITS: u32 = 10_000;
+
+/// How many times recording every deposit of a busy client allocates.
+fn allocations(mut account: AccountState) -> usize {
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    for tx in 0..DEPOSITS {
+        account.transact(Transaction::Deposit {
+            client: 1,
+            tx,
+            amount: rust_decimal::Decimal::ONE,
+            currency: None,
+            timestamp: None,
+        });
+    }
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst) - before;
+    assert_eq!(account.total, rust_decimal::Decimal::from(DEPOSITS));
+    allocations
+}
+
+#[test]
+/// With room for every deposit, the store doesn't grow at all while they come in
+fn hint_saves_allocations() {
+    let growing = allocations(AccountState::new());
+    let hinted = allocations(AccountState::with_deposit_capacity(DEPOSITS as usize));
+    assert!(growing >= 10, "{}", growing);
+    assert!(hinted < growing / 4, "{} vs {}", hinted, growing);
+}
@@ -0,0 +1,111 @@
+//! What the engine tells the `log` facade. The logger is set once for the whole process, which
+//! is why this is the only test in here.
+#![cfg(feature = "std")]
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rust_decimal::Decimal;
+use std::sync::Mutex;
+use track::account::{IgnoreReason, RejectReason, TransactionOutcome};
+use track::system::AccountSystem;
+use track::transaction::Transaction;
+
+/// Keeps every event of this crate, with its level.
+struct Captured(Mutex<Vec<(Level, String)>>);
+
+impl Log for Captured {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("track")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let event = (record.level(), record.args().to_string());
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Captured = Captured(Mutex::new(Vec::new()));
+
+fn withdrawal(tx: u32, amount: i64) -> Transaction {
+    Transaction::Withdrawal {
+        client: 1,
+        tx,
+        amount: Decimal::from(amount),
+        currency: None,
+        timestamp: None,
+    }
+}
+
+#[test]
+/// Nothing is logged without a logger. With one, new accounts, disputes of clients without an
+/// account, and withdrawals that are ignored or rejected all show up, at their levels
+fn events() {
+    let mut system = AccountSystem::new();
+    system.transact(Transaction::Dispute {
+        client: 9,
+        tx: 1,
+        timestamp: None,
+    });
+    assert!(LOGGER.0.lock().unwrap().is_empty());
+
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Debug);
+    let mut system = AccountSystem::new();
+    system.skip_unknown_references();
+    system.transact(Transaction::Deposit {
+        client: 1,
+        tx: 1,
+        amount: Decimal::from(10),
+        currency: None,
+        timestamp: None,
+    });
+    assert_eq!(
+        system.transact(withdrawal(2, 50)),
+        TransactionOutcome::Ignored(IgnoreReason::InsufficientFunds)
+    );
+    system.transact(Transaction::Close {
+        client: 1,
+        tx: 3,
+        timestamp: None,
+    });
+    assert_eq!(
+        system.transact(withdrawal(4, 5)),
+        TransactionOutcome::Rejected(RejectReason::Closed)
+    );
+    system.transact(Transaction::Dispute {
+        client: 9,
+        tx: 1,
+        timestamp: None,
+    });
+
+    let events = LOGGER.0.lock().unwrap();
+    let expected = [
+        (Level::Debug, "client 1: account created"),
+        (
+            Level::Debug,
+            "client 1: withdrawal 2 ignored as insufficient_funds",
+        ),
+        (
+            Level::Warn,
+            "client 1: withdrawal 4 rejected, the account is closed",
+        ),
+        (
+            Level::Info,
+            "client 9: dispute of unknown tx 1 ignored, the client has no account",
+        ),
+    ];
+    for (level, message) in expected {
+        assert!(
+            events
+                .iter()
+                .any(|event| event.0 == level && event.1 == message),
+            "{:?} lacks {} {:?}",
+            events,
+            level,
+            message
+        );
+    }
+}
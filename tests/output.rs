@@ -0,0 +1,118 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Exercises every kind of stderr output there is: an ignored withdrawal, a rejected deposit,
+/// a transaction id anomaly, and a summary and timings on request.
+const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,50.0
+deposit,2,4,5000.0
+deposit,1,0,1.0
+";
+
+const REPORT: &str = "client,available,held,total,locked
+1,11.0,0.0,11.0,false
+2,5.0,0.0,5.0,false
+";
+
+fn input() -> PathBuf {
+    let path = std::env::temp_dir().join(format!("track-output-{}.csv", std::process::id()));
+    std::fs::write(&path, INPUT).unwrap();
+    path
+}
+
+fn track(input: &PathBuf, verbosity: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_track"))
+        .args(verbosity)
+        .args(["--summary", "--timings", "--check-tx-order"])
+        .args(["--max-amount", "1000"])
+        .arg(input)
+        .output()
+        .unwrap()
+}
+
+/// Accounts come out in no particular order.
+fn sorted(output: &[u8]) -> String {
+    let output = String::from_utf8(output.to_vec()).unwrap();
+    let mut lines: Vec<&str> = output.lines().collect();
+    lines[1..].sort();
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+#[test]
+/// Stdout has the report and nothing else at every verbosity, stderr gets more the louder
+fn stdout_is_only_the_report() {
+    let input = input();
+    let levels: [&[&str]; 5] = [&["-q"], &[], &["-v"], &["-vv"], &["--verbosity", "debug"]];
+    let stderr: Vec<String> = levels
+        .into_iter()
+        .map(|verbosity| {
+            let output = track(&input, verbosity);
+            assert!(output.status.success(), "{:?}", output);
+            assert_eq!(sorted(&output.stdout), REPORT, "{:?}", verbosity);
+            String::from_utf8(output.stderr).unwrap()
+        })
+        .collect();
+    std::fs::remove_file(&input).unwrap();
+
+    assert_eq!(stderr[0], "");
+    for stderr in &stderr[1..] {
+        assert!(
+            stderr.contains("rejected: line 5, client 2, tx 4"),
+            "{}",
+            stderr
+        );
+        assert!(
+            stderr.contains("warning: line 6, client 1, tx 0"),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("rows processed: 5"), "{}", stderr);
+        assert!(stderr.contains("transacting: "), "{}", stderr);
+    }
+    let ignored = "ignored: client 1, tx 3, withdrawal: insufficient_funds";
+    assert!(!stderr[1].contains(ignored));
+    assert!(!stderr[2].contains(ignored));
+    assert!(stderr[3].contains(ignored));
+    assert!(stderr[4].contains(ignored));
+}
+
+#[test]
+/// Like diff(1), `track diff` exits with 0 for the same accounts, 1 for differences, which go
+/// to stdout, and 2 for trouble, which goes to stderr as it is
+fn diff_exit_codes() {
+    let dir = std::env::temp_dir();
+    let old = dir.join(format!("track-diff-old-{}.csv", std::process::id()));
+    let new = dir.join(format!("track-diff-new-{}.csv", std::process::id()));
+    std::fs::write(&old, REPORT).unwrap();
+    std::fs::write(&new, REPORT.replace("5.0,false", "5.0,true")).unwrap();
+    let diff = |new: &PathBuf| {
+        Command::new(env!("CARGO_BIN_EXE_track"))
+            .arg("diff")
+            .args([&old, new])
+            .output()
+            .unwrap()
+    };
+
+    let same = diff(&old);
+    assert_eq!(same.status.code(), Some(0), "{:?}", same);
+    assert!(same.stdout.is_empty());
+
+    let differing = diff(&new);
+    assert_eq!(differing.status.code(), Some(1), "{:?}", differing);
+    assert!(differing.stderr.is_empty(), "{:?}", differing);
+    assert_eq!(
+        String::from_utf8(differing.stdout).unwrap(),
+        "client 2: locked expected false, got true\n"
+    );
+
+    let missing = dir.join(format!("track-diff-missing-{}.csv", std::process::id()));
+    let trouble = diff(&missing);
+    std::fs::remove_file(&old).unwrap();
+    std::fs::remove_file(&new).unwrap();
+    assert_eq!(trouble.status.code(), Some(2), "{:?}", trouble);
+    let stderr = String::from_utf8(trouble.stderr).unwrap();
+    assert!(stderr.starts_with("Error: "), "{}", stderr);
+    assert!(!stderr.contains('"'), "{}", stderr);
+}
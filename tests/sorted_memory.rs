@@ -0,0 +1,75 @@
+//! Writing a sorted report from accounts kept in order takes memory for a handful of accounts
+//! per shard, however many accounts there are. Allocations are counted process-wide, which is
+//! why this is the only test in here.
+#![cfg(feature = "std")]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use track::flush::{FlushPolicy, ReportWriter};
+use track::system::{ReportOptions, ShardedAccountSystem};
+use track::transaction::Transaction;
+
+struct Counting;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let current = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK.fetch_max(current, Ordering::SeqCst);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const SHARDS: usize = 4;
+
+fn system(accounts: usize, ordered: bool) -> ShardedAccountSystem {
+    let mut system = ShardedAccountSystem::new(SHARDS);
+    if ordered {
+        system.enable_ordered_accounts();
+    }
+    for (client, tx) in (0..).zip(0..).take(accounts) {
+        system.transact(Transaction::Deposit {
+            client,
+            tx,
+            amount: rust_decimal::Decimal::ONE,
+            currency: None,
+            timestamp: None,
+        });
+    }
+    system
+}
+
+/// The most memory held at once while writing the sorted report, on top of what was held
+/// before.
+fn peak_while_writing(system: &ShardedAccountSystem) -> usize {
+    let mut writer = ReportWriter::new(std::io::sink(), FlushPolicy::AtEnd);
+    let before = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    system
+        .write_sorted(&mut writer, ReportOptions::default())
+        .unwrap();
+    PEAK.load(Ordering::SeqCst) - before
+}
+
+#[test]
+/// Ten times the accounts take no more memory to write in order, unless they have to be sorted
+fn peak_memory_follows_shards() {
+    let small = peak_while_writing(&system(1_000, true));
+    let large = peak_while_writing(&system(10_000, true));
+    // The CSV writer's buffer and a few bytes per shard for the merge.
+    assert!(large <= small + 1024, "{} vs {}", large, small);
+    assert!(large < 64 * 1024, "{}", large);
+
+    let hashed = peak_while_writing(&system(10_000, false));
+    assert!(hashed > 10_000 * std::mem::size_of::<usize>(), "{}", hashed);
+}
@@ -0,0 +1,138 @@
+//! Spilling deposits to disk changes where they're kept, not what happens to them.
+#![cfg(feature = "spill-deposits")]
+
+use rust_decimal::Decimal;
+use std::sync::Arc;
+use track::account::TransactionOutcome;
+use track::deposits::DepositStore;
+use track::spill::SpillFile;
+use track::system::ShardedAccountSystem;
+use track::transaction::{ClientId, Transaction};
+use track::tune::Xorshift;
+
+const CLIENTS: u32 = 40;
+const ROWS: u32 = 20_000;
+
+/// Deposits and the odd withdrawal, with a dispute of an earlier deposit of the same client
+/// every few rows, settled some rows later by a resolve or now and then a chargeback. Plenty
+/// of them refer to deposits that were spilled long ago.
+fn workload() -> Vec<Transaction> {
+    let mut xorshift = Xorshift::new(0x9e37_79b9_7f4a_7c15);
+    let mut random = move |bound: u32| xorshift.below(u64::from(bound)) as u32;
+    let mut deposits: Vec<Vec<u32>> = vec![Vec::new(); CLIENTS as usize];
+    let mut disputed: Vec<(ClientId, u32)> = Vec::new();
+    let mut rows = Vec::new();
+    for tx in 0..ROWS {
+        let client = random(CLIENTS);
+        let made = &mut deposits[client as usize];
+        let client = client as ClientId;
+        match random(10) {
+            0..=1 if !made.is_empty() => {
+                let tx = made[random(made.len() as u32) as usize];
+                disputed.push((client, tx));
+                rows.push(Transaction::Dispute {
+                    client,
+                    tx,
+                    timestamp: None,
+                });
+            }
+            2 if !disputed.is_empty() => {
+                let (client, tx) = disputed.swap_remove(random(disputed.len() as u32) as usize);
+                rows.push(match random(20) {
+                    0 => Transaction::Chargeback {
+                        client,
+                        tx,
+                        amount: None,
+                        timestamp: None,
+                    },
+                    _ => Transaction::Resolve {
+                        client,
+                        tx,
+                        amount: None,
+                        timestamp: None,
+                    },
+                });
+            }
+            3 => rows.push(Transaction::Withdrawal {
+                client,
+                tx,
+                amount: Decimal::new(i64::from(random(500)), 2),
+                currency: None,
+                timestamp: None,
+            }),
+            _ => {
+                made.push(tx);
+                let currency = (random(8) == 0).then(|| "EUR".parse().unwrap());
+                rows.push(Transaction::Deposit {
+                    client,
+                    tx,
+                    amount: Decimal::new(i64::from(random(100_000)) + 1, 2),
+                    currency,
+                    timestamp: None,
+                });
+            }
+        }
+    }
+    rows
+}
+
+fn run(
+    rows: &[Transaction],
+    spill: Option<&Arc<SpillFile>>,
+) -> (ShardedAccountSystem, Vec<TransactionOutcome>) {
+    let mut system = ShardedAccountSystem::new(4);
+    if let Some(file) = spill {
+        system.set_spill_file(file.clone());
+    }
+    let outcomes = rows
+        .iter()
+        .map(|row| system.transact(row.clone()))
+        .collect();
+    (system, outcomes)
+}
+
+fn spilled(system: &ShardedAccountSystem) -> usize {
+    system
+        .accounts()
+        .map(|(_, account)| account.deposits.spilled())
+        .sum()
+}
+
+#[test]
+/// Keeping a single deposit per account in memory gives the same outcomes and accounts as
+/// keeping them all, as does spilling everything that can be at the end
+fn spilling_changes_nothing() {
+    let rows = workload();
+    // Nothing is spilled without a spill file.
+    let (mut in_memory, expected) = run(&rows, None);
+    assert_eq!(spilled(&in_memory), 0);
+
+    let path = std::env::temp_dir().join(format!("track-spill-{}", std::process::id()));
+    let file = SpillFile::create(&path, Some(1)).unwrap();
+    let (spilling, outcomes) = run(&rows, Some(&file));
+    assert_eq!(outcomes, expected);
+    // What's still in memory are deposits under dispute, and those fetched back for a dispute
+    // since the account's last deposit. A chargeback locks the account, so that can be a while.
+    let stored: usize = spilling
+        .accounts()
+        .map(|(_, account)| account.deposits.len())
+        .sum();
+    assert!(
+        spilled(&spilling) * 2 > stored,
+        "{} of {}",
+        spilled(&spilling),
+        stored
+    );
+    assert!(spilling == in_memory);
+    assert!(spilling.memory_footprint() < in_memory.memory_footprint());
+
+    // A spill file of its own for the accounts that kept everything in memory so far.
+    let other = path.with_extension("other");
+    in_memory.set_spill_file(SpillFile::create(&other, None).unwrap());
+    assert!(in_memory.spill_deposits() > 0);
+    assert!(spilling == in_memory);
+    assert!(spilling.take_spill_error().is_none());
+    assert!(in_memory.take_spill_error().is_none());
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_file(other).unwrap();
+}